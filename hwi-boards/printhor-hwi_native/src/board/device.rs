@@ -45,6 +45,9 @@ cfg_if::cfg_if! {
 pub type TrinamicUart = crate::board::comm::SingleWireSoftwareUart;
 
 #[cfg(feature = "with-trinamic")]
+pub use crate::board::mocked_peripherals::MockedStall;
+
+#[cfg(feature = "trinamic-simulator")]
 pub use crate::board::mocked_peripherals::{MockedTrinamicDriver, trinamic_driver_simulator};
 
 #[cfg(feature = "with-trinamic")]
@@ -143,6 +146,35 @@ pub struct MotionPins {
     pub y_dir_pin: crate::board::mocked_peripherals::MockedIOPin,
     pub z_dir_pin: crate::board::mocked_peripherals::MockedIOPin,
     pub e_dir_pin: crate::board::mocked_peripherals::MockedIOPin,
+
+    // The secondary motors of the axes, each one with its own endstop
+    #[cfg(feature = "with-x2-axis")]
+    pub x2_enable_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-x2-axis")]
+    pub x2_endstop_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-x2-axis")]
+    pub x2_step_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-x2-axis")]
+    pub x2_dir_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-y2-axis")]
+    pub y2_enable_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-y2-axis")]
+    pub y2_endstop_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-y2-axis")]
+    pub y2_step_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-y2-axis")]
+    pub y2_dir_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-z2-axis")]
+    pub z2_enable_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-z2-axis")]
+    pub z2_endstop_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-z2-axis")]
+    pub z2_step_pin: crate::board::mocked_peripherals::MockedIOPin,
+    #[cfg(feature = "with-z2-axis")]
+    pub z2_dir_pin: crate::board::mocked_peripherals::MockedIOPin,
+
+    /// The (signed) steps done by the X, Y, Z and E motors since the start, to check the step generation
+    pub step_count: [i32; 4],
 }
 
 #[cfg(feature = "with-motion")]
@@ -181,6 +213,12 @@ impl MotionPins {
         self.y_enable_pin.set_high();
         self.z_enable_pin.set_high();
         self.e_enable_pin.set_high();
+        #[cfg(feature = "with-x2-axis")]
+        self.x2_enable_pin.set_high();
+        #[cfg(feature = "with-y2-axis")]
+        self.y2_enable_pin.set_high();
+        #[cfg(feature = "with-z2-axis")]
+        self.z2_enable_pin.set_high();
     }
     #[inline]
     pub fn enable_all_steppers(&mut self) {
@@ -188,6 +226,12 @@ impl MotionPins {
         self.y_enable_pin.set_low();
         self.z_enable_pin.set_low();
         self.e_enable_pin.set_low();
+        #[cfg(feature = "with-x2-axis")]
+        self.x2_enable_pin.set_low();
+        #[cfg(feature = "with-y2-axis")]
+        self.y2_enable_pin.set_low();
+        #[cfg(feature = "with-z2-axis")]
+        self.z2_enable_pin.set_low();
     }
 
     pub fn disable(&mut self, _channels: printhor_hwa_common::StepperChannel)
@@ -202,12 +246,66 @@ impl MotionPins {
 
     pub fn set_forward_direction(&mut self, _channels: printhor_hwa_common::StepperChannel)
     {
-
+        // The X, Y, Z and E bits of the channels, as of [printhor_hwa_common::StepperChannel]
+        for (i, dir_pin) in [
+            &mut self.x_dir_pin, &mut self.y_dir_pin, &mut self.z_dir_pin, &mut self.e_dir_pin,
+        ].into_iter().enumerate() {
+            match _channels.bits() & (1 << i) != 0 {
+                true => dir_pin.set_high(),
+                false => dir_pin.set_low(),
+            }
+        }
+        #[cfg(feature = "with-x2-axis")]
+        match _channels.contains(printhor_hwa_common::StepperChannel::X2) {
+            true => self.x2_dir_pin.set_high(),
+            false => self.x2_dir_pin.set_low(),
+        }
+        #[cfg(feature = "with-y2-axis")]
+        match _channels.contains(printhor_hwa_common::StepperChannel::Y2) {
+            true => self.y2_dir_pin.set_high(),
+            false => self.y2_dir_pin.set_low(),
+        }
+        #[cfg(feature = "with-z2-axis")]
+        match _channels.contains(printhor_hwa_common::StepperChannel::Z2) {
+            true => self.z2_dir_pin.set_high(),
+            false => self.z2_dir_pin.set_low(),
+        }
+        #[cfg(feature = "with-trinamic")]
+        crate::board::mocked_peripherals::MockedStall::set_forward_direction(_channels);
     }
 
+    /// One step of the motors in `_channels`, counted forward or backward as of their dir pins (but for the
+    /// secondary ones)
     pub fn step_toggle(&mut self, _channels: printhor_hwa_common::StepperChannel)
     {
-
+        for (i, (step_pin, dir_pin)) in [
+            (&mut self.x_step_pin, &self.x_dir_pin),
+            (&mut self.y_step_pin, &self.y_dir_pin),
+            (&mut self.z_step_pin, &self.z_dir_pin),
+            (&mut self.e_step_pin, &self.e_dir_pin),
+        ].into_iter().enumerate() {
+            if _channels.bits() & (1 << i) != 0 {
+                step_pin.toggle();
+                self.step_count[i] += match dir_pin.is_high() {
+                    true => 1,
+                    false => -1,
+                };
+            }
+        }
+        #[cfg(feature = "with-x2-axis")]
+        if _channels.contains(printhor_hwa_common::StepperChannel::X2) {
+            self.x2_step_pin.toggle();
+        }
+        #[cfg(feature = "with-y2-axis")]
+        if _channels.contains(printhor_hwa_common::StepperChannel::Y2) {
+            self.y2_step_pin.toggle();
+        }
+        #[cfg(feature = "with-z2-axis")]
+        if _channels.contains(printhor_hwa_common::StepperChannel::Z2) {
+            self.z2_step_pin.toggle();
+        }
+        #[cfg(feature = "with-trinamic")]
+        crate::board::mocked_peripherals::MockedStall::step(_channels);
     }
 
 
@@ -222,9 +320,17 @@ impl MotionPins {
     }
 
 
+    /// With Trinamic drivers, the endstops are the DIAG outputs of the simulated drivers (See [crate::board::mocked_peripherals::MockedStall])
     pub fn endstop_triggered(&mut self, _channels: printhor_hwa_common::StepperChannel) -> bool
     {
-        false
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "with-trinamic")] {
+                crate::board::mocked_peripherals::MockedStall::stalled(_channels)
+            }
+            else {
+                false
+            }
+        }
     }
 
 }
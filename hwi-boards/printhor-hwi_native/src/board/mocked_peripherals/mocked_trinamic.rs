@@ -1,12 +1,83 @@
+use core::sync::atomic::{AtomicI32, AtomicU8, Ordering};
+#[cfg(feature = "trinamic-simulator")]
 use embassy_time::Duration;
+use printhor_hwa_common::StepperChannel;
+#[cfg(feature = "trinamic-simulator")]
 use crate::board::{comm, TRINAMIC_UART_BAUD_RATE};
+#[cfg(feature = "trinamic-simulator")]
 use crate::device::AxisChannel;
 
+/// The bits (as of [StepperChannel]) of the simulated motors: X, Y and Z, then their secondary ones X2, Y2 and Z2
+const SIMULATED_MOTORS: [u8; 6] = [1 << 0, 1 << 1, 1 << 2, 1 << 4, 1 << 5, 1 << 6];
+
+/// The travel of the carriage moved by each simulated motor, in steps: 200mm with the default steps per mm
+const SIMULATED_TRAVEL_STEPS: [i32; 6] = [16_000, 16_000, 80_000, 16_000, 16_000, 80_000];
+
+/// The position of the carriage moved by each simulated motor, in steps from its min. end. They start in the
+/// middle, but for the one of Z2, 2mm higher than the one of Z (a racked gantry, squared when homing)
+static SIMULATED_POSITION: [AtomicI32; 6] = [
+    AtomicI32::new(8_000), AtomicI32::new(8_000), AtomicI32::new(40_000),
+    AtomicI32::new(8_000), AtomicI32::new(8_000), AtomicI32::new(40_800),
+];
+
+/// The bits (as of [StepperChannel]) of the simulated motors going forward
+static SIMULATED_FORWARD: AtomicU8 = AtomicU8::new(0);
+
+/// Simulates the stall of the motors of the carriages pushed against the frame, signaled by the DIAG output
+/// of their drivers, wired to the endstop pins (as in the SKR Mini E3). This is what the sensorless homing
+/// (StallGuard) waits for.
+pub struct MockedStall;
+
+impl MockedStall {
+    /// The motors in `channels` go forward, the others backward
+    pub fn set_forward_direction(channels: StepperChannel) {
+        SIMULATED_FORWARD.store(channels.bits(), Ordering::Relaxed);
+    }
+
+    /// One step of the motors in `channels`. A carriage does not go past the ends of its travel
+    pub fn step(channels: StepperChannel) {
+        let forward = SIMULATED_FORWARD.load(Ordering::Relaxed);
+        for (motor, position) in SIMULATED_POSITION.iter().enumerate() {
+            let bit = SIMULATED_MOTORS[motor];
+            if channels.bits() & bit == 0 {
+                continue;
+            }
+            let delta = if forward & bit != 0 { 1 } else { -1 };
+            let travel = SIMULATED_TRAVEL_STEPS[motor];
+            let _ = position.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |p| {
+                Some((p + delta).clamp(0, travel))
+            });
+        }
+    }
+
+    /// The position of the carriage of the `motor` (0 for X to 2 for Z, 3 for X2 to 5 for Z2), in steps
+    /// from its min. end
+    pub fn position(motor: usize) -> i32 {
+        SIMULATED_POSITION[motor].load(Ordering::Relaxed)
+    }
+
+    /// Whether the motor of any of the `channels` is stalled: Its carriage is at an end of its travel
+    /// and still pushed towards it
+    pub fn stalled(channels: StepperChannel) -> bool {
+        let forward = SIMULATED_FORWARD.load(Ordering::Relaxed);
+        SIMULATED_POSITION.iter().enumerate().any(|(motor, position)| {
+            let bit = SIMULATED_MOTORS[motor];
+            let position = position.load(Ordering::Relaxed);
+            channels.bits() & bit != 0 && match forward & bit != 0 {
+                true => position >= SIMULATED_TRAVEL_STEPS[motor],
+                false => position <= 0,
+            }
+        })
+    }
+}
+
+#[cfg(feature = "trinamic-simulator")]
 #[allow(unused)]
 pub struct MockedTrinamicDriver {
     pub(crate) uart_trinamic: comm::SingleWireSoftwareUart
 }
 
+#[cfg(feature = "trinamic-simulator")]
 impl MockedTrinamicDriver {
     pub(crate) fn new(x_rxtx_pin: crate::board::MockedIOPin, y_rxtx_pin: crate::board::MockedIOPin, z_rxtx_pin: crate::board::MockedIOPin, e_rxtx_pin: crate::board::MockedIOPin) -> Self {
         let uart_trinamic = comm::SingleWireSoftwareUart::new(
@@ -20,6 +91,7 @@ impl MockedTrinamicDriver {
     }
 }
 
+#[cfg(feature = "trinamic-simulator")]
 #[embassy_executor::task(pool_size=1)]
 pub async fn trinamic_driver_simulator(mut driver: MockedTrinamicDriver) {
 
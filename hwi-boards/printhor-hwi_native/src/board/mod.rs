@@ -210,7 +210,7 @@ pub async fn setup(_spawner: Spawner, _p: HWIPeripherals) -> MachineContext<Cont
         )
     };
 
-    #[cfg(all(feature = "with-trinamic"))]
+    #[cfg(all(feature = "trinamic-simulator"))]
     {
         #[link_section = "__DATA,.bss"]
         static EXECUTOR: printhor_hwa_common::TrackedStaticCell<embassy_executor::Executor> = printhor_hwa_common::TrackedStaticCell::new();
@@ -276,6 +276,31 @@ pub async fn setup(_spawner: Spawner, _p: HWIPeripherals) -> MachineContext<Cont
             y_dir_pin: MockedIOPin::new(17, _pin_state),
             z_dir_pin: MockedIOPin::new(18, _pin_state),
             e_dir_pin: MockedIOPin::new(19, _pin_state),
+            #[cfg(feature = "with-x2-axis")]
+            x2_enable_pin: MockedIOPin::new(30, _pin_state),
+            #[cfg(feature = "with-x2-axis")]
+            x2_endstop_pin: MockedIOPin::new(31, _pin_state),
+            #[cfg(feature = "with-x2-axis")]
+            x2_step_pin: MockedIOPin::new(32, _pin_state),
+            #[cfg(feature = "with-x2-axis")]
+            x2_dir_pin: MockedIOPin::new(33, _pin_state),
+            #[cfg(feature = "with-y2-axis")]
+            y2_enable_pin: MockedIOPin::new(34, _pin_state),
+            #[cfg(feature = "with-y2-axis")]
+            y2_endstop_pin: MockedIOPin::new(35, _pin_state),
+            #[cfg(feature = "with-y2-axis")]
+            y2_step_pin: MockedIOPin::new(36, _pin_state),
+            #[cfg(feature = "with-y2-axis")]
+            y2_dir_pin: MockedIOPin::new(37, _pin_state),
+            #[cfg(feature = "with-z2-axis")]
+            z2_enable_pin: MockedIOPin::new(38, _pin_state),
+            #[cfg(feature = "with-z2-axis")]
+            z2_endstop_pin: MockedIOPin::new(39, _pin_state),
+            #[cfg(feature = "with-z2-axis")]
+            z2_step_pin: MockedIOPin::new(40, _pin_state),
+            #[cfg(feature = "with-z2-axis")]
+            z2_dir_pin: MockedIOPin::new(41, _pin_state),
+            step_count: [0; 4],
         }
     };
     #[cfg(feature = "with-motion")]
@@ -415,6 +415,14 @@ pub async fn setup(_spawner: Spawner, p: embassy_stm32::Peripherals) -> printhor
             z_dir_pin: Output::new(p.PC5, Level::Low, Speed::VeryHigh),
             #[cfg(feature = "with-e-axis")]
             e_dir_pin: Output::new(p.PB4, Level::Low, Speed::VeryHigh),
+            #[cfg(feature = "with-z2-axis")]
+            z2_enable_pin: Output::new(p.PD1, Level::High, Speed::VeryHigh),
+            #[cfg(feature = "with-z2-axis")]
+            z2_endstop_pin: Input::new(p.PC15, Pull::Down),
+            #[cfg(feature = "with-z2-axis")]
+            z2_step_pin: Output::new(p.PB3, Level::Low, Speed::VeryHigh),
+            #[cfg(feature = "with-z2-axis")]
+            z2_dir_pin: Output::new(p.PB4, Level::Low, Speed::VeryHigh),
         }
     };
     #[cfg(feature = "with-motion")]
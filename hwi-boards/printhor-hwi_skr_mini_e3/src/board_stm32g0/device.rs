@@ -190,6 +190,16 @@ cfg_if::cfg_if! {
             pub z_dir_pin: embassy_stm32::gpio::Output<'static>,
             #[cfg(feature = "with-e-axis")]
             pub e_dir_pin: embassy_stm32::gpio::Output<'static>,
+
+            // The secondary Z motor, on the E driver (with E0-STOP as its endstop)
+            #[cfg(feature = "with-z2-axis")]
+            pub z2_enable_pin: embassy_stm32::gpio::Output<'static>,
+            #[cfg(feature = "with-z2-axis")]
+            pub z2_endstop_pin: embassy_stm32::gpio::Input<'static>,
+            #[cfg(feature = "with-z2-axis")]
+            pub z2_step_pin: embassy_stm32::gpio::Output<'static>,
+            #[cfg(feature = "with-z2-axis")]
+            pub z2_dir_pin: embassy_stm32::gpio::Output<'static>,
         }
     }
 }
@@ -236,6 +246,8 @@ impl MotionPins {
         self.z_enable_pin.set_high();
         #[cfg(feature = "with-hot-end")]
         self.e_enable_pin.set_high();
+        #[cfg(feature = "with-z2-axis")]
+        self.z2_enable_pin.set_high();
     }
     #[inline]
     pub fn enable_all_steppers(&mut self) {
@@ -244,6 +256,8 @@ impl MotionPins {
         self.z_enable_pin.set_low();
         #[cfg(feature = "with-hot-end")]
         self.e_enable_pin.set_low();
+        #[cfg(feature = "with-z2-axis")]
+        self.z2_enable_pin.set_low();
     }
 
     pub fn disable(&mut self, _channels: printhor_hwa_common::StepperChannel)
@@ -276,6 +290,13 @@ impl MotionPins {
         else {
             self.e_enable_pin.set_low();
         }
+        #[cfg(feature = "with-z2-axis")]
+        if _channels.contains(printhor_hwa_common::StepperChannel::Z2) {
+            self.z2_enable_pin.set_high();
+        }
+        else {
+            self.z2_enable_pin.set_low();
+        }
     }
 
     pub fn enable(&mut self, _channels: printhor_hwa_common::StepperChannel)
@@ -308,6 +329,13 @@ impl MotionPins {
         else {
             self.e_enable_pin.set_high();
         }
+        #[cfg(feature = "with-z2-axis")]
+        if _channels.contains(printhor_hwa_common::StepperChannel::Z2) {
+            self.z2_enable_pin.set_low();
+        }
+        else {
+            self.z2_enable_pin.set_high();
+        }
     }
 
     pub fn set_forward_direction(&mut self, _channels: printhor_hwa_common::StepperChannel)
@@ -340,6 +368,13 @@ impl MotionPins {
         else {
             self.e_dir_pin.set_low();
         }
+        #[cfg(feature = "with-z2-axis")]
+        if _channels.contains(printhor_hwa_common::StepperChannel::Z2) {
+            self.z2_dir_pin.set_high();
+        }
+        else {
+            self.z2_dir_pin.set_low();
+        }
     }
 
     pub fn step_toggle(&mut self, _channels: printhor_hwa_common::StepperChannel)
@@ -360,6 +395,10 @@ impl MotionPins {
         if _channels.contains(printhor_hwa_common::StepperChannel::E) {
             self.e_step_pin.toggle();
         }
+        #[cfg(feature = "with-z2-axis")]
+        if _channels.contains(printhor_hwa_common::StepperChannel::Z2) {
+            self.z2_step_pin.toggle();
+        }
     }
 
 
@@ -381,6 +420,10 @@ impl MotionPins {
         if _channels.contains(printhor_hwa_common::StepperChannel::E) {
             self.e_step_pin.set_high();
         }
+        #[cfg(feature = "with-z2-axis")]
+        if _channels.contains(printhor_hwa_common::StepperChannel::Z2) {
+            self.z2_step_pin.set_high();
+        }
     }
 
     pub fn step_low(&mut self, _channels: printhor_hwa_common::StepperChannel)
@@ -401,6 +444,10 @@ impl MotionPins {
         if _channels.contains(printhor_hwa_common::StepperChannel::E) {
             self.e_step_pin.set_low();
         }
+        #[cfg(feature = "with-z2-axis")]
+        if _channels.contains(printhor_hwa_common::StepperChannel::Z2) {
+            self.z2_step_pin.set_low();
+        }
     }
 
 
@@ -420,6 +467,10 @@ impl MotionPins {
         if _channels.contains(printhor_hwa_common::StepperChannel::Z) {
             triggered |= self.z_endstop_pin.is_high();
         }
+        #[cfg(feature = "with-z2-axis")]
+        if _channels.contains(printhor_hwa_common::StepperChannel::Z2) {
+            triggered |= self.z2_endstop_pin.is_high();
+        }
         triggered
     }
 }
@@ -3,6 +3,12 @@
 pub use defmt::{trace, debug, info, warn, error};
 pub use defmt;
 
+// The board has four drivers: A secondary Z motor takes the one of E (and E0-STOP as its endstop)
+#[cfg(all(feature = "with-z2-axis", feature = "with-e-axis"))]
+compile_error!("with-z2-axis drives Z2 with the E driver, so it is not available with with-e-axis");
+#[cfg(any(feature = "with-x2-axis", feature = "with-y2-axis"))]
+compile_error!("No driver left for X2 or Y2: Only with-z2-axis is supported");
+
 cfg_if::cfg_if! {
     if #[cfg(feature="skr_mini_e3_v3")] {
         //#region SKR Mini E3 V3 (STM32G0 MCU) setup and reexports
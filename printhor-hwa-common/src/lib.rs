@@ -259,6 +259,8 @@ bitflags! {
     /// - `Y`: Represents the Y-axis stepper channel. This flag is only available if the `with-y-axis` feature is enabled.
     /// - `Z`: Represents the Z-axis stepper channel. This flag is only available if the `with-z-axis` feature is enabled.
     /// - `E`: Represents the E-axis stepper channel. This flag is only available if the `with-e-axis` feature is enabled.
+    /// - `X2`, `Y2`, `Z2`: Represent the secondary motors of the X, Y and Z axes, mirrored from the primary one. These
+    ///   flags are only available if the `with-x2-axis`, `with-y2-axis` and `with-z2-axis` features are enabled.
     /// - `UNSET`: Represents an unset or undefined channel. This flag is always available.
     ///
     /// Example usage:
@@ -299,7 +301,33 @@ bitflags! {
         /// Represents the E-axis stepper channel. This flag is only available if the `with-e-axis` feature is enabled.
         #[cfg(feature = "with-e-axis")]
         const E    = 0b00001000;
+        /// Represents the secondary X-axis motor. This flag is only available if the `with-x2-axis` feature is enabled.
+        #[cfg(feature = "with-x2-axis")]
+        const X2   = 0b00010000;
+        /// Represents the secondary Y-axis motor. This flag is only available if the `with-y2-axis` feature is enabled.
+        #[cfg(feature = "with-y2-axis")]
+        const Y2   = 0b00100000;
+        /// Represents the secondary Z-axis motor. This flag is only available if the `with-z2-axis` feature is enabled.
+        #[cfg(feature = "with-z2-axis")]
+        const Z2   = 0b01000000;
         /// Represents an unset or undefined channel. This flag is always available.
         const UNSET  = 0b10000000;
     }
 }
+
+impl StepperChannel {
+    /// The channels with the secondary motors of the primary ones added, as they move together.
+    ///
+    /// Example: `StepperChannel::Z.mirrored()` is `StepperChannel::Z | StepperChannel::Z2` with the `with-z2-axis` feature.
+    pub fn mirrored(self) -> Self {
+        #[allow(unused_mut)]
+        let mut channels = self;
+        #[cfg(feature = "with-x2-axis")]
+        channels.set(StepperChannel::X2, self.contains(StepperChannel::X) || self.contains(StepperChannel::X2));
+        #[cfg(feature = "with-y2-axis")]
+        channels.set(StepperChannel::Y2, self.contains(StepperChannel::Y) || self.contains(StepperChannel::Y2));
+        #[cfg(feature = "with-z2-axis")]
+        channels.set(StepperChannel::Z2, self.contains(StepperChannel::Z) || self.contains(StepperChannel::Z2));
+        channels
+    }
+}
@@ -17,11 +17,12 @@ use rust_decimal_macros::dec;
 use crate::tgeo::{TVector, CoordSel};
 use crate::control::motion::*;
 use crate::hwa::controllers::motion;
+use crate::hwa::controllers::motion::Kinematics;
 use crate::hwa::device::MotionDevice;
 use crate::hwa::drivers::{MotionDriver, MotionDriverParams};
 use crate::math::{RealInclusiveRange, TWO, ZERO};
 use crate::hwa::CommChannel;
-use crate::control::{GCodeCmd, GCodeValue, XYZF};
+use crate::control::{GCodeCmd, GCodeParams, GCodeValue};
 use crate::hwa::controllers::SoftTimer;
 use crate::hwa::controllers::LinearMicrosegmentStepInterpolator;
 use crate::hwa::controllers::StepPlanner;
@@ -217,6 +218,20 @@ async fn main(spawner: embassy_executor::Spawner)
     motion_planner.set_steps_per_mm(math::Real::new(10, 0), math::Real::new(10, 0), math::Real::new(10, 0), math::Real::new(10, 0)).await;
     motion_planner.set_usteps(16, 16, 16, 16).await;
 
+    // Cornering model: The junction deviation (mm) is given as first argument.
+    // When omitted (or zero), the projection rule is used, so both models can be compared.
+    let junction_deviation = std::env::args()
+        .nth(1)
+        .and_then(|arg| arg.parse::<f32>().ok())
+        .map(Real::from_f32)
+        .unwrap_or(math::ZERO);
+    motion_planner.set_junction_deviation(junction_deviation).await;
+    let cornering_model = if junction_deviation.is_defined_positive() {
+        format!("junction deviation {} mm", junction_deviation)
+    } else {
+        String::from("projection")
+    };
+
     motion_planner.start(&event_bus).await;
 
     {
@@ -240,10 +255,9 @@ async fn main(spawner: embassy_executor::Spawner)
         CommChannel::Internal,
         &GCodeCmd::new(
             1, Some(1),
-            GCodeValue::G0(XYZF {
-                x: None, y: Some(Real::from_f32(100.0f32)), z: None,
-                f: Some(Real::from_f32(2000.0f32)),
-            })
+            GCodeValue::G0
+        ).with_params(
+            GCodeParams::new().with_real('y', Real::from_f32(100.0f32)).with_real('f', Real::from_f32(2000.0f32))
         ),
         false, &event_bus,
     ).await;
@@ -252,10 +266,9 @@ async fn main(spawner: embassy_executor::Spawner)
         CommChannel::Internal,
         &GCodeCmd::new(
             2, Some(2),
-            GCodeValue::G0(XYZF {
-                x: None, y: Some(Real::from_f32(0.0f32)), z: None,
-                f: Some(Real::from_f32(2000.0f32))
-            })
+            GCodeValue::G0
+        ).with_params(
+            GCodeParams::new().with_real('y', Real::from_f32(0.0f32)).with_real('f', Real::from_f32(2000.0f32))
         ),
         false, &event_bus,
     ).await;
@@ -267,10 +280,9 @@ async fn main(spawner: embassy_executor::Spawner)
         CommChannel::Internal,
         &GCodeCmd::new(
             3, Some(3),
-            GCodeValue::G0(XYZF {
-                x: Some(Real::from_f32(1.0f32)), y: None, z: None,
-                f:Some(Real::from_f32(2000.0f32)),
-            })
+            GCodeValue::G0
+        ).with_params(
+            GCodeParams::new().with_real('x', Real::from_f32(1.0f32)).with_real('f', Real::from_f32(2000.0f32))
         ),
         false, &event_bus,
     ).await;
@@ -280,10 +292,9 @@ async fn main(spawner: embassy_executor::Spawner)
         CommChannel::Internal,
         &GCodeCmd::new(
             5, Some(5),
-            GCodeValue::G0(XYZF {
-                x: Some(Real::from_f32(2.0f32)), y: None, z: None,
-                f:Some(Real::from_f32(2000.0f32))
-            })
+            GCodeValue::G0
+        ).with_params(
+            GCodeParams::new().with_real('x', Real::from_f32(2.0f32)).with_real('f', Real::from_f32(2000.0f32))
         ),
         false, &event_bus,
     ).await;
@@ -293,10 +304,9 @@ async fn main(spawner: embassy_executor::Spawner)
         CommChannel::Internal,
         &GCodeCmd::new(
             6, Some(6),
-            GCodeValue::G0(XYZF {
-                x: Some(Real::from_f32(3.0f32)), y: None, z: None,
-                f:Some(Real::from_f32(2000.0f32)),
-            })
+            GCodeValue::G0
+        ).with_params(
+            GCodeParams::new().with_real('x', Real::from_f32(3.0f32)).with_real('f', Real::from_f32(2000.0f32))
         ),
         false, &event_bus,
     ).await;
@@ -305,10 +315,9 @@ async fn main(spawner: embassy_executor::Spawner)
         CommChannel::Internal,
         &GCodeCmd::new(
             7, Some(7),
-            GCodeValue::G0(XYZF {
-                x: Some(Real::from_f32(4.0f32)), y: None, z: None,
-                f:Some(Real::from_f32(2000.0f32)),
-            })
+            GCodeValue::G0
+        ).with_params(
+            GCodeParams::new().with_real('x', Real::from_f32(4.0f32)).with_real('f', Real::from_f32(2000.0f32))
         ),
         false, &event_bus,
     ).await;
@@ -317,10 +326,9 @@ async fn main(spawner: embassy_executor::Spawner)
         CommChannel::Internal,
         &GCodeCmd::new(
             8, Some(8),
-            GCodeValue::G0(XYZF {
-                x: Some(Real::from_f32(5.0f32)), y: None, z: None,
-                f:Some(Real::from_f32(2000.0f32)),
-            })
+            GCodeValue::G0
+        ).with_params(
+            GCodeParams::new().with_real('x', Real::from_f32(5.0f32)).with_real('f', Real::from_f32(2000.0f32))
         ),
         false, &event_bus,
     ).await;
@@ -335,7 +343,13 @@ async fn main(spawner: embassy_executor::Spawner)
                 s_id += 1;
                 hwa::debug!("Dequeuing move at t_ref={}", ref_time);
                 data_points.seg_start(ref_time, total_disp, segment.segment_data.speed_enter_mms);
-                let neutral_element = segment.segment_data.unit_vector_dir.map_val(&math::ZERO);
+                let kinematics = motion_planner.motion_cfg().lock().await.kinematics;
+                let motor_vector_dir = kinematics.motor_direction(
+                    &segment.segment_data.dest_pos,
+                    &segment.segment_data.unit_vector_dir,
+                    segment.segment_data.displacement_mm,
+                );
+                let neutral_element = motor_vector_dir.map_val(&math::ZERO);
 
                 match SCurveMotionProfile::compute(segment.segment_data.displacement_mm, segment.segment_data.speed_enter_mms, segment.segment_data.speed_exit_mms,
                                                    &segment.segment_data.constraints, false) {
@@ -354,7 +368,7 @@ async fn main(spawner: embassy_executor::Spawner)
                         let mut microsegment_iterator = motion::SegmentIterator::new(&motion_profile, math::ZERO);
 
                         let mut microsegment_interpolator = LinearMicrosegmentStepInterpolator::new(
-                            segment.segment_data.unit_vector_dir.abs(),
+                            motor_vector_dir.abs(),
                             segment.segment_data.displacement_mm,
                             mm_per_unit,
                         );
@@ -522,8 +536,9 @@ async fn main(spawner: embassy_executor::Spawner)
         fg.set_multiplot_layout(2, 1)
             .set_title(
                 format!(
-                    "{} Double S-Curve velocity profile\n[{} segments, {} mm displacement, {} hz interp, {} hz sampling]",
+                    "{} Double S-Curve velocity profile ({} cornering)\n[{} segments, {} mm displacement, {} hz interp, {} hz sampling]",
                     MATH_PRECISION,
+                    cornering_model,
                     s_id,
                     total_disp.rdp(6),
                     STEPPER_PLANNER_MICROSEGMENT_FREQUENCY,
@@ -32,5 +32,7 @@ pub enum CodeExecutionFailure {
     NumericalError,
     /// The GCode is considered, but not yet implemented
     NotYetImplemented,
+    /// Cannot perform because the move would end out of the soft endstops
+    OutOfBounds,
 }
 pub type CodeExecutionResult = Result<CodeExecutionSuccess, CodeExecutionFailure>;
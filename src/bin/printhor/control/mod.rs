@@ -1,4 +1,3 @@
-use crate::math::Real;
 #[allow(unused)]
 use crate::hwa;
 
@@ -15,9 +14,17 @@ mod processing;
 pub use processing::*;
 
 mod base;
+mod params;
+pub use params::*;
 pub mod task_control;
 #[cfg(feature = "with-motion")]
 pub mod task_defer;
+#[cfg(any(
+    feature = "with-serial-usb",
+    feature = "with-serial-port-1",
+    feature = "with-serial-port-2"
+))]
+pub mod task_input_scan;
 #[cfg(any(test, feature = "integration-test"))]
 pub mod task_integration;
 #[cfg(feature = "with-printjob")]
@@ -27,143 +34,6 @@ pub mod task_stepper;
 #[cfg(any(feature = "with-hot-end", feature = "with-hot-bed"))]
 pub mod task_temperature;
 
-#[allow(dead_code)]
-#[derive(Clone, Debug)]
-pub struct S {
-    pub s: Option<Real>,
-}
-
-impl S {
-    pub const fn new() -> Self {
-        Self {
-            s: None,
-        }
-    }
-}
-
-#[allow(dead_code)]
-#[derive(Clone, Debug)]
-pub struct N {
-    pub n: Option<Real>,
-}
-
-impl N {
-    pub const fn new() -> Self {
-        Self {
-            n: None,
-        }
-    }
-}
-
-#[allow(dead_code)]
-#[derive(Clone, Debug)]
-pub struct XYZE {
-    pub x: Option<Real>,
-    pub y: Option<Real>,
-    pub z: Option<Real>,
-    pub e: Option<Real>,
-}
-
-impl XYZE {
-    pub const fn new() -> Self {
-        Self {
-            x: None,
-            y: None,
-            z: None,
-            e: None,
-        }
-    }
-}
-
-#[cfg(feature = "with-defmt")]
-impl defmt::Format for XYZE {
-    fn format(&self, fmt: defmt::Formatter) {
-        defmt::write!(fmt, "X_Y_Z_E")
-    }
-}
-
-#[derive(Clone, Debug)]
-pub struct XYZF {
-    pub x: Option<Real>,
-    pub y: Option<Real>,
-    pub z: Option<Real>,
-    pub f: Option<Real>,
-}
-
-impl XYZF {
-    pub const fn new() -> Self {
-        Self {
-            x: None,
-            y: None,
-            z: None,
-            f: None,
-        }
-    }
-}
-
-#[cfg(feature = "with-defmt")]
-impl defmt::Format for XYZF {
-    fn format(&self, fmt: defmt::Formatter) {
-        defmt::write!(fmt, "X_Y_Z_F")
-    }
-}
-
-#[cfg(feature = "native")]
-impl core::fmt::Display for XYZF {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        core::write!(
-            f,
-            "X {} Y {} Z {} F {}",
-            self.x.map_or_else(|| "NaN".to_string(), |v| v.to_string()),
-            self.y.map_or_else(|| "NaN".to_string(), |v| v.to_string()),
-            self.z.map_or_else(|| "NaN".to_string(), |v| v.to_string()),
-            self.f.map_or_else(|| "NaN".to_string(), |v| v.to_string()),
-        )
-    }
-}
-
-#[allow(dead_code)]
-#[derive(Clone, Default, Debug)]
-pub struct XYZEFS {
-    pub x: Option<Real>,
-    pub y: Option<Real>,
-    pub z: Option<Real>,
-    pub e: Option<Real>,
-    pub f: Option<Real>,
-    pub s: Option<Real>,
-}
-
-impl XYZEFS {
-    pub const fn new() -> Self {
-        Self {
-            x: None,
-            y: None,
-            z: None,
-            e: None,
-            f: None,
-            s: None,
-        }
-    }
-    #[allow(unused)]
-    pub fn with_x(&self, pos: i32) -> Self {
-        Self {
-            e: self.e,
-            f: self.f,
-            s: self.s,
-            x: Some(Real::from_lit(pos as i64, 0)),
-            y: self.y,
-            z: self.z,
-        }
-    }
-}
-
-#[cfg(feature = "with-defmt")]
-impl defmt::Format for XYZEFS {
-    fn format(&self, fmt: crate::hwa::defmt::Formatter) {
-        defmt::write!(fmt, "XYZEFS")
-    }
-}
-
 #[derive(Debug)]
 pub struct GCodeCmd {
     /// The gcode sequential number as coming from parser
@@ -171,7 +41,9 @@ pub struct GCodeCmd {
     /// The line number tagged in the gcode (if any)
     pub line_tag: Option<u32>,
     /// The gcode variant
-    pub value: GCodeValue
+    pub value: GCodeValue,
+    /// The words (arguments) of the gcode
+    pub params: GCodeParams,
 }
 
 impl GCodeCmd {
@@ -180,6 +52,15 @@ impl GCodeCmd {
             order_num: num,
             line_tag: line,
             value,
+            params: GCodeParams::new(),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn with_params(self, params: GCodeParams) -> Self {
+        Self {
+            params,
+            ..self
         }
     }
 }
@@ -198,8 +79,9 @@ impl core::fmt::Display for GCodeCmd {
     }
 }
 
+/// The gcode variant, as identified by its mnemonic. Arguments are kept apart in [GCodeParams]
 //noinspection SpellCheckingInspection
-#[derive(Clone, VariantNames, AsRefStr, Default, Debug)]
+#[derive(Clone, Copy, PartialEq, VariantNames, AsRefStr, Default, Debug)]
 #[cfg_attr(feature = "native", derive(Display))]
 pub enum GCodeValue {
     /// No Operation
@@ -216,25 +98,40 @@ pub enum GCodeValue {
     /// List supported G-Codes
     G,
     /// Rapid move
-    G0(XYZF),
+    G0,
 
     /// Linear move
-    G1(XYZEFS),
+    G1,
+    /// Clockwise arc (or helix) move
+    G2,
+    /// Counter-clockwise arc (or helix) move
+    G3,
 
     /// Dwell
     G4,
-    /// Set coordinate system data
+    /// Cubic Bezier spline move
+    G5,
+    /// Set coordinate system data or retract (firmware retraction)
     G10,
-    G11, // retraction
+    /// Recover (firmware retraction)
+    G11,
+    /// Select XY plane for arcs
     G17,
+    /// Select ZX plane for arcs
     G18,
-    G19, // CNC Plane selection
-    G21, // Settings
+    /// Select YZ plane for arcs
+    G19,
+    /// Set units to inches
+    G20,
+    /// Set units to millimeters
+    G21,
+    /// Retract (firmware retraction)
     G22,
-    G23, // Retraction
+    /// Recover (firmware retraction)
+    G23,
 
-    /// Move to Origin (Home)
-    G28(XYZE),
+    /// Move to Origin (Home) the given axes (X, Y, Z), or all of them if none
+    G28,
     /// Detailed Z-Probe
     G29,
     /// Set Z probe head offset
@@ -249,6 +146,8 @@ pub enum GCodeValue {
     G31,
     /// Undock Sled
     G32,
+    /// Align the Z motors probing the bed at the point of each one (See M422), then home Z
+    G34,
     #[strum(serialize = "G38.2")]
     G38_2,
     #[strum(serialize = "G38.3")]
@@ -257,6 +156,20 @@ pub enum GCodeValue {
     G38_4,
     #[strum(serialize = "G38.5")]
     G38_5,
+    /// Move in machine coordinates
+    G53,
+    /// Select work coordinate system 1
+    G54,
+    /// Select work coordinate system 2
+    G55,
+    /// Select work coordinate system 3
+    G56,
+    /// Select work coordinate system 4
+    G57,
+    /// Select work coordinate system 5
+    G58,
+    /// Select work coordinate system 6
+    G59,
     G80,
     G81,
     G82, // Probing
@@ -265,7 +178,7 @@ pub enum GCodeValue {
     /// Set to Relative Positioning
     G91,
     /// Set position
-    G92(XYZE),
+    G92,
     #[strum(serialize = "G92.1")]
     G92_1,
     #[strum(serialize = "G92.2")]
@@ -292,11 +205,11 @@ pub enum GCodeValue {
     M17,
     M18, // Stepper motors
     /// List SD
-    M20(Option<alloc::string::String>),
+    M20,
     M21,
     M22,
     /// Select SD file
-    M23(Option<alloc::string::String>),
+    M23,
     /// Start/resume SD print
     M24,
     /// Pause SD print
@@ -322,19 +235,25 @@ pub enum GCodeValue {
     /// Disable steppers
     M84,
     M92,
+    /// Call a macro (a gcode file of the SD card)
+    M98,
+    /// Return from a macro
+    M99,
     /// Show memory usage
     M100,
     /// Set Hotend Temperature
-    M104(S),
+    M104,
     /// Get Hotend and/or Hotbed Temperature
     M105,
     /// Fan On
     M106,
     /// Fan Off
     M107,
+    /// Break out of the heating waits
+    M108,
     /// Wait for hotend temp
-    M109(S),
-    M110(N), // Settings
+    M109,
+    M110, // Settings
     /// Debug level
     M111,
     /// Full emergency stop
@@ -354,7 +273,7 @@ pub enum GCodeValue {
     M120,
     M121, // Endstops get/set
     /// Set hotbed temperature
-    M140(S),
+    M140,
     /// Wait for hotbed temperature
     M190,
     M200,
@@ -368,18 +287,26 @@ pub enum GCodeValue {
     M205,
     /// Set Home Offsets
     M206,
+    /// Set firmware retraction
     M207,
+    /// Set firmware recovery
     M208,
+    /// Set automatic retraction
     M209,
+    /// Set Homing Settings
     M210,
+    /// Enable (S1) or disable (S0) the soft endstops, clamping (C1) or rejecting (C0) the moves out of them
     M211,
     M212,
     M218, // Settings
-    /// Set Feedrate percentage
-    M220(S),
-    /// Set Flow Percentage
-    M221(S),
-    M290, // Babystepping
+    /// Set Feedrate percentage (S). Applied to the queued moves as well. B saves it and R restores it
+    M220,
+    /// Set Flow Percentage (S). Applied to the moves queued from now on
+    M221,
+    /// Set Curve Approximation
+    M229,
+    /// Babystepping
+    M290,
     M302,
     M305,
     M350,
@@ -390,8 +317,12 @@ pub enum GCodeValue {
     M402, // Probing
     M404,
     M407, // Settings
-    M410, // quick stop
-    M422, // Probe point
+    /// Quick stop
+    M410,
+    /// Set the point (X, Y) probed for the Z motor S (1 or 2) when aligning them (G34)
+    M422,
+    /// Backlash Compensation
+    M425,
     M450,
     M451,
     M452,
@@ -410,6 +341,13 @@ pub enum GCodeValue {
     M524,
     M555,
     M563,
+    /// Set input shaping
+    M593,
+    /// Set delta, SCARA or polar geometry
+    M665,
+    /// Set delta endstop adjustments
+    M666,
+    /// Set the offset of the probe from the tool
     M851,
     /// Report the status of position encoder modules.
     #[strum(serialize = "M862.1")]
@@ -424,6 +362,8 @@ pub enum GCodeValue {
     M900,
     /// Set motor current
     M907,
+    /// Set the StallGuard threshold (X, Y, Z) of the sensorless homing: 0 to 255, the higher the more sensitive
+    M914,
     M929, // Logging
 }
 
@@ -0,0 +1,170 @@
+//! The generic word (parameter) model of a gcode.
+//!
+//! Every word following the gcode mnemonic (`X10.5`, `F1200`, `P"file.g"`, ...) is kept
+//! as a (letter, value) pair so the handlers can query what they need, regardless of the variant.
+use crate::math::Real;
+#[cfg(feature = "with-motion")]
+use crate::tgeo::TVector;
+
+#[cfg(feature = "with-defmt")]
+use crate::hwa::defmt;
+
+/// The value of a gcode word
+#[derive(Clone, Debug)]
+pub enum GCodeParamValue {
+    /// A numeric value. For instance: `X10.5`
+    Number(Real),
+    /// A string value. For instance: `M23 F"file.g"`
+    Text(alloc::string::String),
+    /// The letter was given without value. For instance: `G28 X`
+    Flag,
+}
+
+/// The set of words of a gcode, indexed by (lowercase) letter
+#[derive(Clone, Debug, Default)]
+pub struct GCodeParams {
+    words: alloc::vec::Vec<(char, GCodeParamValue)>,
+}
+
+#[allow(unused)]
+impl GCodeParams {
+    pub const fn new() -> Self {
+        Self {
+            words: alloc::vec::Vec::new(),
+        }
+    }
+
+    /// Sets the value of a letter, replacing the previous one if it was already given
+    pub fn set(&mut self, letter: char, value: GCodeParamValue) {
+        let letter = letter.to_ascii_lowercase();
+        match self.words.iter_mut().find(|(l, _)| *l == letter) {
+            Some((_, v)) => *v = value,
+            None => self.words.push((letter, value)),
+        }
+    }
+
+    pub fn with_real(mut self, letter: char, value: Real) -> Self {
+        self.set(letter, GCodeParamValue::Number(value));
+        self
+    }
+
+    pub fn with_string(mut self, letter: char, value: &str) -> Self {
+        self.set(letter, GCodeParamValue::Text(alloc::string::String::from(value)));
+        self
+    }
+
+    pub fn with_flag(mut self, letter: char) -> Self {
+        self.set(letter, GCodeParamValue::Flag);
+        self
+    }
+
+    pub fn get(&self, letter: char) -> Option<&GCodeParamValue> {
+        let letter = letter.to_ascii_lowercase();
+        self.words.iter().find(|(l, _)| *l == letter).map(|(_, v)| v)
+    }
+
+    /// True when the letter was given, with or without value
+    pub fn has(&self, letter: char) -> bool {
+        self.get(letter).is_some()
+    }
+
+    /// The numeric value of a letter
+    pub fn real(&self, letter: char) -> Option<Real> {
+        match self.get(letter) {
+            Some(GCodeParamValue::Number(v)) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// The numeric value of a letter, as integer (See [Real::to_i32]: Truncated, but rounded with fixed point)
+    pub fn int(&self, letter: char) -> Option<i32> {
+        self.real(letter).and_then(|v| v.to_i32())
+    }
+
+    /// The string value of a letter
+    pub fn string(&self, letter: char) -> Option<&str> {
+        match self.get(letter) {
+            Some(GCodeParamValue::Text(s)) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    /// A copy with the numeric values of the given (lowercase) letters multiplied by `factor`
+    pub fn scaled(&self, letters: &str, factor: Real) -> Self {
+        Self {
+            words: self
+                .words
+                .iter()
+                .map(|(l, v)| match v {
+                    GCodeParamValue::Number(n) if letters.contains(*l) => {
+                        (*l, GCodeParamValue::Number(*n * factor))
+                    }
+                    _ => (*l, v.clone()),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(char, GCodeParamValue)> {
+        self.words.iter()
+    }
+
+    /// The numeric X, Y, Z and E words as a vector. Missing ones are None
+    #[cfg(feature = "with-motion")]
+    pub fn vector(&self) -> TVector<Real> {
+        TVector::from_coords(
+            self.real('x'),
+            self.real('y'),
+            self.real('z'),
+            self.real('e'),
+        )
+    }
+}
+
+#[cfg(feature = "with-defmt")]
+impl defmt::Format for GCodeParams {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "GCodeParams({})", self.words.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::control::{GCodeParams, GCodeParamValue};
+    use crate::math::Real;
+
+    #[test]
+    fn query_words() {
+        let mut params = GCodeParams::new()
+            .with_real('X', Real::new(105, 1))
+            .with_string('f', "dir/file.g")
+            .with_flag('y');
+        params.set('x', GCodeParamValue::Number(Real::new(80, 0)));
+
+        assert_eq!(params.real('x'), Some(Real::new(80, 0)));
+        assert_eq!(params.int('X'), Some(80));
+        assert_eq!(params.string('F'), Some("dir/file.g"));
+        assert_eq!(params.real('f'), None);
+        assert!(params.has('y'));
+        assert_eq!(params.real('y'), None);
+        assert!(!params.has('z'));
+        assert_eq!(params.iter().count(), 3);
+    }
+
+    #[test]
+    fn scale_words() {
+        let params = GCodeParams::new()
+            .with_real('x', Real::new(2, 0))
+            .with_real('p', Real::new(500, 0))
+            .with_flag('y')
+            .scaled("xy", Real::new(254, 1));
+
+        assert_eq!(params.real('x'), Some(Real::new(508, 1)));
+        assert_eq!(params.real('p'), Some(Real::new(500, 0)));
+        assert!(params.has('y'));
+    }
+}
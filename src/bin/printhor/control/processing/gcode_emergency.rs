@@ -0,0 +1,105 @@
+//! The emergency gcodes (M112, M108, M410), performed as soon as they are received.
+//!
+//! In the serial channels they are recognized at byte level (See [crate::control::EmergencyScanner]),
+//! so they take effect even when `task_control` is blocked waiting for a heater or for room in the
+//! motion queue.
+use crate::control::GCodeValue;
+use crate::hwa;
+use embassy_futures::select::{select, Either};
+use embassy_sync::signal::Signal;
+use hwa::{EventBusSubscriber, EventFlags, EventStatus};
+
+/// Raised by M108 to end the wait for the heaters of the gcode files (See [wait_deferred])
+pub static BREAK_WAIT: Signal<hwa::ControllerMutexType, ()> = Signal::new();
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "with-defmt", derive(defmt::Format))]
+pub enum EmergencyCommand {
+    /// M112: Full stop. SYS_ALARM is raised, motion is stopped and heaters are cut
+    Kill,
+    /// M108: Stops waiting for the heaters
+    BreakWait,
+    /// M410: Motion is stopped, discarding the queued moves
+    QuickStop,
+}
+
+impl EmergencyCommand {
+    pub fn from_gcode(value: &GCodeValue) -> Option<Self> {
+        match value {
+            GCodeValue::M112 => Some(EmergencyCommand::Kill),
+            GCodeValue::M108 => Some(EmergencyCommand::BreakWait),
+            GCodeValue::M410 => Some(EmergencyCommand::QuickStop),
+            _ => None,
+        }
+    }
+}
+
+impl hwa::GCodeProcessor {
+    /// Performs an emergency gcode immediately, regardless of what is queued or running
+    pub async fn emergency(&self, channel: hwa::CommChannel, command: EmergencyCommand) {
+        hwa::warn!("[{:?}] Emergency: {:?}", channel, command);
+        match command {
+            EmergencyCommand::Kill => {
+                self.event_bus
+                    .publish_event(hwa::EventStatus::containing(hwa::EventFlags::SYS_ALARM))
+                    .await;
+                #[cfg(feature = "with-motion")]
+                self.motion_planner.quick_stop(&self.event_bus).await;
+                #[cfg(feature = "with-hot-end")]
+                self.hotend
+                    .lock()
+                    .await
+                    .set_target_temp(hwa::CommChannel::Internal, hwa::DeferAction::HotEndTemperature, 0.0)
+                    .await;
+                #[cfg(feature = "with-hot-bed")]
+                self.hotbed
+                    .lock()
+                    .await
+                    .set_target_temp(hwa::CommChannel::Internal, hwa::DeferAction::HotbedTemperature, 0.0)
+                    .await;
+                self.write(channel, "echo: Emergency stop. Reset required\n").await;
+            }
+            EmergencyCommand::BreakWait => {
+                // The pending M109/M190 of the serial channels are acknowledged. The temperature flags
+                // are left as they are: The heaters keep heating
+                #[cfg(feature = "with-hot-end")]
+                {
+                    let mut h = self.hotend.lock().await;
+                    if h.is_awaited() {
+                        h.flush_notification(hwa::DeferAction::HotEndTemperature).await;
+                    }
+                }
+                #[cfg(feature = "with-hot-bed")]
+                {
+                    let mut h = self.hotbed.lock().await;
+                    if h.is_awaited() {
+                        h.flush_notification(hwa::DeferAction::HotbedTemperature).await;
+                    }
+                }
+                BREAK_WAIT.signal(());
+            }
+            EmergencyCommand::QuickStop => {
+                #[cfg(feature = "with-motion")]
+                self.motion_planner.quick_stop(&self.event_bus).await;
+            }
+        }
+    }
+}
+
+/// Waits for a deferred gcode of a gcode file to complete. The wait for the heaters ends early on M108.
+///
+/// Err on SYS_ALARM, as [EventBusSubscriber::ft_wait_for]
+pub async fn wait_deferred(subscriber: &mut EventBusSubscriber<'_>, status: EventStatus) -> Result<(), ()> {
+    if !status.mask.intersects(EventFlags::HOT_END_TEMP_OK | EventFlags::HOT_BED_TEMP_OK) {
+        return subscriber.ft_wait_for(status).await;
+    }
+    // Only a M108 received while waiting counts
+    BREAK_WAIT.reset();
+    match select(subscriber.ft_wait_for(status), BREAK_WAIT.wait()).await {
+        Either::First(result) => result,
+        Either::Second(_) => {
+            hwa::info!("Wait for the heaters broken");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,957 @@
+//! A small expression language for the gcode files (print jobs and macros).
+//!
+//! The lines are preprocessed before reaching the gcode parser:
+//! - `var <name> = <expr>` declares (or redefines) a variable, read as `var.<name>`
+//! - `set var.<name> = <expr>` assigns a declared variable
+//! - `if <expr>`, `elif <expr>`, `else`, `endif` and `while <expr>`, `endwhile` delimit blocks
+//! - `{<expr>}` in any other line is replaced by its value. For instance: `G1 X{var.start + 10}`
+//!
+//! Expressions have numbers, `true` and `false`, arithmetic (`+ - * / %`), comparisons,
+//! `&&`, `||`, `!`, parentheses and the functions `abs`, `min`, `max`, `sqrt`, `sin` and `cos`.
+//! The machine state can only be read, through the `state.` variables (See [StateVariables]).
+//!
+//! Variables are local to the file. Memory is bounded: the number of variables,
+//! the nesting of blocks, the length of the lines and the lines of a loop are limited,
+//! and so are the iterations of a loop.
+use crate::control::{GCodeCmd, GCodeLineParser, GCodeLineParserError};
+use crate::hwa;
+use crate::math;
+use crate::math::Real;
+use core::fmt::Write;
+
+/// Maximum number of variables of a file
+pub const MAX_VARIABLES: usize = 16;
+/// Maximum length of a variable name
+pub const MAX_NAME_LEN: usize = 24;
+/// Maximum nesting of if and while blocks
+pub const MAX_BLOCK_DEPTH: usize = 8;
+/// Maximum number of lines of a while block, including the nested ones
+pub const MAX_LOOP_LINES: usize = 64;
+/// Maximum size in bytes of the lines of a while block, including the nested ones
+pub const MAX_LOOP_BYTES: usize = 2048;
+/// Maximum number of iterations of a while block
+pub const MAX_LOOP_ITERATIONS: u32 = 10000;
+/// Maximum length of a line, before and after the substitution of values
+pub const MAX_LINE_LEN: usize = 160;
+/// Maximum nesting of an expression
+const MAX_EVAL_DEPTH: u8 = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "with-defmt", derive(defmt::Format))]
+pub enum ExpressionError {
+    /// The expression or the statement is not well-formed
+    Syntax,
+    /// The variable is not declared (or the state variable does not exist)
+    UnknownVariable,
+    /// Division by zero, square root of a negative number...
+    NumericalError,
+    /// The maximum number of variables was reached
+    TooManyVariables,
+    /// The expression or the blocks are nested too deep
+    TooDeep,
+    /// The line is too long
+    LineTooLong,
+    /// The variable name is too long
+    NameTooLong,
+    /// The while block has too many lines
+    LoopTooLong,
+    /// The while block was repeated too many times
+    TooManyIterations,
+    /// elif, else, endif or endwhile without its block
+    UnbalancedBlock,
+}
+
+/// A line of a file, before or after the substitution of values
+pub type Line = heapless::String<MAX_LINE_LEN>;
+
+/// A bounded set of named values
+#[derive(Default)]
+pub struct Variables {
+    entries: heapless::Vec<(heapless::String<MAX_NAME_LEN>, Real), MAX_VARIABLES>,
+}
+
+impl Variables {
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Real> {
+        self.entries.iter().find(|(n, _)| n.as_str() == name).map(|(_, v)| *v)
+    }
+
+    /// Declares a variable, or redefines it when already declared
+    pub fn declare(&mut self, name: &str, value: Real) -> Result<(), ExpressionError> {
+        if self.assign(name, value).is_ok() {
+            return Ok(());
+        }
+        let mut key = heapless::String::new();
+        key.push_str(name).map_err(|_| ExpressionError::NameTooLong)?;
+        self.entries
+            .push((key, value))
+            .map_err(|_| ExpressionError::TooManyVariables)
+    }
+
+    /// Assigns a declared variable
+    pub fn assign(&mut self, name: &str, value: Real) -> Result<(), ExpressionError> {
+        match self.entries.iter_mut().find(|(n, _)| n.as_str() == name) {
+            Some((_, v)) => {
+                *v = value;
+                Ok(())
+            }
+            None => Err(ExpressionError::UnknownVariable),
+        }
+    }
+}
+
+fn to_bool(value: Real) -> bool {
+    !value.is_zero()
+}
+
+fn from_bool(value: bool) -> Real {
+    match value {
+        true => math::ONE,
+        false => math::ZERO,
+    }
+}
+
+/// Evaluates an expression
+///
+/// * `variables` - The variables of the file (`var.` prefix).
+/// * `state` - The state variables referenced by the expression (`state.` prefix).
+pub fn evaluate(expr: &str, variables: &Variables, state: &Variables) -> Result<Real, ExpressionError> {
+    let mut evaluator = Evaluator {
+        src: expr.as_bytes(),
+        pos: 0,
+        depth: 0,
+        variables,
+        state,
+    };
+    let value = evaluator.or()?;
+    evaluator.skip_ws();
+    match evaluator.pos == evaluator.src.len() {
+        true => Ok(value),
+        false => Err(ExpressionError::Syntax),
+    }
+}
+
+/// Recursive descent evaluation, with no intermediate representation
+struct Evaluator<'a> {
+    src: &'a [u8],
+    pos: usize,
+    depth: u8,
+    variables: &'a Variables,
+    state: &'a Variables,
+}
+
+impl<'a> Evaluator<'a> {
+    fn skip_ws(&mut self) {
+        while self.pos < self.src.len() && self.src[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_ws();
+        if self.src[self.pos..].starts_with(token.as_bytes()) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Real, ExpressionError> {
+        let mut value = self.and()?;
+        while self.eat("||") {
+            let rhs = self.and()?;
+            value = from_bool(to_bool(value) || to_bool(rhs));
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<Real, ExpressionError> {
+        let mut value = self.comparison()?;
+        while self.eat("&&") {
+            let rhs = self.comparison()?;
+            value = from_bool(to_bool(value) && to_bool(rhs));
+        }
+        Ok(value)
+    }
+
+    fn comparison(&mut self) -> Result<Real, ExpressionError> {
+        let lhs = self.sum()?;
+        let value = if self.eat("==") {
+            from_bool(lhs == self.sum()?)
+        } else if self.eat("!=") {
+            from_bool(lhs != self.sum()?)
+        } else if self.eat("<=") {
+            from_bool(lhs <= self.sum()?)
+        } else if self.eat(">=") {
+            from_bool(lhs >= self.sum()?)
+        } else if self.eat("<") {
+            from_bool(lhs < self.sum()?)
+        } else if self.eat(">") {
+            from_bool(lhs > self.sum()?)
+        } else {
+            lhs
+        };
+        Ok(value)
+    }
+
+    fn sum(&mut self) -> Result<Real, ExpressionError> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat("+") {
+                value = value + self.product()?;
+            } else if self.eat("-") {
+                value = value - self.product()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<Real, ExpressionError> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat("*") {
+                value = value * self.unary()?;
+            } else if self.eat("/") {
+                let rhs = self.unary()?;
+                if rhs.is_zero() {
+                    return Err(ExpressionError::NumericalError);
+                }
+                value = value / rhs;
+            } else if self.eat("%") {
+                let rhs = self.unary()?;
+                if rhs.is_zero() {
+                    return Err(ExpressionError::NumericalError);
+                }
+                value = value - rhs * (value / rhs).floor();
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Real, ExpressionError> {
+        self.depth += 1;
+        if self.depth > MAX_EVAL_DEPTH {
+            return Err(ExpressionError::TooDeep);
+        }
+        let value = if self.eat("-") {
+            -self.unary()?
+        } else if self.eat("+") {
+            self.unary()?
+        } else if self.eat("!") {
+            from_bool(!to_bool(self.unary()?))
+        } else {
+            self.primary()?
+        };
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    fn primary(&mut self) -> Result<Real, ExpressionError> {
+        self.skip_ws();
+        match self.src.get(self.pos) {
+            Some(b'(') => {
+                self.pos += 1;
+                let value = self.or()?;
+                match self.eat(")") {
+                    true => Ok(value),
+                    false => Err(ExpressionError::Syntax),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || *c == b'.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() || *c == b'_' => {
+                let start = self.pos;
+                while self.pos < self.src.len()
+                    && (self.src[self.pos].is_ascii_alphanumeric()
+                        || self.src[self.pos] == b'_'
+                        || self.src[self.pos] == b'.')
+                {
+                    self.pos += 1;
+                }
+                let name = core::str::from_utf8(&self.src[start..self.pos])
+                    .map_err(|_| ExpressionError::Syntax)?;
+                if self.eat("(") {
+                    self.function(name)
+                } else {
+                    self.variable(name)
+                }
+            }
+            _ => Err(ExpressionError::Syntax),
+        }
+    }
+
+    fn number(&mut self) -> Result<Real, ExpressionError> {
+        let mut num: i64 = 0;
+        let mut scale: u32 = 0;
+        let mut decimals = false;
+        let mut digits = 0;
+        while let Some(c) = self.src.get(self.pos) {
+            if c.is_ascii_digit() {
+                num = num
+                    .checked_mul(10)
+                    .and_then(|n| n.checked_add((*c - b'0') as i64))
+                    .ok_or(ExpressionError::NumericalError)?;
+                digits += 1;
+                if decimals {
+                    scale += 1;
+                }
+            } else if *c == b'.' && !decimals {
+                decimals = true;
+            } else {
+                break;
+            }
+            self.pos += 1;
+        }
+        match digits > 0 {
+            true => Ok(Real::from_lit(num, scale)),
+            false => Err(ExpressionError::Syntax),
+        }
+    }
+
+    fn variable(&mut self, name: &str) -> Result<Real, ExpressionError> {
+        match name {
+            "true" => Ok(math::ONE),
+            "false" => Ok(math::ZERO),
+            _ => {
+                if let Some(var_name) = name.strip_prefix("var.") {
+                    self.variables.get(var_name).ok_or(ExpressionError::UnknownVariable)
+                } else if let Some(state_name) = name.strip_prefix("state.") {
+                    self.state.get(state_name).ok_or(ExpressionError::UnknownVariable)
+                } else {
+                    Err(ExpressionError::UnknownVariable)
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, name: &str) -> Result<Real, ExpressionError> {
+        let arg = self.or()?;
+        let value = match name {
+            "abs" => arg.abs(),
+            "sin" => arg.sin(),
+            "cos" => arg.cos(),
+            "sqrt" => match arg < math::ZERO {
+                true => return Err(ExpressionError::NumericalError),
+                false => arg.sqrt().ok_or(ExpressionError::NumericalError)?,
+            },
+            "min" | "max" => {
+                if !self.eat(",") {
+                    return Err(ExpressionError::Syntax);
+                }
+                let arg2 = self.or()?;
+                match name {
+                    "min" => arg.min(arg2),
+                    _ => arg.max(arg2),
+                }
+            }
+            _ => return Err(ExpressionError::Syntax),
+        };
+        match self.eat(")") {
+            true => Ok(value),
+            false => Err(ExpressionError::Syntax),
+        }
+    }
+}
+
+/// The names of the state variables referenced by a line (without the `state.` prefix)
+pub fn state_references(line: &str) -> impl Iterator<Item = &str> {
+    line.match_indices("state.").filter_map(move |(idx, _)| {
+        let rest = &line[idx + "state.".len()..];
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+        (len > 0).then(|| &rest[..len])
+    })
+}
+
+enum Block {
+    If {
+        parent_active: bool,
+        active: bool,
+        taken: bool,
+    },
+    While {
+        active: bool,
+        /// The index of the while line in the recorded lines
+        start: usize,
+        /// The times the block was repeated
+        iterations: u32,
+    },
+}
+
+impl Block {
+    fn is_active(&self) -> bool {
+        match self {
+            Block::If { active, .. } | Block::While { active, .. } => *active,
+        }
+    }
+}
+
+/// The block and variable handling of the lines, independent of the stream
+pub struct Preprocessor {
+    variables: Variables,
+    blocks: heapless::Vec<Block, MAX_BLOCK_DEPTH>,
+    /// The lines since the outermost while being repeated, so the loops can be replayed
+    loop_bytes: heapless::Vec<u8, MAX_LOOP_BYTES>,
+    /// The start and end of each recorded line in `loop_bytes`
+    loop_lines: heapless::Vec<(u16, u16), MAX_LOOP_LINES>,
+    /// The next recorded line to replay, if replaying
+    replay_pos: Option<usize>,
+    /// The index in the recorded lines of the line being processed, if recorded
+    current_index: Option<usize>,
+    /// The while line to replay next and the times its block was repeated
+    repeating: Option<(usize, u32)>,
+}
+
+impl Preprocessor {
+    pub const fn new() -> Self {
+        Self {
+            variables: Variables::new(),
+            blocks: heapless::Vec::new(),
+            loop_bytes: heapless::Vec::new(),
+            loop_lines: heapless::Vec::new(),
+            replay_pos: None,
+            current_index: None,
+            repeating: None,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.blocks.iter().all(|b| b.is_active())
+    }
+
+    fn in_loop(&self) -> bool {
+        self.blocks.iter().any(|b| matches!(b, Block::While { .. }))
+    }
+
+    /// Whether the lines can be repeated, as they are in a while block whose condition holds
+    fn in_repeated_loop(&self) -> bool {
+        self.blocks.iter().any(|b| matches!(b, Block::While { active: true, .. }))
+    }
+
+    fn clear_loop(&mut self) {
+        self.loop_bytes.clear();
+        self.loop_lines.clear();
+    }
+
+    /// The next line of a loop being repeated, if any
+    pub fn replayed_line(&mut self) -> Option<Line> {
+        let idx = self.replay_pos?;
+        let line = self.loop_lines.get(idx).map(|(start, end)| {
+            let mut line = Line::new();
+            let _ = line.push_str(to_str(&self.loop_bytes[*start as usize..*end as usize]));
+            line
+        });
+        self.replay_pos = match idx + 1 < self.loop_lines.len() {
+            true => Some(idx + 1),
+            false => None,
+        };
+        self.current_index = line.as_ref().map(|_| idx);
+        line
+    }
+
+    /// Takes a new line from the stream, recording it when it could be repeated: The lines of the
+    /// while blocks whose condition holds and the while lines out of any block
+    pub fn record(&mut self, line: Line) -> Result<Line, ExpressionError> {
+        if self.in_repeated_loop() || (!self.in_loop() && first_word(line.trim()).0 == "while") {
+            let start = self.loop_bytes.len();
+            self.loop_bytes
+                .extend_from_slice(line.as_bytes())
+                .map_err(|_| ExpressionError::LoopTooLong)?;
+            self.loop_lines
+                .push((start as u16, self.loop_bytes.len() as u16))
+                .map_err(|_| ExpressionError::LoopTooLong)?;
+            self.current_index = Some(self.loop_lines.len() - 1);
+        } else {
+            self.current_index = None;
+        }
+        Ok(line)
+    }
+
+    fn push_block(&mut self, block: Block) -> Result<(), ExpressionError> {
+        self.blocks.push(block).map_err(|_| ExpressionError::TooDeep)
+    }
+
+    /// Processes a line: Returns the gcode to parse, if any
+    ///
+    /// `state` holds the state variables referenced by the line (See [state_references])
+    pub fn process(&mut self, line: &str, state: &Variables) -> Result<Option<Line>, ExpressionError> {
+        let line = line.trim();
+        let (keyword, rest) = first_word(line);
+        let active = self.is_active();
+        match keyword {
+            "if" => {
+                let cond = active && to_bool(evaluate(rest, &self.variables, state)?);
+                self.push_block(Block::If { parent_active: active, active: cond, taken: cond })?;
+            }
+            "elif" | "else" => {
+                let evaluate_cond = match self.blocks.last() {
+                    Some(Block::If { parent_active, taken, .. }) => *parent_active && !*taken,
+                    _ => return Err(ExpressionError::UnbalancedBlock),
+                };
+                let cond = match (keyword, evaluate_cond) {
+                    ("else", _) => evaluate_cond,
+                    (_, true) => to_bool(evaluate(rest, &self.variables, state)?),
+                    (_, false) => false,
+                };
+                if let Some(Block::If { active, taken, .. }) = self.blocks.last_mut() {
+                    *active = cond;
+                    *taken |= cond;
+                }
+            }
+            "endif" => match self.blocks.pop() {
+                Some(Block::If { .. }) => {}
+                _ => return Err(ExpressionError::UnbalancedBlock),
+            },
+            "while" => {
+                let cond = active && to_bool(evaluate(rest, &self.variables, state)?);
+                // Only the blocks whose condition holds are repeated, so they are the only ones recorded
+                let start = match cond {
+                    true => self.current_index.ok_or(ExpressionError::LoopTooLong)?,
+                    false => self.current_index.unwrap_or_default(),
+                };
+                let iterations = match self.repeating.take() {
+                    Some((line, iterations)) if line == start => iterations,
+                    _ => 0,
+                };
+                self.push_block(Block::While { active: cond, start, iterations })?;
+            }
+            "endwhile" => match self.blocks.pop() {
+                Some(Block::While { active, start, iterations }) => {
+                    if active {
+                        if iterations + 1 >= MAX_LOOP_ITERATIONS {
+                            return Err(ExpressionError::TooManyIterations);
+                        }
+                        // The while line is evaluated again
+                        self.replay_pos = Some(start);
+                        self.repeating = Some((start, iterations + 1));
+                    } else if !self.in_loop() && self.replay_pos.is_none() {
+                        self.clear_loop();
+                    }
+                }
+                _ => return Err(ExpressionError::UnbalancedBlock),
+            },
+            "var" | "set" => {
+                if active {
+                    let (name, expr) = rest.split_once('=').ok_or(ExpressionError::Syntax)?;
+                    let name = name.trim();
+                    let value = evaluate(expr, &self.variables, state)?;
+                    if keyword == "var" {
+                        self.variables.declare(name, value)?;
+                    } else {
+                        let name = name.strip_prefix("var.").ok_or(ExpressionError::Syntax)?;
+                        self.variables.assign(name, value)?;
+                    }
+                }
+            }
+            _ => {
+                if active && !line.is_empty() {
+                    return substitute(line, &self.variables, state).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Default for Preprocessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits the first word of a line (ASCII whitespace separated)
+fn first_word(line: &str) -> (&str, &str) {
+    match line.find(|c: char| c.is_ascii_whitespace()) {
+        Some(idx) => (&line[..idx], line[idx..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+/// The text of a line. The bytes out of UTF-8 are taken as `?`
+fn to_str(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("?")
+}
+
+/// Replaces every `{expr}` with its value. Comments are copied as they are
+fn substitute(line: &str, variables: &Variables, state: &Variables) -> Result<Line, ExpressionError> {
+    let code_len = line.find(';').unwrap_or(line.len());
+    let mut output = Line::new();
+    let mut rest = &line[..code_len];
+    while let Some(open) = rest.find('{') {
+        output.push_str(&rest[..open]).map_err(|_| ExpressionError::LineTooLong)?;
+        let close = rest[open..].find('}').ok_or(ExpressionError::Syntax)? + open;
+        let value = evaluate(&rest[open + 1..close], variables, state)?;
+        write_value(&mut output, value)?;
+        rest = &rest[close + 1..];
+    }
+    output.push_str(rest).map_err(|_| ExpressionError::LineTooLong)?;
+    output.push_str(&line[code_len..]).map_err(|_| ExpressionError::LineTooLong)?;
+    Ok(output)
+}
+
+/// Writes a value as a plain decimal number (gcode has no exponents), with up to 4 decimals
+fn write_value(output: &mut Line, value: Real) -> Result<(), ExpressionError> {
+    let scaled = (value * Real::from_lit(10000, 0))
+        .round()
+        .to_i64()
+        .ok_or(ExpressionError::NumericalError)?;
+    let sign = if scaled < 0 { "-" } else { "" };
+    let (int_part, decimals) = (scaled.unsigned_abs() / 10000, scaled.unsigned_abs() % 10000);
+    match decimals {
+        0 => write!(output, "{}{}", sign, int_part),
+        _ => write!(output, "{}{}.{:04}", sign, int_part, decimals),
+    }
+    .map_err(|_| ExpressionError::LineTooLong)?;
+    if decimals != 0 {
+        while output.ends_with('0') {
+            output.pop();
+        }
+    }
+    Ok(())
+}
+
+/// Provides the (read-only) state variables of the machine
+#[allow(async_fn_in_trait)]
+pub trait StateVariables {
+    /// The value of a state variable, given its name without the `state.` prefix
+    async fn state_variable(&self, name: &str) -> Option<Real>;
+}
+
+/// The last error of an [ExpressionStream], as the parser only sees a stream error
+pub type ExpressionErrorSlot = alloc::rc::Rc<core::cell::Cell<Option<ExpressionError>>>;
+
+/// A gcode byte stream with its lines preprocessed (See [Preprocessor])
+pub struct ExpressionStream<STREAM, STATE>
+where
+    STREAM: async_gcode::ByteStream<Item = Result<u8, async_gcode::Error>>,
+    STATE: StateVariables,
+{
+    inner: STREAM,
+    state_provider: STATE,
+    preprocessor: Preprocessor,
+    /// The line being given, with its line terminator
+    output: heapless::Vec<u8, { MAX_LINE_LEN + 1 }>,
+    output_index: usize,
+    error: ExpressionErrorSlot,
+}
+
+impl<STREAM, STATE> ExpressionStream<STREAM, STATE>
+where
+    STREAM: async_gcode::ByteStream<Item = Result<u8, async_gcode::Error>>,
+    STATE: StateVariables,
+{
+    pub fn new(inner: STREAM, state_provider: STATE) -> Self {
+        Self {
+            inner,
+            state_provider,
+            preprocessor: Preprocessor::new(),
+            output: heapless::Vec::new(),
+            output_index: 0,
+            error: ExpressionErrorSlot::default(),
+        }
+    }
+
+    /// Where the errors of the stream are left
+    pub fn error_slot(&self) -> ExpressionErrorSlot {
+        self.error.clone()
+    }
+
+    /// Keeps the error for the parser user and gives the stream error the parser sees
+    fn fail(&self, error: ExpressionError) -> Option<Result<u8, async_gcode::Error>> {
+        self.error.set(Some(error));
+        Some(Err(async_gcode::Error::NumberOverflow))
+    }
+
+    /// Reads a line of the inner stream, without the line terminator. None at EOF
+    async fn read_line(&mut self) -> Result<Option<Line>, Result<ExpressionError, async_gcode::Error>> {
+        let mut bytes: heapless::Vec<u8, MAX_LINE_LEN> = heapless::Vec::new();
+        loop {
+            match self.inner.next().await {
+                None if bytes.is_empty() => return Ok(None),
+                None | Some(Ok(b'\n')) => break,
+                Some(Err(e)) => return Err(Err(e)),
+                Some(Ok(b'\r')) => {}
+                Some(Ok(byte)) => bytes.push(byte).map_err(|_| Ok(ExpressionError::LineTooLong))?,
+            }
+        }
+        let mut line = Line::new();
+        match core::str::from_utf8(&bytes) {
+            Ok(text) => {
+                let _ = line.push_str(text);
+            }
+            Err(_) => {
+                // Not in UTF-8: The bytes out of ASCII are taken as `?`
+                for byte in bytes {
+                    let _ = line.push(if byte.is_ascii() { byte as char } else { '?' });
+                }
+            }
+        }
+        Ok(Some(line))
+    }
+}
+
+impl<STREAM, STATE> async_gcode::ByteStream for ExpressionStream<STREAM, STATE>
+where
+    STREAM: async_gcode::ByteStream<Item = Result<u8, async_gcode::Error>>,
+    STATE: StateVariables,
+{
+    type Item = Result<u8, async_gcode::Error>;
+
+    async fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.output_index < self.output.len() {
+                let byte = self.output[self.output_index];
+                self.output_index += 1;
+                return Some(Ok(byte));
+            }
+            self.output.clear();
+            self.output_index = 0;
+            let line = match self.preprocessor.replayed_line() {
+                Some(line) => {
+                    // A loop without gcodes would not give the other tasks a chance to run otherwise
+                    embassy_futures::yield_now().await;
+                    line
+                }
+                None => match self.read_line().await {
+                    Ok(Some(line)) => match self.preprocessor.record(line) {
+                        Ok(line) => line,
+                        Err(e) => {
+                            hwa::error!("Expression error: {:?}", e);
+                            return self.fail(e);
+                        }
+                    },
+                    Ok(None) => return None,
+                    Err(Ok(e)) => {
+                        hwa::error!("Expression error: {:?}", e);
+                        return self.fail(e);
+                    }
+                    Err(Err(e)) => return Some(Err(e)),
+                },
+            };
+            let mut state = Variables::new();
+            for name in state_references(line.as_str()) {
+                if state.get(name).is_none() {
+                    if let Some(value) = self.state_provider.state_variable(name).await {
+                        let _ = state.declare(name, value);
+                    }
+                }
+            }
+            match self.preprocessor.process(line.as_str(), &state) {
+                Ok(Some(gcode)) => {
+                    let _ = self.output.extend_from_slice(gcode.as_bytes());
+                    let _ = self.output.push(b'\n');
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    hwa::error!("Expression error: {:?} in \"{}\"", e, line.as_str());
+                    return self.fail(e);
+                }
+            }
+        }
+    }
+}
+
+/// A parser of a preprocessed stream, telling the expression errors from the parse errors
+pub struct ExpressionParser<STREAM, STATE>
+where
+    STREAM: async_gcode::ByteStream<Item = Result<u8, async_gcode::Error>>,
+    STATE: StateVariables,
+{
+    parser: GCodeLineParser<ExpressionStream<STREAM, STATE>>,
+    error: ExpressionErrorSlot,
+}
+
+impl<STREAM, STATE> ExpressionParser<STREAM, STATE>
+where
+    STREAM: async_gcode::ByteStream<Item = Result<u8, async_gcode::Error>>,
+    STATE: StateVariables,
+{
+    pub fn new(inner: STREAM, state_provider: STATE) -> Self {
+        let stream = ExpressionStream::new(inner, state_provider);
+        let error = stream.error_slot();
+        Self {
+            parser: GCodeLineParser::new(stream),
+            error,
+        }
+    }
+
+    pub async fn next_gcode(&mut self) -> Result<GCodeCmd, GCodeLineParserError> {
+        let result = self.parser.next_gcode().await;
+        match (result, self.error.take()) {
+            (Err(GCodeLineParserError::ParseError(ln)), Some(error)) => {
+                Err(GCodeLineParserError::ExpressionError(ln, error))
+            }
+            (result, _) => result,
+        }
+    }
+
+    pub fn get_line(&self) -> u32 {
+        self.parser.get_line()
+    }
+
+    pub async fn close(&mut self) {
+        self.parser.close().await
+    }
+}
+
+impl StateVariables for hwa::GCodeProcessor {
+    async fn state_variable(&self, name: &str) -> Option<Real> {
+        match name {
+            // The work position, in the active units
+            #[cfg(feature = "with-motion")]
+            "x" | "y" | "z" | "e" => {
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                let pos = self.motion_planner.get_last_planned_work_pos().await? / mm_per_unit;
+                match name {
+                    "x" => pos.x,
+                    "y" => pos.y,
+                    "z" => pos.z,
+                    _ => pos.e,
+                }
+            }
+            #[cfg(feature = "with-hot-end")]
+            "hotend.temp" => Some(Real::from_f32(self.hotend.lock().await.get_current_temp())),
+            #[cfg(feature = "with-hot-end")]
+            "hotend.target" => Some(Real::from_f32(self.hotend.lock().await.get_target_temp())),
+            #[cfg(feature = "with-hot-bed")]
+            "hotbed.temp" => Some(Real::from_f32(self.hotbed.lock().await.get_current_temp())),
+            #[cfg(feature = "with-hot-bed")]
+            "hotbed.target" => Some(Real::from_f32(self.hotbed.lock().await.get_target_temp())),
+            _ => {
+                // The event bus flags. For instance: state.flags.atx_on
+                let flag_name = name.strip_prefix("flags.")?.to_ascii_uppercase();
+                let flag = hwa::EventFlags::from_name(flag_name.as_str())?;
+                Some(from_bool(self.event_bus.has_flags(flag).await))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> Result<Real, ExpressionError> {
+        let mut variables = Variables::new();
+        variables.declare("start", Real::new(5, 0)).unwrap();
+        let mut state = Variables::new();
+        state.declare("hotend.temp", Real::new(200, 0)).unwrap();
+        evaluate(expr, &variables, &state)
+    }
+
+    #[test]
+    fn evaluate_expressions() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(Real::new(7, 0)));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(Real::new(9, 0)));
+        assert_eq!(eval("var.start + 10.5"), Ok(Real::new(155, 1)));
+        assert_eq!(eval("-var.start % 3"), Ok(Real::new(1, 0)));
+        assert_eq!(eval("max(var.start, 2) - abs(-1)"), Ok(Real::new(4, 0)));
+        assert_eq!(eval("state.hotend.temp >= 190 && !false"), Ok(math::ONE));
+        assert_eq!(eval("var.start == 4 || 1 > 2"), Ok(math::ZERO));
+    }
+
+    #[test]
+    fn expression_errors() {
+        assert_eq!(eval("1 +"), Err(ExpressionError::Syntax));
+        assert_eq!(eval("(1 + 2"), Err(ExpressionError::Syntax));
+        assert_eq!(eval("var.missing"), Err(ExpressionError::UnknownVariable));
+        assert_eq!(eval("1 / (var.start - 5)"), Err(ExpressionError::NumericalError));
+        assert_eq!(eval("sqrt(-1)"), Err(ExpressionError::NumericalError));
+        assert_eq!(eval("--------------------1"), Err(ExpressionError::TooDeep));
+    }
+
+    #[test]
+    fn find_state_references() {
+        let names: alloc::vec::Vec<&str> =
+            state_references("G1 X{state.x + 1} Y{state.flags.atx_on}").collect();
+        assert_eq!(names, ["x", "flags.atx_on"]);
+    }
+
+    /// Runs the preprocessor over a file, as the stream does
+    fn run(file: &str) -> Result<alloc::vec::Vec<Line>, ExpressionError> {
+        let state = Variables::new();
+        let mut preprocessor = Preprocessor::new();
+        let mut lines = file.lines();
+        let mut output = alloc::vec::Vec::new();
+        loop {
+            let line = match preprocessor.replayed_line() {
+                Some(line) => line,
+                None => match lines.next() {
+                    Some(line) => preprocessor.record(Line::try_from(line).map_err(|_| ExpressionError::LineTooLong)?)?,
+                    None => return Ok(output),
+                },
+            };
+            if let Some(gcode) = preprocessor.process(line.as_str(), &state)? {
+                output.push(gcode);
+            }
+        }
+    }
+
+    #[test]
+    fn conditional_blocks() {
+        let output = run(
+            "var t = 2\n\
+             if var.t == 1\n  G1 X1\n\
+             elif var.t == 2\n  G1 X{var.t * 10} ; comment {not evaluated}\n\
+             else\n  G1 X3\n\
+             endif",
+        ).unwrap();
+        assert_eq!(output, ["G1 X20 ; comment {not evaluated}"]);
+        assert_eq!(run("else"), Err(ExpressionError::UnbalancedBlock));
+    }
+
+    #[test]
+    fn nested_loops() {
+        let output = run(
+            "var i = 0\n\
+             while var.i < 2\n\
+               var j = 0\n\
+               while var.j < 2\n\
+                 G1 X{var.i} Y{var.j}\n\
+                 set var.j = var.j + 1\n\
+               endwhile\n\
+               set var.i = var.i + 1\n\
+             endwhile\n\
+             M400",
+        ).unwrap();
+        assert_eq!(output, ["G1 X0 Y0", "G1 X0 Y1", "G1 X1 Y0", "G1 X1 Y1", "M400"]);
+    }
+
+    #[test]
+    fn bounded_loops() {
+        // The lines of a while block not repeated are not kept
+        let mut file = alloc::string::String::from("while 0\n");
+        for _ in 0..MAX_LOOP_LINES * 2 {
+            file.push_str("G1 X1\n");
+        }
+        file.push_str("endwhile\nM400");
+        assert_eq!(run(file.as_str()).unwrap(), ["M400"]);
+        assert_eq!(run("while 1\nendwhile"), Err(ExpressionError::TooManyIterations));
+        let mut file = alloc::string::String::from("var i = 0\nwhile var.i < 2\n");
+        for _ in 0..MAX_LOOP_LINES {
+            file.push_str("set var.i = var.i + 1\n");
+        }
+        file.push_str("endwhile");
+        assert_eq!(run(file.as_str()), Err(ExpressionError::LoopTooLong));
+        assert_eq!(run("var a_very_long_variable_name = 1"), Err(ExpressionError::NameTooLong));
+    }
+
+    #[test]
+    fn substitute_values() {
+        let variables = Variables::new();
+        let state = Variables::new();
+        let line = substitute("G1 X{-1 / 4} Y{2 / 3} Z{10 * 10}", &variables, &state);
+        assert_eq!(line.unwrap(), "G1 X-0.25 Y0.6667 Z100");
+        let long_line = "{1/3}".repeat(MAX_LINE_LEN / 5);
+        assert_eq!(substitute(long_line.as_str(), &variables, &state), Err(ExpressionError::LineTooLong));
+        assert_eq!(substitute("G1 X{1", &variables, &state), Err(ExpressionError::Syntax));
+    }
+}
@@ -0,0 +1,415 @@
+//! Execution of gcode files of the SD card as subprograms (M98/M99)
+use crate::control::{CodeExecutionFailure, CodeExecutionResult, CodeExecutionSuccess};
+use crate::control::{ExpressionParser, GCodeCmd, GCodeLineParserError, GCodeValue};
+use crate::hwa;
+use hwa::controllers::sdcard_controller::{SDCardError, SDCardStream};
+use hwa::controllers::CardController;
+use hwa::{CommChannel, EventBusSubscriber};
+
+/// Maximum nesting of macro calls (M98 inside a macro)
+pub const MAX_MACRO_DEPTH: usize = 3;
+
+/// The optional machine configuration, run at boot if present
+pub const BOOT_CONFIG_PATH: &str = "/sys/config.g";
+
+/// A macro being run
+trait MacroReader {
+    /// The next gcode of the macro
+    async fn next_gcode(&mut self) -> Result<GCodeCmd, GCodeLineParserError>;
+    /// Consumes the remaining of the macro, so its file is released
+    async fn close(&mut self);
+    fn file_path(&self) -> &str;
+    /// The line being read
+    fn line(&self) -> u32;
+}
+
+/// Where the macros are run: It opens them, executes their gcodes and reports their errors
+trait MacroHost {
+    type Reader: MacroReader;
+    async fn open(&mut self, file_path: &str) -> Result<Self::Reader, SDCardError>;
+    /// Executes a gcode of a macro, waiting for it to complete
+    async fn execute(&mut self, gc: &GCodeCmd) -> CodeExecutionResult;
+    async fn report(&mut self, message: &str);
+}
+
+struct MacroFrame {
+    file_path: alloc::string::String,
+    /// Boxed, as it is too big to be kept in the futures running the macros
+    parser: alloc::boxed::Box<ExpressionParser<SDCardStream, hwa::GCodeProcessor>>,
+}
+
+impl MacroReader for MacroFrame {
+    async fn next_gcode(&mut self) -> Result<GCodeCmd, GCodeLineParserError> {
+        self.parser.next_gcode().await
+    }
+
+    async fn close(&mut self) {
+        loop {
+            match self.parser.next_gcode().await {
+                Err(GCodeLineParserError::EOF) | Err(GCodeLineParserError::FatalError) => break,
+                _ => {}
+            }
+        }
+        self.parser.close().await;
+    }
+
+    fn file_path(&self) -> &str {
+        self.file_path.as_str()
+    }
+
+    fn line(&self) -> u32 {
+        self.parser.get_line()
+    }
+}
+
+/// The macros of the SD card, run in blocking mode as in a print job
+struct CardMacroHost<'a, 'b> {
+    processor: &'a mut hwa::GCodeProcessor,
+    channel: CommChannel,
+    card_controller: &'a CardController,
+    subscriber: &'a mut EventBusSubscriber<'b>,
+}
+
+impl MacroHost for CardMacroHost<'_, '_> {
+    type Reader = MacroFrame;
+
+    async fn open(&mut self, file_path: &str) -> Result<MacroFrame, SDCardError> {
+        let stream = self.card_controller.new_stream(file_path).await?;
+        Ok(MacroFrame {
+            file_path: alloc::string::String::from(file_path),
+            parser: alloc::boxed::Box::new(ExpressionParser::new(stream, self.processor.clone())),
+        })
+    }
+
+    async fn execute(&mut self, gc: &GCodeCmd) -> CodeExecutionResult {
+        match self.processor.execute(CommChannel::Internal, gc, true).await? {
+            CodeExecutionSuccess::DEFERRED(status) => match crate::control::wait_deferred(self.subscriber, status).await {
+                Ok(_) => Ok(CodeExecutionSuccess::OK),
+                // SYS_ALARM
+                Err(_) => Err(CodeExecutionFailure::ERR),
+            },
+            success => Ok(success),
+        }
+    }
+
+    async fn report(&mut self, message: &str) {
+        self.processor.write(self.channel, message).await;
+    }
+}
+
+impl hwa::GCodeProcessor {
+    /// Runs a gcode file of the SD card until its end or M99, including the macros it calls with M98.
+    ///
+    /// The gcodes are executed in blocking mode, as in a print job, so only the outcome of the whole
+    /// file is reported. The errors are written to `channel` with the file and line where they happened.
+    pub async fn run_macro(
+        &mut self,
+        channel: CommChannel,
+        card_controller: &CardController,
+        subscriber: &mut EventBusSubscriber<'_>,
+        file_path: &str,
+    ) -> CodeExecutionResult {
+        let mut host = CardMacroHost { processor: self, channel, card_controller, subscriber };
+        // Boxed, as the gcodes of the macro are executed from the execution of M98
+        alloc::boxed::Box::pin(run_macro_with(&mut host, file_path)).await
+    }
+
+    /// Runs the machine configuration ([BOOT_CONFIG_PATH]). It is optional: Nothing is run nor
+    /// reported if there is no such file (or no card)
+    pub async fn run_boot_config(
+        &mut self,
+        card_controller: &CardController,
+        subscriber: &mut EventBusSubscriber<'_>,
+    ) -> CodeExecutionResult {
+        let mut host = CardMacroHost {
+            processor: self,
+            channel: CommChannel::Internal,
+            card_controller,
+            subscriber,
+        };
+        let mut stack = heapless::Vec::new();
+        match host.open(BOOT_CONFIG_PATH).await {
+            Ok(frame) => {
+                let _ = stack.push(frame);
+            }
+            Err(SDCardError::NotFound) | Err(SDCardError::NoSuchVolume) => {
+                return Ok(CodeExecutionSuccess::OK);
+            }
+            Err(e) => {
+                let s = alloc::format!("error; {}: {}\n", BOOT_CONFIG_PATH, open_failure(e));
+                host.report(s.as_str()).await;
+                return Err(CodeExecutionFailure::ERR);
+            }
+        }
+        run_stack(&mut host, stack).await
+    }
+}
+
+/// Runs a macro until its end or M99, including the macros it calls with M98
+async fn run_macro_with<H: MacroHost>(host: &mut H, file_path: &str) -> CodeExecutionResult {
+    let mut stack = heapless::Vec::new();
+    push_macro(host, &mut stack, file_path).await?;
+    run_stack(host, stack).await
+}
+
+/// Runs the macros of the call stack, from the one on top, until the stack is empty or an error happens.
+/// Then, the macros left are closed
+async fn run_stack<H: MacroHost>(
+    host: &mut H,
+    mut stack: heapless::Vec<H::Reader, MAX_MACRO_DEPTH>,
+) -> CodeExecutionResult {
+    let mut result = Ok(CodeExecutionSuccess::OK);
+    while let Some(frame) = stack.last_mut() {
+        let gc: GCodeCmd = match frame.next_gcode().await {
+            Ok(gc) => gc,
+            Err(GCodeLineParserError::EOF) => {
+                // Implicit M99
+                if let Some(mut frame) = stack.pop() {
+                    frame.close().await;
+                }
+                continue;
+            }
+            Err(GCodeLineParserError::GCodeNotImplemented(_ln, gcode_name)) => {
+                hwa::warn!("Ignoring GCode not supported {} in macro", gcode_name.as_str());
+                continue;
+            }
+            Err(GCodeLineParserError::ExpressionError(_ln, _error)) => {
+                let s = alloc::format!(
+                    "error; {}:{}: Expression error ({:?})\n",
+                    frame.file_path(), frame.line(), _error
+                );
+                host.report(s.as_str()).await;
+                result = Err(CodeExecutionFailure::ERR);
+                break;
+            }
+            Err(_e) => {
+                let s = alloc::format!(
+                    "error; {}:{}: Unable to parse ({:?})\n",
+                    frame.file_path(), frame.line(), _e
+                );
+                host.report(s.as_str()).await;
+                result = Err(CodeExecutionFailure::ERR);
+                break;
+            }
+        };
+        match &gc.value {
+            GCodeValue::M98 => {
+                let nested_path = gc.params.string('p').unwrap_or("");
+                if let Err(e) = push_macro(host, &mut stack, nested_path).await {
+                    result = Err(e);
+                    break;
+                }
+            }
+            GCodeValue::M99 => {
+                if let Some(mut frame) = stack.pop() {
+                    frame.close().await;
+                }
+            }
+            _ => match host.execute(&gc).await {
+                Ok(_) => {}
+                Err(CodeExecutionFailure::NotYetImplemented) => {
+                    hwa::warn!("Ignoring GCode not implemented {}", gc);
+                }
+                Err(e) => {
+                    let reason = alloc::format!("{:?}", e);
+                    report_macro_error(host, &stack, &gc, reason.as_str()).await;
+                    result = Err(e);
+                    break;
+                }
+            },
+        }
+    }
+    while let Some(mut frame) = stack.pop() {
+        frame.close().await;
+    }
+    result
+}
+
+/// The reason a macro could not be opened
+fn open_failure(error: SDCardError) -> &'static str {
+    match error {
+        SDCardError::NoSuchVolume => "Card not ready",
+        SDCardError::NotFound => "File not found",
+        _ => "Internal error",
+    }
+}
+
+/// Opens a macro and places it on top of the call stack
+async fn push_macro<H: MacroHost>(
+    host: &mut H,
+    stack: &mut heapless::Vec<H::Reader, MAX_MACRO_DEPTH>,
+    file_path: &str,
+) -> Result<(), CodeExecutionFailure> {
+    let reason = if stack.is_full() {
+        "Too many nested macros"
+    } else {
+        match host.open(file_path).await {
+            Ok(frame) => {
+                let _ = stack.push(frame);
+                return Ok(());
+            }
+            Err(e) => open_failure(e),
+        }
+    };
+    let s = match stack.last() {
+        Some(frame) => alloc::format!(
+            "error; {}:{}: M98 {}: {}\n",
+            frame.file_path(), frame.line(), file_path, reason
+        ),
+        None => alloc::format!("error; M98 {}: {}\n", file_path, reason),
+    };
+    host.report(s.as_str()).await;
+    Err(CodeExecutionFailure::ERR)
+}
+
+async fn report_macro_error<H: MacroHost>(
+    host: &mut H,
+    stack: &heapless::Vec<H::Reader, MAX_MACRO_DEPTH>,
+    gc: &GCodeCmd,
+    reason: &str,
+) {
+    if let Some(frame) = stack.last() {
+        let s = alloc::format!(
+            "error; {}:{}: {} ({})\n",
+            frame.file_path(), frame.line(), gc, reason
+        );
+        host.report(s.as_str()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::GCodeParamValue;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    /// A gcode with a text argument
+    type TestGCode = (GCodeValue, char, &'static str);
+
+    /// A macro given as its gcodes
+    struct TestMacro {
+        file_path: &'static str,
+        gcodes: Vec<TestGCode>,
+        next: usize,
+        open: alloc::rc::Rc<core::cell::Cell<i32>>,
+    }
+
+    impl MacroReader for TestMacro {
+        async fn next_gcode(&mut self) -> Result<GCodeCmd, GCodeLineParserError> {
+            let gc = self.gcodes.get(self.next).ok_or(GCodeLineParserError::EOF);
+            self.next += 1;
+            gc.map(|(value, letter, text)| gcode(*value, *letter, text))
+        }
+
+        async fn close(&mut self) {
+            self.next = self.gcodes.len();
+            self.open.set(self.open.get() - 1);
+        }
+
+        fn file_path(&self) -> &str {
+            self.file_path
+        }
+
+        fn line(&self) -> u32 {
+            self.next as u32
+        }
+    }
+
+    /// Runs the macros of `files`, recording the gcodes executed, the errors reported and the files open
+    struct TestHost {
+        files: Vec<(&'static str, Vec<TestGCode>)>,
+        executed: Vec<(String, GCodeValue)>,
+        reported: Vec<String>,
+        open: alloc::rc::Rc<core::cell::Cell<i32>>,
+    }
+
+    impl MacroHost for TestHost {
+        type Reader = TestMacro;
+
+        async fn open(&mut self, file_path: &str) -> Result<TestMacro, SDCardError> {
+            let (file_path, gcodes) = self
+                .files
+                .iter()
+                .find(|(path, _)| *path == file_path)
+                .ok_or(SDCardError::NotFound)?;
+            self.open.set(self.open.get() + 1);
+            Ok(TestMacro { file_path: *file_path, gcodes: gcodes.clone(), next: 0, open: self.open.clone() })
+        }
+
+        async fn execute(&mut self, gc: &GCodeCmd) -> CodeExecutionResult {
+            // The argument tells which macro it is in
+            let file = String::from(gc.params.string('s').unwrap_or(""));
+            self.executed.push((file, gc.value));
+            Ok(CodeExecutionSuccess::OK)
+        }
+
+        async fn report(&mut self, message: &str) {
+            self.reported.push(String::from(message));
+        }
+    }
+
+    fn gcode(value: GCodeValue, letter: char, text: &str) -> GCodeCmd {
+        let mut gc = GCodeCmd::new(0, None, value);
+        gc.params.set(letter, GCodeParamValue::Text(String::from(text)));
+        gc
+    }
+
+    fn test_host(files: Vec<(&'static str, Vec<TestGCode>)>) -> TestHost {
+        TestHost {
+            files,
+            executed: Vec::new(),
+            reported: Vec::new(),
+            open: alloc::rc::Rc::new(core::cell::Cell::new(0)),
+        }
+    }
+
+    #[futures_test::test]
+    async fn nested_macros_return_to_the_caller() {
+        let mut host = test_host(alloc::vec![
+            ("main.g", alloc::vec![
+                (GCodeValue::G4, 's', "main"),
+                (GCodeValue::M98, 'p', "sub.g"),
+                (GCodeValue::G4, 's', "main"),
+                (GCodeValue::M99, 's', "main"),
+                (GCodeValue::G28, 's', "main"),
+            ]),
+            ("sub.g", alloc::vec![
+                (GCodeValue::M98, 'p', "leaf.g"),
+                (GCodeValue::G4, 's', "sub"),
+                (GCodeValue::M99, 's', "sub"),
+                (GCodeValue::G28, 's', "sub"),
+            ]),
+            // Implicit M99 at its end
+            ("leaf.g", alloc::vec![(GCodeValue::G4, 's', "leaf")]),
+        ]);
+        assert!(run_macro_with(&mut host, "main.g").await.is_ok());
+        let executed: Vec<(&str, GCodeValue)> = host.executed.iter().map(|(f, v)| (f.as_str(), *v)).collect();
+        assert_eq!(
+            executed,
+            [("main", GCodeValue::G4), ("leaf", GCodeValue::G4), ("sub", GCodeValue::G4), ("main", GCodeValue::G4)]
+        );
+        assert!(host.reported.is_empty());
+        assert_eq!(host.open.get(), 0);
+    }
+
+    #[futures_test::test]
+    async fn macro_nesting_errors() {
+        // Calls itself until the stack is full
+        let mut host = test_host(alloc::vec![
+            ("loop.g", alloc::vec![(GCodeValue::M98, 'p', "loop.g"), (GCodeValue::G4, 's', "loop")]),
+        ]);
+        assert!(run_macro_with(&mut host, "loop.g").await.is_err());
+        assert!(host.executed.is_empty());
+        assert_eq!(host.reported, ["error; loop.g:1: M98 loop.g: Too many nested macros\n"]);
+        assert_eq!(host.open.get(), 0);
+
+        let mut host = test_host(alloc::vec![
+            ("main.g", alloc::vec![(GCodeValue::M98, 'p', "missing.g"), (GCodeValue::G4, 's', "main")]),
+        ]);
+        assert!(run_macro_with(&mut host, "main.g").await.is_err());
+        assert!(host.executed.is_empty());
+        assert_eq!(host.reported, ["error; main.g:1: M98 missing.g: File not found\n"]);
+        assert_eq!(host.open.get(), 0);
+    }
+}
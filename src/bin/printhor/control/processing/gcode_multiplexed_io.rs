@@ -3,7 +3,7 @@
 ///! This feature is a bit inefficient and could be improved leveraging macros
 ///! because currently useless polls are performed in disabled channels
 use crate::hwa;
-use crate::control::{GCodeCmd, GCodeLineParserError};
+use crate::control::{GCodeCmd, GCodeValue, GCodeLineParserError};
 use embassy_futures::select::Either3;
 #[allow(unused)]
 use futures_util::future;
@@ -13,23 +13,30 @@ use futures_util::future;
 pub struct GCodeMultiplexedInputStream {
     #[cfg(feature = "with-serial-usb")]
     serial_usb_line_parser:
-        crate::control::GCodeLineParser<hwa::device::USBSerialDeviceInputStream>,
+        crate::control::GCodeLineParser<crate::control::ScannedInputStream>,
     #[cfg(feature = "with-serial-port-1")]
     serial_port1_line_parser:
-        crate::control::GCodeLineParser<hwa::device::UartPort1RxInputStream>,
+        crate::control::GCodeLineParser<crate::control::ScannedInputStream>,
     #[cfg(feature = "with-serial-port-2")]
     serial_port2_line_parser:
-        crate::control::GCodeLineParser<hwa::device::UartPort2RxInputStream>,
+        crate::control::GCodeLineParser<crate::control::ScannedInputStream>,
+    /// Last accepted line number (N) in each channel. Next one is expected to be +1
+    #[cfg(feature = "with-serial-usb")]
+    serial_usb_last_line: u32,
+    #[cfg(feature = "with-serial-port-1")]
+    serial_port1_last_line: u32,
+    #[cfg(feature = "with-serial-port-2")]
+    serial_port2_last_line: u32,
 }
 
 impl GCodeMultiplexedInputStream {
     pub fn new(
         #[cfg(feature = "with-serial-usb")]
-        serial_usb_rx_stream: hwa::device::USBSerialDeviceInputStream,
+        serial_usb_rx_stream: crate::control::ScannedInputStream,
         #[cfg(feature = "with-serial-port-1")]
-        serial_port1_rx_stream: hwa::device::UartPort1RxInputStream,
+        serial_port1_rx_stream: crate::control::ScannedInputStream,
         #[cfg(feature = "with-serial-port-2")]
-        serial_port2_rx_stream: hwa::device::UartPort2RxInputStream,
+        serial_port2_rx_stream: crate::control::ScannedInputStream,
     ) -> Self {
         Self {
             #[cfg(feature = "with-serial-usb")]
@@ -38,6 +45,12 @@ impl GCodeMultiplexedInputStream {
             serial_port1_line_parser: crate::control::GCodeLineParser::new(serial_port1_rx_stream),
             #[cfg(feature = "with-serial-port-2")]
             serial_port2_line_parser: crate::control::GCodeLineParser::new(serial_port2_rx_stream),
+            #[cfg(feature = "with-serial-usb")]
+            serial_usb_last_line: 0,
+            #[cfg(feature = "with-serial-port-1")]
+            serial_port1_last_line: 0,
+            #[cfg(feature = "with-serial-port-2")]
+            serial_port2_last_line: 0,
         }
     }
 
@@ -46,6 +59,40 @@ impl GCodeMultiplexedInputStream {
     ) -> (
         Result<GCodeCmd, GCodeLineParserError>,
         hwa::CommChannel,
+    ) {
+        let (result, channel) = self.next_raw_gcode().await;
+        (self.check_line_number(result, channel), channel)
+    }
+
+    /// Verifies the line number (N) of a tagged gcode is the expected one in the given channel.
+    /// See [verify_line_number]
+    fn check_line_number(
+        &mut self,
+        result: Result<GCodeCmd, GCodeLineParserError>,
+        channel: hwa::CommChannel,
+    ) -> Result<GCodeCmd, GCodeLineParserError> {
+        let gc = result?;
+        let m110_line = match gc.value {
+            GCodeValue::M110 => Some(gc.params.int('n').map(|n| n as u32)),
+            _ => None,
+        };
+        let last_line = verify_line_number(
+            m110_line,
+            gc.line_tag,
+            self.get_gcode_checksum(channel),
+            self.get_expected_line(channel),
+        )?;
+        if let Some(line) = last_line {
+            self.set_last_line(channel, line);
+        }
+        Ok(gc)
+    }
+
+    async fn next_raw_gcode(
+        &mut self,
+    ) -> (
+        Result<GCodeCmd, GCodeLineParserError>,
+        hwa::CommChannel,
     ) {
         cfg_if::cfg_if! {
             if #[cfg(feature="with-serial-usb")] {
@@ -156,6 +203,52 @@ impl GCodeMultiplexedInputStream {
         }
     }
 
+    /// Whether the last gcode parsed in the given channel carried a checksum (*)
+    pub fn get_gcode_checksum(&self, channel: hwa::CommChannel) -> bool {
+
+        match channel {
+            #[cfg(feature="with-serial-usb")]
+            hwa::CommChannel::SerialUsb => self.serial_usb_line_parser.gcode_checksum(),
+            #[cfg(feature="with-serial-port-1")]
+            hwa::CommChannel::SerialPort1 => self.serial_port1_line_parser.gcode_checksum(),
+            #[cfg(feature="with-serial-port-2")]
+            hwa::CommChannel::SerialPort2 => self.serial_port2_line_parser.gcode_checksum(),
+            hwa::CommChannel::Internal => {
+                false
+            }
+        }
+    }
+
+    /// The line number (N) the given channel expects to receive next.
+    /// This is the one to request with `Resend:` when a line is rejected
+    pub fn get_expected_line(&self, channel: hwa::CommChannel) -> u32 {
+
+        match channel {
+            #[cfg(feature="with-serial-usb")]
+            hwa::CommChannel::SerialUsb => self.serial_usb_last_line + 1,
+            #[cfg(feature="with-serial-port-1")]
+            hwa::CommChannel::SerialPort1 => self.serial_port1_last_line + 1,
+            #[cfg(feature="with-serial-port-2")]
+            hwa::CommChannel::SerialPort2 => self.serial_port2_last_line + 1,
+            hwa::CommChannel::Internal => {
+                0
+            }
+        }
+    }
+
+    pub fn set_last_line(&mut self, channel: hwa::CommChannel, line: u32) {
+
+        match channel {
+            #[cfg(feature="with-serial-usb")]
+            hwa::CommChannel::SerialUsb => self.serial_usb_last_line = line,
+            #[cfg(feature="with-serial-port-1")]
+            hwa::CommChannel::SerialPort1 => self.serial_port1_last_line = line,
+            #[cfg(feature="with-serial-port-2")]
+            hwa::CommChannel::SerialPort2 => self.serial_port2_last_line = line,
+            hwa::CommChannel::Internal => {}
+        }
+    }
+
     pub fn reset(&mut self, comm_channel: hwa::CommChannel) {
         match comm_channel {
             #[cfg(feature="with-serial-usb")]
@@ -168,3 +261,46 @@ impl GCodeMultiplexedInputStream {
         }
     }
 }
+
+/// Verifies the line number (N) of a gcode, giving the new last line number of the channel, if it changes.
+///
+/// - `m110_line`: Some(N argument) if the gcode is M110, which sets the last line number to its N argument
+///   (or to its own line tag), with or without checksum.
+/// - Untagged gcodes are always accepted, as Marlin does.
+/// - Tagged gcodes must carry a checksum and be the `expected` line.
+pub fn verify_line_number(
+    m110_line: Option<Option<u32>>,
+    line_tag: Option<u32>,
+    has_checksum: bool,
+    expected: u32,
+) -> Result<Option<u32>, GCodeLineParserError> {
+    match (m110_line, line_tag) {
+        (Some(n), tag) => Ok(n.or(tag)),
+        (None, Some(tag)) if !has_checksum => Err(GCodeLineParserError::ChecksumMissing(tag)),
+        (None, Some(tag)) if tag == expected => Ok(Some(tag)),
+        (None, Some(tag)) => Err(GCodeLineParserError::LineNumberMismatch(tag)),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_number_verification() {
+        // Untagged lines are accepted and do not change the line number
+        assert!(matches!(verify_line_number(None, None, false, 5), Ok(None)));
+        assert!(matches!(verify_line_number(None, None, true, 5), Ok(None)));
+        // The expected line, with checksum
+        assert!(matches!(verify_line_number(None, Some(5), true, 5), Ok(Some(5))));
+        // Out of sequence: Resend requested
+        assert!(matches!(verify_line_number(None, Some(7), true, 5), Err(GCodeLineParserError::LineNumberMismatch(7))));
+        // Tagged without checksum: Resend requested, even if it is the expected one
+        assert!(matches!(verify_line_number(None, Some(5), false, 5), Err(GCodeLineParserError::ChecksumMissing(5))));
+        // M110 sets the line number from its argument, or from its tag
+        assert!(matches!(verify_line_number(Some(Some(0)), Some(9), true, 5), Ok(Some(0))));
+        assert!(matches!(verify_line_number(Some(None), Some(9), false, 5), Ok(Some(9))));
+        assert!(matches!(verify_line_number(Some(None), None, false, 5), Ok(None)));
+    }
+}
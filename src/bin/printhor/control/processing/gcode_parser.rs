@@ -1,4 +1,4 @@
-use crate::control::{GCodeCmd, GCodeValue, N, S, XYZF, XYZE, XYZEFS};
+use crate::control::{GCodeCmd, GCodeParamValue, GCodeValue};
 use crate::helpers;
 use crate::hwa;
 
@@ -8,6 +8,15 @@ pub enum GCodeLineParserError {
     ParseError(u32),
     /// The Gcode was not implemented
     GCodeNotImplemented(u32, alloc::string::String),
+    /// The checksum (*) of the line did not match
+    ChecksumMismatch(u32),
+    /// The line number (N) was not the expected one
+    LineNumberMismatch(u32),
+    /// The line had a line number (N), but no checksum (*)
+    ChecksumMissing(u32),
+    /// The preprocessing of the line (of a file) failed
+    #[cfg(feature = "with-sdcard")]
+    ExpressionError(u32, crate::control::ExpressionError),
     /// EOF Reading from parser
     EOF,
     /// Unexpected fatal error
@@ -30,6 +39,19 @@ impl defmt::Format for GCodeLineParserError {
                     string.as_str()
                 );
             }
+            GCodeLineParserError::ChecksumMismatch(ln) => {
+                defmt::write!(fmt, "ChecksumMismatch(line={})", ln);
+            }
+            GCodeLineParserError::LineNumberMismatch(ln) => {
+                defmt::write!(fmt, "LineNumberMismatch(line={})", ln);
+            }
+            GCodeLineParserError::ChecksumMissing(ln) => {
+                defmt::write!(fmt, "ChecksumMissing(line={})", ln);
+            }
+            #[cfg(feature = "with-sdcard")]
+            GCodeLineParserError::ExpressionError(ln, error) => {
+                defmt::write!(fmt, "ExpressionError(line={}, error={})", ln, error);
+            }
             GCodeLineParserError::FatalError => {
                 defmt::write!(fmt, "FatalError");
             }
@@ -97,13 +119,71 @@ impl core::fmt::Debug for RawGCodeSpec {
     }
 }
 
+/// Tells whether a line carries a checksum (`*`), as the parser only reports when it does not match.
+/// A `*` in a comment does not count
+#[derive(Clone, Copy, Default)]
+pub struct LineChecksumWatch {
+    checksum: bool,
+    comment: bool,
+    end_of_line: bool,
+}
+
+impl LineChecksumWatch {
+    /// Feeds a byte of the line. The first one after the end of a line starts a new one
+    pub fn feed(&mut self, byte: u8) {
+        let end_of_line = matches!(byte, b'\n' | b'\r');
+        if self.end_of_line && !end_of_line {
+            *self = Self::default();
+        }
+        match byte {
+            _ if end_of_line => self.end_of_line = true,
+            b';' | b'(' => self.comment = true,
+            b')' => self.comment = false,
+            b'*' if !self.comment => self.checksum = true,
+            _ => {}
+        }
+    }
+
+    /// Whether the line being fed (or the one just ended) carries a checksum
+    pub fn has_checksum(&self) -> bool {
+        self.checksum
+    }
+}
+
+/// The stream given to the parser: The bytes of `STREAM`, watched for the checksum of the line
+pub struct ChecksumWatchStream<STREAM> {
+    stream: STREAM,
+    watch: alloc::rc::Rc<core::cell::Cell<LineChecksumWatch>>,
+}
+
+impl<STREAM> async_gcode::ByteStream for ChecksumWatchStream<STREAM>
+where
+    STREAM: async_gcode::ByteStream<Item = Result<u8, async_gcode::Error>>,
+{
+    type Item = Result<u8, async_gcode::Error>;
+
+    async fn next(&mut self) -> Option<Self::Item> {
+        let item = self.stream.next().await;
+        if let Some(Ok(byte)) = &item {
+            let mut watch = self.watch.get();
+            watch.feed(*byte);
+            self.watch.set(watch);
+        }
+        item
+    }
+}
+
 // dyn trait could reduce code size a lot with the penalty of the indirection
 pub struct GCodeLineParser<STREAM>
 where
     STREAM: async_gcode::ByteStream<Item = Result<u8, async_gcode::Error>>,
 {
-    raw_parser: async_gcode::Parser<STREAM, async_gcode::Error>,
+    raw_parser: async_gcode::Parser<ChecksumWatchStream<STREAM>, async_gcode::Error>,
     gcode_line: Option<u32>,
+    /// The checksum watch of the stream given to the parser
+    line_checksum: alloc::rc::Rc<core::cell::Cell<LineChecksumWatch>>,
+    /// Whether the last gcode parsed carried a checksum
+    gcode_checksum: bool,
 }
 
 impl<STREAM> GCodeLineParser<STREAM>
@@ -111,9 +191,15 @@ where
     STREAM: async_gcode::ByteStream<Item = Result<u8, async_gcode::Error>>,
 {
     pub fn new(stream: STREAM) -> Self {
+        let line_checksum = alloc::rc::Rc::new(core::cell::Cell::new(LineChecksumWatch::default()));
         Self {
-            raw_parser: async_gcode::Parser::new(stream),
+            raw_parser: async_gcode::Parser::new(ChecksumWatchStream {
+                stream,
+                watch: line_checksum.clone(),
+            }),
             gcode_line: None,
+            line_checksum,
+            gcode_checksum: false,
         }
     }
 
@@ -122,6 +208,11 @@ where
         self.gcode_line
     }
 
+    /// Whether the last gcode parsed carried a checksum (*)
+    pub fn gcode_checksum(&self) -> bool {
+        self.gcode_checksum
+    }
+
     pub async fn next_gcode(&mut self) -> Result<GCodeCmd, GCodeLineParserError> {
 
         // The GcodeCmd being constructed.
@@ -201,6 +292,7 @@ where
                                     // Reset skip_gcode status
                                     skip_gcode = false;
                                     self.gcode_line = line_num;
+                                    self.gcode_checksum = self.line_checksum.get().has_checksum();
                                     match current_gcode.take() {
                                         None => {
                                             match raw_gcode_spec.take() {
@@ -240,6 +332,10 @@ where
                                 async_gcode::Error::BadNumberFormat => {
                                     hwa::warn!("Bad number format");
                                 }
+                                async_gcode::Error::BadChecksum(_cs) => {
+                                    hwa::warn!("Bad checksum: {}", _cs);
+                                    return Err(GCodeLineParserError::ChecksumMismatch(self.raw_parser.get_current_line()));
+                                }
                                 _e => {
                                     #[cfg(feature = "native")]
                                     hwa::error!("Parse error {:?}", _e);
@@ -304,28 +400,47 @@ fn init_current(ch: char, frx: Option<(i32, u8)>) -> Option<GCodeValue> {
         #[cfg(feature = "grbl-compat")]
         ('$', None) => Some(GCodeValue::GRBLCmd),
         ('g', None) => Some(GCodeValue::G),
-        ('g', Some((0, 0))) => Some(GCodeValue::G0(XYZF::new())),
-        ('g', Some((1, 0))) => Some(GCodeValue::G1(XYZEFS::new())),
+        ('g', Some((0, 0))) => Some(GCodeValue::G0),
+        ('g', Some((1, 0))) => Some(GCodeValue::G1),
+        ('g', Some((2, 0))) => Some(GCodeValue::G2),
+        ('g', Some((3, 0))) => Some(GCodeValue::G3),
         ('g', Some((4, 0))) => Some(GCodeValue::G4),
+        ('g', Some((5, 0))) => Some(GCodeValue::G5),
         ('g', Some((10, 0))) => Some(GCodeValue::G10),
+        ('g', Some((11, 0))) => Some(GCodeValue::G11),
         ('g', Some((17, 0))) => Some(GCodeValue::G17),
+        ('g', Some((18, 0))) => Some(GCodeValue::G18),
+        ('g', Some((19, 0))) => Some(GCodeValue::G19),
+        ('g', Some((20, 0))) => Some(GCodeValue::G20),
         ('g', Some((21, 0))) => Some(GCodeValue::G21),
-        ('g', Some((28, 0))) => Some(GCodeValue::G28(XYZE::new())),
+        ('g', Some((22, 0))) => Some(GCodeValue::G22),
+        ('g', Some((23, 0))) => Some(GCodeValue::G23),
+        ('g', Some((28, 0))) => Some(GCodeValue::G28),
         ('g', Some((29, 0))) => Some(GCodeValue::G29),
+        ('g', Some((291, 1))) => Some(GCodeValue::G29_1),
+        ('g', Some((292, 1))) => Some(GCodeValue::G29_2),
         ('g', Some((31, 0))) => Some(GCodeValue::G31),
         ('g', Some((32, 0))) => Some(GCodeValue::G32),
+        ('g', Some((34, 0))) => Some(GCodeValue::G34),
+        ('g', Some((53, 0))) => Some(GCodeValue::G53),
+        ('g', Some((54, 0))) => Some(GCodeValue::G54),
+        ('g', Some((55, 0))) => Some(GCodeValue::G55),
+        ('g', Some((56, 0))) => Some(GCodeValue::G56),
+        ('g', Some((57, 0))) => Some(GCodeValue::G57),
+        ('g', Some((58, 0))) => Some(GCodeValue::G58),
+        ('g', Some((59, 0))) => Some(GCodeValue::G59),
         ('g', Some((80, 0))) => Some(GCodeValue::G80),
         ('g', Some((90, 0))) => Some(GCodeValue::G90),
         ('g', Some((91, 0))) => Some(GCodeValue::G91),
-        ('g', Some((92, 0))) => Some(GCodeValue::G92(XYZE::new())),
+        ('g', Some((92, 0))) => Some(GCodeValue::G92),
+        ('g', Some((921, 1))) => Some(GCodeValue::G92_1),
+        ('g', Some((922, 1))) => Some(GCodeValue::G92_2),
         ('g', Some((94, 0))) => Some(GCodeValue::G94),
-        ('g', Some((291, 1))) => Some(GCodeValue::G29_1),
-        ('g', Some((292, 1))) => Some(GCodeValue::G29_2),
         ('m', None) => Some(GCodeValue::M),
         ('m', Some((3, 0))) => Some(GCodeValue::M3),
         ('m', Some((5, 0))) => Some(GCodeValue::M5),
-        ('m', Some((20, 0))) => Some(GCodeValue::M20(None)),
-        ('m', Some((23, 0))) => Some(GCodeValue::M23(None)),
+        ('m', Some((20, 0))) => Some(GCodeValue::M20),
+        ('m', Some((23, 0))) => Some(GCodeValue::M23),
         ('m', Some((24, 0))) => Some(GCodeValue::M24),
         ('m', Some((25, 0))) => Some(GCodeValue::M25),
         ('m', Some((73, 0))) => Some(GCodeValue::M73),
@@ -334,138 +449,101 @@ fn init_current(ch: char, frx: Option<(i32, u8)>) -> Option<GCodeValue> {
         ('m', Some((81, 0))) => Some(GCodeValue::M81),
         ('m', Some((83, 0))) => Some(GCodeValue::M83),
         ('m', Some((84, 0))) => Some(GCodeValue::M84),
+        ('m', Some((92, 0))) => Some(GCodeValue::M92),
+        ('m', Some((98, 0))) => Some(GCodeValue::M98),
+        ('m', Some((99, 0))) => Some(GCodeValue::M99),
         ('m', Some((100, 0))) => Some(GCodeValue::M100),
-        ('m', Some((104, 0))) => Some(GCodeValue::M104(S::new())),
+        ('m', Some((104, 0))) => Some(GCodeValue::M104),
         ('m', Some((105, 0))) => Some(GCodeValue::M105),
         ('m', Some((106, 0))) => Some(GCodeValue::M106),
         ('m', Some((107, 0))) => Some(GCodeValue::M107),
-        ('m', Some((109, 0))) => Some(GCodeValue::M109(S::new())),
-        ('m', Some((110, 0))) => Some(GCodeValue::M110(N::new())),
+        ('m', Some((108, 0))) => Some(GCodeValue::M108),
+        ('m', Some((109, 0))) => Some(GCodeValue::M109),
+        ('m', Some((110, 0))) => Some(GCodeValue::M110),
+        ('m', Some((112, 0))) => Some(GCodeValue::M112),
         ('m', Some((114, 0))) => Some(GCodeValue::M114),
         ('m', Some((115, 0))) => Some(GCodeValue::M115),
         ('m', Some((117, 0))) => Some(GCodeValue::M117),
         ('m', Some((119, 0))) => Some(GCodeValue::M119),
-        ('m', Some((140, 0))) => Some(GCodeValue::M140(S::new())),
+        ('m', Some((140, 0))) => Some(GCodeValue::M140),
         ('m', Some((190, 0))) => Some(GCodeValue::M190),
         ('m', Some((201, 0))) => Some(GCodeValue::M201),
         ('m', Some((203, 0))) => Some(GCodeValue::M203),
         ('m', Some((204, 0))) => Some(GCodeValue::M204),
         ('m', Some((205, 0))) => Some(GCodeValue::M205),
         ('m', Some((206, 0))) => Some(GCodeValue::M206),
-        ('m', Some((220, 0))) => Some(GCodeValue::M220(S::new())),
-        ('m', Some((221, 0))) => Some(GCodeValue::M221(S::new())),
+        ('m', Some((207, 0))) => Some(GCodeValue::M207),
+        ('m', Some((208, 0))) => Some(GCodeValue::M208),
+        ('m', Some((209, 0))) => Some(GCodeValue::M209),
+        ('m', Some((210, 0))) => Some(GCodeValue::M210),
+        ('m', Some((211, 0))) => Some(GCodeValue::M211),
+        ('m', Some((220, 0))) => Some(GCodeValue::M220),
+        ('m', Some((221, 0))) => Some(GCodeValue::M221),
+        ('m', Some((229, 0))) => Some(GCodeValue::M229),
+        ('m', Some((290, 0))) => Some(GCodeValue::M290),
+        ('m', Some((400, 0))) => Some(GCodeValue::M400),
+        ('m', Some((410, 0))) => Some(GCodeValue::M410),
+        ('m', Some((422, 0))) => Some(GCodeValue::M422),
+        ('m', Some((425, 0))) => Some(GCodeValue::M425),
         ('m', Some((502, 0))) => Some(GCodeValue::M502),
+        ('m', Some((593, 0))) => Some(GCodeValue::M593),
+        ('m', Some((665, 0))) => Some(GCodeValue::M665),
+        ('m', Some((666, 0))) => Some(GCodeValue::M666),
+        ('m', Some((851, 0))) => Some(GCodeValue::M851),
         ('m', Some((8621, 1))) => Some(GCodeValue::M862_1),
         ('m', Some((8623, 1))) => Some(GCodeValue::M862_3),
         ('m', Some((900, 0))) => Some(GCodeValue::M900),
         ('m', Some((907, 0))) => Some(GCodeValue::M907),
+        ('m', Some((914, 0))) => Some(GCodeValue::M914),
         _ => {
             None
         }
     }
 }
 
+/// Stores the word in the parameter set of the GCodeCmd being built
 fn update_current(gcode_cmd: &mut GCodeCmd, ch: char, frx: Option<(i32, u8)>, fv: async_gcode::RealValue) {
-    match &mut gcode_cmd.value {
-        #[cfg(feature = "grbl-compat")]
-        GCodeValue::Status => match (ch, frx) {
-            ('I', Some(val)) => {
-                hwa::warn!("TODO!!");
-            }
-            _ => {}
-        },
-        GCodeValue::G0(coord) => match (ch, frx) {
-            ('x', Some(val)) => {
-                coord.x.replace(helpers::to_fixed(val));
-            }
-            ('y', Some(val)) => {
-                coord.y.replace(helpers::to_fixed(val));
-            }
-            ('z', Some(val)) => {
-                coord.z.replace(helpers::to_fixed(val));
-            }
-            ('f', Some(val)) => {
-                coord.f.replace(helpers::to_fixed(val));
-            }
-            _ => {}
-        },
-
-        GCodeValue::G1(coord) => match (ch, frx) {
-            ('x', Some(val)) => {
-                coord.x.replace(helpers::to_fixed(val));
-            }
-            ('y', Some(val)) => {
-                coord.y.replace(helpers::to_fixed(val));
-            }
-            ('z', Some(val)) => {
-                coord.z.replace(helpers::to_fixed(val));
-            }
-            ('e', Some(val)) => {
-                coord.e.replace(helpers::to_fixed(val));
-            }
-            _ => {}
-        },
-        GCodeValue::G28(coord) => match (ch, frx) {
-            ('x', Some(val)) => {
-                coord.x.replace(helpers::to_fixed(val));
-            }
-            ('y', Some(val)) => {
-                coord.y.replace(helpers::to_fixed(val));
-            }
-            ('z', Some(val)) => {
-                coord.z.replace(helpers::to_fixed(val));
-            }
-            _ => {}
-        },
-        GCodeValue::G92(coord) => match (ch, frx) {
-            ('x', Some(val)) => {
-                coord.x.replace(helpers::to_fixed(val));
-            }
-            ('y', Some(val)) => {
-                coord.y.replace(helpers::to_fixed(val));
-            }
-            ('z', Some(val)) => {
-                coord.z.replace(helpers::to_fixed(val));
-            }
-            ('e', Some(val)) => {
-                coord.e.replace(helpers::to_fixed(val));
-            }
-            _ => {}
-        },
-        GCodeValue::M20(path) => {
-            if ch == 'f' {
-                if let async_gcode::RealValue::Literal(
-                    async_gcode::Literal::String(mstr),
-                ) = fv {
-                    path.replace(mstr);
-                }
-            }
-        }
-        GCodeValue::M23(file) => {
-            if ch == 'f' {
-                if let async_gcode::RealValue::Literal(
-                    async_gcode::Literal::String(mstr),
-                ) = fv
-                {
-                    file.replace(mstr);
-                }
-            }
+    let value = match (frx, fv) {
+        (Some(val), _) => GCodeParamValue::Number(helpers::to_fixed(val)),
+        (None, async_gcode::RealValue::Literal(async_gcode::Literal::String(mstr))) => {
+            GCodeParamValue::Text(mstr)
         }
-        GCodeValue::M104(coord)
-        | GCodeValue::M109(coord)
-        | GCodeValue::M140(coord)
-        | GCodeValue::M220(coord)  => match (ch, frx) {
-            ('s', Some(val)) => {
-                coord.s.replace(helpers::to_fixed(val));
-            }
-            _ => {}
-        },
-        GCodeValue::M110(coord) => match (ch, frx) {
-            ('n', Some(val)) => {
-                coord.n.replace(helpers::to_fixed(val));
-            }
-            _ => {}
-        },
-        _ => {}
+        (None, _) => GCodeParamValue::Flag,
+    };
+    gcode_cmd.params.set(ch, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has_checksum(line: &str) -> bool {
+        let mut watch = LineChecksumWatch::default();
+        line.bytes().for_each(|b| watch.feed(b));
+        watch.has_checksum()
+    }
+
+    #[test]
+    fn line_checksum_watch() {
+        assert!(has_checksum("N3 G1 X10*86\n"));
+        assert!(has_checksum("N3 G1 X10*86\r\n"));
+        assert!(!has_checksum("N3 G1 X10\n"));
+        assert!(!has_checksum("N3 G1 X10 ; no checksum*\n"));
+        assert!(!has_checksum("N3 G1 (not *) X10\n"));
+        // A new line starts without checksum
+        assert!(!has_checksum("N3 G1 X10*86\nN4 G1 X20\n"));
+        assert!(has_checksum("N3 G1 X10\nN4 G1 X20*85\n"));
+    }
+
+    #[test]
+    fn gcode_lookup() {
+        assert!(matches!(init_current('g', Some((1, 0))), Some(GCodeValue::G1)));
+        assert!(matches!(init_current('g', Some((291, 1))), Some(GCodeValue::G29_1)));
+        assert!(matches!(init_current('m', Some((8623, 1))), Some(GCodeValue::M862_3)));
+        assert!(matches!(init_current('m', None), Some(GCodeValue::M)));
+        // Not implemented, even if known
+        assert!(init_current('m', Some((500, 0))).is_none());
+        assert!(init_current('m', Some((9999, 0))).is_none());
+        assert!(init_current('x', Some((1, 0))).is_none());
     }
-}
\ No newline at end of file
+}
@@ -58,7 +58,7 @@ use hwa::controllers::ProbeTrait;
 use hwa::{CommChannel, DeferAction, DeferEvent, EventBusRef, EventFlags, EventStatus};
 use strum::VariantNames;
 #[cfg(feature = "with-motion")]
-use crate::tgeo::TVector;
+use crate::tgeo::{CoordSel, TVector};
 cfg_if::cfg_if! {
     if #[cfg(any(feature = "with-serial-port-1", feature="with-serial-port-2"))]
     {
@@ -266,6 +266,33 @@ impl GCodeProcessor {
         let result = match &gc.value {
             #[cfg(feature = "grbl-compat")]
             GCodeValue::GRBLCmd => {
+                #[cfg(feature = "with-motion")]
+                if gc.params.has('#') {
+                    // $#: The work coordinate offsets
+                    let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                    let offsets = self.motion_planner.get_work_offsets().await;
+                    for (idx, offset) in offsets.iter().enumerate() {
+                        let offset = *offset / mm_per_unit;
+                        let str = format!(
+                            "[G{}:{},{},{}]\n",
+                            54 + idx,
+                            offset.x.unwrap_or(math::ZERO),
+                            offset.y.unwrap_or(math::ZERO),
+                            offset.z.unwrap_or(math::ZERO),
+                        );
+                        self.write(channel, str.as_str()).await;
+                    }
+                    let g92 = self.motion_planner.get_g92_offset().await
+                        .unwrap_or(TVector::zero()) / mm_per_unit;
+                    let str = format!(
+                        "[G92:{},{},{}]\n",
+                        g92.x.unwrap_or(math::ZERO),
+                        g92.y.unwrap_or(math::ZERO),
+                        g92.z.unwrap_or(math::ZERO),
+                    );
+                    self.write(channel, str.as_str()).await;
+                    return Ok(CodeExecutionSuccess::OK);
+                }
                 let str = format!(
                     "[VER:1.1 {} v{}:]\n[MSG: Machine: {} {}]\n",
                     MACHINE_INFO.firmware_name,
@@ -285,7 +312,7 @@ impl GCodeProcessor {
                 Ok(CodeExecutionSuccess::OK)
             }
             #[cfg(feature = "with-motion")]
-            GCodeValue::G0(_) | GCodeValue::G1(_) => {
+            GCodeValue::G0 | GCodeValue::G1 | GCodeValue::G2 | GCodeValue::G3 | GCodeValue::G5 | GCodeValue::G53 => {
                 if !self
                     .event_bus
                     .get_status()
@@ -312,11 +339,107 @@ impl GCodeProcessor {
                     .plan(channel, &gc, blocking, &self.event_bus)
                     .await?)
             }
-            GCodeValue::G10 => Ok(CodeExecutionSuccess::OK),
-            GCodeValue::G17 => Ok(CodeExecutionSuccess::OK),
-            GCodeValue::G21 => Ok(CodeExecutionSuccess::OK),
             #[cfg(feature = "with-motion")]
-            GCodeValue::G28(_) => match self.event_bus.has_flags(EventFlags::HOMING).await {
+            GCodeValue::M400 => {
+                // A dwell of no duration completes once the moves queued before are done
+                if !blocking {
+                    self.motion_planner
+                        .defer_channel
+                        .send(DeferEvent::AwaitRequested(DeferAction::Dwell, channel))
+                        .await;
+                }
+                let dwell = GCodeCmd::new(gc.order_num, gc.line_tag, GCodeValue::G4);
+                Ok(self
+                    .motion_planner
+                    .plan(channel, &dwell, blocking, &self.event_bus)
+                    .await?)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G10 => match gc.params.int('l') {
+                // L2: Set the offset. L20: Set the offset from the current position
+                Some(2) | Some(20) => {
+                    // P1 to P6 select G54 to G59. P0 (or none) the current one
+                    let system = match gc.params.int('p') {
+                        None | Some(0) => None,
+                        Some(p) if (1..=hwa::controllers::NUM_WORK_COORDINATE_SYSTEMS as i32).contains(&p) => {
+                            Some(p as usize - 1)
+                        }
+                        Some(_) => return Err(CodeExecutionFailure::ERR),
+                    };
+                    let values = self.motion_planner.params_to_mm(&gc.params, "xyz").await.vector();
+                    if gc.params.int('l') == Some(2) {
+                        self.motion_planner.set_work_offset(system, &values).await;
+                    } else {
+                        self.motion_planner.set_work_position(system, &values).await?;
+                    }
+                    Ok(CodeExecutionSuccess::OK)
+                }
+                // Without L: Firmware retraction (See M207)
+                None => {
+                    if !self
+                        .event_bus
+                        .get_status()
+                        .await
+                        .contains(EventFlags::ATX_ON)
+                    {
+                        return Err(CodeExecutionFailure::PowerRequired);
+                    }
+                    Ok(self
+                        .motion_planner
+                        .retract(channel, blocking, &self.event_bus, gc.order_num, gc.line_tag)
+                        .await?)
+                }
+                // Neither the tool offsets (L1, L10) nor any other
+                Some(_) => Err(CodeExecutionFailure::ERR),
+            },
+            // Firmware retraction (G22) and recovery (G11, G23)
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G11 | GCodeValue::G22 | GCodeValue::G23 => {
+                if !self
+                    .event_bus
+                    .get_status()
+                    .await
+                    .contains(EventFlags::ATX_ON)
+                {
+                    return Err(CodeExecutionFailure::PowerRequired);
+                }
+                let result = match gc.value == GCodeValue::G22 {
+                    true => self.motion_planner
+                        .retract(channel, blocking, &self.event_bus, gc.order_num, gc.line_tag)
+                        .await,
+                    false => self.motion_planner
+                        .recover(channel, blocking, &self.event_bus, gc.order_num, gc.line_tag)
+                        .await,
+                };
+                Ok(result?)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G17 => {
+                self.motion_planner.set_arc_plane(hwa::controllers::ArcPlane::XY).await;
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G18 => {
+                self.motion_planner.set_arc_plane(hwa::controllers::ArcPlane::ZX).await;
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G19 => {
+                self.motion_planner.set_arc_plane(hwa::controllers::ArcPlane::YZ).await;
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G20 => {
+                self.motion_planner.set_unit_mode(hwa::controllers::UnitMode::Inches).await;
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G21 => {
+                self.motion_planner.set_unit_mode(hwa::controllers::UnitMode::Millimeters).await;
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G28 => match self.event_bus.has_flags(EventFlags::HOMING).await {
                 true => Err(CodeExecutionFailure::BUSY),
                 false => {
                     if !self
@@ -376,6 +499,23 @@ impl GCodeProcessor {
                     Ok(CodeExecutionSuccess::OK)
                 }
             }
+            #[cfg(feature = "with-z2-axis")]
+            GCodeValue::G34 => match self.event_bus.has_flags(EventFlags::HOMING).await {
+                true => Err(CodeExecutionFailure::BUSY),
+                false => {
+                    if !self
+                        .event_bus
+                        .get_status()
+                        .await
+                        .contains(EventFlags::ATX_ON)
+                    {
+                        return Err(CodeExecutionFailure::PowerRequired);
+                    }
+                    self.motion_planner
+                        .plan(channel, &gc, blocking, &self.event_bus)
+                        .await
+                }
+            },
             GCodeValue::G80 => Ok(CodeExecutionSuccess::OK),
             #[cfg(feature = "with-motion")]
             GCodeValue::G90 => {
@@ -388,15 +528,38 @@ impl GCodeProcessor {
                 Ok(CodeExecutionSuccess::OK)
             }
             #[cfg(feature = "with-motion")]
-            GCodeValue::G92(_pos) => {
-                self.motion_planner
-                    .set_last_planned_pos(&TVector {
-                        x: _pos.x,
-                        y: _pos.y,
-                        z: _pos.z,
-                        e: _pos.e,
-                    })
-                    .await;
+            GCodeValue::G54
+            | GCodeValue::G55
+            | GCodeValue::G56
+            | GCodeValue::G57
+            | GCodeValue::G58
+            | GCodeValue::G59 => {
+                let system = match gc.value {
+                    GCodeValue::G54 => 0,
+                    GCodeValue::G55 => 1,
+                    GCodeValue::G56 => 2,
+                    GCodeValue::G57 => 3,
+                    GCodeValue::G58 => 4,
+                    _ => 5,
+                };
+                self.motion_planner.select_work_coordinate_system(system).await;
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G92 => {
+                let params = self.motion_planner.params_to_mm(&gc.params, "xyze").await;
+                // X, Y and Z are set through the G92 offset, so machine coordinates are kept
+                self.motion_planner.set_g92_position(&params.vector()).await;
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G92_1 => {
+                self.motion_planner.reset_g92_offset(true).await;
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::G92_2 => {
+                self.motion_planner.reset_g92_offset(false).await;
                 Ok(CodeExecutionSuccess::OK)
             }
             GCodeValue::G94 => Ok(CodeExecutionSuccess::OK),
@@ -479,7 +642,7 @@ impl GCodeProcessor {
             // Set hotend temperature
             // An immediate command
             #[cfg(feature = "with-hot-end")]
-            GCodeValue::M104(s) => {
+            GCodeValue::M104 => {
                 if !self
                     .event_bus
                     .get_status()
@@ -488,7 +651,7 @@ impl GCodeProcessor {
                 {
                     return Err(CodeExecutionFailure::PowerRequired);
                 }
-                let val = s.s.and_then(|v| v.to_i32()).unwrap_or(0);
+                let val = gc.params.int('s').unwrap_or(0);
                 let mut h = self.hotend.lock().await;
                 h.set_target_temp(
                     CommChannel::Internal,
@@ -547,7 +710,8 @@ impl GCodeProcessor {
                     return Err(CodeExecutionFailure::PowerRequired);
                 }
                 //crate::info!("M106 BEGIN");
-                self.fan_layer.lock().await.set_power(255).await;
+                let power = gc.params.int('s').unwrap_or(255).clamp(0, 255);
+                self.fan_layer.lock().await.set_power(power as u8).await;
                 //crate::info!("M106 END");
                 Ok(CodeExecutionSuccess::OK)
             }
@@ -559,7 +723,7 @@ impl GCodeProcessor {
             // Wait for hot-end temperature
             // Mostly deferred code
             #[cfg(feature = "with-hot-end")]
-            GCodeValue::M109(s) => {
+            GCodeValue::M109 => {
                 if !self
                     .event_bus
                     .get_status()
@@ -570,7 +734,7 @@ impl GCodeProcessor {
                 }
                 let deferred = {
                     let mut he = self.hotend.lock().await;
-                    let value = s.s.and_then(|v| v.to_i32()).unwrap_or(0);
+                    let value = gc.params.int('s').unwrap_or(0);
                     let was_deferred = he
                         .set_target_temp(channel, DeferAction::HotEndTemperature, value as f32)
                         .await;
@@ -584,21 +748,43 @@ impl GCodeProcessor {
                     Ok(CodeExecutionSuccess::OK)
                 }
             }
-            GCodeValue::M110(_n) => Ok(CodeExecutionSuccess::OK),
+            // Return from the main program: Nothing to do
+            GCodeValue::M99 => Ok(CodeExecutionSuccess::OK),
+            GCodeValue::M110 => Ok(CodeExecutionSuccess::OK),
+            GCodeValue::M108 | GCodeValue::M112 | GCodeValue::M410 => {
+                // The serial channels are scanned for these when received, so they were already performed.
+                // The gcode files are not.
+                if channel == CommChannel::Internal {
+                    if let Some(command) = crate::control::EmergencyCommand::from_gcode(&gc.value) {
+                        self.emergency(channel, command).await;
+                    }
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
             #[cfg(feature = "with-motion")]
             GCodeValue::M114 => {
+                // Reported in the active units
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
                 let _pos = self
+                    .motion_planner
+                    .get_last_planned_work_pos()
+                    .await
+                    .unwrap_or(TVector::zero())
+                    / mm_per_unit;
+                let _mpos = self
                     .motion_planner
                     .get_last_planned_pos()
                     .await
-                    .unwrap_or(TVector::zero());
+                    .unwrap_or(TVector::zero())
+                    / mm_per_unit;
+                // Count is given in motor space (steps), according to the machine kinematics
                 let _spos = self
                     .motion_planner
                     .get_last_planned_real_pos()
                     .await
                     .unwrap_or(TVector::zero());
                 let z = alloc::format!(
-                    "X:{} Y:{} Z:{} E:{} Count X:{} Y:{} Z:{}\n",
+                    "X:{} Y:{} Z:{} E:{} Count X:{} Y:{} Z:{} Machine X:{} Y:{} Z:{}\n",
                     _pos.x.unwrap_or(math::ZERO),
                     _pos.y.unwrap_or(math::ZERO),
                     _pos.z.unwrap_or(math::ZERO),
@@ -606,6 +792,9 @@ impl GCodeProcessor {
                     _spos.x.unwrap_or(math::ZERO),
                     _spos.y.unwrap_or(math::ZERO),
                     _spos.z.unwrap_or(math::ZERO),
+                    _mpos.x.unwrap_or(math::ZERO),
+                    _mpos.y.unwrap_or(math::ZERO),
+                    _mpos.z.unwrap_or(math::ZERO),
                 );
                 let _ = self.write(channel, z.as_str()).await;
 
@@ -667,7 +856,7 @@ impl GCodeProcessor {
             // Set hot-bed temperature
             // An immediate command
             #[cfg(feature = "with-hot-bed")]
-            GCodeValue::M140(s) => {
+            GCodeValue::M140 => {
                 if !self
                     .event_bus
                     .get_status()
@@ -676,7 +865,7 @@ impl GCodeProcessor {
                 {
                     return Err(CodeExecutionFailure::PowerRequired);
                 }
-                let val = s.s.and_then(|v| v.to_i32()).unwrap_or(0);
+                let val = gc.params.int('s').unwrap_or(0);
                 let mut h = self.hotbed.lock().await;
                 h.set_target_temp(
                     CommChannel::Internal,
@@ -711,13 +900,544 @@ impl GCodeProcessor {
                     Ok(CodeExecutionSuccess::OK)
                 }
             }
-            GCodeValue::M201 => Ok(CodeExecutionSuccess::OK),
-            GCodeValue::M203 => Ok(CodeExecutionSuccess::OK),
-            GCodeValue::M204 => Ok(CodeExecutionSuccess::OK),
-            GCodeValue::M205 => Ok(CodeExecutionSuccess::OK),
-            GCodeValue::M206 => Ok(CodeExecutionSuccess::OK),
-            GCodeValue::M220(_) => Ok(CodeExecutionSuccess::OK),
-            GCodeValue::M221(_) => Ok(CodeExecutionSuccess::OK),
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M92 => {
+                let steps_per_mm = gc.params.vector();
+                self.motion_planner
+                    .motion_cfg()
+                    .lock()
+                    .await
+                    .units_per_mm
+                    .assign_if_set(CoordSel::all(), &steps_per_mm);
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M201 => {
+                let max_accel = gc.params.vector().map_coords(|v| v.to_i32().map(|v| v.max(0) as u32));
+                self.motion_planner
+                    .motion_cfg()
+                    .lock()
+                    .await
+                    .max_accel
+                    .assign_if_set(CoordSel::all(), &max_accel);
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M203 => {
+                let max_speed = gc.params.vector().map_coords(|v| v.to_i32().map(|v| v.max(0) as u32));
+                self.motion_planner
+                    .motion_cfg()
+                    .lock()
+                    .await
+                    .max_speed
+                    .assign_if_set(CoordSel::all(), &max_speed);
+                Ok(CodeExecutionSuccess::OK)
+            }
+            GCodeValue::M204 => Err(CodeExecutionFailure::NotYetImplemented),
+            // Junction deviation (J) in mm. Always in mm, regardless of the unit mode. J0 restores the projection rule
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M205 => {
+                match gc.params.real('j') {
+                    Some(junction_deviation) => {
+                        self.motion_planner
+                            .set_junction_deviation(junction_deviation.max(math::ZERO))
+                            .await;
+                    }
+                    None => {
+                        let s = alloc::format!(
+                            "echo: M205 J{}\n",
+                            self.motion_planner.get_junction_deviation().await
+                        );
+                        self.write(channel, s.as_str()).await;
+                    }
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Home offsets (X, Y, Z) in the active units, applied from the next homing of each axis
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M206 => {
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                let p = self.motion_planner.params_to_mm(&gc.params, "xyz").await;
+                // X and Y are not linear in the motors of these kinematics, so only Z can be offset
+                #[cfg(any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar"))]
+                if ['x', 'y'].iter().any(|letter| p.real(*letter).is_some_and(|offset| !offset.is_zero())) {
+                    return Err(CodeExecutionFailure::ERR);
+                }
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if p.has('x') || p.has('y') || p.has('z') {
+                    for (axis, letter) in ['x', 'y', 'z'].iter().enumerate() {
+                        if let Some(offset) = p.real(*letter) {
+                            cfg_g.homing.axes[axis].offset = offset;
+                        }
+                    }
+                } else {
+                    let homing = cfg_g.homing;
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M206 X{} Y{} Z{}\n",
+                        homing.axes[0].offset / mm_per_unit,
+                        homing.axes[1].offset / mm_per_unit,
+                        homing.axes[2].offset / mm_per_unit
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Firmware retraction: Length (S), Z hop (Z) and speed (F), in the active units
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M207 => {
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                let p = self.motion_planner.params_to_mm(&gc.params, "sfz").await;
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if p.has('s') || p.has('f') || p.has('z') {
+                    let retraction = &mut cfg_g.retraction;
+                    retraction.length = p.real('s').unwrap_or(retraction.length).max(math::ZERO);
+                    retraction.speed = p.real('f').unwrap_or(retraction.speed).max(math::ZERO);
+                    retraction.z_hop = p.real('z').unwrap_or(retraction.z_hop).max(math::ZERO);
+                } else {
+                    let retraction = cfg_g.retraction;
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M207 S{} F{} Z{}\n",
+                        retraction.length / mm_per_unit,
+                        retraction.speed / mm_per_unit,
+                        retraction.z_hop / mm_per_unit
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Firmware recovery: Extra length (S), on top of the retracted one, and speed (F), in the active units.
+            // F0 takes the speed of the retraction
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M208 => {
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                let p = self.motion_planner.params_to_mm(&gc.params, "sf").await;
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if p.has('s') || p.has('f') {
+                    let retraction = &mut cfg_g.retraction;
+                    retraction.recover_extra = p.real('s').unwrap_or(retraction.recover_extra);
+                    retraction.recover_speed = p.real('f').unwrap_or(retraction.recover_speed).max(math::ZERO);
+                } else {
+                    let retraction = cfg_g.retraction;
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M208 S{} F{}\n",
+                        retraction.recover_extra / mm_per_unit,
+                        retraction.recover_speed / mm_per_unit
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Automatic retraction: The E only moves are taken as retractions and recoveries (S1) or not (S0)
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M209 => {
+                match gc.params.int('s') {
+                    Some(s) => {
+                        self.motion_planner.motion_cfg().lock().await.retraction.auto_retract = s != 0;
+                    }
+                    None => {
+                        let auto_retract = self.motion_planner.motion_cfg().lock().await.retraction.auto_retract;
+                        let s = alloc::format!("echo: M209 S{}\n", auto_retract as u8);
+                        self.write(channel, s.as_str()).await;
+                    }
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Homing of the X, Y and Z axes given (all of them if none): Order (O), lower first, direction (D),
+            // -1 for the min. end and 1 for the max. one, fast (F) and slow (S) feed rates, bump (B) in the active
+            // units, whether the probe is the endstop (P1) or not (P0), Z only, and whether the stall of the motor
+            // is (H1) or not (H0), with Trinamic drivers. A value of the axis letter sets its fast feed rate, as in
+            // Marlin. Applied from the next homing. Reported when none is set
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M210 => {
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                let p = self.motion_planner.params_to_mm(&gc.params, "xyzfsb").await;
+                let letters = ['x', 'y', 'z'];
+                let axes = match letters.iter().any(|letter| p.has(*letter)) {
+                    true => letters.map(|letter| p.has(letter)),
+                    false => [true; 3],
+                };
+                if p.int('d').is_some_and(|d| d != -1 && d != 1)
+                    || ['f', 's'].iter().chain(letters.iter()).any(|letter| {
+                        p.real(*letter).is_some_and(|speed| !speed.is_defined_positive())
+                    })
+                    || p.real('b').is_some_and(|bump| bump < math::ZERO)
+                    || (p.has('p') && (axes[0] || axes[1]))
+                {
+                    return Err(CodeExecutionFailure::ERR);
+                }
+                #[cfg(not(feature = "with-probe"))]
+                if p.int('p').is_some_and(|use_probe| use_probe != 0) {
+                    return Err(CodeExecutionFailure::ERR);
+                }
+                #[cfg(not(feature = "with-trinamic"))]
+                if p.int('h').is_some_and(|sensorless| sensorless != 0) {
+                    return Err(CodeExecutionFailure::ERR);
+                }
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if ['o', 'd', 'f', 's', 'b', 'p', 'h'].iter().chain(letters.iter()).any(|letter| p.real(*letter).is_some()) {
+                    for (axis, letter) in letters.iter().enumerate().filter(|(axis, _)| axes[*axis]) {
+                        let axis_homing = &mut cfg_g.homing.axes[axis];
+                        if let Some(order) = p.int('o') {
+                            axis_homing.order = order.clamp(0, 255) as u8;
+                        }
+                        if let Some(direction) = p.int('d') {
+                            axis_homing.direction = match direction > 0 {
+                                true => hwa::controllers::HomingDirection::Max,
+                                false => hwa::controllers::HomingDirection::Min,
+                            };
+                        }
+                        axis_homing.fast_speed = p.real('f').or(p.real(*letter)).unwrap_or(axis_homing.fast_speed);
+                        axis_homing.slow_speed = p.real('s').unwrap_or(axis_homing.slow_speed);
+                        axis_homing.bump = p.real('b').unwrap_or(axis_homing.bump);
+                        if let Some(use_probe) = p.int('p') {
+                            axis_homing.use_probe = use_probe != 0;
+                        }
+                        if let Some(sensorless) = p.int('h') {
+                            axis_homing.sensorless = sensorless != 0;
+                        }
+                    }
+                } else {
+                    let homing = cfg_g.homing;
+                    drop(cfg_g);
+                    for (axis, letter) in ['X', 'Y', 'Z'].iter().enumerate().filter(|(axis, _)| axes[*axis]) {
+                        let axis_homing = &homing.axes[axis];
+                        let s = alloc::format!(
+                            "echo: M210 {} O{} D{} F{} S{} B{} P{} H{}\n",
+                            letter,
+                            axis_homing.order,
+                            axis_homing.sign(),
+                            axis_homing.fast_speed / mm_per_unit,
+                            axis_homing.slow_speed / mm_per_unit,
+                            axis_homing.bump / mm_per_unit,
+                            axis_homing.use_probe as u8,
+                            axis_homing.sensorless as u8
+                        );
+                        self.write(channel, s.as_str()).await;
+                    }
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Soft endstops: Enabled (S1) or not (S0). The moves out of them are clamped (C1) or rejected (C0)
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M211 => {
+                let p = &gc.params;
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if p.has('s') || p.has('c') {
+                    if let Some(s) = p.int('s') {
+                        cfg_g.soft_endstops.enabled = s != 0;
+                    }
+                    if let Some(c) = p.int('c') {
+                        cfg_g.soft_endstops.action = match c != 0 {
+                            true => hwa::controllers::SoftEndstopAction::Clamp,
+                            false => hwa::controllers::SoftEndstopAction::Reject,
+                        };
+                    }
+                } else {
+                    let soft_endstops = cfg_g.soft_endstops;
+                    drop(cfg_g);
+                    let limit = |c: Option<math::Real>| match c {
+                        Some(c) => alloc::format!("{}", c),
+                        None => alloc::string::String::from("-"),
+                    };
+                    let s = alloc::format!(
+                        "echo: M211 S{} C{} Min: X{} Y{} Z{} Max: X{} Y{} Z{}\n",
+                        soft_endstops.enabled as u8,
+                        (soft_endstops.action == hwa::controllers::SoftEndstopAction::Clamp) as u8,
+                        limit(soft_endstops.min.x), limit(soft_endstops.min.y), limit(soft_endstops.min.z),
+                        limit(soft_endstops.max.x), limit(soft_endstops.max.y), limit(soft_endstops.max.z),
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Input shaping of the given channels (X, Y, Z. X and Y if none): Shaper type (P: "none",
+            // "zv", "mzv", "zvd", "ei" or "2hump_ei"), frequency (F) in Hz and damping ratio (D)
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M593 => {
+                let p = &gc.params;
+                let kind = match p.string('p') {
+                    Some(kind) => match kind.parse::<hwa::controllers::ShaperType>() {
+                        Ok(kind) => Some(kind),
+                        Err(_) => return Err(CodeExecutionFailure::ERR),
+                    },
+                    None => None,
+                };
+                let mut channels = [p.has('x'), p.has('y'), p.has('z')];
+                if !channels.iter().any(|c| *c) {
+                    channels = [true, true, false];
+                }
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if kind.is_some() || p.has('f') || p.has('d') {
+                    let selected = cfg_g.input_shapers.iter_mut().zip(channels).filter(|(_, c)| *c);
+                    for (shaper, _) in selected {
+                        let kind = kind.unwrap_or(match shaper.is_active() {
+                            true => shaper.kind(),
+                            false => hwa::controllers::ShaperType::ZV,
+                        });
+                        let frequency = p.real('f').unwrap_or(shaper.frequency());
+                        let damping = p.real('d').unwrap_or(match shaper.is_active() {
+                            true => shaper.damping(),
+                            false => math::Real::from_lit(1, 1),
+                        });
+                        *shaper = hwa::controllers::InputShaper::new(kind, frequency, damping);
+                    }
+                } else {
+                    let shapers = cfg_g.input_shapers;
+                    drop(cfg_g);
+                    for (shaper, axis) in shapers.iter().zip(["X", "Y", "Z"]) {
+                        let s = alloc::format!(
+                            "echo: M593 {} P\"{}\" F{} D{}\n",
+                            axis, shaper.kind().as_ref(), shaper.frequency(), shaper.damping()
+                        );
+                        self.write(channel, s.as_str()).await;
+                    }
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Delta geometry: Diagonal rod (L), radius (R), height (H), print radius (B) in mm,
+            // segments per second (S) and tower angle trims (X, Y, Z) in degrees
+            #[cfg(all(feature = "with-motion", feature = "kinematics-delta"))]
+            GCodeValue::M665 => {
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                let p = &gc.params;
+                let (l, r, h, b, s) = (p.real('l'), p.real('r'), p.real('h'), p.real('b'), p.real('s'));
+                let trim = [p.real('x'), p.real('y'), p.real('z')];
+                if [l, r, h, b, s].iter().chain(trim.iter()).any(|v| v.is_some()) {
+                    cfg_g.kinematics.set_geometry(l, r, h, b, s, trim);
+                } else {
+                    let (l, r, h, b, s, trim) = cfg_g.kinematics.geometry();
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M665 L{} R{} H{} B{} S{} X{} Y{} Z{}\n",
+                        l, r, h, b, s, trim[0], trim[1], trim[2]
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // SCARA geometry: Proximal (P) and distal (D) arm lengths and shoulder position (X, Y)
+            // in mm, arm angles at the endstops (A, B) in degrees and segments per second (S)
+            #[cfg(all(feature = "with-motion", feature = "kinematics-scara"))]
+            GCodeValue::M665 => {
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                let p = &gc.params;
+                let (proximal, distal, s) = (p.real('p'), p.real('d'), p.real('s'));
+                let shoulder = [p.real('x'), p.real('y')];
+                let home_angles = [p.real('a'), p.real('b')];
+                if [proximal, distal, s].iter().chain(shoulder.iter()).chain(home_angles.iter()).any(|v| v.is_some()) {
+                    cfg_g.kinematics.set_geometry(proximal, distal, shoulder, home_angles, s);
+                } else {
+                    let (proximal, distal, shoulder, home_angles, s) = cfg_g.kinematics.geometry();
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M665 P{} D{} X{} Y{} A{} B{} S{}\n",
+                        proximal, distal, shoulder[0], shoulder[1], home_angles[0], home_angles[1], s
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Polar geometry: Bed radius (R) in mm, bed angle (A) in degrees and arm radius (B) in mm
+            // at the endstops and segments per second (S)
+            #[cfg(all(feature = "with-motion", feature = "kinematics-polar"))]
+            GCodeValue::M665 => {
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                let p = &gc.params;
+                let (r, a, b, s) = (p.real('r'), p.real('a'), p.real('b'), p.real('s'));
+                if [r, a, b, s].iter().any(|v| v.is_some()) {
+                    cfg_g.kinematics.set_geometry(r, a, b, s);
+                } else {
+                    let (r, a, b, s) = cfg_g.kinematics.geometry();
+                    drop(cfg_g);
+                    let s = alloc::format!("echo: M665 R{} A{} B{} S{}\n", r, a, b, s);
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Delta endstop adjustments (X, Y, Z) in mm
+            #[cfg(all(feature = "with-motion", feature = "kinematics-delta"))]
+            GCodeValue::M666 => {
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                let p = &gc.params;
+                if p.real('x').is_some() || p.real('y').is_some() || p.real('z').is_some() {
+                    cfg_g.kinematics.set_endstop_offsets([p.real('x'), p.real('y'), p.real('z')]);
+                } else {
+                    let offsets = cfg_g.kinematics.endstop_offsets();
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M666 X{} Y{} Z{}\n",
+                        offsets[0], offsets[1], offsets[2]
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Speed rate (S), in percent, applied at once to the queued moves. B saves it and R restores it
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M220 => {
+                let p = &gc.params;
+                if p.has('b') {
+                    let cfg = self.motion_planner.motion_cfg();
+                    let mut cfg_g = cfg.lock().await;
+                    cfg_g.speed_rate_backup = cfg_g.speed_rate;
+                }
+                if let Some(rate) = p.int('s') {
+                    self.motion_planner.set_speed_rate(rate.clamp(1, u8::MAX as i32) as u8).await;
+                } else if p.has('r') {
+                    let rate = self.motion_planner.motion_cfg().lock().await.speed_rate_backup;
+                    self.motion_planner.set_speed_rate(rate).await;
+                } else if !p.has('b') {
+                    let rate = self.motion_planner.motion_cfg().lock().await.speed_rate;
+                    let s = alloc::format!("echo: M220 S{}\n", rate);
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(not(feature = "with-motion"))]
+            GCodeValue::M220 => Ok(CodeExecutionSuccess::OK),
+            // Flow rate (S), in percent, applied to the moves queued from now on
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M221 => {
+                match gc.params.int('s') {
+                    Some(rate) => {
+                        self.motion_planner.set_flow_rate(rate.clamp(1, u8::MAX as i32) as u8).await;
+                    }
+                    None => {
+                        let rate = self.motion_planner.motion_cfg().lock().await.flow_rate;
+                        let s = alloc::format!("echo: M221 S{}\n", rate);
+                        self.write(channel, s.as_str()).await;
+                    }
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(not(feature = "with-motion"))]
+            GCodeValue::M221 => Ok(CodeExecutionSuccess::OK),
+            // Curve approximation: Maximum deviation of the chords of the arcs (A) and of the lines of the
+            // Bezier curves (B), in the active units
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M229 => {
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                let p = self.motion_planner.params_to_mm(&gc.params, "ab").await;
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if p.has('a') || p.has('b') {
+                    let tolerance = p.real('a').unwrap_or(cfg_g.arc_chord_tolerance);
+                    let max_deviation = p.real('b').unwrap_or(cfg_g.bezier_max_deviation);
+                    if !tolerance.is_defined_positive() || !max_deviation.is_defined_positive() {
+                        return Err(CodeExecutionFailure::ERR);
+                    }
+                    cfg_g.arc_chord_tolerance = tolerance;
+                    cfg_g.bezier_max_deviation = max_deviation;
+                } else {
+                    let (arc_chord_tolerance, bezier_max_deviation) =
+                        (cfg_g.arc_chord_tolerance, cfg_g.bezier_max_deviation);
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M229 A{} B{}\n",
+                        arc_chord_tolerance / mm_per_unit,
+                        bezier_max_deviation / mm_per_unit
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Babystepping: X, Y and Z (or S) offsets in the active units, done along the moves being executed.
+            // P1 also applies the Z one to the probe offset
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M290 => {
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                let p = self.motion_planner.params_to_mm(&gc.params, "xyzs").await;
+                let offset = [p.real('x'), p.real('y'), p.real('z').or(p.real('s'))];
+                if offset.iter().any(|o| o.is_some()) {
+                    self.motion_planner.babystep(offset).await;
+                    if let (Some(z), true) = (offset[2], p.int('p').is_some_and(|p| p != 0)) {
+                        let cfg = self.motion_planner.motion_cfg();
+                        let mut cfg_g = cfg.lock().await;
+                        cfg_g.probe_offset.increment(CoordSel::Z, z);
+                    }
+                } else {
+                    let total = self.motion_planner.get_babystep_total().await;
+                    let s = alloc::format!(
+                        "echo: M290 X{} Y{} Z{}\n",
+                        total[0] / mm_per_unit, total[1] / mm_per_unit, total[2] / mm_per_unit
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // The point probed for each Z motor when aligning them (G34): Index (S) from 1, X and Y in the active units
+            #[cfg(feature = "with-z2-axis")]
+            GCodeValue::M422 => {
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                let p = self.motion_planner.params_to_mm(&gc.params, "xy").await;
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if p.has('s') {
+                    let index = p.int('s').unwrap_or(0);
+                    if index < 1 || index > hwa::controllers::Z_ALIGN_MOTORS as i32 {
+                        return Err(CodeExecutionFailure::ERR);
+                    }
+                    let point = &mut cfg_g.z_align.points[(index - 1) as usize];
+                    *point = TVector::from_coords(p.real('x').or(point.x), p.real('y').or(point.y), None, None);
+                } else {
+                    let points = cfg_g.z_align.points;
+                    drop(cfg_g);
+                    for (index, point) in points.iter().enumerate() {
+                        let point = point.map_nan(&math::ZERO) / mm_per_unit;
+                        let s = alloc::format!(
+                            "echo: M422 S{} X{} Y{}\n",
+                            index + 1, point.x.unwrap(), point.y.unwrap()
+                        );
+                        self.write(channel, s.as_str()).await;
+                    }
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            // Backlash compensation: Play of the X, Y and Z motors, fraction taken up (F, 0 to 1) and distance
+            // the correction is spread over (S), zero for the whole segment. Lengths in the active units
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M425 => {
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                let p = self.motion_planner.params_to_mm(&gc.params, "xyzs").await;
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if p.has('x') || p.has('y') || p.has('z') || p.has('f') || p.has('s') {
+                    let backlash = &mut cfg_g.backlash;
+                    for (axis, letter) in ['x', 'y', 'z'].iter().enumerate() {
+                        if let Some(distance) = p.real(*letter) {
+                            backlash.distance[axis] = distance.max(math::ZERO);
+                        }
+                    }
+                    backlash.correction = p.real('f').unwrap_or(backlash.correction).max(math::ZERO).min(math::ONE);
+                    backlash.smoothing = p.real('s').unwrap_or(backlash.smoothing).max(math::ZERO);
+                } else {
+                    let backlash = cfg_g.backlash;
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M425 F{} S{} X{} Y{} Z{}\n",
+                        backlash.correction,
+                        backlash.smoothing / mm_per_unit,
+                        backlash.distance[0] / mm_per_unit,
+                        backlash.distance[1] / mm_per_unit,
+                        backlash.distance[2] / mm_per_unit
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
             #[cfg(feature = "with-trinamic")]
             GCodeValue::M502 => {
                 /*
@@ -742,10 +1462,82 @@ impl GCodeProcessor {
                 }
                 Ok(CodeExecutionSuccess::OK)
             }
+            // Offset of the probe from the tool: X, Y and Z in the active units
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M851 => {
+                let mm_per_unit = self.motion_planner.get_unit_mode().await.mm_per_unit();
+                let p = self.motion_planner.params_to_mm(&gc.params, "xyz").await;
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if p.has('x') || p.has('y') || p.has('z') {
+                    let probe_offset = cfg_g.probe_offset;
+                    cfg_g.probe_offset = TVector::from_coords(
+                        p.real('x').or(probe_offset.x),
+                        p.real('y').or(probe_offset.y),
+                        p.real('z').or(probe_offset.z),
+                        None,
+                    );
+                } else {
+                    let probe_offset = cfg_g.probe_offset.map_nan(&math::ZERO) / mm_per_unit;
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M851 X{} Y{} Z{}\n",
+                        probe_offset.x.unwrap(), probe_offset.y.unwrap(), probe_offset.z.unwrap()
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
             GCodeValue::M862_1 => Ok(CodeExecutionSuccess::OK),
             GCodeValue::M862_3 => Ok(CodeExecutionSuccess::OK),
+            // Pressure advance of the extruder (K) and its smoothing time (S), in seconds. K0 disables it
+            #[cfg(feature = "with-motion")]
+            GCodeValue::M900 => {
+                let p = &gc.params;
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                let pressure_advance = cfg_g.pressure_advance;
+                if p.has('k') || p.has('s') {
+                    cfg_g.pressure_advance = hwa::controllers::PressureAdvance::new(
+                        p.real('k').unwrap_or(pressure_advance.k()),
+                        p.real('s').unwrap_or(pressure_advance.smooth_time()),
+                    );
+                } else {
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M900 K{} S{}\n",
+                        pressure_advance.k(), pressure_advance.smooth_time()
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
+            #[cfg(not(feature = "with-motion"))]
             GCodeValue::M900 => Ok(CodeExecutionSuccess::OK),
             GCodeValue::M907 => Ok(CodeExecutionSuccess::OK),
+            // StallGuard threshold (SGTHRS) of the X, Y and Z drivers, from 0 to 255, applied from the next homing
+            #[cfg(feature = "with-trinamic")]
+            GCodeValue::M914 => {
+                let p = &gc.params;
+                let cfg = self.motion_planner.motion_cfg();
+                let mut cfg_g = cfg.lock().await;
+                if p.has('x') || p.has('y') || p.has('z') {
+                    for (axis, letter) in ['x', 'y', 'z'].iter().enumerate() {
+                        if let Some(threshold) = p.int(*letter) {
+                            cfg_g.stallguard_threshold[axis] = threshold.clamp(0, 255) as u8;
+                        }
+                    }
+                } else {
+                    let threshold = cfg_g.stallguard_threshold;
+                    drop(cfg_g);
+                    let s = alloc::format!(
+                        "echo: M914 X{} Y{} Z{}\n",
+                        threshold[0], threshold[1], threshold[2]
+                    );
+                    self.write(channel, s.as_str()).await;
+                }
+                Ok(CodeExecutionSuccess::OK)
+            }
             _ => Err(CodeExecutionFailure::NotYetImplemented),
         };
         result
@@ -0,0 +1,223 @@
+//! The input of the serial channels, scanned for emergency gcodes.
+//!
+//! The bytes of every serial channel are scanned as soon as they are received, before they are queued
+//! for the line parser, so the emergency gcodes are performed at once (See [EmergencyCommand]).
+//! The line is forwarded anyway: When it is parsed later, the processor acknowledges it without acting again.
+//!
+//! The channel is never held waiting for the line parser, so every byte is scanned: When the pipe is full
+//! (the host sent more than it was acknowledged), the rest of the line is dropped (See [PipeForwarder]).
+use crate::control::EmergencyCommand;
+use crate::hwa;
+use embassy_sync::pipe::Pipe;
+use embassy_time::{Duration, Timer};
+
+/// Size of the buffer between a channel and its line parser
+pub const INPUT_PIPE_SIZE: usize = 256;
+
+/// Time to wait before polling again a channel that gave nothing (disconnected or failed)
+const INPUT_RETRY_DELAY_MS: u64 = 100;
+
+/// The buffer between a channel and its line parser
+pub type InputPipe = Pipe<hwa::ControllerMutexType, INPUT_PIPE_SIZE>;
+
+#[derive(Clone, Copy)]
+enum ScanState {
+    /// Only whitespace seen in the line so far
+    LineStart,
+    /// In the line number (N) word
+    LineNumber,
+    /// In the number of a M word
+    Code { value: u16, digits: u8 },
+    /// Nothing else to look for until the end of the line
+    Skip,
+}
+
+/// Recognizes the emergency gcodes in a byte stream.
+///
+/// Only the first word of a line is considered, optionally preceded by its line number,
+/// as in `M112` or `N12 M112*97`
+pub struct EmergencyScanner {
+    state: ScanState,
+}
+
+impl EmergencyScanner {
+    pub const fn new() -> Self {
+        Self {
+            state: ScanState::LineStart,
+        }
+    }
+
+    /// Feeds a byte, returning the emergency gcode it completes, if any
+    pub fn feed(&mut self, byte: u8) -> Option<EmergencyCommand> {
+        let mut command = None;
+        self.state = match (self.state, byte) {
+            (_, b'\n') | (_, b'\r') => {
+                if let ScanState::Code { value, digits } = self.state {
+                    command = Self::command(value, digits);
+                }
+                ScanState::LineStart
+            }
+            (ScanState::LineStart, b' ' | b'\t') => ScanState::LineStart,
+            (ScanState::LineStart, b'N' | b'n') => ScanState::LineNumber,
+            (ScanState::LineStart | ScanState::LineNumber, b'M' | b'm') => {
+                ScanState::Code { value: 0, digits: 0 }
+            }
+            (ScanState::LineNumber, b'0'..=b'9') => ScanState::LineNumber,
+            (ScanState::LineNumber, b' ' | b'\t') => ScanState::LineNumber,
+            (ScanState::Code { value, digits }, b'0'..=b'9') if digits < 3 => ScanState::Code {
+                value: value * 10 + (byte - b'0') as u16,
+                digits: digits + 1,
+            },
+            (ScanState::Code { value, digits }, b' ' | b'\t' | b'*' | b';') => {
+                command = Self::command(value, digits);
+                ScanState::Skip
+            }
+            _ => ScanState::Skip,
+        };
+        command
+    }
+
+    fn command(value: u16, digits: u8) -> Option<EmergencyCommand> {
+        match (value, digits) {
+            (112, 3) => Some(EmergencyCommand::Kill),
+            (108, 3) => Some(EmergencyCommand::BreakWait),
+            (410, 3) => Some(EmergencyCommand::QuickStop),
+            _ => None,
+        }
+    }
+}
+
+impl Default for EmergencyScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes the bytes of a channel to its pipe without waiting for room.
+///
+/// When a byte does not fit, the rest of its line is dropped, but the line terminator, so the line
+/// parser sees the line cut (and requests it again if it was numbered) instead of merged with the next one
+pub struct PipeForwarder {
+    dropping: bool,
+}
+
+impl PipeForwarder {
+    pub const fn new() -> Self {
+        Self { dropping: false }
+    }
+
+    /// Writes a byte, or drops it. Returns true when a line starts being dropped
+    pub fn forward<const N: usize>(&mut self, pipe: &Pipe<hwa::ControllerMutexType, N>, byte: u8) -> bool {
+        if self.dropping && !matches!(byte, b'\n' | b'\r') {
+            return false;
+        }
+        match pipe.try_write(&[byte]) {
+            Ok(_) => {
+                self.dropping = false;
+                false
+            }
+            Err(_) => !core::mem::replace(&mut self.dropping, true),
+        }
+    }
+}
+
+impl Default for PipeForwarder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Moves the bytes of a channel to its pipe, acting on the emergency gcodes on the way.
+/// Never returns
+pub async fn scan_input_stream<STREAM>(
+    mut stream: STREAM,
+    pipe: &'static InputPipe,
+    processor: hwa::GCodeProcessor,
+    channel: hwa::CommChannel,
+) where
+    STREAM: async_gcode::ByteStream<Item = Result<u8, async_gcode::Error>>,
+{
+    let mut scanner = EmergencyScanner::new();
+    let mut forwarder = PipeForwarder::new();
+    loop {
+        match stream.next().await {
+            Some(Ok(byte)) => {
+                if let Some(command) = scanner.feed(byte) {
+                    processor.emergency(channel, command).await;
+                }
+                if forwarder.forward(pipe, byte) {
+                    hwa::warn!("[{:?}] Input buffer full: Line dropped", channel);
+                }
+            }
+            Some(Err(_e)) => {
+                hwa::warn!("[{:?}] Input error: {:?}", channel, _e);
+            }
+            None => {
+                // Nothing received (disconnected or failed). The device is polled again later
+                Timer::after(Duration::from_millis(INPUT_RETRY_DELAY_MS)).await;
+            }
+        }
+    }
+}
+
+/// The scanned bytes of a channel, as given to its line parser
+pub struct ScannedInputStream {
+    pipe: &'static InputPipe,
+}
+
+impl ScannedInputStream {
+    pub const fn new(pipe: &'static InputPipe) -> Self {
+        Self { pipe }
+    }
+}
+
+impl async_gcode::ByteStream for ScannedInputStream {
+    type Item = Result<u8, async_gcode::Error>;
+
+    async fn next(&mut self) -> Option<Self::Item> {
+        let mut byte = [0u8; 1];
+        self.pipe.read(&mut byte).await;
+        Some(Ok(byte[0]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(input: &str) -> alloc::vec::Vec<EmergencyCommand> {
+        let mut scanner = EmergencyScanner::new();
+        input.bytes().filter_map(|b| scanner.feed(b)).collect()
+    }
+
+    #[test]
+    fn recognize_emergency_gcodes() {
+        assert_eq!(scan("M112\n"), [EmergencyCommand::Kill]);
+        assert_eq!(scan("N12 M410*97\n"), [EmergencyCommand::QuickStop]);
+        assert_eq!(scan("G1 X10\n  m108 ; break\r\n"), [EmergencyCommand::BreakWait]);
+    }
+
+    #[test]
+    fn drop_lines_not_fitting() {
+        let pipe: Pipe<hwa::ControllerMutexType, 8> = Pipe::new();
+        let mut forwarder = PipeForwarder::new();
+        let dropped: alloc::vec::Vec<bool> =
+            "G1 X10\nG1 Y20\n".bytes().map(|b| forwarder.forward(&pipe, b)).collect();
+        // Only the first byte that does not fit starts the dropping
+        assert_eq!(dropped.iter().filter(|d| **d).count(), 1);
+        let mut buf = [0u8; 8];
+        assert_eq!(pipe.try_read(&mut buf), Ok(8));
+        assert_eq!(&buf, b"G1 X10\nG");
+        // The cut line is ended when there is room again
+        for byte in "M1\nM2\n".bytes() {
+            forwarder.forward(&pipe, byte);
+        }
+        assert_eq!(pipe.try_read(&mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"\nM2\n");
+    }
+
+    #[test]
+    fn ignore_other_gcodes() {
+        assert!(scan("M1120\nM11\nG1 M112\nM112.1\n;M112\nM104 S112\n").is_empty());
+    }
+}
@@ -1,14 +1,37 @@
+mod gcode_emergency;
 mod gcode_multiplexed_io;
 mod gcode_parser;
 mod gcode_processor;
-
 #[cfg(any(
     feature = "with-serial-usb",
     feature = "with-serial-port-1",
     feature = "with-serial-port-2"
 ))]
+mod gcode_scanned_io;
+#[cfg(feature = "with-sdcard")]
+mod gcode_macro;
+#[cfg(feature = "with-sdcard")]
+mod gcode_expression;
+
+#[cfg(any(
+    feature = "with-serial-usb",
+    feature = "with-serial-port-1",
+    feature = "with-serial-port-2",
+    feature = "with-sdcard"
+))]
 pub use gcode_parser::GCodeLineParser;
 pub use gcode_parser::GCodeLineParserError;
 pub use gcode_processor::{GCodeProcessor, GCodeProcessorParams};
+#[cfg(feature = "with-sdcard")]
+pub use gcode_macro::*;
+#[cfg(feature = "with-sdcard")]
+pub use gcode_expression::*;
 
 pub use gcode_multiplexed_io::GCodeMultiplexedInputStream;
+pub use gcode_emergency::*;
+#[cfg(any(
+    feature = "with-serial-usb",
+    feature = "with-serial-port-1",
+    feature = "with-serial-port-2"
+))]
+pub use gcode_scanned_io::*;
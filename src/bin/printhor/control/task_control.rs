@@ -39,6 +39,16 @@ pub async fn task_control(
         Err(_) => crate::initialization_error(),
     }
 
+    #[cfg(feature = "with-sdcard")]
+    {
+        // The optional machine configuration
+        hwa::info!("[task_control] Running {}", control::BOOT_CONFIG_PATH);
+        let _ = processor.run_boot_config(
+            &_controllers.card_controller,
+            &mut subscriber,
+        ).await;
+    }
+
     // The task loop
     loop {
 
@@ -77,6 +87,23 @@ pub async fn task_control(
                 hwa::error!("[{:?}] GCode N/A ParserError", channel);
                 processor.write(channel, "error; (ParserError)\n").await;
             }
+            Ok((Err(control::GCodeLineParserError::ChecksumMismatch(_ln)), channel)) => {
+                hwa::error!("[{:?}] GCode checksum mismatch at line {}", channel, _ln);
+                request_resend(&mut processor, &gcode_input_stream, channel, "checksum mismatch").await;
+            }
+            Ok((Err(control::GCodeLineParserError::LineNumberMismatch(_ln)), channel)) => {
+                hwa::error!("[{:?}] GCode line number mismatch: got N{}", channel, _ln);
+                request_resend(&mut processor, &gcode_input_stream, channel, "Line Number is not Last Line Number+1").await;
+            }
+            Ok((Err(control::GCodeLineParserError::ChecksumMissing(_ln)), channel)) => {
+                hwa::error!("[{:?}] GCode without checksum at line {}", channel, _ln);
+                request_resend(&mut processor, &gcode_input_stream, channel, "No Checksum with line number").await;
+            }
+            #[cfg(feature = "with-sdcard")]
+            Ok((Err(control::GCodeLineParserError::ExpressionError(_ln, _error)), channel)) => {
+                hwa::error!("[{:?}] GCode expression error at line {}: {:?}", channel, _ln, _error);
+                processor.write(channel, "error; (ExpressionError)\n").await;
+            }
             Ok((Err(control::GCodeLineParserError::GCodeNotImplemented(_ln, _gcode_name)), channel)) => {
                 hwa::error!("GCode {} (NotImplemented)", _gcode_name.as_str());
                 let s = alloc::format!("error; {} (NotImplemented)\n", _gcode_name);
@@ -98,6 +125,8 @@ pub async fn task_control(
                     channel, &gc,
                     #[cfg(feature = "with-sdcard")]
                     &mut _controllers.card_controller,
+                    #[cfg(feature = "with-sdcard")]
+                    &mut subscriber,
                     #[cfg(feature = "with-printjob")]
                     &mut _controllers.printer_controller,
                 ).await;
@@ -107,6 +136,20 @@ pub async fn task_control(
     }
 }
 
+/// Marlin-style resend request, so the host sends again from the expected line number
+async fn request_resend(processor: &mut hwa::GCodeProcessor,
+                        gcode_input_stream: &control::GCodeMultiplexedInputStream,
+                        comm_channel: hwa::CommChannel,
+                        reason: &str,
+) {
+    let expected_line = gcode_input_stream.get_expected_line(comm_channel);
+    let s = alloc::format!(
+        "Error:{}, Last Line: {}\nResend: {}\nok\n",
+        reason, expected_line.saturating_sub(1), expected_line
+    );
+    processor.write(comm_channel, s.as_str()).await;
+}
+
 async fn manage_timeout(processor: &mut hwa::GCodeProcessor,
                         subscriber: &mut hwa::EventBusSubscriber<'_>,
                         gcode_input_stream: &mut control::GCodeMultiplexedInputStream,
@@ -154,6 +197,8 @@ pub(in super) async fn execute(
     gc: &control::GCodeCmd,
     #[cfg(feature = "with-sdcard")]
     _card_controller: &mut hwa::controllers::CardController,
+    #[cfg(feature = "with-sdcard")]
+    _subscriber: &mut hwa::EventBusSubscriber<'_>,
     #[cfg(feature = "with-printjob")]
     _printer_controller: &mut hwa::controllers::PrinterController,
 ) -> control::CodeExecutionResult {
@@ -170,9 +215,9 @@ pub(in super) async fn execute(
             Ok(control::CodeExecutionSuccess::CONSUMED)
         }
         #[cfg(feature = "with-sdcard")]
-        control::GCodeValue::M20(path) => {
-            let path = path.clone().unwrap_or(alloc::string::String::from("/"));
-            match _card_controller.list_dir(path.as_str()).await {
+        control::GCodeValue::M20 => {
+            let path = gc.params.string('f').unwrap_or("/");
+            match _card_controller.list_dir(path).await {
                 Ok(mut it) => {
                     loop {
                         //crate::debug!("will get next");
@@ -219,15 +264,12 @@ pub(in super) async fn execute(
             }
         }
         #[cfg(feature = "with-printjob")]
-        control::GCodeValue::M23(f) => {
+        control::GCodeValue::M23 => {
             match _printer_controller
                 .set(
                     hwa::controllers::PrinterControllerEvent::SetFile(
                         channel,
-                        match f {
-                            None => alloc::string::String::new(),
-                            Some(str) => str.clone()
-                        },
+                        alloc::string::String::from(gc.params.string('f').unwrap_or("")),
                     )
                 ).await {
 
@@ -277,6 +319,15 @@ pub(in super) async fn execute(
                 }
             }
         }
+        #[cfg(feature = "with-sdcard")]
+        control::GCodeValue::M98 => {
+            processor.run_macro(
+                channel,
+                _card_controller,
+                _subscriber,
+                gc.params.string('p').unwrap_or(""),
+            ).await
+        }
         // Otherwise... delegate
         _ => processor.execute(channel, &gc, false).await
     }
@@ -3,8 +3,11 @@
 //! GCodes that aren't processed immediately, so processor can accept more
 //! Some firmwares resolves this by allocating extra space in the queue, but that case issues because you can get blocked
 use crate::hwa;
-use crate::hwa::{CommChannel, DeferAction, DeferEvent};
-use embassy_time::{with_timeout, Duration};
+use crate::hwa::{CommChannel, DeferAction, DeferEvent, EventFlags};
+use embassy_time::{with_timeout, Duration, Instant};
+
+/// Period of the busy messages sent to a channel waiting for a deferred gcode, so the host does not time out
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(Clone, Copy, Default)]
 struct SubscriptionCounting {
@@ -18,6 +21,23 @@ struct SubscriptionCounting {
     num_hotbed: u8,
 }
 
+impl SubscriptionCounting {
+    /// True when a deferred gcode is still outstanding
+    fn is_busy(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut busy = self.num_homes > 0 || self.num_linear > 0 || self.num_rapid > 0 || self.num_dwell > 0;
+        #[cfg(feature = "with-hot-end")]
+        {
+            busy |= self.num_hotend > 0;
+        }
+        #[cfg(feature = "with-hot-bed")]
+        {
+            busy |= self.num_hotbed > 0;
+        }
+        busy
+    }
+}
+
 struct Subscriptions {
     channel_counts: [SubscriptionCounting; CommChannel::count() - 1],
     total_counts: i32,
@@ -71,17 +91,30 @@ pub async fn task_defer(processor: hwa::GCodeProcessor) {
     hwa::info!("[task_defer] started");
 
     let mut subscriptions = Subscriptions::new();
+    let mut next_keepalive = Instant::now() + KEEPALIVE_INTERVAL;
 
     loop {
 
         match with_timeout(
-            Duration::from_secs(30),
+            next_keepalive.saturating_duration_since(Instant::now()),
             processor.motion_planner.defer_channel.receive(),
         ).await {
 
             Err(_) => {
                 #[cfg(feature = "trace-commands")]
                 hwa::info!("[task_defer] Timeout: counts: {}", subscriptions.total_counts);
+                next_keepalive = Instant::now() + KEEPALIVE_INTERVAL;
+                if subscriptions.total_counts > 0 {
+                    let msg = match processor.event_bus.has_flags(EventFlags::JOB_PAUSED).await {
+                        true => "busy: paused for user\n",
+                        false => "echo:busy: processing\n",
+                    };
+                    for (idx, counts) in subscriptions.channel_counts.iter().enumerate() {
+                        if counts.is_busy() {
+                            processor.write(CommChannel::index(idx), msg).await;
+                        }
+                    }
+                }
                 #[cfg(test)]
                 if crate::control::task_integration::INTEGRATION_STATUS.signaled() {
                     hwa::info!("[task_defer] Ending gracefully");
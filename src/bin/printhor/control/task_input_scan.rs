@@ -0,0 +1,42 @@
+//! The input tasks of the serial channels.
+//!
+//! Each one moves the bytes received in its channel to the pipe read by [crate::control::GCodeMultiplexedInputStream],
+//! scanning them for emergency gcodes (M112, M108, M410) on the way, so they are performed
+//! even when `task_control` is blocked.
+use crate::control::{scan_input_stream, InputPipe};
+use crate::hwa;
+use embassy_sync::pipe::Pipe;
+
+#[cfg(feature = "with-serial-usb")]
+pub static SERIAL_USB_INPUT: InputPipe = Pipe::new();
+#[cfg(feature = "with-serial-port-1")]
+pub static SERIAL_PORT1_INPUT: InputPipe = Pipe::new();
+#[cfg(feature = "with-serial-port-2")]
+pub static SERIAL_PORT2_INPUT: InputPipe = Pipe::new();
+
+#[cfg(feature = "with-serial-usb")]
+#[embassy_executor::task(pool_size = 1)]
+pub async fn task_input_scan_usb(
+    stream: hwa::device::USBSerialDeviceInputStream,
+    processor: hwa::GCodeProcessor,
+) {
+    scan_input_stream(stream, &SERIAL_USB_INPUT, processor, hwa::CommChannel::SerialUsb).await
+}
+
+#[cfg(feature = "with-serial-port-1")]
+#[embassy_executor::task(pool_size = 1)]
+pub async fn task_input_scan_port1(
+    stream: hwa::device::UartPort1RxInputStream,
+    processor: hwa::GCodeProcessor,
+) {
+    scan_input_stream(stream, &SERIAL_PORT1_INPUT, processor, hwa::CommChannel::SerialPort1).await
+}
+
+#[cfg(feature = "with-serial-port-2")]
+#[embassy_executor::task(pool_size = 1)]
+pub async fn task_input_scan_port2(
+    stream: hwa::device::UartPort2RxInputStream,
+    processor: hwa::GCodeProcessor,
+) {
+    scan_input_stream(stream, &SERIAL_PORT2_INPUT, processor, hwa::CommChannel::SerialPort2).await
+}
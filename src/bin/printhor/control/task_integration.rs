@@ -145,12 +145,7 @@ pub async fn task_integration(mut params: IntegrationaskParams) {
         let test_name = "T4 [G28 (Homming)]";
         let homing_gcode = control::GCodeCmd::new(
             0, None,
-            control::GCodeValue::G28(control::XYZE {
-                x: None,
-                y: None,
-                z: None,
-                e: None,
-            })
+            control::GCodeValue::G28
         );
 
         hwa::info!("## {} - BEGIN", test_name);
@@ -184,12 +179,13 @@ pub async fn task_integration(mut params: IntegrationaskParams) {
         let test_name = "T5 [G92 (Reset Position)]";
         let set_pos_gcode = control::GCodeCmd::new(
             5, Some(5),
-            control::GCodeValue::G92(control::XYZE {
-                x: Some(math::ZERO),
-                y: Some(math::ZERO),
-                z: Some(math::ZERO),
-                e: Some(math::ZERO),
-            })
+            control::GCodeValue::G92
+        ).with_params(
+            control::GCodeParams::new()
+                .with_real('x', math::ZERO)
+                .with_real('y', math::ZERO)
+                .with_real('z', math::ZERO)
+                .with_real('e', math::ZERO)
         );
 
         hwa::info!("## {} - BEGIN", test_name);
@@ -248,7 +244,7 @@ pub async fn task_integration(mut params: IntegrationaskParams) {
         let test_name = "T7 [M20 (List SDCard)]";
         let gcode = control::GCodeCmd::new(
             7, Some(7),
-            control::GCodeValue::M20(None)
+            control::GCodeValue::M20
         );
 
         hwa::info!("## {} - BEGIN", test_name);
@@ -257,6 +253,8 @@ pub async fn task_integration(mut params: IntegrationaskParams) {
             CommChannel::Internal, &gcode,
             #[cfg(feature = "with-sdcard")]
             &mut params.card_controller,
+            #[cfg(feature = "with-sdcard")]
+            &mut subscriber,
             #[cfg(feature = "with-printjob")]
             &mut params.printer_controller,
         ).await;
@@ -276,7 +274,9 @@ pub async fn task_integration(mut params: IntegrationaskParams) {
         let test_name = "T8 [M20 (List SDCard)]";
         let gcode = control::GCodeCmd::new(
             8, Some(8),
-            control::GCodeValue::M20(Some("/dir/".to_string()))
+            control::GCodeValue::M20
+        ).with_params(
+            control::GCodeParams::new().with_string('f', "/dir/")
         );
 
         hwa::info!("## {} - BEGIN", test_name);
@@ -285,6 +285,8 @@ pub async fn task_integration(mut params: IntegrationaskParams) {
             CommChannel::Internal, &gcode,
             #[cfg(feature = "with-sdcard")]
             &mut params.card_controller,
+            #[cfg(feature = "with-sdcard")]
+            &mut subscriber,
             #[cfg(feature = "with-printjob")]
             &mut params.printer_controller,
         ).await;
@@ -295,6 +297,175 @@ pub async fn task_integration(mut params: IntegrationaskParams) {
             return;
         }
     }
+
+    // Separator
+    hwa::info!("##");
+
+    #[cfg(all(feature = "with-motion", feature = "with-e-axis"))]
+    {
+        let test_name = "T9 [M900 (Pressure advance)]";
+        hwa::info!("## {} - BEGIN", test_name);
+
+        // The E steps expected for the 2 mm of filament of the moves
+        let expected_steps = {
+            let cfg = params.processor.motion_planner.motion_cfg();
+            let cfg_g = cfg.lock().await;
+            let steps_per_mm = cfg_g.units_per_mm * cfg_g.get_usteps_as_vector();
+            steps_per_mm.e.and_then(|spm| (spm * math::TWO).round().to_i32()).unwrap_or(0)
+        };
+        let steps_before = params.processor.motion_planner.motion_driver.lock().await.pins.step_count[3];
+
+        let gcodes = [
+            control::GCodeCmd::new(9, Some(9), control::GCodeValue::M900).with_params(
+                control::GCodeParams::new().with_real('k', Real::new(5, 2))
+            ),
+            control::GCodeCmd::new(9, Some(9), control::GCodeValue::G1).with_params(
+                control::GCodeParams::new()
+                    .with_real('x', Real::new(10, 0))
+                    .with_real('e', math::ONE)
+            ),
+            control::GCodeCmd::new(9, Some(9), control::GCodeValue::G1).with_params(
+                control::GCodeParams::new()
+                    .with_real('y', Real::new(10, 0))
+                    .with_real('e', math::ONE)
+            ),
+            control::GCodeCmd::new(9, Some(9), control::GCodeValue::M900).with_params(
+                control::GCodeParams::new().with_real('k', math::ZERO)
+            ),
+        ];
+        for gcode in gcodes.iter() {
+            if params
+                .processor
+                .execute(CommChannel::Internal, gcode, false)
+                .await
+                .and_then(expect_immediate)
+                .is_err()
+            {
+                finish_task(Err(test_name));
+                return;
+            }
+        }
+        // Wait for the moves to be done
+        match params
+            .processor
+            .execute(CommChannel::Internal, &control::GCodeCmd::new(9, Some(9), control::GCodeValue::M400), false)
+            .await
+            .and_then(expect_deferred)
+        {
+            Ok(evt) if subscriber.ft_wait_for(evt).await.is_ok() => {}
+            _ => {
+                finish_task(Err(test_name));
+                return;
+            }
+        }
+
+        // The advance pushes and pulls back the filament along the moves, but ends where they do
+        let steps_done = params.processor.motion_planner.motion_driver.lock().await.pins.step_count[3] - steps_before;
+        if (steps_done - expected_steps).abs() <= 1 {
+            hwa::info!("## {} - END", test_name);
+        } else {
+            hwa::error!("E steps: expected {}, done {}", expected_steps, steps_done);
+            finish_task(Err(test_name));
+            return;
+        }
+    }
+
+    // Separator
+    hwa::info!("##");
+
+    #[cfg(all(feature = "integration-test-trinamic", feature = "native"))]
+    {
+        let test_name = "T10 [G28 X (Sensorless homing)]";
+        hwa::info!("## {} - BEGIN", test_name);
+
+        // X homes on the stall of its motor, simulated when the carriage is pushed against the frame
+        let gcodes = [
+            control::GCodeCmd::new(10, Some(10), control::GCodeValue::M210).with_params(
+                control::GCodeParams::new().with_flag('x').with_real('h', math::ONE)
+            ),
+            control::GCodeCmd::new(10, Some(10), control::GCodeValue::G28).with_params(
+                control::GCodeParams::new().with_flag('x')
+            ),
+            control::GCodeCmd::new(10, Some(10), control::GCodeValue::M210).with_params(
+                control::GCodeParams::new().with_flag('x').with_real('h', math::ZERO)
+            ),
+        ];
+        for gcode in gcodes.iter() {
+            let result = params
+                .processor
+                .execute(CommChannel::Internal, gcode, false)
+                .await;
+            let done = match result {
+                Ok(control::CodeExecutionSuccess::DEFERRED(evt)) => subscriber.ft_wait_for(evt).await.is_ok(),
+                result => result.and_then(expect_immediate).is_ok(),
+            };
+            if !done {
+                finish_task(Err(test_name));
+                return;
+            }
+        }
+
+        // The carriage stopped at its min. end, where it stalled
+        if hwa::device::MockedStall::position(0) == 0 {
+            hwa::info!("## {} - END", test_name);
+        } else {
+            finish_task(Err(test_name));
+            return;
+        }
+    }
+
+    #[cfg(all(feature = "integration-test-dual-z", feature = "integration-test-trinamic", feature = "native"))]
+    {
+        let test_name = "T11 [G28 Z / G34 (Dual Z)]";
+        hwa::info!("## {} - BEGIN", test_name);
+
+        // Z homes on its endstops (the stall of each motor) instead of the probe, so Z and Z2 are squared
+        let homing = [
+            control::GCodeCmd::new(11, Some(11), control::GCodeValue::M210).with_params(
+                control::GCodeParams::new()
+                    .with_flag('z')
+                    .with_real('p', math::ZERO)
+                    .with_real('f', Real::new(20, 0))
+                    .with_real('s', Real::new(10, 0))
+            ),
+            control::GCodeCmd::new(11, Some(11), control::GCodeValue::G28).with_params(
+                control::GCodeParams::new().with_flag('z')
+            ),
+        ];
+        // Then aligned, homing Z again, before restoring the homing of Z
+        let alignment = [
+            control::GCodeCmd::new(11, Some(11), control::GCodeValue::G34),
+            control::GCodeCmd::new(11, Some(11), control::GCodeValue::M210).with_params(
+                control::GCodeParams::new()
+                    .with_flag('z')
+                    .with_real('p', if cfg!(feature = "with-probe") { math::ONE } else { math::ZERO })
+                    .with_real('f', Real::new(5, 0))
+                    .with_real('s', Real::new(25, 1))
+            ),
+        ];
+        for gcodes in [&homing, &alignment] {
+            for gcode in gcodes.iter() {
+                let result = params
+                    .processor
+                    .execute(CommChannel::Internal, gcode, false)
+                    .await;
+                let done = match result {
+                    Ok(control::CodeExecutionSuccess::DEFERRED(evt)) => subscriber.ft_wait_for(evt).await.is_ok(),
+                    result => result.and_then(expect_immediate).is_ok(),
+                };
+                if !done {
+                    finish_task(Err(test_name));
+                    return;
+                }
+            }
+            // The carriages of Z and Z2 (which started 2mm apart) stopped at their min. end
+            if hwa::device::MockedStall::position(2) != 0 || hwa::device::MockedStall::position(5) != 0 {
+                finish_task(Err(test_name));
+                return;
+            }
+        }
+        hwa::info!("## {} - END", test_name);
+    }
     /*
     #[cfg(feature = "integration-test-move-ortho")]
     {
@@ -575,7 +746,9 @@ pub async fn task_integration(mut params: IntegrationaskParams) {
 
         let gcode = control::GCodeCmd::new(
             8, Some(8),
-            control::GCodeValue::M23(Some("dir/laser.g".to_string()))
+            control::GCodeValue::M23
+        ).with_params(
+            control::GCodeParams::new().with_string('f', "dir/laser.g")
         );
         hwa::info!("## {} - BEGIN", test_name);
         let resp = control::task_control::execute(
@@ -583,6 +756,8 @@ pub async fn task_integration(mut params: IntegrationaskParams) {
             CommChannel::Internal, &gcode,
             #[cfg(feature = "with-sdcard")]
             &mut params.card_controller,
+            #[cfg(feature = "with-sdcard")]
+            &mut subscriber,
             #[cfg(feature = "with-printjob")]
             &mut params.printer_controller,
         ).await;
@@ -612,6 +787,8 @@ pub async fn task_integration(mut params: IntegrationaskParams) {
                     CommChannel::Internal, &gcode,
                     #[cfg(feature = "with-sdcard")]
                     &mut params.card_controller,
+                    #[cfg(feature = "with-sdcard")]
+                    &mut subscriber,
                     #[cfg(feature = "with-printjob")]
                     &mut params.printer_controller,
                 ).await;
@@ -1,10 +1,10 @@
 //! TODO: This feature is still incomplete
-use crate::control::{GCodeLineParser, GCodeLineParserError};
+use crate::control::{ExpressionParser, GCodeLineParserError};
 use crate::hwa;
 use embassy_time::Duration;
 use embassy_time::{Instant, Timer};
 
-use crate::control::GCodeCmd;
+use crate::control::{GCodeCmd, GCodeValue};
 use crate::control::{CodeExecutionFailure, CodeExecutionSuccess};
 use hwa::controllers::sdcard_controller::SDCardError;
 use hwa::controllers::sdcard_controller::SDCardStream;
@@ -40,6 +40,8 @@ pub async fn task_print_job(
 
     let mut print_job_parser = None;
     let mut job_time = Duration::from_ticks(0);
+    // Whether the current job already ran, so the next Resume is notified to the host as such
+    let mut job_started = false;
 
     loop {
         let _ = subscriber
@@ -63,9 +65,11 @@ pub async fn task_print_job(
                 #[cfg(feature = "trace-commands")]
                 hwa::info!("[task_print_job] SetFile: {}", file_path.as_str());
                 job_time = Duration::from_ticks(0);
+                job_started = false;
                 print_job_parser = match card_controller.new_stream(file_path.as_str()).await {
                     Ok(stream) => {
-                        let parser = GCodeLineParser::new(stream);
+                        // Boxed, as it is too big to be kept in the future of the task
+                        let parser = alloc::boxed::Box::new(ExpressionParser::new(stream, processor.clone()));
                         processor.write(channel, "ok\n").await;
                         Some(parser)
                     },
@@ -94,6 +98,14 @@ pub async fn task_print_job(
                     .await;
             }
             Ok(PrinterControllerEvent::Resume(channel)) => {
+                if job_started {
+                    processor.write(channel, "//action:resume\n").await;
+                }
+                job_started = true;
+                processor
+                    .event_bus
+                    .publish_event(EventStatus::not_containing(EventFlags::JOB_PAUSED))
+                    .await;
                 let mut interrupted = false;
                 let mut fatal_error = false;
                 let job_t0 = Instant::now();
@@ -102,7 +114,20 @@ pub async fn task_print_job(
                         Ok(gcode) => {
                             #[cfg(feature="trace-commands")]
                             hwa::info!("Executing {}", gcode);
-                            match processor.execute(CommChannel::Internal, &gcode, true).await {
+                            let result = match &gcode.value {
+                                GCodeValue::M98 => {
+                                    processor.run_macro(
+                                        channel,
+                                        &card_controller,
+                                        &mut subscriber,
+                                        gcode.params.string('p').unwrap_or(""),
+                                    ).await
+                                }
+                                // Not in a macro: There is nothing to return from
+                                GCodeValue::M99 => Ok(CodeExecutionSuccess::OK),
+                                _ => processor.execute(CommChannel::Internal, &gcode, true).await,
+                            };
+                            match result {
                                 Ok(CodeExecutionSuccess::OK) => {
                                     hwa::debug!("Response: OK {} (I)", gcode);
                                 }
@@ -114,8 +139,12 @@ pub async fn task_print_job(
                                 }
                                 Ok(CodeExecutionSuccess::DEFERRED(_status)) => {
                                     hwa::debug!("Deferred {} (D)", gcode);
-                                    if subscriber.ft_wait_for(_status).await.is_err() {
+                                    if crate::control::wait_deferred(&mut subscriber, _status).await.is_err() {
                                         // Must pause. Recoverable when SYS_ALARM go down
+                                        let _ = printer_controller
+                                            .set(PrinterControllerEvent::Pause(channel))
+                                            .await;
+                                        interrupted = true;
                                         break;
                                     } else {
                                         hwa::debug!("Response: OK {} (D)", gcode);
@@ -146,6 +175,11 @@ pub async fn task_print_job(
                                     );
                                     break;
                                 }
+                                Err(CodeExecutionFailure::OutOfBounds) => {
+                                    fatal_error = true;
+                                    hwa::error!("Out of the soft endstops: {}", gcode);
+                                    break;
+                                }
                                 Err(CodeExecutionFailure::PowerRequired) => {
                                     fatal_error = true;
                                     hwa::error!(
@@ -179,6 +213,13 @@ pub async fn task_print_job(
                                 GCodeLineParserError::ParseError(_ln) => {
                                     hwa::warn!("Parse error at {}", current_line);
                                 }
+                                GCodeLineParserError::ExpressionError(_ln, _error) => {
+                                    // The blocks and variables of the file are not reliable anymore
+                                    processor.write(channel, alloc::format!("error; Expression error ({:?}) at {}\n", _error, current_line).as_str()).await;
+                                    hwa::error!("Expression error {:?} at line {}", _error, current_line);
+                                    fatal_error = true;
+                                    break;
+                                }
                                 _e => {
                                     hwa::warn!("Internal error at {} :  {:?}", current_line, _e);
                                     fatal_error = true;
@@ -222,8 +263,20 @@ pub async fn task_print_job(
                     );
                 }
             }
+            Ok(PrinterControllerEvent::Pause(channel)) => {
+                processor.write(channel, "//action:pause\n").await;
+                processor
+                    .event_bus
+                    .publish_event(EventStatus::containing(EventFlags::JOB_PAUSED))
+                    .await;
+            }
             Ok(PrinterControllerEvent::Abort(channel)) => {
                 processor.write(channel, "echo: Job aborted\n").await;
+                processor.write(channel, "//action:cancel\n").await;
+                processor
+                    .event_bus
+                    .publish_event(EventStatus::not_containing(EventFlags::JOB_PAUSED))
+                    .await;
                 match print_job_parser.take() {
                     None => {}
                     Some(mut p) => p.close().await,
@@ -240,7 +293,7 @@ pub async fn task_print_job(
 }
 
 async fn gcode_pull(
-    print_job_parser: &mut Option<GCodeLineParser<SDCardStream>>,
+    print_job_parser: &mut Option<alloc::boxed::Box<ExpressionParser<SDCardStream, hwa::GCodeProcessor>>>,
 ) -> Result<GCodeCmd, GCodeLineParserError> {
     match print_job_parser.as_mut() {
         Some(parser) => {
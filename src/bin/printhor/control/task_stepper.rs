@@ -6,9 +6,20 @@
 //!   - If motion segment is execution-ready, dequeue it and then:
 //!     - Enable all steppers
 //!     - Evaluate the motion profile displacement at [`crate::hwa::STEPPER_PLANNER_CLOCK_FREQUENCY`]
-//!     - Compute number of steps to do in each axis (independently)
+//!     - Convert the direction of the move to motor space (See [`Kinematics`](hwa::controllers::motion::Kinematics))
+//!     - Compute number of steps to do in each motor (independently)
 //!     - Compute pulse rate across each axis (independently) and construct an iterator leveraging [`MultiTimer`](hwa::controllers::motion::MultiTimer)
+//!       - When input shaping is set (M593), the steps of each motor are the ones of its position convolved by its shaper
+//!         instead (See [`InputShaping`](hwa::controllers::motion::InputShaping))
 //!     - Consume a micro-segment until iterator is exhausted
+//!     - When pressure advance is set (M900), the E motor is driven apart, ahead of its position by the extrusion rate
+//!       (See [`ExtruderAdvance`](hwa::controllers::motion::ExtruderAdvance))
+//!     - When babysteps are requested (M290), they are taken along the micro-segments and their steps are done on top
+//!       of the ones of the move (See [`Babystepping`](hwa::controllers::motion::Babystepping))
+//!     - When a motor reverses, its backlash (M425) is taken up along the segment the same way
+//!       (See [`BacklashCompensator`](hwa::controllers::motion::BacklashCompensator))
+//!     - When the move ends with the machine stopped, keep on generating micro-segments until the shaped position settles
+//!       and release the advance left
 //!
 //! TODO: This is a work still in progress
 
@@ -24,6 +35,11 @@ use hwa::{EventFlags, EventStatus};
 use hwa::controllers::motion::SegmentIterator;
 use hwa::controllers::LinearMicrosegmentStepInterpolator;
 use hwa::controllers::motion::STEP_DRIVER;
+use hwa::controllers::motion::Kinematics;
+use hwa::controllers::motion::InputShaping;
+use hwa::controllers::motion::ExtruderAdvance;
+use hwa::controllers::motion::{BabystepInjector, BacklashCompensator, BABYSTEP_SPEED_DIVISOR};
+use hwa::controllers::MultiTimer;
 
 const DO_NOTHING: bool = false;
 
@@ -146,6 +162,11 @@ pub async fn task_stepper(
     let mut steppers_off = true;
 
     let mut real_steppper_pos: TVector<i32> = TVector::zero();
+    // Boxed, as its history is too big to be kept in the future of the task
+    let mut input_shaping = alloc::boxed::Box::new(InputShaping::new());
+    let mut extruder_advance = ExtruderAdvance::new();
+    let mut babystep_injector = BabystepInjector::new();
+    let mut backlash_compensator = BacklashCompensator::new();
 
     let micro_segment_period_secs: Real =
         Real::from_lit(STEPPER_PLANNER_MICROSEGMENT_PERIOD_US as i64, 6);
@@ -217,8 +238,6 @@ pub async fn task_stepper(
                     .publish_event(EventStatus::containing(EventFlags::MOVING))
                     .await;
 
-                // Vector helper to filter out irrelevant axes
-                let neutral_element = segment.segment_data.unit_vector_dir.map_val(&math::ZERO);
                 // Compute the Motion Profile
                 match SCurveMotionProfile::compute(
                     segment.segment_data.displacement_mm,
@@ -236,12 +255,17 @@ pub async fn task_stepper(
                         }
 
                         // First, translate displacement in mm to steps
-                        let (units_per_mm, micro_steps) = hwa::interrupt_free(|| {
+                        let (kinematics, units_per_mm, micro_steps, input_shapers, pressure_advance, max_speed, backlash) = hwa::interrupt_free(|| {
                             let motion_cfg = motion_planner.motion_cfg();
                             match motion_cfg.try_lock() {
                                 Ok(_g) => (
-                                    neutral_element + _g.units_per_mm,
-                                    neutral_element + _g.get_usteps_as_vector(),
+                                    _g.kinematics,
+                                    _g.units_per_mm,
+                                    _g.get_usteps_as_vector(),
+                                    _g.input_shapers,
+                                    _g.pressure_advance,
+                                    _g.max_speed,
+                                    _g.backlash,
                                 ),
                                 Err(_e) => {
                                     panic!("Unexpectedly, cannot lock motion cfg")
@@ -249,7 +273,46 @@ pub async fn task_stepper(
                             }
                         });
 
-                        let steps_per_mm: TVector<Real> = units_per_mm * micro_steps;
+                        // The direction of the move in motor space. The steppers are driven along it
+                        let motor_vector_dir = kinematics.motor_direction(
+                            &segment.segment_data.dest_pos,
+                            &segment.segment_data.unit_vector_dir,
+                            segment.segment_data.displacement_mm,
+                        );
+                        // Vector helper to filter out irrelevant axes
+                        let neutral_element = motor_vector_dir.map_val(&math::ZERO);
+
+                        let steps_per_mm: TVector<Real> =
+                            (neutral_element + units_per_mm) * (neutral_element + micro_steps);
+
+                        input_shaping.set_shapers(&input_shapers);
+                        let shaped = input_shaping.is_active();
+
+                        // With pressure advance, the E motor is driven apart from the direction of the move
+                        extruder_advance.set_advance(&pressure_advance);
+                        let advanced = extruder_advance.is_active();
+                        let extruder_dir = motor_vector_dir.e.unwrap_or(math::ZERO);
+                        let extruder_steps_per_mm = steps_per_mm.e.unwrap_or(math::ZERO);
+                        // Only the extrusion along XY moves is advanced
+                        let unit_vector_dir = segment.segment_data.unit_vector_dir;
+                        let advance_dir = match extruder_dir.is_defined_positive()
+                            && [unit_vector_dir.x, unit_vector_dir.y].iter().any(|c| c.is_some_and(|c| !c.is_zero()))
+                        {
+                            true => extruder_dir,
+                            false => math::ZERO,
+                        };
+                        let motor_vector_dir = match advanced {
+                            true => motor_vector_dir.with_coord(CoordSel::E, None),
+                            false => motor_vector_dir,
+                        };
+
+                        // The babysteps may be done in any motor, not only the ones of the move
+                        let babystep_steps_per_mm = units_per_mm * micro_steps;
+                        let babystep_speed = [max_speed.x, max_speed.y, max_speed.z].map(|v| {
+                            Real::from_lit(v.unwrap_or(0) as i64, 0) / Real::from_lit(BABYSTEP_SPEED_DIVISOR as i64, 0)
+                        });
+                        // The motors reversing take up their backlash along this segment
+                        backlash_compensator.start_segment(&backlash, &motor_vector_dir);
 
                         // The relative real time position (starting after first micro-segment)
                         let mut micro_segment_real_time_rel = micro_segment_period_secs;
@@ -257,7 +320,7 @@ pub async fn task_stepper(
                             SegmentIterator::new(&motion_profile, math::ZERO);
 
                         let mut microsegment_interpolator = LinearMicrosegmentStepInterpolator::new(
-                            segment.segment_data.unit_vector_dir.abs(),
+                            motor_vector_dir.abs(),
                             segment.segment_data.displacement_mm,
                             steps_per_mm,
                         );
@@ -265,7 +328,7 @@ pub async fn task_stepper(
                         // Prepare enable and dir flags
                         let mut stepper_enable_flags = StepperChannel::empty();
                         let mut stepper_dir_fwd_flags = StepperChannel::empty();
-                        segment.segment_data.unit_vector_dir.apply_coords(|cs| {
+                        motor_vector_dir.apply_coords(|cs| {
                             #[cfg(feature = "with-x-axis")]
                             if cs.0.contains(CoordSel::X) {
                                 stepper_enable_flags.set(StepperChannel::X, true);
@@ -315,7 +378,7 @@ pub async fn task_stepper(
                         loop {
                             // Microsegment start
 
-                            if DO_NOTHING {
+                            if DO_NOTHING || STEP_DRIVER.is_aborted() {
                                 break;
                             }
                             hwa::trace!("Micro-segment START");
@@ -359,23 +422,111 @@ pub async fn task_stepper(
                                 micro_segment_real_time_rel += current_period_width;
 
                                 let w = (current_period_width * math::ONE_MILLION).round();
-                                let _has_more =
-                                    microsegment_interpolator.advance_to(estimated_position, w);
-
-                                cfg_if::cfg_if! {
-                                    if #[cfg(feature="assert-motion")] {
-                                        steps_to_advance += microsegment_interpolator.delta;
-                                    }
-                                }
-
                                 #[cfg(feature = "verbose-timings")]
                                 let t1 = embassy_time::Instant::now();
-                                STEP_DRIVER.push(
-                                    microsegment_interpolator.state().clone(),
-                                    stepper_enable_flags,
-                                    stepper_dir_fwd_flags,
-                                )
-                                    .await;
+                                let extruder_steps = match advanced {
+                                    true => extruder_advance.advance(
+                                        current_period_width,
+                                        extruder_dir * estimated_position,
+                                        advance_dir * ds / current_period_width,
+                                        extruder_steps_per_mm,
+                                    ),
+                                    false => math::ZERO,
+                                };
+                                let babysteps = match motion_planner
+                                    .take_babystep(&babystep_speed.map(|v| v * current_period_width))
+                                    .await
+                                {
+                                    Some(offset) => Some(babystep_injector.steps(
+                                        &kinematics,
+                                        &segment.segment_data.dest_pos,
+                                        &offset,
+                                        &babystep_steps_per_mm,
+                                    )),
+                                    None => None,
+                                };
+                                // The backlash correction is done along with the babysteps, the same way
+                                let babysteps = match backlash_compensator.steps(
+                                    &backlash,
+                                    estimated_position,
+                                    segment.segment_data.displacement_mm,
+                                    &babystep_steps_per_mm,
+                                ) {
+                                    Some(backlash_steps) => Some(babysteps.unwrap_or(TVector::zero()) + backlash_steps),
+                                    None => babysteps,
+                                };
+                                let _has_more = if shaped {
+                                    let segment_steps = motor_vector_dir * estimated_position * steps_per_mm;
+                                    let steps = input_shaping.advance(current_period_width, &segment_steps);
+                                    let steps = match babysteps {
+                                        Some(babysteps) => steps.map_nan(&math::ZERO) + babysteps,
+                                        None => steps,
+                                    };
+                                    let steps = match advanced {
+                                        true => steps.with_coord(CoordSel::E, Some(extruder_steps)),
+                                        false => steps,
+                                    };
+                                    let _pushed = push_steps(
+                                        &steps, w, stepper_enable_flags, &mut real_steppper_pos,
+                                    ).await;
+                                    cfg_if::cfg_if! {
+                                        if #[cfg(feature="assert-motion")] {
+                                            steps_to_advance += _pushed;
+                                        }
+                                    }
+                                    true
+                                } else {
+                                    let steps_before = microsegment_interpolator.advanced_steps();
+                                    let _has_more =
+                                        microsegment_interpolator.advance_to(estimated_position, w);
+
+                                    if let Some(babysteps) = babysteps {
+                                        // The (signed) steps of the move along the micro-segment, with the babysteps (and the
+                                        // backlash correction) on top
+                                        let move_steps = (microsegment_interpolator.advanced_steps() - steps_before)
+                                            .map_coords(|c| Some(Real::from_lit(c as i64, 0)))
+                                            * motor_vector_dir.map_coords(|c| match c.is_defined_positive() {
+                                                true => Some(math::ONE),
+                                                false => Some(-math::ONE),
+                                            });
+                                        let steps = move_steps.map_nan(&math::ZERO) + babysteps;
+                                        let steps = match advanced {
+                                            true => steps.with_coord(CoordSel::E, Some(extruder_steps)),
+                                            false => steps,
+                                        };
+                                        let _pushed = push_uniform_steps(&steps, w, stepper_enable_flags).await;
+                                        // The steps of the move are accounted at the end of the segment
+                                        real_steppper_pos += babysteps
+                                            .map_coords(|c| c.to_i32())
+                                            .with_coord(CoordSel::E, Some(0));
+                                        cfg_if::cfg_if! {
+                                            if #[cfg(feature="assert-motion")] {
+                                                steps_to_advance += _pushed;
+                                            }
+                                        }
+                                    } else {
+                                        cfg_if::cfg_if! {
+                                            if #[cfg(feature="assert-motion")] {
+                                                steps_to_advance += microsegment_interpolator.delta;
+                                                steps_to_advance += TVector::zero().with_coord(
+                                                    CoordSel::E, extruder_steps.abs().to_i32().and_then(|c| Some(c as u32)),
+                                                );
+                                            }
+                                        }
+
+                                        let mut multi_timer = microsegment_interpolator.state().clone();
+                                        let mut enable_flags = stepper_enable_flags;
+                                        let mut dir_fwd_flags = stepper_dir_fwd_flags;
+                                        #[cfg(feature = "with-e-axis")]
+                                        if advanced {
+                                            multi_timer.set_extruder_steps(extruder_steps.abs().to_i32().unwrap_or(0) as u32);
+                                            enable_flags.set(StepperChannel::E, !extruder_steps.is_zero());
+                                            dir_fwd_flags.set(StepperChannel::E, extruder_steps.is_defined_positive());
+                                        }
+                                        STEP_DRIVER.push(multi_timer, enable_flags, dir_fwd_flags).await;
+                                    }
+                                    _has_more
+                                };
                                 cfg_if::cfg_if! {
                                     if #[cfg(feature="assert-motion")] {
                                         steps_advanced += STEP_DRIVER.flush().await;
@@ -406,6 +557,56 @@ pub async fn task_stepper(
                         ////
                         hwa::debug!("Micro-segment interpolation END");
 
+                        if shaped {
+                            input_shaping.end_segment();
+                            if STEP_DRIVER.is_aborted() {
+                                input_shaping.reset();
+                            }
+                            else if !segment.segment_data.speed_exit_mms.is_defined_positive() {
+                                // The machine stops: let the shaped position catch up with the commanded one
+                                let w = Real::from_lit(STEPPER_PLANNER_MICROSEGMENT_PERIOD_US as i64, 0);
+                                while !input_shaping.is_settled() && !STEP_DRIVER.is_aborted() {
+                                    let steps = input_shaping.advance(micro_segment_period_secs, &TVector::zero());
+                                    let _pushed = push_steps(
+                                        &steps, w, stepper_enable_flags, &mut real_steppper_pos,
+                                    ).await;
+                                    cfg_if::cfg_if! {
+                                        if #[cfg(feature="assert-motion")] {
+                                            steps_to_advance += _pushed;
+                                            steps_advanced += STEP_DRIVER.flush().await;
+                                        }
+                                    }
+                                }
+                                input_shaping.end_segment();
+                            }
+                        }
+
+                        if advanced {
+                            if STEP_DRIVER.is_aborted() {
+                                extruder_advance.reset();
+                            }
+                            else {
+                                if !segment.segment_data.speed_exit_mms.is_defined_positive() {
+                                    // The machine stops: release the advance left
+                                    let extruder_steps = extruder_advance.settle();
+                                    if !extruder_steps.is_zero() {
+                                        let w = Real::from_lit(STEPPER_PLANNER_MICROSEGMENT_PERIOD_US as i64, 0);
+                                        let steps = TVector::zero().with_coord(CoordSel::E, Some(extruder_steps));
+                                        let _pushed = push_steps(
+                                            &steps, w, StepperChannel::empty(), &mut real_steppper_pos,
+                                        ).await;
+                                        cfg_if::cfg_if! {
+                                            if #[cfg(feature="assert-motion")] {
+                                                steps_to_advance += _pushed;
+                                                steps_advanced += STEP_DRIVER.flush().await;
+                                            }
+                                        }
+                                    }
+                                }
+                                extruder_advance.end_segment();
+                            }
+                        }
+
                         cfg_if::cfg_if! {
                             if #[cfg(feature="assert-motion")] {
                                 if steps_advanced != steps_to_advance {
@@ -414,9 +615,9 @@ pub async fn task_stepper(
                             }
                         }
 
-                        {
+                        if !shaped {
                             let adv_steps = microsegment_interpolator.advanced_steps();
-                            if let Some(c) = segment.segment_data.unit_vector_dir.x {
+                            if let Some(c) = motor_vector_dir.x {
                                 if c.is_defined_positive() {
                                     real_steppper_pos.set_coord(
                                         CoordSel::X,
@@ -435,7 +636,7 @@ pub async fn task_stepper(
                                     );
                                 }
                             }
-                            if let Some(c) = segment.segment_data.unit_vector_dir.y {
+                            if let Some(c) = motor_vector_dir.y {
                                 if c.is_defined_positive() {
                                     real_steppper_pos.set_coord(
                                         CoordSel::Y,
@@ -454,7 +655,7 @@ pub async fn task_stepper(
                                     );
                                 }
                             }
-                            if let Some(c) = segment.segment_data.unit_vector_dir.z {
+                            if let Some(c) = motor_vector_dir.z {
                                 if c.is_defined_positive() {
                                     real_steppper_pos.set_coord(
                                         CoordSel::Z,
@@ -529,6 +730,8 @@ pub async fn task_stepper(
                         // Do nothing
                     }
                     else {
+                        // The homing drives the motors itself, once the queued micro-segments are done
+                        STEP_DRIVER.flush().await;
                         if !motion_planner.do_homing(&event_bus).await.is_ok() {
                             // TODO
                         }
@@ -538,12 +741,75 @@ pub async fn task_stepper(
                     .consume_current_segment_data(&event_bus)
                     .await;
                 real_steppper_pos.set_coord(CoordSel::all(), Some(0));
+                input_shaping.reset();
+                extruder_advance.reset();
+                babystep_injector.reset();
+                backlash_compensator.reset();
+                motion_planner.reset_babystep().await;
                 hwa::debug!("Homing done");
             }
         }
     }
 }
 
+/// Pushes a micro-segment of `width` us with the given (signed) steps of each motor, as given
+/// by [InputShaping] and [ExtruderAdvance], and keeps track of the position of the steppers.
+///
+/// Returns the number of steps of each motor
+async fn push_steps(
+    steps: &TVector<Real>,
+    width: Real,
+    stepper_enable_flags: StepperChannel,
+    real_steppper_pos: &mut TVector<i32>,
+) -> TVector<u32> {
+    let steps_abs = push_uniform_steps(steps, width, stepper_enable_flags).await;
+    *real_steppper_pos += steps
+        .map_coords(|c| c.to_i32())
+        .with_coord(CoordSel::E, Some(0));
+    steps_abs
+}
+
+/// Pushes a micro-segment of `width` us with the given (signed) steps of each motor, spread uniformly.
+///
+/// Returns the number of steps of each motor
+async fn push_uniform_steps(
+    steps: &TVector<Real>,
+    width: Real,
+    stepper_enable_flags: StepperChannel,
+) -> TVector<u32> {
+    let steps_abs: TVector<u32> = steps.abs().map_coords(|c| c.to_i32().and_then(|c| Some(c as u32)));
+    let mut multi_timer = MultiTimer::new();
+    multi_timer.set_uniform_steps(&steps_abs, width.to_i32().unwrap_or(0) as u32);
+
+    let mut enable_flags = stepper_enable_flags;
+    let mut dir_fwd_flags = StepperChannel::empty();
+    steps.apply_coords(|cs| {
+        #[cfg(feature = "with-x-axis")]
+        if cs.0.contains(CoordSel::X) {
+            enable_flags.set(StepperChannel::X, enable_flags.contains(StepperChannel::X) || !cs.1.is_zero());
+            dir_fwd_flags.set(StepperChannel::X, cs.1.is_defined_positive());
+        }
+        #[cfg(feature = "with-y-axis")]
+        if cs.0.contains(CoordSel::Y) {
+            enable_flags.set(StepperChannel::Y, enable_flags.contains(StepperChannel::Y) || !cs.1.is_zero());
+            dir_fwd_flags.set(StepperChannel::Y, cs.1.is_defined_positive());
+        }
+        #[cfg(feature = "with-z-axis")]
+        if cs.0.contains(CoordSel::Z) {
+            enable_flags.set(StepperChannel::Z, enable_flags.contains(StepperChannel::Z) || !cs.1.is_zero());
+            dir_fwd_flags.set(StepperChannel::Z, cs.1.is_defined_positive());
+        }
+        #[cfg(feature = "with-e-axis")]
+        if cs.0.contains(CoordSel::E) {
+            enable_flags.set(StepperChannel::E, enable_flags.contains(StepperChannel::E) || !cs.1.is_zero());
+            dir_fwd_flags.set(StepperChannel::E, cs.1.is_defined_positive());
+        }
+    });
+
+    STEP_DRIVER.push(multi_timer, enable_flags, dir_fwd_flags).await;
+    steps_abs
+}
+
 async fn park(motion_planner: &hwa::controllers::MotionPlannerRef) {
     hwa::warn!("Stepping parked");
     STEP_DRIVER.flush().await;
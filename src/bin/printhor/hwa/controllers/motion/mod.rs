@@ -13,21 +13,71 @@ mod motion_ring_buffer;
 /// The module for motion planner functionalities.
 mod motion_planner;
 
+/// The module for look-ahead planning functionalities.
+#[cfg(feature = "cornering")]
+mod motion_lookahead;
+
 /// The module for motion interpolation functionalities.
 mod motion_interpolation;
 
+/// The module for machine kinematics functionalities.
+mod motion_kinematics;
+
+/// The module for input shaping functionalities.
+mod motion_shaping;
+
+/// The module for pressure advance functionalities.
+mod motion_advance;
+
+/// The module for firmware retraction functionalities.
+mod motion_retraction;
+
+/// The module for babystepping functionalities.
+mod motion_babystep;
+
+/// The module for soft endstops functionalities.
+mod motion_soft_endstops;
+
+/// The module for backlash compensation functionalities.
+mod motion_backlash;
+
+/// The module for homing sequence functionalities.
+mod motion_homing;
+
+/// The module for Z motors alignment functionalities.
+mod motion_z_align;
+
 /// The module for motion timing functionalities.
 mod motion_timing;
 
 /// The module for motion time driver functionalities.
 mod motion_time_driver;
 
+/// The module for arc (circular and helical) interpolation functionalities.
+mod motion_arc;
+
+/// The module for cubic Bezier spline interpolation functionalities.
+mod motion_bezier;
+
+pub use motion_advance::*;
+pub use motion_arc::*;
+pub use motion_babystep::*;
+pub use motion_backlash::*;
+pub use motion_bezier::*;
 pub use motion_config::*;
+pub use motion_homing::*;
 pub use motion_planner::*;
+pub use motion_retraction::*;
+#[cfg(feature = "cornering")]
+pub use motion_lookahead::*;
 pub use motion_interpolation::*;
+pub use motion_kinematics::*;
 pub use motion_segment::*;
+pub use motion_shaping::*;
+pub use motion_soft_endstops::*;
 pub use motion_status::*;
 pub use motion_timing::*;
+pub use motion_z_align::*;
 pub use motion_time_driver::*;
 use crate::hwa;
 
@@ -35,10 +85,10 @@ use crate::hwa;
 pub enum ScheduledMove {
     /// A movement segment.
     Move(SegmentData),
-    /// A homing action.
-    Homing,
-    /// A dwell action.
-    Dwell,
+    /// A homing action (or a Z alignment).
+    Homing(HomingRequest),
+    /// A dwell action, lasting the given milliseconds (if any).
+    Dwell(Option<u32>),
 }
 
 /// Types of movements in the motion system.
@@ -46,8 +96,8 @@ pub enum ScheduledMove {
 pub enum MovType {
     /// A normal move with deferred action and communication channel.
     Move(hwa::DeferAction, hwa::CommChannel),
-    /// A homing move with a communication channel.
-    Homing(hwa::CommChannel),
+    /// A homing move (or a Z alignment) with a communication channel.
+    Homing(HomingRequest, hwa::CommChannel),
     /// A dwell action with a communication channel.
     Dwell(hwa::CommChannel),
 }
@@ -67,16 +117,20 @@ pub enum PlanEntry {
     PlannedMove(Segment, hwa::DeferAction, hwa::CommChannel, bool),
     /// A homing action request.
     ///
-    /// *_1: CommChannel* - The input channel requesting the move.
+    /// *_1: HomingRequest* - The axes to home (or the Z alignment).
     ///
-    /// *_2: bool* - Indicates if motion is deferred or not.
-    Homing(hwa::CommChannel, bool),
+    /// *_2: CommChannel* - The input channel requesting the move.
+    ///
+    /// *_3: bool* - Indicates if motion is deferred or not.
+    Homing(HomingRequest, hwa::CommChannel, bool),
     /// A Dwell action request.
     ///
-    /// *_1: CommChannel* - The input channel requesting the move.
+    /// *_1: Option<u32>* - The dwell time in milliseconds (if any).
     ///
-    /// *_2: bool* - Indicates if motion is deferred or not.
-    Dwell(hwa::CommChannel, bool),
+    /// *_2: CommChannel* - The input channel requesting the move.
+    ///
+    /// *_3: bool* - Indicates if motion is deferred or not.
+    Dwell(Option<u32>, hwa::CommChannel, bool),
     /// An executing move.
     ///
     /// *_1: MovType* - The type of the move.
@@ -0,0 +1,217 @@
+//! Pressure advance: Compensation of the lag of the extruded flow behind the extruder motor.
+//!
+//! The melt in the nozzle behaves like a spring, so the flow lags behind the motor when it speeds up
+//! and keeps on oozing when it slows down. The E motor is placed ahead of its nominal position by
+//! `K` times the extrusion rate, so it leads the XY move along the acceleration and lags along the
+//! deceleration. The offset may be smoothed along a time, so the E motor does not have to follow
+//! the steps of the acceleration. Set with M900.
+//!
+//! The E motor is driven apart from the other motors by [crate::control::task_stepper] while the
+//! advance is set, so the motion plan is not altered.
+use crate::math;
+use crate::math::Real;
+
+/// The pressure advance settings of the extruder
+#[derive(Clone, Copy, PartialEq)]
+pub struct PressureAdvance {
+    /// The advance (in seconds): The mm of filament ahead per mm/s of extrusion rate
+    k: Real,
+    /// The time constant (in seconds) of the smoothing of the advance
+    smooth_time: Real,
+}
+
+impl PressureAdvance {
+    /// No pressure advance
+    pub const fn none() -> Self {
+        Self {
+            k: Real::zero(),
+            smooth_time: Real::zero(),
+        }
+    }
+
+    /// The advance of `k` seconds, smoothed along `smooth_time` seconds. Negative values are taken as zero
+    pub fn new(k: Real, smooth_time: Real) -> Self {
+        Self {
+            k: k.max(math::ZERO),
+            smooth_time: smooth_time.max(math::ZERO),
+        }
+    }
+
+    /// The advance, in seconds
+    pub fn k(&self) -> Real {
+        self.k
+    }
+
+    /// The smoothing time, in seconds
+    pub fn smooth_time(&self) -> Real {
+        self.smooth_time
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.k.is_defined_positive()
+    }
+}
+
+/// The state of the E motor along the step generation when driven with pressure advance.
+///
+/// The positions are in (micro)steps, relative to the start of the segment being stepped.
+pub struct ExtruderAdvance {
+    settings: PressureAdvance,
+    /// The current advance, in mm of filament
+    offset: Real,
+    /// The nominal position at the start of the segment
+    segment_origin: Real,
+    /// The nominal position at the end of the last micro-segment
+    commanded: Real,
+    /// The steps already given (the rounded advanced position)
+    emitted: Real,
+}
+
+impl ExtruderAdvance {
+    pub const fn new() -> Self {
+        Self {
+            settings: PressureAdvance::none(),
+            offset: Real::zero(),
+            segment_origin: Real::zero(),
+            commanded: Real::zero(),
+            emitted: Real::zero(),
+        }
+    }
+
+    /// Whether the E motor has to be driven apart: The advance is set or it is not released yet
+    pub fn is_active(&self) -> bool {
+        self.settings.is_active() || !self.offset.is_zero()
+    }
+
+    /// Sets the pressure advance. It is taken at once: a change of the advance along an extrusion is
+    /// smoothed as any other change of the extrusion rate
+    pub fn set_advance(&mut self, settings: &PressureAdvance) {
+        if !self.is_active() {
+            self.reset();
+        }
+        self.settings = *settings;
+    }
+
+    /// Forgets the advance, taking the current position of the E motor as the origin
+    pub fn reset(&mut self) {
+        self.offset = math::ZERO;
+        self.segment_origin = math::ZERO;
+        self.commanded = math::ZERO;
+        self.emitted = math::ZERO;
+    }
+
+    /// Advances a micro-segment lasting `duration` seconds, at the end of which the E motor is
+    /// nominally `e_position` mm away from the start of the segment, extruding at `extrusion_rate` mm/s.
+    /// The extrusion rate is expected to be zero when the move is not to be advanced.
+    ///
+    /// Returns the (signed) steps of the E motor along the micro-segment
+    pub fn advance(&mut self, duration: Real, e_position: Real, extrusion_rate: Real, steps_per_mm: Real) -> Real {
+        let target = match self.settings.is_active() {
+            true => self.settings.k * extrusion_rate,
+            false => math::ZERO,
+        };
+        let smooth_time = self.settings.smooth_time;
+        self.offset = match smooth_time.is_defined_positive() && duration.is_defined_positive() {
+            true => self.offset + (target - self.offset) * (math::ONE - (-duration / smooth_time).exp()),
+            false => target,
+        };
+        self.commanded = self.segment_origin + e_position * steps_per_mm;
+        self.emit(self.commanded + self.offset * steps_per_mm)
+    }
+
+    /// Releases the advance left, as the machine has stopped.
+    ///
+    /// Returns the (signed) steps of the E motor to reach its nominal position
+    pub fn settle(&mut self) -> Real {
+        self.offset = math::ZERO;
+        self.emit(self.commanded)
+    }
+
+    /// Ends the segment: The next one starts where it ends
+    pub fn end_segment(&mut self) {
+        let offset = self.emitted;
+        self.commanded -= offset;
+        self.segment_origin = self.commanded;
+        self.emitted = math::ZERO;
+    }
+
+    fn emit(&mut self, position: Real) -> Real {
+        let position = position.round();
+        let steps = position - self.emitted;
+        self.emitted = position;
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Steps a move accelerating at `accel` mm/s^2 along `t_half` seconds and decelerating the same
+    /// with `e_ratio` mm of filament per mm, in micro-segments of 1 ms.
+    ///
+    /// Returns the total E steps, the E steps ahead of the nominal ones at the end of
+    /// the acceleration and the E steps at the end of the deceleration (before the settle)
+    fn step_move(advance: &mut ExtruderAdvance, accel: f32, t_half: f32, e_ratio: f32) -> (Real, Real, Real) {
+        let dt = 0.001f32;
+        let steps_per_mm = Real::from_f32(100.0);
+        let distance = accel * t_half * t_half;
+        let mut total = math::ZERO;
+        let mut lead = math::ZERO;
+        let mut t = 0.0f32;
+        let mut p0 = 0.0f32;
+        while t < 2.0 * t_half {
+            t = (t + dt).min(2.0 * t_half);
+            let p = match t <= t_half {
+                true => 0.5 * accel * t * t,
+                false => distance - 0.5 * accel * (2.0 * t_half - t) * (2.0 * t_half - t),
+            };
+            let v = (p - p0) / dt;
+            p0 = p;
+            total += advance.advance(
+                Real::from_f32(dt),
+                Real::from_f32(e_ratio * p),
+                Real::from_f32(e_ratio * v),
+                steps_per_mm,
+            );
+            if t <= t_half {
+                lead = total - (Real::from_f32(e_ratio * p) * steps_per_mm).round();
+            }
+        }
+        let before_settle = total;
+        total += advance.settle();
+        advance.end_segment();
+        (total, lead, before_settle)
+    }
+
+    #[test]
+    fn advance_steps() {
+        // Without advance, the E steps are the nominal ones
+        let mut advance = ExtruderAdvance::new();
+        advance.set_advance(&PressureAdvance::none());
+        let (total, lead, _) = step_move(&mut advance, 1000.0, 0.1, 0.05);
+        assert_eq!(total, Real::from_f32(50.0));
+        assert!(lead.is_zero());
+
+        // With advance, the E motor leads along the acceleration and gets the same steps
+        advance.set_advance(&PressureAdvance::new(Real::from_f32(0.05), math::ZERO));
+        assert!(advance.is_active());
+        let (total, lead, before_settle) = step_move(&mut advance, 1000.0, 0.1, 0.05);
+        assert_eq!(total, Real::from_f32(50.0));
+        // 0.05 s * 0.05 * 100 mm/s = 0.25 mm (25 steps) ahead at the top speed
+        assert!((lead - Real::from_f32(25.0)).abs() <= Real::from_f32(1.0));
+        assert!((before_settle - Real::from_f32(50.0)).abs() <= Real::from_f32(3.0));
+
+        // Smoothed, the lead is less and the steps are still the same
+        advance.set_advance(&PressureAdvance::new(Real::from_f32(0.05), Real::from_f32(0.04)));
+        let (total, smoothed_lead, _) = step_move(&mut advance, 1000.0, 0.1, 0.05);
+        assert_eq!(total, Real::from_f32(50.0));
+        assert!(smoothed_lead.is_defined_positive() && smoothed_lead < lead);
+
+        // The extrusion rate is what is advanced: Travel moves are not
+        let (total, lead, _) = step_move(&mut advance, 1000.0, 0.1, 0.0);
+        assert!(total.is_zero() && lead.is_zero());
+        advance.set_advance(&PressureAdvance::none());
+        assert!(!advance.is_active());
+    }
+}
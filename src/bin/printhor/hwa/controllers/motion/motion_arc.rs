@@ -0,0 +1,283 @@
+//! Circular and helical interpolation (G2/G3) as a sequence of chords.
+//!
+//! The arc is described in the active plane (G17, G18, G19) by its first and second axes, so that
+//! a positive angle is counter-clockwise. The linear axis (and E, if given) is interpolated
+//! proportionally to the angle, producing an helix.
+use crate::math;
+use crate::math::Real;
+use crate::tgeo::{CoordSel, TVector};
+
+/// The plane where circular motion is performed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArcPlane {
+    /// G17
+    XY,
+    /// G18
+    ZX,
+    /// G19
+    YZ,
+}
+
+impl ArcPlane {
+    /// The (first, second, linear) axes of the plane
+    pub fn axes(&self) -> (CoordSel, CoordSel, CoordSel) {
+        match self {
+            ArcPlane::XY => (CoordSel::X, CoordSel::Y, CoordSel::Z),
+            ArcPlane::ZX => (CoordSel::Z, CoordSel::X, CoordSel::Y),
+            ArcPlane::YZ => (CoordSel::Y, CoordSel::Z, CoordSel::X),
+        }
+    }
+
+    /// The words giving the centre offset along the (first, second) axes of the plane
+    pub fn offset_letters(&self) -> (char, char) {
+        match self {
+            ArcPlane::XY => ('i', 'j'),
+            ArcPlane::ZX => ('k', 'i'),
+            ArcPlane::YZ => ('j', 'k'),
+        }
+    }
+}
+
+fn coord(v: &TVector<Real>, sel: CoordSel) -> Real {
+    match sel {
+        CoordSel::X => v.x,
+        CoordSel::Y => v.y,
+        CoordSel::Z => v.z,
+        _ => v.e,
+    }
+    .unwrap_or(math::ZERO)
+}
+
+/// Iterates the chord end points of an arc, from the first one after the start to the end (included)
+#[derive(Clone)]
+pub struct ArcInterpolator {
+    plane: ArcPlane,
+    start: TVector<Real>,
+    end: TVector<Real>,
+    center: (Real, Real),
+    radius: Real,
+    start_angle: Real,
+    angular_travel: Real,
+    num_chords: u32,
+    current_chord: u32,
+}
+
+impl ArcInterpolator {
+    /// Builds the arc from the centre offset relative to start (I, J, K form).
+    ///
+    /// When start and end are the same point, a full circle is described.
+    /// None is returned if the arc is degenerated (zero radius)
+    pub fn from_offsets(
+        plane: ArcPlane,
+        clockwise: bool,
+        start: TVector<Real>,
+        end: TVector<Real>,
+        offset: (Real, Real),
+        chord_tolerance: Real,
+    ) -> Option<Self> {
+        let (axis_0, axis_1, _) = plane.axes();
+        let center = (coord(&start, axis_0) + offset.0, coord(&start, axis_1) + offset.1);
+        let (rs_0, rs_1) = (-offset.0, -offset.1);
+        let (re_0, re_1) = (coord(&end, axis_0) - center.0, coord(&end, axis_1) - center.1);
+        let radius = (rs_0 * rs_0 + rs_1 * rs_1).sqrt()?;
+        if radius.is_negligible() {
+            return None;
+        }
+        let cross = rs_0 * re_1 - rs_1 * re_0;
+        let dot = rs_0 * re_0 + rs_1 * re_1;
+        let mut angular_travel = cross.atan2(dot);
+        let two_pi = math::PI * math::TWO;
+        let angular_epsilon = Real::new(5, 7);
+        if clockwise {
+            if angular_travel >= -angular_epsilon {
+                angular_travel -= two_pi;
+            }
+        } else if angular_travel <= angular_epsilon {
+            angular_travel += two_pi;
+        }
+
+        // Chord angle for a sagitta equal to the tolerance: r * (1 - cos(a/2)) ~= r * a^2 / 8
+        // Never less than a chord per quadrant
+        let quadrant = math::PI / math::TWO;
+        let max_chord_angle = match chord_tolerance.is_defined_positive() {
+            true => match (chord_tolerance * Real::from_lit(8, 0) / radius).sqrt() {
+                Some(a) if a.is_defined_positive() => a.min(quadrant),
+                _ => quadrant,
+            },
+            false => quadrant,
+        };
+        let num_chords = (angular_travel.abs() / max_chord_angle)
+            .ceil()
+            .to_i32()
+            .unwrap_or(1)
+            .max(1) as u32;
+
+        Some(Self {
+            plane,
+            start,
+            end,
+            center,
+            radius,
+            start_angle: rs_1.atan2(rs_0),
+            angular_travel,
+            num_chords,
+            current_chord: 0,
+        })
+    }
+
+    /// Builds the arc from its radius (R form).
+    ///
+    /// A negative radius selects the arc longer than a half circle.
+    /// None is returned when there is no such arc (start and end are the same point or too far apart)
+    pub fn from_radius(
+        plane: ArcPlane,
+        clockwise: bool,
+        start: TVector<Real>,
+        end: TVector<Real>,
+        radius: Real,
+        chord_tolerance: Real,
+    ) -> Option<Self> {
+        let (axis_0, axis_1, _) = plane.axes();
+        let x = coord(&end, axis_0) - coord(&start, axis_0);
+        let y = coord(&end, axis_1) - coord(&start, axis_1);
+        let d = (x * x + y * y).sqrt()?;
+        if d.is_negligible() {
+            return None;
+        }
+        let h_x2 = math::FOUR * radius * radius - x * x - y * y;
+        if h_x2 < math::ZERO && !h_x2.is_negligible() {
+            return None;
+        }
+        let mut h_x2_div_d = match h_x2 > math::ZERO {
+            true => -h_x2.sqrt()? / d,
+            false => math::ZERO,
+        };
+        if !clockwise {
+            h_x2_div_d = -h_x2_div_d;
+        }
+        if radius < math::ZERO {
+            h_x2_div_d = -h_x2_div_d;
+        }
+        let offset = (
+            (x - (y * h_x2_div_d)) * math::HALF,
+            (y + (x * h_x2_div_d)) * math::HALF,
+        );
+        Self::from_offsets(plane, clockwise, start, end, offset, chord_tolerance)
+    }
+
+    pub fn num_chords(&self) -> u32 {
+        self.num_chords
+    }
+
+    pub fn radius(&self) -> Real {
+        self.radius
+    }
+}
+
+impl Iterator for ArcInterpolator {
+    type Item = TVector<Real>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current_chord >= self.num_chords {
+            return None;
+        }
+        self.current_chord += 1;
+        if self.current_chord == self.num_chords {
+            // Last chord ends exactly at the requested point
+            return Some(self.end);
+        }
+        let fraction = Real::from_lit(self.current_chord as i64, 0)
+            / Real::from_lit(self.num_chords as i64, 0);
+        let angle = self.start_angle + self.angular_travel * fraction;
+        let (axis_0, axis_1, _) = self.plane.axes();
+        let mut point = self.start + (self.end - self.start) * fraction;
+        point.set_coord(axis_0, Some(self.center.0 + self.radius * angle.cos()));
+        point.set_coord(axis_1, Some(self.center.1 + self.radius * angle.sin()));
+        Some(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(x: f32, y: f32, z: f32) -> TVector<Real> {
+        TVector::from_coords(
+            Some(Real::from_f32(x)),
+            Some(Real::from_f32(y)),
+            Some(Real::from_f32(z)),
+            None,
+        )
+    }
+
+    fn distance_2d(a: (Real, Real), b: (Real, Real)) -> Real {
+        ((a.0 - b.0) * (a.0 - b.0) + (a.1 - b.1) * (a.1 - b.1)).sqrt().unwrap()
+    }
+
+    #[test]
+    fn quarter_circle_ccw_with_offsets() {
+        let tolerance = Real::from_f32(0.01);
+        let arc = ArcInterpolator::from_offsets(
+            ArcPlane::XY, false,
+            pos(10.0, 0.0, 0.0), pos(0.0, 10.0, 0.0),
+            (Real::from_f32(-10.0), Real::from_f32(0.0)),
+            tolerance,
+        ).unwrap();
+        // sqrt(8 * 0.01 / 10) ~= 0.0894 rad per chord
+        assert_eq!(arc.num_chords(), 18);
+        let points: alloc::vec::Vec<TVector<Real>> = arc.collect();
+        assert_eq!(points.len(), 18);
+        for p in points.iter() {
+            let r = distance_2d((p.x.unwrap(), p.y.unwrap()), (math::ZERO, math::ZERO));
+            assert!((r - Real::from_f32(10.0)).abs() < tolerance);
+            assert!(p.x.unwrap() > -tolerance && p.y.unwrap() > -tolerance);
+        }
+        assert!(points.last().unwrap() == &pos(0.0, 10.0, 0.0));
+    }
+
+    #[test]
+    fn half_circle_cw_with_radius() {
+        let arc = ArcInterpolator::from_radius(
+            ArcPlane::XY, true,
+            pos(0.0, 0.0, 0.0), pos(10.0, 0.0, 0.0),
+            Real::from_f32(5.0),
+            Real::from_f32(0.01),
+        ).unwrap();
+        assert!((arc.radius() - Real::from_f32(5.0)).abs() < Real::from_f32(0.001));
+        // Clockwise from the left to the right of the centre goes over it
+        for p in arc {
+            assert!(p.y.unwrap() > Real::from_f32(-0.001));
+        }
+    }
+
+    #[test]
+    fn full_helix_in_zx_plane() {
+        let start = pos(10.0, 0.0, 0.0);
+        let end = pos(10.0, 4.0, 0.0);
+        let arc = ArcInterpolator::from_offsets(
+            ArcPlane::ZX, false,
+            start, end,
+            (Real::from_f32(0.0), Real::from_f32(-5.0)),
+            Real::from_f32(0.1),
+        ).unwrap();
+        let num_chords = arc.num_chords();
+        assert!(num_chords >= 4);
+        let mut last_y = math::ZERO;
+        for p in arc {
+            // The linear axis (Y) grows along the helix
+            assert!(p.y.unwrap() > last_y);
+            last_y = p.y.unwrap();
+        }
+        assert_eq!(last_y, Real::from_f32(4.0));
+    }
+
+    #[test]
+    fn unreachable_radius() {
+        assert!(ArcInterpolator::from_radius(
+            ArcPlane::XY, true,
+            pos(0.0, 0.0, 0.0), pos(10.0, 0.0, 0.0),
+            Real::from_f32(4.0),
+            Real::from_f32(0.01),
+        ).is_none());
+    }
+}
@@ -0,0 +1,145 @@
+//! Babystepping: Small offsets of the tool (M290), mostly of Z along the first layer, done along
+//! the moves being executed instead of queuing moves of their own.
+//!
+//! The offsets are taken by [crate::control::task_stepper] micro-segment by micro-segment, at a
+//! fraction of the max speed of each axis, and turned into steps of the motors on top of the ones
+//! of the move. The coordinates are not changed: The tool is just shifted from them. The offsets
+//! requested while the machine is idle are done along the next move. Homing clears them.
+use crate::hwa::controllers::motion::Kinematics;
+use crate::math;
+use crate::math::Real;
+use crate::tgeo::TVector;
+
+/// The fraction of the max speed of each axis the babysteps are done at
+pub const BABYSTEP_SPEED_DIVISOR: u32 = 10;
+
+/// The babysteps requested (M290): The ones not done yet and their running total
+pub struct Babystepping {
+    /// The X, Y and Z offsets (in mm) requested and not done yet
+    pending: [Real; 3],
+    /// The X, Y and Z offsets (in mm) requested since homing
+    total: [Real; 3],
+}
+
+impl Babystepping {
+    pub const fn new() -> Self {
+        Self {
+            pending: [Real::zero(); 3],
+            total: [Real::zero(); 3],
+        }
+    }
+
+    /// Requests the X, Y and Z offsets (in mm) given, on top of the ones already requested
+    pub fn request(&mut self, offset: [Option<Real>; 3]) {
+        for (i, o) in offset.iter().enumerate() {
+            if let Some(o) = o {
+                self.pending[i] += *o;
+                self.total[i] += *o;
+            }
+        }
+    }
+
+    /// The X, Y and Z offsets (in mm) requested since homing
+    pub fn total(&self) -> [Real; 3] {
+        self.total
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.iter().any(|o| !o.is_zero())
+    }
+
+    /// Takes the pending offsets, up to `max_offset` mm of each axis.
+    ///
+    /// Returns the tool space offset taken, if any
+    pub fn take(&mut self, max_offset: &[Real; 3]) -> Option<TVector<Real>> {
+        if !self.is_pending() {
+            return None;
+        }
+        let mut taken = [math::ZERO; 3];
+        for (i, max) in max_offset.iter().enumerate() {
+            taken[i] = self.pending[i].max(-*max).min(*max);
+            self.pending[i] -= taken[i];
+        }
+        Some(TVector::from_coords(Some(taken[0]), Some(taken[1]), Some(taken[2]), Some(math::ZERO)))
+    }
+
+    /// Forgets the offsets, as the machine has been homed
+    pub fn reset(&mut self) {
+        self.pending = [math::ZERO; 3];
+        self.total = [math::ZERO; 3];
+    }
+}
+
+/// The babysteps being done by the step generation, in steps of each motor
+pub struct BabystepInjector {
+    /// The fraction of step of each motor not done yet
+    residual: TVector<Real>,
+}
+
+impl BabystepInjector {
+    pub const fn new() -> Self {
+        Self {
+            residual: TVector::new(),
+        }
+    }
+
+    /// The (signed) steps of each motor to shift the tool by `offset` mm from `tool_pos`
+    pub fn steps<K: Kinematics>(
+        &mut self,
+        kinematics: &K,
+        tool_pos: &TVector<Real>,
+        offset: &TVector<Real>,
+        steps_per_mm: &TVector<Real>,
+    ) -> TVector<Real> {
+        let tool_pos = tool_pos.map_nan(&math::ZERO);
+        let motor_offset = kinematics.tool_to_motor(&(tool_pos + offset.map_nan(&math::ZERO)))
+            - kinematics.tool_to_motor(&tool_pos);
+        let steps = motor_offset.map_nan(&math::ZERO) * steps_per_mm.map_nan(&math::ZERO)
+            + self.residual.map_nan(&math::ZERO);
+        let rounded = steps.round();
+        self.residual = steps - rounded;
+        rounded
+    }
+
+    pub fn reset(&mut self) {
+        self.residual = TVector::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hwa::controllers::motion::CartesianKinematics;
+
+    #[test]
+    fn babysteps() {
+        let mut babystepping = Babystepping::new();
+        assert!(babystepping.take(&[math::ONE; 3]).is_none());
+        babystepping.request([None, None, Some(Real::from_f32(0.05))]);
+        babystepping.request([None, None, Some(Real::from_f32(-0.02))]);
+        assert_eq!(babystepping.total()[2], Real::from_f32(0.05) + Real::from_f32(-0.02));
+
+        // Taken at a limited rate: 0.03 mm in three micro-segments of up to 0.01 mm
+        let max_offset = [Real::from_f32(0.01); 3];
+        let mut injector = BabystepInjector::new();
+        let kinematics = CartesianKinematics;
+        let pos = TVector::from_coords(Some(math::ONE), Some(math::ONE), Some(math::ONE), None);
+        let steps_per_mm = TVector::from_coords(None, None, Some(Real::from_f32(400.0)), None);
+        let mut z_steps = math::ZERO;
+        let mut micro_segments = 0;
+        while let Some(offset) = babystepping.take(&max_offset) {
+            let steps = injector.steps(&kinematics, &pos, &offset, &steps_per_mm);
+            assert!(steps.x.unwrap().is_zero() && steps.e.unwrap().is_zero());
+            z_steps += steps.z.unwrap();
+            micro_segments += 1;
+            assert!(micro_segments < 10);
+        }
+        assert!(micro_segments >= 3);
+        // 0.03 mm at 400 steps/mm, with the fractions carried along
+        assert!((z_steps - Real::from_f32(12.0)).abs() <= math::ONE);
+        assert!(!babystepping.is_pending());
+
+        babystepping.reset();
+        assert!(babystepping.total()[2].is_zero());
+    }
+}
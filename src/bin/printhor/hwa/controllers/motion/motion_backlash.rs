@@ -0,0 +1,167 @@
+//! Backlash compensation: The play of the X, Y and Z motors (M425), taken up when their direction reverses.
+//!
+//! The extra steps are done by [crate::control::task_stepper] along the segment following the reversal,
+//! micro-segment by micro-segment, on top of the ones of the move (as the babysteps, see
+//! [crate::hwa::controllers::motion::Babystepping]). The coordinates are not changed: Only the motors
+//! are. The correction is spread over the smoothing distance (or the whole segment), so there is no
+//! spike of steps, and the part not done by the end of a segment is carried to the next one.
+use crate::math;
+use crate::math::Real;
+use crate::tgeo::TVector;
+
+/// The backlash compensation settings
+#[derive(Clone, Copy)]
+pub struct BacklashConfig {
+    /// The play of the X, Y and Z motors, in mm
+    pub distance: [Real; 3],
+    /// The fraction (0 to 1) of the play taken up
+    pub correction: Real,
+    /// The distance along the segment the correction is spread over, in mm. Zero for the whole segment
+    pub smoothing: Real,
+}
+
+impl BacklashConfig {
+    /// No play, fully corrected along the whole segment
+    pub const fn new() -> Self {
+        Self {
+            distance: [Real::zero(); 3],
+            correction: math::ONE,
+            smoothing: Real::zero(),
+        }
+    }
+}
+
+/// The backlash correction being done by the step generation, in steps of each motor
+pub struct BacklashCompensator {
+    /// The direction the X, Y and Z motors last moved in (forward or not), if known
+    direction: [Option<bool>; 3],
+    /// The correction (signed, in mm) to do along the segment being executed
+    pending: [Real; 3],
+    /// The part of it already done
+    done: [Real; 3],
+    /// The fraction of step of each motor not done yet
+    residual: TVector<Real>,
+}
+
+impl BacklashCompensator {
+    pub const fn new() -> Self {
+        Self {
+            direction: [None; 3],
+            pending: [Real::zero(); 3],
+            done: [Real::zero(); 3],
+            residual: TVector::new(),
+        }
+    }
+
+    /// Starts a segment moving the motors along `motor_dir` (in motor space). The correction of the
+    /// motors reversing is added to the one left from the previous segment, if any
+    pub fn start_segment(&mut self, config: &BacklashConfig, motor_dir: &TVector<Real>) {
+        let dir = [motor_dir.x, motor_dir.y, motor_dir.z];
+        for (i, d) in dir.iter().enumerate() {
+            self.pending[i] -= self.done[i];
+            self.done[i] = math::ZERO;
+            let d = match d {
+                Some(d) if !d.is_zero() => *d,
+                _ => continue,
+            };
+            let forward = d.is_positive();
+            if self.direction[i].is_some_and(|prev| prev != forward) {
+                let play = config.distance[i] * config.correction;
+                self.pending[i] += match forward {
+                    true => play,
+                    false => -play,
+                };
+            }
+            self.direction[i] = Some(forward);
+        }
+    }
+
+    /// The (signed) steps of each motor to do up to `advanced` mm along the segment of `displacement` mm,
+    /// or None if there is nothing to correct
+    pub fn steps(
+        &mut self,
+        config: &BacklashConfig,
+        advanced: Real,
+        displacement: Real,
+        steps_per_mm: &TVector<Real>,
+    ) -> Option<TVector<Real>> {
+        if self.pending.iter().all(|p| p.is_zero()) {
+            return None;
+        }
+        let spread = match config.smoothing > math::ZERO {
+            true => config.smoothing,
+            false => displacement,
+        };
+        let fraction = match spread > math::ZERO {
+            true => (advanced / spread).max(math::ZERO).min(math::ONE),
+            false => math::ONE,
+        };
+        let mut delta = [math::ZERO; 3];
+        for (i, d) in delta.iter_mut().enumerate() {
+            let target = self.pending[i] * fraction;
+            *d = target - self.done[i];
+            self.done[i] = target;
+        }
+        let steps = TVector::from_coords(Some(delta[0]), Some(delta[1]), Some(delta[2]), Some(math::ZERO))
+            * steps_per_mm.map_nan(&math::ZERO)
+            + self.residual.map_nan(&math::ZERO);
+        let rounded = steps.round();
+        self.residual = steps - rounded;
+        Some(rounded)
+    }
+
+    /// Forgets the directions and the correction left, as the machine has been homed
+    pub fn reset(&mut self) {
+        self.direction = [None; 3];
+        self.pending = [math::ZERO; 3];
+        self.done = [math::ZERO; 3];
+        self.residual = TVector::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlash_compensation() {
+        let mut config = BacklashConfig::new();
+        config.distance = [Real::from_f32(0.1), math::ZERO, Real::from_f32(0.05)];
+        let steps_per_mm = TVector::from_coords(
+            Some(Real::from_f32(100.0)), Some(Real::from_f32(100.0)), Some(Real::from_f32(400.0)), None,
+        );
+        let dir = |x: f32, z: f32| TVector::from_coords(Some(Real::from_f32(x)), None, Some(Real::from_f32(z)), None);
+        let displacement = Real::from_f32(10.0);
+        let mut compensator = BacklashCompensator::new();
+
+        // The first moves set the directions
+        compensator.start_segment(&config, &dir(1.0, 1.0));
+        assert!(compensator.steps(&config, displacement, displacement, &steps_per_mm).is_none());
+
+        // X reverses: 0.1 mm backwards, spread along the segment
+        compensator.start_segment(&config, &dir(-1.0, 0.0));
+        let mut x_steps = math::ZERO;
+        for i in 1..=10 {
+            let steps = compensator.steps(&config, Real::from_f32(i as f32), displacement, &steps_per_mm).unwrap();
+            assert!(steps.x.unwrap().abs() <= math::TWO);
+            assert!(steps.z.unwrap().is_zero());
+            x_steps += steps.x.unwrap();
+        }
+        assert_eq!(x_steps, Real::from_f32(-10.0));
+
+        // Z reverses, half corrected over the first 2 mm of a 1 mm segment: The rest is carried on
+        config.correction = Real::from_f32(0.5);
+        config.smoothing = Real::from_f32(2.0);
+        compensator.start_segment(&config, &dir(-1.0, -1.0));
+        let z_steps = compensator.steps(&config, math::ONE, math::ONE, &steps_per_mm).unwrap().z.unwrap();
+        assert_eq!(z_steps, Real::from_f32(-5.0));
+        compensator.start_segment(&config, &dir(-1.0, -1.0));
+        let z_steps = compensator.steps(&config, Real::from_f32(2.0), displacement, &steps_per_mm).unwrap().z.unwrap();
+        assert_eq!(z_steps, Real::from_f32(-5.0));
+        assert!(compensator.steps(&config, displacement, displacement, &steps_per_mm).unwrap().is_nan_or_zero());
+
+        compensator.reset();
+        compensator.start_segment(&config, &dir(1.0, 1.0));
+        assert!(compensator.steps(&config, displacement, displacement, &steps_per_mm).is_none());
+    }
+}
@@ -0,0 +1,184 @@
+//! Cubic Bezier spline interpolation (G5) as a sequence of lines.
+//!
+//! The curve lies in the XY plane. It is flattened adaptively: a piece of the curve is split by half
+//! (de Casteljau) until it is flat enough to be replaced by its chord within the given deviation,
+//! so straight stretches produce few lines and tight bends many.
+//! Z and E (if given) are interpolated proportionally to the curve parameter.
+use crate::math;
+use crate::math::Real;
+use crate::tgeo::TVector;
+
+/// Maximum number of times a piece of the curve is halved (up to 2^MAX_DEPTH lines)
+const MAX_DEPTH: u8 = 10;
+
+type Point = (Real, Real);
+
+#[derive(Clone, Copy)]
+struct Piece {
+    control_points: [Point; 4],
+    t_end: Real,
+    t_len: Real,
+    depth: u8,
+}
+
+impl Piece {
+    /// Bound of the distance between the control points and the chord (Willcocks):
+    /// the piece is flat if 16 * deviation^2 is not exceeded
+    fn is_flat(&self, max_deviation_sq_x16: Real) -> bool {
+        let [p0, p1, p2, p3] = self.control_points;
+        let ux = (math::THREE * p1.0 - math::TWO * p0.0 - p3.0).abs();
+        let uy = (math::THREE * p1.1 - math::TWO * p0.1 - p3.1).abs();
+        let vx = (math::THREE * p2.0 - p0.0 - math::TWO * p3.0).abs();
+        let vy = (math::THREE * p2.1 - p0.1 - math::TWO * p3.1).abs();
+        let mx = ux.max(vx);
+        let my = uy.max(vy);
+        mx * mx + my * my <= max_deviation_sq_x16
+    }
+
+    /// Splits the piece at its middle, giving the (first, second) halves
+    fn split(&self) -> (Piece, Piece) {
+        let mid = |a: Point, b: Point| ((a.0 + b.0) * math::HALF, (a.1 + b.1) * math::HALF);
+        let [p0, p1, p2, p3] = self.control_points;
+        let p01 = mid(p0, p1);
+        let p12 = mid(p1, p2);
+        let p23 = mid(p2, p3);
+        let p012 = mid(p01, p12);
+        let p123 = mid(p12, p23);
+        let p0123 = mid(p012, p123);
+        let t_len = self.t_len * math::HALF;
+        (
+            Piece {
+                control_points: [p0, p01, p012, p0123],
+                t_end: self.t_end - t_len,
+                t_len,
+                depth: self.depth + 1,
+            },
+            Piece {
+                control_points: [p0123, p123, p23, p3],
+                t_end: self.t_end,
+                t_len,
+                depth: self.depth + 1,
+            },
+        )
+    }
+}
+
+/// Iterates the line end points of a cubic Bezier curve, from the first one after the start to the end (included)
+#[derive(Clone)]
+pub struct BezierInterpolator {
+    start: TVector<Real>,
+    end: TVector<Real>,
+    max_deviation_sq_x16: Real,
+    /// Pieces pending to be flattened. The next one is on top
+    stack: [Option<Piece>; MAX_DEPTH as usize + 1],
+    stack_len: usize,
+}
+
+impl BezierInterpolator {
+    /// Builds the curve from start to end with the control points given as offsets:
+    /// `control_1` relative to start (I, J) and `control_2` relative to end (P, Q).
+    ///
+    /// The pieces of the curve are replaced by lines when they deviate less than `max_deviation`.
+    /// A non positive deviation flattens down to the maximum number of lines
+    pub fn new(
+        start: TVector<Real>,
+        end: TVector<Real>,
+        control_1: Point,
+        control_2: Point,
+        max_deviation: Real,
+    ) -> Self {
+        let p0 = (start.x.unwrap_or(math::ZERO), start.y.unwrap_or(math::ZERO));
+        let p3 = (end.x.unwrap_or(math::ZERO), end.y.unwrap_or(math::ZERO));
+        let mut stack = [None; MAX_DEPTH as usize + 1];
+        stack[0] = Some(Piece {
+            control_points: [
+                p0,
+                (p0.0 + control_1.0, p0.1 + control_1.1),
+                (p3.0 + control_2.0, p3.1 + control_2.1),
+                p3,
+            ],
+            t_end: math::ONE,
+            t_len: math::ONE,
+            depth: 0,
+        });
+        Self {
+            start,
+            end,
+            max_deviation_sq_x16: max_deviation * max_deviation * Real::from_lit(16, 0),
+            stack,
+            stack_len: 1,
+        }
+    }
+}
+
+impl Iterator for BezierInterpolator {
+    type Item = TVector<Real>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.stack_len == 0 {
+                return None;
+            }
+            self.stack_len -= 1;
+            let piece = self.stack[self.stack_len].take()?;
+            if piece.depth >= MAX_DEPTH || piece.is_flat(self.max_deviation_sq_x16) {
+                if self.stack_len == 0 {
+                    // Last line ends exactly at the requested point
+                    return Some(self.end);
+                }
+                let [_, _, _, (x, y)] = piece.control_points;
+                let point = self.start + (self.end - self.start) * piece.t_end;
+                return Some(TVector::from_coords(Some(x), Some(y), point.z, point.e));
+            }
+            let (first, second) = piece.split();
+            // Depth first: The stack never holds more than a piece per level
+            self.stack[self.stack_len] = Some(second);
+            self.stack[self.stack_len + 1] = Some(first);
+            self.stack_len += 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(x: f32, y: f32) -> TVector<Real> {
+        TVector::from_coords(Some(Real::from_f32(x)), Some(Real::from_f32(y)), Some(math::ZERO), None)
+    }
+
+    #[test]
+    fn straight_curve_is_a_single_line() {
+        let points: alloc::vec::Vec<TVector<Real>> = BezierInterpolator::new(
+            pos(0.0, 0.0), pos(30.0, 0.0),
+            (Real::from_f32(10.0), math::ZERO),
+            (Real::from_f32(-10.0), math::ZERO),
+            Real::from_f32(0.01),
+        ).collect();
+        assert_eq!(points.len(), 1);
+        assert!(points[0] == pos(30.0, 0.0));
+    }
+
+    #[test]
+    fn tighter_deviation_gives_more_lines() {
+        let curve = |deviation: f32| BezierInterpolator::new(
+            pos(0.0, 0.0), pos(20.0, 0.0),
+            (math::ZERO, Real::from_f32(20.0)),
+            (math::ZERO, Real::from_f32(20.0)),
+            Real::from_f32(deviation),
+        );
+        let coarse = curve(0.5).count();
+        let fine = curve(0.01).count();
+        assert!(coarse > 1);
+        assert!(fine > coarse);
+
+        let mut last_x = math::ZERO;
+        for p in curve(0.01) {
+            // The curve is symmetric and never goes back in X
+            assert!(p.x.unwrap() >= last_x);
+            assert!(p.y.unwrap() >= math::ZERO && p.y.unwrap() <= Real::from_f32(15.0));
+            last_x = p.x.unwrap();
+        }
+        assert_eq!(last_x, Real::from_f32(20.0));
+    }
+}
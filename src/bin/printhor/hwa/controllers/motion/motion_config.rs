@@ -1,5 +1,6 @@
 use crate::math::Real;
 use crate::tgeo::TVector;
+use crate::hwa::controllers::motion::{BacklashConfig, HomingConfig, InputShaper, MachineKinematics, PressureAdvance, RetractionConfig, SoftEndstops, ZAlignConfig, MACHINE_KINEMATICS};
 
 /// Type alias for the motion configuration's mutex type.
 pub type MotionConfigMutexType = printhor_hwa_common::InterruptControllerMutexType;
@@ -24,6 +25,20 @@ pub type MotionConfigRef = printhor_hwa_common::ControllerRef<MotionConfigMutexT
 /// * `usteps` - An array of micro-stepping values for each axis.
 /// * `flow_rate` - The flow rate for the motion, represented as a percentage.
 /// * `speed_rate` - The speed rate for the motion, represented as a percentage.
+/// * `speed_rate_backup` - The speed rate saved by M220 B, to be restored by M220 R.
+/// * `arc_chord_tolerance` - The maximum deviation in mm of the chords approximating an arc.
+/// * `bezier_max_deviation` - The maximum deviation in mm of the lines approximating a Bezier curve.
+/// * `junction_deviation` - The junction deviation in mm limiting the corner speed. Zero to use the projection rule.
+/// * `kinematics` - The kinematics of the machine, with its parameters (if any).
+/// * `input_shapers` - The input shapers of the X, Y and Z stepper channels.
+/// * `pressure_advance` - The pressure advance of the extruder.
+/// * `retraction` - The firmware retraction settings.
+/// * `probe_offset` - The offset of the probe from the tool.
+/// * `soft_endstops` - The limits of the position of the tool.
+/// * `homing` - How the axes are homed.
+/// * `stallguard_threshold` - The StallGuard thresholds of the X, Y and Z drivers.
+/// * `z_align` - How the Z motors are aligned.
+/// * `backlash` - The backlash compensation of the X, Y and Z motors.
 ///
 /// # Example
 ///
@@ -52,6 +67,8 @@ pub struct MotionConfig {
     pub default_travel_speed: u16,
 
     /// Units per millimeters NOT considering micro-stepping.
+    /// Units per degree for the angular motors (the arms of a SCARA and the bed of a polar machine),
+    /// whose speed, acceleration and jerk limits are also per degree.
     pub units_per_mm: TVector<Real>,
 
     /// Machine's motion bounds.
@@ -62,6 +79,36 @@ pub struct MotionConfig {
     pub flow_rate: u8,
     /// Speed rate for the motion, represented as a percentage.
     pub speed_rate: u8,
+    /// Speed rate saved by M220 B, to be restored by M220 R.
+    pub speed_rate_backup: u8,
+    /// Maximum deviation (in mm) between an arc and the chords approximating it.
+    pub arc_chord_tolerance: Real,
+    /// Maximum deviation (in mm) between a Bezier curve and the lines approximating it.
+    pub bezier_max_deviation: Real,
+    /// Junction deviation (in mm) limiting the speed through the corners (M205 J).
+    /// When zero, the speed is limited by the projection of one move onto the next one instead.
+    pub junction_deviation: Real,
+    /// The conversion between tool-space and motor-space positions.
+    pub kinematics: MachineKinematics,
+    /// The input shapers of the X, Y and Z stepper channels (M593).
+    pub input_shapers: [InputShaper; 3],
+    /// The pressure advance of the extruder (M900).
+    pub pressure_advance: PressureAdvance,
+    /// The firmware retraction settings (M207, M208, M209).
+    pub retraction: RetractionConfig,
+    /// The X, Y and Z offset (in mm) of the probe from the tool (M851). Also tuned by M290 P1.
+    pub probe_offset: TVector<Real>,
+    /// The limits of the position of the tool, checked before a move is queued (M211).
+    pub soft_endstops: SoftEndstops,
+    /// How the axes are homed (G28), with the home offsets (M206).
+    pub homing: HomingConfig,
+    /// The StallGuard threshold (SGTHRS) of the X, Y and Z drivers (M914): The higher, the more sensitive.
+    /// Used by the sensorless homing (See [crate::hwa::controllers::AxisHoming::sensorless]).
+    pub stallguard_threshold: [u8; 3],
+    /// How the Z motors are aligned (G34), with their probing points (M422).
+    pub z_align: ZAlignConfig,
+    /// The backlash compensation of the X, Y and Z motors (M425), done by the step generation.
+    pub backlash: BacklashConfig,
 }
 
 impl MotionConfig {
@@ -78,6 +125,20 @@ impl MotionConfig {
             default_travel_speed: 1,
             flow_rate: 100,
             speed_rate: 100,
+            speed_rate_backup: 100,
+            arc_chord_tolerance: Real::zero(),
+            bezier_max_deviation: Real::zero(),
+            junction_deviation: Real::zero(),
+            kinematics: MACHINE_KINEMATICS,
+            input_shapers: [InputShaper::none(); 3],
+            pressure_advance: PressureAdvance::none(),
+            retraction: RetractionConfig::new(),
+            probe_offset: TVector::from_coords(Some(Real::zero()), Some(Real::zero()), Some(Real::zero()), None),
+            soft_endstops: SoftEndstops::new(),
+            homing: HomingConfig::new(),
+            stallguard_threshold: [0; 3],
+            z_align: ZAlignConfig::new(),
+            backlash: BacklashConfig::new(),
         }
     }
 
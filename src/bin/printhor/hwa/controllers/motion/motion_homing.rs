@@ -0,0 +1,161 @@
+//! Homing sequence: How each axis is homed by [crate::hwa::drivers::MotionDriver::homing_action], kept in
+//! [crate::hwa::controllers::MotionConfig] so it can be set at runtime (M210 and M206).
+//!
+//! Each axis goes to its endstop (or the probe, for Z) at the fast speed, backs off the bump distance and
+//! approaches it again at the slow speed. A sensorless axis stops at the stall of its motor instead, in a
+//! single approach. Its position is then the one of its end (0 or the machine bound),
+//! plus the home offset (M206) and minus the Z offset of the probe when homed with it (M851).
+//!
+//! G28 homes the axes given (X, Y, Z), or all of them if none. The axes are homed in their order, and Z
+//! is raised before homing the other ones, so the tool does not hit the bed.
+use crate::math;
+use crate::math::Real;
+use crate::tgeo::CoordSel;
+
+/// What a homing entry of the motion queue does
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HomingRequest {
+    /// Homes the given axes, or all of them if none (G28)
+    Home(CoordSel),
+    /// Homes the machine and aligns the Z motors, then homes Z again (G34)
+    #[cfg(feature = "with-z2-axis")]
+    AlignZ,
+}
+
+/// The end of the axis the endstop is at
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HomingDirection {
+    Min,
+    Max,
+}
+
+/// How an axis is homed
+#[derive(Clone, Copy)]
+pub struct AxisHoming {
+    /// The position in the sequence: The lower ones are homed first (X, Y, Z on a tie)
+    pub order: u8,
+    /// The end of the axis the endstop is at
+    pub direction: HomingDirection,
+    /// The speed towards the endstop, in mm/s
+    pub fast_speed: Real,
+    /// The speed of the second approach to the endstop, in mm/s
+    pub slow_speed: Real,
+    /// The distance backed off from the endstop before the second approach, in mm
+    pub bump: Real,
+    /// The home offset (M206): Added to the position of the axis once homed, in mm
+    pub offset: Real,
+    /// Whether the probe is the endstop (Z only). It is deployed while homing
+    pub use_probe: bool,
+    /// Whether the stall of the motor (StallGuard) is the endstop, for machines without switches.
+    /// The axis is homed in a single approach at the fast speed, as StallGuard is not reliable when slow
+    pub sensorless: bool,
+}
+
+impl AxisHoming {
+    /// Homed at the min. end, in the given `order`. The speeds and the bump are to be set
+    pub const fn new(order: u8) -> Self {
+        Self {
+            order,
+            direction: HomingDirection::Min,
+            fast_speed: Real::zero(),
+            slow_speed: Real::zero(),
+            bump: Real::zero(),
+            offset: Real::zero(),
+            use_probe: false,
+            sensorless: false,
+        }
+    }
+
+    /// The direction (in tool space) towards the endstop, as a factor
+    pub fn sign(&self) -> Real {
+        match self.direction {
+            HomingDirection::Min => -math::ONE,
+            HomingDirection::Max => math::ONE,
+        }
+    }
+}
+
+/// The homing sequence
+#[derive(Clone, Copy)]
+pub struct HomingConfig {
+    /// How the X, Y and Z axes are homed
+    pub axes: [AxisHoming; 3],
+    /// The height Z is raised before homing the other axes, in mm
+    pub z_raise: Real,
+}
+
+impl HomingConfig {
+    /// X, Y and Z in this order, at their min. end
+    pub const fn new() -> Self {
+        Self {
+            axes: [AxisHoming::new(0), AxisHoming::new(1), AxisHoming::new(2)],
+            z_raise: Real::zero(),
+        }
+    }
+
+    /// The axes (0 for X to 2 for Z) to home when `requested` (all of them if none), in the order they are homed
+    pub fn sequence(&self, requested: CoordSel) -> [Option<usize>; 3] {
+        let requested = match requested.intersects(CoordSel::X | CoordSel::Y | CoordSel::Z) {
+            true => requested,
+            false => CoordSel::X | CoordSel::Y | CoordSel::Z,
+        };
+        let mut sequence = [None; 3];
+        let mut len = 0;
+        for (axis, coord) in [CoordSel::X, CoordSel::Y, CoordSel::Z].iter().enumerate() {
+            if !requested.contains(*coord) {
+                continue;
+            }
+            // Insertion after the ones of the same or lower order
+            let mut at = len;
+            while at > 0 && sequence[at - 1].is_some_and(|prev: usize| self.axes[prev].order > self.axes[axis].order) {
+                sequence[at] = sequence[at - 1];
+                at -= 1;
+            }
+            sequence[at] = Some(axis);
+            len += 1;
+        }
+        sequence
+    }
+
+    /// The position of the `axis` once homed, given its `machine_bound` and the Z offset of the probe
+    pub fn home_position(&self, axis: usize, machine_bound: Real, probe_z_offset: Real) -> Real {
+        let homing = &self.axes[axis];
+        let end = match homing.direction {
+            HomingDirection::Min => math::ZERO,
+            HomingDirection::Max => machine_bound,
+        };
+        match homing.use_probe && axis == 2 {
+            true => end + homing.offset - probe_z_offset,
+            false => end + homing.offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn homing_sequence() {
+        let mut homing = HomingConfig::new();
+        assert_eq!(homing.sequence(CoordSel::empty()), [Some(0), Some(1), Some(2)]);
+        assert_eq!(homing.sequence(CoordSel::Y | CoordSel::E), [Some(1), None, None]);
+
+        // Z first, then X and Y on a tie
+        homing.axes[2].order = 0;
+        homing.axes[0].order = 1;
+        homing.axes[1].order = 1;
+        assert_eq!(homing.sequence(CoordSel::XYZE), [Some(2), Some(0), Some(1)]);
+        assert_eq!(homing.sequence(CoordSel::X | CoordSel::Z), [Some(2), Some(0), None]);
+
+        let bound = Real::from_f32(200.0);
+        let probe_z_offset = Real::from_f32(-1.5);
+        assert_eq!(homing.home_position(0, bound, probe_z_offset), math::ZERO);
+        homing.axes[0].direction = HomingDirection::Max;
+        homing.axes[0].offset = Real::from_f32(-2.0);
+        assert_eq!(homing.home_position(0, bound, probe_z_offset), Real::from_f32(198.0));
+        // The probe triggers with the tool over the bed
+        homing.axes[2].use_probe = true;
+        assert_eq!(homing.home_position(2, bound, probe_z_offset), Real::from_f32(1.5));
+    }
+}
@@ -1,5 +1,4 @@
 use crate::hwa;
-use hwa::StepperChannel;
 use hwa::controllers::MultiTimer;
 use crate::math;
 use math::Real;
@@ -89,22 +88,11 @@ impl LinearMicrosegmentStepInterpolator {
         if numerator == 0 {
             panic!("bad advance");
         }
-        let tick_period_by_axis = steps_to_advance_precise.map_all(|c| {
-            c.unwrap_or(math::ZERO).to_i32().and_then(|i| {
-                if i > 0 {
-                    Some(numerator / (i as u32))
-                } else {
-                    None
-                }
-            })
-        });
-
         let step_increment =
             steps_to_advance_precise.map_coords(|cv| cv.to_i32().and_then(|c| Some(c as u32)));
 
         self.usteps_advanced += step_increment;
-        self.multi_timer.set_width(numerator);
-        self.multi_timer.set_max_count(&step_increment);
+        self.multi_timer.set_uniform_steps(&step_increment, numerator);
 
         #[cfg(test)]
         {
@@ -121,24 +109,12 @@ impl LinearMicrosegmentStepInterpolator {
                 steps_to_advance_precise
             );
             hwa::trace!(
-                "width: {} tick period by axis: {}",
+                "width: {} step increment: {}",
                 numerator,
-                tick_period_by_axis
+                step_increment
             );
         }
 
-        #[cfg(feature = "with-x-axis")]
-        self.multi_timer
-            .set_channel_ticks(StepperChannel::X, tick_period_by_axis.x);
-        #[cfg(feature = "with-y-axis")]
-        self.multi_timer
-            .set_channel_ticks(StepperChannel::Y, tick_period_by_axis.y);
-        #[cfg(feature = "with-z-axis")]
-        self.multi_timer
-            .set_channel_ticks(StepperChannel::Z, tick_period_by_axis.z);
-        #[cfg(feature = "with-e-axis")]
-        self.multi_timer
-            .set_channel_ticks(StepperChannel::E, tick_period_by_axis.e);
         //can_advance_more
         true
     }
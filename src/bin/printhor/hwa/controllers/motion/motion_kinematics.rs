@@ -0,0 +1,972 @@
+//! Machine kinematics: the conversion between the tool space (the X, Y, Z, E coordinates of the
+//! gcodes) and the motor space (the positions of the steppers, one per [crate::hwa::StepperChannel]).
+//!
+//! The kinematics in use is selected at build time with the `kinematics-*` features, defaulting to
+//! Cartesian (one motor per axis). Its default setup is [MACHINE_KINEMATICS], kept (and tuned, if it
+//! has parameters) in [crate::hwa::controllers::MotionConfig].
+//!
+//! The Cartesian, CoreXY and CoreXZ kinematics are linear, so they also convert displacements and
+//! directions, not only positions. The non-linear ones (linear delta, SCARA, polar) get the moves
+//! split into short lines, each one driven linearly in motor space. The motion profile is always
+//! planned in tool space.
+//!
+//! A coordinate not given (None) is taken as zero, and a coordinate of the result is only None
+//! when every coordinate it depends on is None.
+use crate::math;
+use crate::math::Real;
+use crate::tgeo::{CoordSel, TVector};
+
+/// Conversion between tool-space and motor-space positions
+pub trait Kinematics {
+    /// The motor-space position of a tool-space position
+    fn tool_to_motor(&self, pos: &TVector<Real>) -> TVector<Real>;
+
+    /// The tool-space position of a motor-space position
+    fn motor_to_tool(&self, pos: &TVector<Real>) -> TVector<Real>;
+
+    /// The motor-space displacement per mm of tool-space displacement, for a line of `distance` mm
+    /// along the (unitary) `tool_dir`, ending at `tool_pos`.
+    ///
+    /// Linear kinematics only need the direction.
+    fn motor_direction(&self, _tool_pos: &TVector<Real>, tool_dir: &TVector<Real>, _distance: Real) -> TVector<Real> {
+        self.tool_to_motor(tool_dir)
+    }
+
+    /// The maximum length of the lines the moves are split into at the given speed, so the
+    /// conversion stays accurate along them, or None if the moves are not split (linear kinematics)
+    fn max_segment_length(&self, _speed: Real) -> Option<Real> {
+        None
+    }
+
+    /// The closest position to `pos` inside the workspace of the machine
+    fn clamp_to_workspace(&self, pos: &TVector<Real>) -> TVector<Real> {
+        *pos
+    }
+
+    /// Whether the whole line from `from` to `to` is inside the workspace, given both ends are.
+    ///
+    /// Always, unless the workspace has holes: The ones of the other kinematics are convex
+    fn line_in_workspace(&self, _from: &TVector<Real>, _to: &TVector<Real>) -> bool {
+        true
+    }
+}
+
+/// The maximum rate (speed, acceleration or jerk) along a tool-space direction, given its
+/// [Kinematics::motor_direction], such that no motor exceeds its own limit.
+/// None if the direction drives no limited motor
+pub fn max_tool_rate(motor_dir: &TVector<Real>, motor_limits: &TVector<Real>) -> Option<Real> {
+    (*motor_limits / motor_dir.abs()).vmin()
+}
+
+/// Sum and difference of two coordinates: (a + b, a - b)
+fn sum_diff(a: Option<Real>, b: Option<Real>) -> (Option<Real>, Option<Real>) {
+    if a.is_none() && b.is_none() {
+        return (None, None);
+    }
+    let a = a.unwrap_or(math::ZERO);
+    let b = b.unwrap_or(math::ZERO);
+    (Some(a + b), Some(a - b))
+}
+
+/// Inverse of [sum_diff]: ((s + d) / 2, (s - d) / 2)
+fn half_sum_diff(s: Option<Real>, d: Option<Real>) -> (Option<Real>, Option<Real>) {
+    let (a, b) = sum_diff(s, d);
+    (a.map(|v| v * math::HALF), b.map(|v| v * math::HALF))
+}
+
+/// One motor per axis
+#[derive(Clone, Copy)]
+pub struct CartesianKinematics;
+
+impl Kinematics for CartesianKinematics {
+    fn tool_to_motor(&self, pos: &TVector<Real>) -> TVector<Real> {
+        *pos
+    }
+
+    fn motor_to_tool(&self, pos: &TVector<Real>) -> TVector<Real> {
+        *pos
+    }
+}
+
+/// CoreXY: The X and Y axes are driven together by the A (X channel) and B (Y channel) motors,
+/// with A = X + Y and B = X - Y. Z and E map 1:1
+#[derive(Clone, Copy)]
+pub struct CoreXYKinematics;
+
+impl Kinematics for CoreXYKinematics {
+    fn tool_to_motor(&self, pos: &TVector<Real>) -> TVector<Real> {
+        let (a, b) = sum_diff(pos.x, pos.y);
+        TVector::from_coords(a, b, pos.z, pos.e)
+    }
+
+    fn motor_to_tool(&self, pos: &TVector<Real>) -> TVector<Real> {
+        let (x, y) = half_sum_diff(pos.x, pos.y);
+        TVector::from_coords(x, y, pos.z, pos.e)
+    }
+}
+
+/// CoreXZ: The X and Z axes are driven together by the A (X channel) and C (Z channel) motors,
+/// with A = X + Z and C = X - Z. Y and E map 1:1
+#[derive(Clone, Copy)]
+pub struct CoreXZKinematics;
+
+impl Kinematics for CoreXZKinematics {
+    fn tool_to_motor(&self, pos: &TVector<Real>) -> TVector<Real> {
+        let (a, c) = sum_diff(pos.x, pos.z);
+        TVector::from_coords(a, pos.y, c, pos.e)
+    }
+
+    fn motor_to_tool(&self, pos: &TVector<Real>) -> TVector<Real> {
+        let (x, z) = half_sum_diff(pos.x, pos.z);
+        TVector::from_coords(x, pos.y, z, pos.e)
+    }
+}
+
+/// The nominal angles (in degrees) of the X, Y and Z towers of a linear delta
+const DELTA_TOWER_ANGLES: [i64; 3] = [210, 330, 90];
+
+/// Linear delta: Three vertical towers (A, B and C, in the X, Y and Z channels), each one with a
+/// carriage linked to the effector by two parallel diagonal rods. The motor position is the height
+/// of the carriage over Z = 0.
+///
+/// The geometry is set with [DeltaKinematics::set_geometry] (M665) and
+/// [DeltaKinematics::set_endstop_offsets] (M666).
+#[derive(Clone, Copy)]
+pub struct DeltaKinematics {
+    /// Length of the diagonal rods (M665 L)
+    diagonal_rod: Real,
+    /// Horizontal distance from the centre to each carriage joint, net of the effector offset (M665 R)
+    radius: Real,
+    /// Height of the effector when homed (M665 H)
+    height: Real,
+    /// Radius of the printable cylinder (M665 B). Zero for no limit
+    print_radius: Real,
+    /// Lines per second of motion the moves are split into (M665 S)
+    segments_per_second: Real,
+    /// Correction of the angle of each tower, in degrees (M665 X Y Z)
+    tower_angle_trim: [Real; 3],
+    /// Correction of the height of each endstop, in mm (M666 X Y Z). Negative when it triggers lower
+    endstop_offset: [Real; 3],
+    /// The (x, y) position of each tower, derived from the radius and the angles
+    towers: [(Real, Real); 3],
+}
+
+impl DeltaKinematics {
+    /// A delta with no geometry. It must be set before moving
+    pub const fn new() -> Self {
+        Self {
+            diagonal_rod: Real::zero(),
+            radius: Real::zero(),
+            height: Real::zero(),
+            print_radius: Real::zero(),
+            segments_per_second: Real::zero(),
+            tower_angle_trim: [Real::zero(); 3],
+            endstop_offset: [Real::zero(); 3],
+            towers: [(Real::zero(), Real::zero()); 3],
+        }
+    }
+
+    /// Sets the geometry of the towers. The values not given are kept
+    pub fn set_geometry(
+        &mut self,
+        diagonal_rod: Option<Real>,
+        radius: Option<Real>,
+        height: Option<Real>,
+        print_radius: Option<Real>,
+        segments_per_second: Option<Real>,
+        tower_angle_trim: [Option<Real>; 3],
+    ) {
+        self.diagonal_rod = diagonal_rod.unwrap_or(self.diagonal_rod);
+        self.radius = radius.unwrap_or(self.radius);
+        self.height = height.unwrap_or(self.height);
+        self.print_radius = print_radius.unwrap_or(self.print_radius);
+        self.segments_per_second = segments_per_second.unwrap_or(self.segments_per_second);
+        for (trim, value) in self.tower_angle_trim.iter_mut().zip(tower_angle_trim) {
+            *trim = value.unwrap_or(*trim);
+        }
+        let deg_to_rad = math::ONE / degrees_per_radian();
+        for (i, tower) in self.towers.iter_mut().enumerate() {
+            let angle = (Real::from_lit(DELTA_TOWER_ANGLES[i], 0) + self.tower_angle_trim[i]) * deg_to_rad;
+            let (sin, cos) = sin_cos(angle);
+            *tower = (self.radius * cos, self.radius * sin);
+        }
+    }
+
+    /// Sets the corrections of the endstops. The values not given are kept
+    pub fn set_endstop_offsets(&mut self, endstop_offset: [Option<Real>; 3]) {
+        for (offset, value) in self.endstop_offset.iter_mut().zip(endstop_offset) {
+            *offset = value.unwrap_or(*offset);
+        }
+    }
+
+    /// The geometry as (diagonal rod, radius, height, print radius, segments per second, tower angle trims)
+    pub fn geometry(&self) -> (Real, Real, Real, Real, Real, [Real; 3]) {
+        (
+            self.diagonal_rod, self.radius, self.height,
+            self.print_radius, self.segments_per_second, self.tower_angle_trim,
+        )
+    }
+
+    /// The corrections of the endstops
+    pub fn endstop_offsets(&self) -> [Real; 3] {
+        self.endstop_offset
+    }
+
+    /// The motor position when every carriage is at its endstop
+    pub fn homed_motor_pos(&self) -> TVector<Real> {
+        let top = self.tool_to_motor(&TVector::from_coords(
+            Some(math::ZERO), Some(math::ZERO), Some(self.height), None,
+        ));
+        top + TVector::from_coords(
+            Some(self.endstop_offset[0]),
+            Some(self.endstop_offset[1]),
+            Some(self.endstop_offset[2]),
+            None,
+        )
+    }
+
+    /// The height of a carriage over the effector at (x, y), if reachable
+    fn carriage_height(&self, tower: (Real, Real), x: Real, y: Real) -> Option<Real> {
+        let dx = x - tower.0;
+        let dy = y - tower.1;
+        sqrt_if_positive(self.diagonal_rod * self.diagonal_rod - dx * dx - dy * dy)
+    }
+}
+
+/// The square root, or None if negative
+fn sqrt_if_positive(v: Real) -> Option<Real> {
+    match v < math::ZERO {
+        true => None,
+        false => v.sqrt(),
+    }
+}
+
+/// The length of the lines a move at `speed` is split into, given the lines per second.
+/// No shorter than 0.25 mm. None if not split
+fn segment_length(speed: Real, segments_per_second: Real) -> Option<Real> {
+    match segments_per_second.is_defined_positive() {
+        true => Some((speed / segments_per_second).max(Real::from_lit(25, 2))),
+        false => None,
+    }
+}
+
+/// The position with its distance to the Z axis limited to `radius`. Zero for no limit
+fn clamp_radius(pos: &TVector<Real>, radius: Real) -> TVector<Real> {
+    let mut clamped = *pos;
+    if let (Some(x), Some(y)) = (pos.x, pos.y) {
+        let r = (x * x + y * y).sqrt().unwrap_or(math::ZERO);
+        if radius.is_defined_positive() && r > radius {
+            let k = radius / r;
+            clamped.x = Some(x * k);
+            clamped.y = Some(y * k);
+        }
+    }
+    clamped
+}
+
+/// Degrees per radian
+fn degrees_per_radian() -> Real {
+    Real::from_lit(180, 0) / math::PI
+}
+
+/// The sine and cosine of an angle in radians.
+///
+/// Some backends approximate the trigonometric functions too coarsely for the kinematics (up to
+/// tenths of mm on an arm), so they are computed here by series on the angle reduced to [-π/4, π/4]
+fn sin_cos(angle: Real) -> (Real, Real) {
+    let quarter_turn = math::PI * math::HALF;
+    let quadrant = (angle / quarter_turn).round();
+    let r = angle - quadrant * quarter_turn;
+    let r2 = r * r;
+    let mut sin = r;
+    let mut cos = math::ONE;
+    let mut term_sin = r;
+    let mut term_cos = math::ONE;
+    for n in 1..6 {
+        term_sin = -term_sin * r2 / Real::from_lit((2 * n) * (2 * n + 1), 0);
+        term_cos = -term_cos * r2 / Real::from_lit((2 * n - 1) * (2 * n), 0);
+        sin += term_sin;
+        cos += term_cos;
+    }
+    match quadrant.int().rem_euclid(4) {
+        0 => (sin, cos),
+        1 => (cos, -sin),
+        2 => (-sin, -cos),
+        _ => (-cos, sin),
+    }
+}
+
+/// The four quadrant arctangent of y / x, in radians, refined from the one of the backend
+fn atan2(y: Real, x: Real) -> Real {
+    let estimate = y.atan2(x);
+    let (sin, cos) = sin_cos(estimate);
+    // The angle left from the estimate, small enough to be its tangent
+    let across = y * cos - x * sin;
+    let along = x * cos + y * sin;
+    match along.is_defined_positive() {
+        true => estimate + across / along,
+        false => estimate,
+    }
+}
+
+/// An angular displacement (in degrees) turning the shortest way round, in [-180, 180)
+fn shortest_turn(angle: Real) -> Real {
+    let turn = Real::from_lit(360, 0);
+    angle - turn * ((angle + Real::from_lit(180, 0)) / turn).floor()
+}
+
+/// The start of a line of `distance` mm along `tool_dir` ending at `tool_pos`
+fn line_start(tool_pos: &TVector<Real>, tool_dir: &TVector<Real>, distance: Real) -> TVector<Real> {
+    *tool_pos - tool_dir.map_nan(&math::ZERO) * distance
+}
+
+/// Whether a line does not move the tool in X, Y or Z
+fn is_extruder_only(tool_dir: &TVector<Real>, distance: Real) -> bool {
+    (tool_dir.x.is_none() && tool_dir.y.is_none() && tool_dir.z.is_none()) || distance.is_zero()
+}
+
+type Point3 = [Real; 3];
+
+fn sub3(a: Point3, b: Point3) -> Point3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale3(a: Point3, k: Real) -> Point3 {
+    [a[0] * k, a[1] * k, a[2] * k]
+}
+
+fn dot3(a: Point3, b: Point3) -> Real {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross3(a: Point3, b: Point3) -> Point3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+impl Kinematics for DeltaKinematics {
+    fn tool_to_motor(&self, pos: &TVector<Real>) -> TVector<Real> {
+        if pos.x.is_none() && pos.y.is_none() && pos.z.is_none() {
+            return TVector::from_coords(None, None, None, pos.e);
+        }
+        let x = pos.x.unwrap_or(math::ZERO);
+        let y = pos.y.unwrap_or(math::ZERO);
+        let z = pos.z.unwrap_or(math::ZERO);
+        let carriage = |i: usize| self.carriage_height(self.towers[i], x, y).map(|h| z + h);
+        TVector::from_coords(carriage(0), carriage(1), carriage(2), pos.e)
+    }
+
+    /// The intersection of the three spheres of radius the diagonal rod centred at the carriage
+    /// joints, below them (trilateration)
+    fn motor_to_tool(&self, pos: &TVector<Real>) -> TVector<Real> {
+        let tool = match (pos.x, pos.y, pos.z) {
+            (Some(a), Some(b), Some(c)) => {
+                let p0 = [self.towers[0].0, self.towers[0].1, a];
+                let p1 = [self.towers[1].0, self.towers[1].1, b];
+                let p2 = [self.towers[2].0, self.towers[2].1, c];
+                let p01 = sub3(p1, p0);
+                let p02 = sub3(p2, p0);
+                let d = dot3(p01, p01).sqrt().unwrap_or(math::ZERO);
+                if d.is_zero() {
+                    None
+                } else {
+                    let ex = scale3(p01, math::ONE / d);
+                    let i = dot3(ex, p02);
+                    let ey = sub3(p02, scale3(ex, i));
+                    let j = dot3(ey, ey).sqrt().unwrap_or(math::ZERO);
+                    if j.is_zero() {
+                        None
+                    } else {
+                        let ey = scale3(ey, math::ONE / j);
+                        let ez = cross3(ex, ey);
+                        // The spheres have the same radius
+                        let u = d * math::HALF;
+                        let v = ((i * i + j * j) * math::HALF - i * u) / j;
+                        sqrt_if_positive(self.diagonal_rod * self.diagonal_rod - u * u - v * v)
+                            .map(|w| sub3(
+                                [
+                                    p0[0] + ex[0] * u + ey[0] * v,
+                                    p0[1] + ex[1] * u + ey[1] * v,
+                                    p0[2] + ex[2] * u + ey[2] * v,
+                                ],
+                                scale3(ez, w),
+                            ))
+                    }
+                }
+            }
+            _ => None,
+        };
+        match tool {
+            Some(p) => TVector::from_coords(Some(p[0]), Some(p[1]), Some(p[2]), pos.e),
+            None => TVector::from_coords(None, None, None, pos.e),
+        }
+    }
+
+    /// The mean over the line, from the carriage positions at both ends
+    fn motor_direction(&self, tool_pos: &TVector<Real>, tool_dir: &TVector<Real>, distance: Real) -> TVector<Real> {
+        if is_extruder_only(tool_dir, distance) {
+            return TVector::from_coords(None, None, None, tool_dir.e);
+        }
+        let tool_start = line_start(tool_pos, tool_dir, distance);
+        ((self.tool_to_motor(tool_pos) - self.tool_to_motor(&tool_start)) / distance)
+            .with_coord(CoordSel::E, tool_dir.e)
+    }
+
+    fn max_segment_length(&self, speed: Real) -> Option<Real> {
+        segment_length(speed, self.segments_per_second)
+    }
+
+    /// The workspace is a cylinder of the print radius, from Z = 0 up to the homed height
+    fn clamp_to_workspace(&self, pos: &TVector<Real>) -> TVector<Real> {
+        let mut clamped = clamp_radius(pos, self.print_radius);
+        if let Some(z) = pos.z {
+            if self.height.is_defined_positive() {
+                clamped.z = Some(z.max(math::ZERO).min(self.height));
+            }
+        }
+        clamped
+    }
+}
+
+/// MP-SCARA: A two-link arm in the XY plane. The X channel motor turns the proximal arm around the
+/// shoulder and the Y channel motor turns the distal arm through a belt from the base, so both motor
+/// positions are angles against the X axis, in degrees (and `units_per_mm` are units per degree).
+/// Z and E map 1:1.
+///
+/// The elbow is always kept on the same side. The tool reaches the ring around the shoulder between
+/// the difference and the sum of the arm lengths.
+///
+/// The geometry is set with [ScaraKinematics::set_geometry] (M665).
+#[derive(Clone, Copy)]
+pub struct ScaraKinematics {
+    /// Length of the arm from the shoulder to the elbow (M665 P)
+    proximal_arm: Real,
+    /// Length of the arm from the elbow to the tool (M665 D)
+    distal_arm: Real,
+    /// Position of the shoulder (M665 X Y)
+    shoulder: (Real, Real),
+    /// Angles of the proximal and distal arms at their endstops, in degrees (M665 A B)
+    home_angles: [Real; 2],
+    /// Lines per second of motion the moves are split into (M665 S)
+    segments_per_second: Real,
+}
+
+impl ScaraKinematics {
+    /// A SCARA with no geometry. It must be set before moving
+    pub const fn new() -> Self {
+        Self {
+            proximal_arm: Real::zero(),
+            distal_arm: Real::zero(),
+            shoulder: (Real::zero(), Real::zero()),
+            home_angles: [Real::zero(); 2],
+            segments_per_second: Real::zero(),
+        }
+    }
+
+    /// Sets the geometry of the arm. The values not given are kept
+    pub fn set_geometry(
+        &mut self,
+        proximal_arm: Option<Real>,
+        distal_arm: Option<Real>,
+        shoulder: [Option<Real>; 2],
+        home_angles: [Option<Real>; 2],
+        segments_per_second: Option<Real>,
+    ) {
+        self.proximal_arm = proximal_arm.unwrap_or(self.proximal_arm);
+        self.distal_arm = distal_arm.unwrap_or(self.distal_arm);
+        self.shoulder.0 = shoulder[0].unwrap_or(self.shoulder.0);
+        self.shoulder.1 = shoulder[1].unwrap_or(self.shoulder.1);
+        for (angle, value) in self.home_angles.iter_mut().zip(home_angles) {
+            *angle = value.unwrap_or(*angle);
+        }
+        self.segments_per_second = segments_per_second.unwrap_or(self.segments_per_second);
+    }
+
+    /// The geometry as (proximal arm, distal arm, shoulder, home angles, segments per second)
+    pub fn geometry(&self) -> (Real, Real, [Real; 2], [Real; 2], Real) {
+        (
+            self.proximal_arm, self.distal_arm, [self.shoulder.0, self.shoulder.1],
+            self.home_angles, self.segments_per_second,
+        )
+    }
+
+    /// The position of the X and Y channel motors at their endstops
+    pub fn homed_motor_pos(&self) -> TVector<Real> {
+        TVector::from_coords(Some(self.home_angles[0]), Some(self.home_angles[1]), None, None)
+    }
+
+    /// The longest travel of the X and Y channel motors to their endstops: a full turn
+    pub fn max_homing_travel(&self) -> TVector<Real> {
+        let turn = Real::from_lit(360, 0);
+        TVector::from_coords(Some(turn), Some(turn), None, None)
+    }
+
+    fn has_geometry(&self) -> bool {
+        self.proximal_arm.is_defined_positive() && self.distal_arm.is_defined_positive()
+    }
+}
+
+impl Kinematics for ScaraKinematics {
+    fn tool_to_motor(&self, pos: &TVector<Real>) -> TVector<Real> {
+        if (pos.x.is_none() && pos.y.is_none()) || !self.has_geometry() {
+            return TVector::from_coords(None, None, pos.z, pos.e);
+        }
+        let (l1, l2) = (self.proximal_arm, self.distal_arm);
+        let dx = pos.x.unwrap_or(math::ZERO) - self.shoulder.0;
+        let dy = pos.y.unwrap_or(math::ZERO) - self.shoulder.1;
+        let cos_elbow = (dx * dx + dy * dy - l1 * l1 - l2 * l2) / (math::TWO * l1 * l2);
+        // Out of reach, beyond the rounding of the workspace boundary
+        let angles = match cos_elbow.abs() > math::ONE + Real::from_lit(1, 4) {
+            true => None,
+            false => {
+                let cos_elbow = cos_elbow.max(-math::ONE).min(math::ONE);
+                let sin_elbow = sqrt_if_positive(math::ONE - cos_elbow * cos_elbow).unwrap_or(math::ZERO);
+                let proximal = atan2(dy, dx) - atan2(l2 * sin_elbow, l1 + l2 * cos_elbow);
+                let distal = proximal + atan2(sin_elbow, cos_elbow);
+                Some((proximal * degrees_per_radian(), distal * degrees_per_radian()))
+            }
+        };
+        TVector::from_coords(angles.map(|a| a.0), angles.map(|a| a.1), pos.z, pos.e)
+    }
+
+    fn motor_to_tool(&self, pos: &TVector<Real>) -> TVector<Real> {
+        if pos.x.is_none() && pos.y.is_none() {
+            return TVector::from_coords(None, None, pos.z, pos.e);
+        }
+        let (sin_proximal, cos_proximal) = sin_cos(pos.x.unwrap_or(math::ZERO) / degrees_per_radian());
+        let (sin_distal, cos_distal) = sin_cos(pos.y.unwrap_or(math::ZERO) / degrees_per_radian());
+        let x = self.shoulder.0 + self.proximal_arm * cos_proximal + self.distal_arm * cos_distal;
+        let y = self.shoulder.1 + self.proximal_arm * sin_proximal + self.distal_arm * sin_distal;
+        TVector::from_coords(Some(x), Some(y), pos.z, pos.e)
+    }
+
+    /// The mean over the line, from the arm angles at both ends, turning the shortest way round
+    fn motor_direction(&self, tool_pos: &TVector<Real>, tool_dir: &TVector<Real>, distance: Real) -> TVector<Real> {
+        if is_extruder_only(tool_dir, distance) {
+            return TVector::from_coords(None, None, None, tool_dir.e);
+        }
+        let tool_start = line_start(tool_pos, tool_dir, distance);
+        let mut displacement = self.tool_to_motor(tool_pos) - self.tool_to_motor(&tool_start);
+        displacement.x = displacement.x.map(shortest_turn);
+        displacement.y = displacement.y.map(shortest_turn);
+        (displacement / distance).with_coord(CoordSel::E, tool_dir.e)
+    }
+
+    fn max_segment_length(&self, speed: Real) -> Option<Real> {
+        segment_length(speed, self.segments_per_second)
+    }
+
+    /// The workspace is the ring the arm reaches around the shoulder
+    fn clamp_to_workspace(&self, pos: &TVector<Real>) -> TVector<Real> {
+        let mut clamped = *pos;
+        if let (Some(x), Some(y), true) = (pos.x, pos.y, self.has_geometry()) {
+            let (dx, dy) = (x - self.shoulder.0, y - self.shoulder.1);
+            let r = (dx * dx + dy * dy).sqrt().unwrap_or(math::ZERO);
+            let min_reach = (self.proximal_arm - self.distal_arm).abs();
+            let max_reach = self.proximal_arm + self.distal_arm;
+            let reach = r.max(min_reach).min(max_reach);
+            if reach != r {
+                let (ux, uy) = match r.is_zero() {
+                    true => (math::ONE, math::ZERO),
+                    false => (dx / r, dy / r),
+                };
+                clamped.x = Some(self.shoulder.0 + ux * reach);
+                clamped.y = Some(self.shoulder.1 + uy * reach);
+            }
+        }
+        clamped
+    }
+
+    /// The line must not cross the hole of the ring, where the arm does not reach
+    fn line_in_workspace(&self, from: &TVector<Real>, to: &TVector<Real>) -> bool {
+        if !self.has_geometry() {
+            return true;
+        }
+        let (ax, ay) = (from.x.unwrap_or(math::ZERO) - self.shoulder.0, from.y.unwrap_or(math::ZERO) - self.shoulder.1);
+        let (bx, by) = (to.x.unwrap_or(math::ZERO) - self.shoulder.0, to.y.unwrap_or(math::ZERO) - self.shoulder.1);
+        let (lx, ly) = (bx - ax, by - ay);
+        let length2 = lx * lx + ly * ly;
+        // The point of the line closest to the shoulder
+        let t = match length2.is_zero() {
+            true => math::ZERO,
+            false => (-(ax * lx + ay * ly) / length2).max(math::ZERO).min(math::ONE),
+        };
+        let (cx, cy) = (ax + lx * t, ay + ly * t);
+        let min_reach = (self.proximal_arm - self.distal_arm).abs();
+        // Within 1 um, as the ends may have been clamped to the edge
+        let min_reach = min_reach - Real::from_lit(1, 3);
+        cx * cx + cy * cy >= min_reach * min_reach
+    }
+}
+
+/// Polar: A rotary bed turned by the X channel motor, under a radial arm driven by the Y channel
+/// motor. The motor positions are the angle of the bed, in degrees (and its `units_per_mm` are
+/// units per degree), and the distance from the tool to the centre of the bed, in mm.
+/// Z and E map 1:1.
+///
+/// The angle is undefined at the centre, so the bed keeps still on the lines starting or ending
+/// there. A line through the centre turns the bed half a turn, and the speed of the bed motor limits
+/// the speed of the tool near the centre.
+///
+/// The geometry is set with [PolarKinematics::set_geometry] (M665).
+#[derive(Clone, Copy)]
+pub struct PolarKinematics {
+    /// Radius of the bed. Zero for no limit (M665 R)
+    bed_radius: Real,
+    /// Angle of the bed at its endstop, in degrees (M665 A)
+    home_angle: Real,
+    /// Distance from the tool to the centre at the endstop of the arm (M665 B)
+    home_radius: Real,
+    /// Lines per second of motion the moves are split into (M665 S)
+    segments_per_second: Real,
+}
+
+impl PolarKinematics {
+    /// A polar machine with no geometry. It must be set before moving
+    pub const fn new() -> Self {
+        Self {
+            bed_radius: Real::zero(),
+            home_angle: Real::zero(),
+            home_radius: Real::zero(),
+            segments_per_second: Real::zero(),
+        }
+    }
+
+    /// Sets the geometry of the bed and the arm. The values not given are kept
+    pub fn set_geometry(
+        &mut self,
+        bed_radius: Option<Real>,
+        home_angle: Option<Real>,
+        home_radius: Option<Real>,
+        segments_per_second: Option<Real>,
+    ) {
+        self.bed_radius = bed_radius.unwrap_or(self.bed_radius);
+        self.home_angle = home_angle.unwrap_or(self.home_angle);
+        self.home_radius = home_radius.unwrap_or(self.home_radius);
+        self.segments_per_second = segments_per_second.unwrap_or(self.segments_per_second);
+    }
+
+    /// The geometry as (bed radius, home angle, home radius, segments per second)
+    pub fn geometry(&self) -> (Real, Real, Real, Real) {
+        (self.bed_radius, self.home_angle, self.home_radius, self.segments_per_second)
+    }
+
+    /// The position of the X and Y channel motors at their endstops
+    pub fn homed_motor_pos(&self) -> TVector<Real> {
+        TVector::from_coords(Some(self.home_angle), Some(self.home_radius), None, None)
+    }
+
+    /// The longest travel of the X and Y channel motors to their endstops: a full turn of the bed
+    /// and the radius of the bed
+    pub fn max_homing_travel(&self) -> TVector<Real> {
+        TVector::from_coords(Some(Real::from_lit(360, 0)), Some(self.bed_radius), None, None)
+    }
+}
+
+impl Kinematics for PolarKinematics {
+    fn tool_to_motor(&self, pos: &TVector<Real>) -> TVector<Real> {
+        if pos.x.is_none() && pos.y.is_none() {
+            return TVector::from_coords(None, None, pos.z, pos.e);
+        }
+        let x = pos.x.unwrap_or(math::ZERO);
+        let y = pos.y.unwrap_or(math::ZERO);
+        let radius = (x * x + y * y).sqrt().unwrap_or(math::ZERO);
+        let angle = match radius.is_zero() {
+            true => math::ZERO,
+            false => atan2(y, x) * degrees_per_radian(),
+        };
+        TVector::from_coords(Some(angle), Some(radius), pos.z, pos.e)
+    }
+
+    fn motor_to_tool(&self, pos: &TVector<Real>) -> TVector<Real> {
+        if pos.x.is_none() && pos.y.is_none() {
+            return TVector::from_coords(None, None, pos.z, pos.e);
+        }
+        let (sin, cos) = sin_cos(pos.x.unwrap_or(math::ZERO) / degrees_per_radian());
+        let radius = pos.y.unwrap_or(math::ZERO);
+        TVector::from_coords(Some(radius * cos), Some(radius * sin), pos.z, pos.e)
+    }
+
+    /// The mean over the line, from the motor positions at both ends, turning the bed the
+    /// shortest way round
+    fn motor_direction(&self, tool_pos: &TVector<Real>, tool_dir: &TVector<Real>, distance: Real) -> TVector<Real> {
+        if is_extruder_only(tool_dir, distance) {
+            return TVector::from_coords(None, None, None, tool_dir.e);
+        }
+        let mut from = self.tool_to_motor(&line_start(tool_pos, tool_dir, distance));
+        let mut to = self.tool_to_motor(tool_pos);
+        // Within 0.01 mm of the centre, the angle is the one of the other end
+        let centre = Real::from_lit(1, 2);
+        if from.y.is_some_and(|r| r < centre) {
+            from.x = to.x;
+        } else if to.y.is_some_and(|r| r < centre) {
+            to.x = from.x;
+        }
+        let mut displacement = to - from;
+        displacement.x = displacement.x.map(shortest_turn);
+        (displacement / distance).with_coord(CoordSel::E, tool_dir.e)
+    }
+
+    fn max_segment_length(&self, speed: Real) -> Option<Real> {
+        segment_length(speed, self.segments_per_second)
+    }
+
+    /// The workspace is the bed
+    fn clamp_to_workspace(&self, pos: &TVector<Real>) -> TVector<Real> {
+        clamp_radius(pos, self.bed_radius)
+    }
+}
+
+#[cfg(any(
+    all(feature = "kinematics-corexy", any(feature = "kinematics-corexz", feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar")),
+    all(feature = "kinematics-corexz", any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar")),
+    all(feature = "kinematics-delta", any(feature = "kinematics-scara", feature = "kinematics-polar")),
+    all(feature = "kinematics-scara", feature = "kinematics-polar"),
+))]
+compile_error!("Only one kinematics-* feature can be enabled");
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "kinematics-corexy")] {
+        /// The kinematics of the machine
+        pub type MachineKinematics = CoreXYKinematics;
+        pub const MACHINE_KINEMATICS: MachineKinematics = CoreXYKinematics;
+    }
+    else if #[cfg(feature = "kinematics-corexz")] {
+        /// The kinematics of the machine
+        pub type MachineKinematics = CoreXZKinematics;
+        pub const MACHINE_KINEMATICS: MachineKinematics = CoreXZKinematics;
+    }
+    else if #[cfg(feature = "kinematics-delta")] {
+        /// The kinematics of the machine
+        pub type MachineKinematics = DeltaKinematics;
+        pub const MACHINE_KINEMATICS: MachineKinematics = DeltaKinematics::new();
+    }
+    else if #[cfg(feature = "kinematics-scara")] {
+        /// The kinematics of the machine
+        pub type MachineKinematics = ScaraKinematics;
+        pub const MACHINE_KINEMATICS: MachineKinematics = ScaraKinematics::new();
+    }
+    else if #[cfg(feature = "kinematics-polar")] {
+        /// The kinematics of the machine
+        pub type MachineKinematics = PolarKinematics;
+        pub const MACHINE_KINEMATICS: MachineKinematics = PolarKinematics::new();
+    }
+    else {
+        /// The kinematics of the machine
+        pub type MachineKinematics = CartesianKinematics;
+        pub const MACHINE_KINEMATICS: MachineKinematics = CartesianKinematics;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(x: f32, y: f32, z: f32) -> TVector<Real> {
+        TVector::from_coords(
+            Some(Real::from_f32(x)),
+            Some(Real::from_f32(y)),
+            Some(Real::from_f32(z)),
+            None,
+        )
+    }
+
+    fn assert_close(a: TVector<Real>, b: TVector<Real>) {
+        let d = (a - b).abs();
+        assert!(d.vmax().unwrap() < Real::from_f32(0.0001), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn corexy_round_trip() {
+        let k = CoreXYKinematics;
+        let p = pos(10.0, -4.0, 3.0);
+        let m = k.tool_to_motor(&p);
+        assert_close(m, pos(6.0, 14.0, 3.0));
+        assert_close(k.motor_to_tool(&m), p);
+
+        // A move in X alone drives both motors in the same direction
+        let x_only = TVector::from_coords(Some(math::ONE), None, None, None);
+        let m = k.tool_to_motor(&x_only);
+        assert_eq!(m.x, Some(math::ONE));
+        assert_eq!(m.y, Some(math::ONE));
+        assert_eq!(m.z, None);
+        // A move in Z alone does not involve A and B
+        let z_only = TVector::from_coords(None, None, Some(math::ONE), None);
+        let m = k.tool_to_motor(&z_only);
+        assert_eq!(m.x, None);
+        assert_eq!(m.y, None);
+    }
+
+    #[test]
+    fn corexz_round_trip() {
+        let k = CoreXZKinematics;
+        let p = pos(10.0, -4.0, 3.0);
+        let m = k.tool_to_motor(&p);
+        assert_close(m, pos(13.0, -4.0, 7.0));
+        assert_close(k.motor_to_tool(&m), p);
+    }
+
+    #[test]
+    fn motor_limited_rates() {
+        let limits = pos(100.0, 100.0, 10.0);
+        let x_only = TVector::from_coords(Some(math::ONE), None, None, None);
+        let rate = |k: &dyn Kinematics, dir: &TVector<Real>| {
+            max_tool_rate(&k.motor_direction(&TVector::zero(), dir, math::ONE), &limits)
+        };
+        assert_eq!(rate(&CartesianKinematics, &x_only), Some(Real::from_f32(100.0)));
+        assert_eq!(rate(&CoreXYKinematics, &x_only), Some(Real::from_f32(100.0)));
+        // At 45 degrees only one of the CoreXY motors runs, at sqrt(2) the tool speed
+        let diagonal = pos(1.0, 1.0, 0.0).unit();
+        let rate = rate(&CoreXYKinematics, &diagonal).unwrap();
+        assert!((rate - Real::from_f32(70.7107)).abs() < Real::from_f32(0.001));
+    }
+
+    fn delta() -> DeltaKinematics {
+        let mut k = DeltaKinematics::new();
+        k.set_geometry(
+            Some(Real::from_f32(250.0)), Some(Real::from_f32(125.0)), Some(Real::from_f32(300.0)),
+            Some(Real::from_f32(100.0)), Some(Real::from_f32(100.0)),
+            [Some(Real::from_f32(0.5)), None, Some(Real::from_f32(-0.3))],
+        );
+        k
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let k = delta();
+        // At the centre, every carriage is at the same height
+        let m = k.tool_to_motor(&pos(0.0, 0.0, 10.0));
+        let h = 10.0 + (250.0f32 * 250.0 - 125.0 * 125.0).sqrt();
+        assert_close(m, pos(h, h, h));
+        for p in [pos(0.0, 0.0, 10.0), pos(50.0, -30.0, 0.0), pos(-70.0, 40.0, 120.0)] {
+            let m = k.tool_to_motor(&p);
+            assert_close(k.motor_to_tool(&m), p);
+        }
+        // Out of reach of the rods
+        let m = k.tool_to_motor(&pos(400.0, 0.0, 0.0));
+        assert!(m.x.is_none() || m.y.is_none() || m.z.is_none());
+
+        // When homed, the endstop offsets lower the carriages
+        let mut k = delta();
+        assert_close(k.motor_to_tool(&k.homed_motor_pos()), pos(0.0, 0.0, 300.0));
+        k.set_endstop_offsets([Some(Real::from_f32(-0.2)), None, None]);
+        let homed = k.motor_to_tool(&k.homed_motor_pos());
+        assert!(homed.z.unwrap() < Real::from_f32(300.0));
+    }
+
+    #[test]
+    fn delta_lines() {
+        let k = delta();
+        let p1 = pos(20.0, 10.0, 5.0);
+        let dir = pos(1.0, 0.0, 0.0);
+        let d = Real::from_f32(0.5);
+        let motor_dir = k.motor_direction(&p1, &dir, d);
+        let p0 = pos(19.5, 10.0, 5.0);
+        assert_close(motor_dir * d, k.tool_to_motor(&p1) - k.tool_to_motor(&p0));
+        // The extruder is not involved
+        assert_eq!(motor_dir.e, None);
+
+        assert_eq!(k.max_segment_length(Real::from_f32(50.0)), Some(Real::from_f32(0.5)));
+        assert_eq!(k.max_segment_length(Real::from_f32(1.0)), Some(Real::from_f32(0.25)));
+        assert_eq!(CartesianKinematics.max_segment_length(Real::from_f32(50.0)), None);
+
+        // The soft limits are a cylinder
+        assert_close(k.clamp_to_workspace(&pos(120.0, 160.0, 400.0)), pos(60.0, 80.0, 300.0));
+        assert_close(k.clamp_to_workspace(&pos(30.0, 40.0, -1.0)), pos(30.0, 40.0, 0.0));
+    }
+
+    fn scara() -> ScaraKinematics {
+        let mut k = ScaraKinematics::new();
+        k.set_geometry(
+            Some(Real::from_f32(150.0)), Some(Real::from_f32(100.0)),
+            [Some(Real::from_f32(-20.0)), Some(Real::from_f32(-80.0))],
+            [Some(Real::from_f32(-30.0)), Some(Real::from_f32(120.0))],
+            Some(Real::from_f32(100.0)),
+        );
+        k
+    }
+
+    #[test]
+    fn scara_round_trip() {
+        let k = scara();
+        // Arms at 0 and 90 degrees
+        let m = k.tool_to_motor(&pos(130.0, 20.0, 5.0));
+        assert_close(m, pos(0.0, 90.0, 5.0));
+        for p in [pos(130.0, 20.0, 5.0), pos(50.0, 60.0, 0.0), pos(-120.0, -20.0, 3.0)] {
+            assert_close(k.motor_to_tool(&k.tool_to_motor(&p)), p);
+        }
+        // Out of reach of the arm
+        let m = k.tool_to_motor(&pos(300.0, 0.0, 0.0));
+        assert!(m.x.is_none() && m.y.is_none());
+        let m = k.tool_to_motor(&pos(-20.0, -70.0, 0.0));
+        assert!(m.x.is_none() && m.y.is_none());
+
+        // The workspace is the ring around the shoulder
+        assert_close(k.clamp_to_workspace(&pos(280.0, -80.0, 1.0)), pos(230.0, -80.0, 1.0));
+        assert_close(k.clamp_to_workspace(&pos(-20.0, -60.0, 1.0)), pos(-20.0, -30.0, 1.0));
+        let edge = k.clamp_to_workspace(&pos(-20.0, 300.0, 0.0));
+        assert!(k.tool_to_motor(&edge).x.is_some());
+
+        // The lines across the hole of the ring are out of the workspace, even with their ends in it
+        assert!(!k.line_in_workspace(&pos(-20.0, -20.0, 0.0), &pos(-20.0, -140.0, 0.0)));
+        assert!(!k.line_in_workspace(&pos(-20.0, -30.0, 0.0), &pos(30.0, -80.0, 0.0)));
+        assert!(k.line_in_workspace(&pos(50.0, 60.0, 0.0), &pos(130.0, 20.0, 0.0)));
+        assert!(k.line_in_workspace(&pos(-20.0, -30.0, 0.0), &pos(-20.0, 60.0, 0.0)));
+    }
+
+    #[test]
+    fn scara_lines() {
+        let k = scara();
+        let p1 = pos(50.0, 60.0, 0.0);
+        let dir = pos(0.0, 1.0, 0.0);
+        let d = Real::from_f32(0.5);
+        let motor_dir = k.motor_direction(&p1, &dir, d);
+        let p0 = pos(50.0, 59.5, 0.0);
+        assert_close(motor_dir * d, k.tool_to_motor(&p1) - k.tool_to_motor(&p0));
+
+        // Across the back of the shoulder, the arms turn a little, not almost a full turn
+        let p1 = pos(-170.0, -79.0, 0.0);
+        let motor_dir = k.motor_direction(&p1, &dir, Real::from_f32(2.0));
+        assert!(motor_dir.x.unwrap().abs() < Real::from_f32(5.0), "{}", motor_dir);
+        assert!(motor_dir.y.unwrap().abs() < Real::from_f32(5.0), "{}", motor_dir);
+    }
+
+    fn polar() -> PolarKinematics {
+        let mut k = PolarKinematics::new();
+        k.set_geometry(Some(Real::from_f32(150.0)), None, None, Some(Real::from_f32(100.0)));
+        k
+    }
+
+    #[test]
+    fn polar_round_trip() {
+        let k = polar();
+        assert_close(k.tool_to_motor(&pos(0.0, 10.0, 1.0)), pos(90.0, 10.0, 1.0));
+        assert_close(k.tool_to_motor(&pos(-10.0, 0.0, 1.0)), pos(180.0, 10.0, 1.0));
+        for p in [pos(30.0, 40.0, 0.0), pos(-100.0, 20.0, 2.0), pos(0.0, 0.0, 0.0)] {
+            assert_close(k.motor_to_tool(&k.tool_to_motor(&p)), p);
+        }
+        // The workspace is the bed
+        assert_close(k.clamp_to_workspace(&pos(300.0, -400.0, 7.0)), pos(90.0, -120.0, 7.0));
+    }
+
+    #[test]
+    fn polar_centre() {
+        let k = polar();
+        let d = Real::from_f32(0.5);
+        // From the centre outwards, the bed keeps still
+        let motor_dir = k.motor_direction(&pos(0.0, 0.5, 0.0), &pos(0.0, 1.0, 0.0), d);
+        assert_close(motor_dir, pos(0.0, 1.0, 0.0));
+        // And towards the centre
+        let motor_dir = k.motor_direction(&pos(0.0, 0.0, 0.0), &pos(0.0, -1.0, 0.0), d);
+        assert_close(motor_dir, pos(0.0, -1.0, 0.0));
+        // Through the centre, the bed turns half a turn
+        let motor_dir = k.motor_direction(&pos(0.0, 0.25, 0.0), &pos(0.0, 1.0, 0.0), d);
+        assert!((motor_dir.x.unwrap().abs() - Real::from_f32(360.0)).abs() < Real::from_f32(1.0), "{}", motor_dir);
+        // Across the -X axis, the bed turns a little, not almost a full turn
+        let motor_dir = k.motor_direction(&pos(-50.0, 0.25, 0.0), &pos(0.0, 1.0, 0.0), d);
+        assert!(motor_dir.x.unwrap().abs() < Real::from_f32(2.0), "{}", motor_dir);
+    }
+}
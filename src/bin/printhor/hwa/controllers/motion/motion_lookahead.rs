@@ -0,0 +1,419 @@
+//! Look-ahead planning of the entry and exit speeds of the queued moves.
+//!
+//! Every time a move is queued, two passes are performed over the [PlannedMove](PlanEntry::PlannedMove)
+//! entries of the [RingBuffer]:
+//!
+//! 1. Backward pass: From the last move to the first one, the highest entry speed from which each move
+//!    (and all the following ones) can still stop at the end of the queue.
+//! 2. Forward pass: From the first move to the last one, the speeds actually reachable when
+//!    accelerating from the entry speed of each move.
+//!
+//! The speed changes are bounded by the S-curve constraints of each move ([[1]] (3.18)), and the
+//! junction speed between two moves by one of these cornering models:
+//!
+//! - Projection: The speed is projected onto the direction of the next move, so it is zero at right
+//!   (or sharper) angles.
+//! - Junction deviation: The speed of a circular arc tangent to both moves, with its closest point
+//!   to the corner at the junction deviation distance, under the acceleration limit (As in GRBL and Marlin).
+//!
+//! The moves already handed to `task_stepper` are locked: They are [Executing](PlanEntry::Executing),
+//! so they are skipped, and the entry speed of the first planned move is kept as the stepper expects it.
+//! When the queued moves are slowed down (M220), that entry speed may be higher than the one they can be
+//! entered at, so they decelerate as much as they can until the planned speeds are caught up.
+//!
+//! [1] Biagiotti, L., Melchiorri, C.: Trajectory Planning for Automatic Machines and Robots
+use crate::control::motion::Constraints;
+use crate::hwa::controllers::motion::motion_ring_buffer::RingBuffer;
+use crate::hwa::controllers::PlanEntry;
+use crate::math;
+use crate::math::Real;
+use crate::tgeo::{CoordSel, TVector};
+
+/// Bisection steps to find the highest reachable speed
+const REACHABLE_SPEED_ITERATIONS: u8 = 12;
+
+/// The minimum displacement needed to change the speed from `v_0` to `v_1` within the
+/// acceleration and jerk limits [[1]] (3.18)
+pub fn displacement_to_change_speed(v_0: Real, v_1: Real, constraints: &Constraints) -> Real {
+    let t_jmax = constraints.a_max / constraints.j_max;
+    let t_jstar = Real::vmin(
+        ((v_1 - v_0).abs() / constraints.j_max).sqrt(),
+        Some(t_jmax),
+    )
+    .unwrap_or(math::ZERO);
+    if t_jstar < t_jmax {
+        t_jstar * (v_0 + v_1)
+    } else {
+        (math::HALF * (v_0 + v_1)) * (t_jstar + ((v_1 - v_0).abs() / constraints.a_max))
+    }
+}
+
+/// The highest speed (up to `v_lim`) reachable from `v_0` along the displacement `q_1`.
+///
+/// As (3.18) is symmetric, it is also the highest speed from which `v_0` can be reached.
+pub fn reachable_speed(v_0: Real, q_1: Real, v_lim: Real, constraints: &Constraints) -> Real {
+    if v_lim <= v_0 || displacement_to_change_speed(v_0, v_lim, constraints) < q_1 {
+        return v_lim;
+    }
+    let mut feasible = v_0;
+    let mut unfeasible = v_lim;
+    for _ in 0..REACHABLE_SPEED_ITERATIONS {
+        let v = math::HALF * (feasible + unfeasible);
+        if displacement_to_change_speed(v_0, v, constraints) < q_1 {
+            feasible = v;
+        } else {
+            unfeasible = v;
+        }
+    }
+    feasible
+}
+
+/// The lowest speed reachable from `v_0` along the displacement `q_1` when decelerating.
+pub fn lowest_reachable_speed(v_0: Real, q_1: Real, constraints: &Constraints) -> Real {
+    if displacement_to_change_speed(v_0, math::ZERO, constraints) <= q_1 {
+        return math::ZERO;
+    }
+    let mut feasible = v_0;
+    let mut unfeasible = math::ZERO;
+    for _ in 0..REACHABLE_SPEED_ITERATIONS {
+        let v = math::HALF * (feasible + unfeasible);
+        if displacement_to_change_speed(v_0, v, constraints) <= q_1 {
+            feasible = v;
+        } else {
+            unfeasible = v;
+        }
+    }
+    feasible
+}
+
+/// The cosine of the angle between the directions of two moves, in XYZ only, so the corners do not
+/// depend on the extrusion. A move without XYZ displacement (E only) is taken as a reversal, so the
+/// machine stops at the junction.
+pub fn junction_cosine(prev_dir: &TVector<Real>, next_dir: &TVector<Real>) -> Real {
+    let prev_dir = prev_dir.with_coord(CoordSel::E, None).map_nan(&math::ZERO);
+    let next_dir = next_dir.with_coord(CoordSel::E, None).map_nan(&math::ZERO);
+    if prev_dir.scalar_product(prev_dir).is_zero() || next_dir.scalar_product(next_dir).is_zero() {
+        return -math::ONE;
+    }
+    match (prev_dir.norm2(), next_dir.norm2()) {
+        (Some(prev_norm), Some(next_norm)) => {
+            (prev_dir.scalar_product(next_dir) / (prev_norm * next_norm))
+                .max(-math::ONE)
+                .min(math::ONE)
+        }
+        _ => -math::ONE,
+    }
+}
+
+/// The highest speed through the junction of two moves.
+///
+/// * `proj` - The projection of the direction of the first move onto the second one (cosine of the angle).
+/// * `v_max` - The lowest target speed of both moves.
+/// * `a_max` - The lowest acceleration limit of both moves.
+/// * `junction_deviation` - The junction deviation in mm. When zero, the projection model is used.
+pub fn junction_speed(proj: Real, v_max: Real, a_max: Real, junction_deviation: Real) -> Real {
+    if !junction_deviation.is_defined_positive() {
+        return match proj.is_defined_positive() {
+            true => proj * v_max,
+            false => math::ZERO,
+        };
+    }
+    // Square of the sine of the half of the angle between the moves (pi - turn angle)
+    let sin2_theta_d2 = math::HALF * (math::ONE + proj);
+    if !sin2_theta_d2.is_defined_positive() {
+        // Reversal. Checked before the square root, as it is approximated
+        return math::ZERO;
+    }
+    let sin_theta_d2 = sin2_theta_d2.sqrt().unwrap_or(math::ZERO);
+    if sin_theta_d2 >= math::ONE {
+        // Straight
+        v_max
+    } else {
+        ((a_max * junction_deviation * sin_theta_d2) / (math::ONE - sin_theta_d2))
+            .sqrt()
+            .unwrap_or(math::ZERO)
+            .min(v_max)
+    }
+}
+
+/// Recomputes the entry and exit speeds of every planned move in the ring buffer
+///
+/// The junction speeds are computed with the junction deviation model, unless `junction_deviation` is zero.
+pub fn plan_lookahead(rb: &mut RingBuffer, junction_deviation: Real) {
+    let len = rb.data.len();
+    let head = rb.head as usize;
+    let used = rb.used as usize;
+
+    // Backward pass. The move after the last one is a full stop.
+    // (proj_prev, speed_target_mms, a_max, max. entry speed) of the following move
+    let mut next: Option<(Real, Real, Real, Real)> = None;
+    for offset in (0..used).rev() {
+        match &mut rb.data[(head + offset) % len] {
+            PlanEntry::PlannedMove(segment, _, _, _) => {
+                let sd = &mut segment.segment_data;
+                let exit_max = match next {
+                    Some((proj, target, a_max, enter_max)) => enter_max.min(junction_speed(
+                        proj,
+                        target.min(sd.speed_target_mms),
+                        a_max.min(sd.constraints.a_max),
+                        junction_deviation,
+                    )),
+                    None => math::ZERO,
+                };
+                let enter_max = reachable_speed(
+                    exit_max,
+                    sd.displacement_mm,
+                    sd.speed_target_mms,
+                    &sd.constraints,
+                );
+                sd.speed_exit_constrained_mms = exit_max;
+                sd.speed_enter_constrained_mms = enter_max;
+                next = Some((
+                    sd.proj_prev,
+                    sd.speed_target_mms,
+                    sd.constraints.a_max,
+                    enter_max,
+                ));
+            }
+            _ => next = None,
+        }
+    }
+
+    // Forward pass. The entry speed of the first planned move is locked.
+    let mut prev_exit: Option<Real> = None;
+    let mut locked = true;
+    for offset in 0..used {
+        match &mut rb.data[(head + offset) % len] {
+            PlanEntry::PlannedMove(segment, _, _, _) => {
+                let sd = &mut segment.segment_data;
+                if !locked {
+                    sd.speed_enter_mms = prev_exit.unwrap_or(math::ZERO);
+                }
+                locked = false;
+                let exit_max = sd.speed_exit_constrained_mms;
+                sd.speed_exit_mms = match exit_max < sd.speed_enter_mms
+                    && displacement_to_change_speed(sd.speed_enter_mms, exit_max, &sd.constraints)
+                        > sd.displacement_mm
+                {
+                    // Entered faster than planned: Not enough room to slow down as planned
+                    true => lowest_reachable_speed(sd.speed_enter_mms, sd.displacement_mm, &sd.constraints),
+                    false => reachable_speed(
+                        sd.speed_enter_mms,
+                        sd.displacement_mm,
+                        exit_max,
+                        &sd.constraints,
+                    ),
+                };
+                prev_exit = Some(sd.speed_exit_mms);
+            }
+            PlanEntry::Executing(_, _) => {}
+            _ => {
+                locked = false;
+                prev_exit = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::control::motion::Constraints;
+    use crate::hwa;
+    use crate::hwa::controllers::motion::motion_ring_buffer::RingBuffer;
+    use crate::hwa::controllers::motion::{
+        displacement_to_change_speed, junction_cosine, junction_speed, lowest_reachable_speed, plan_lookahead,
+        reachable_speed, Segment, SegmentData,
+    };
+    use crate::hwa::controllers::{MovType, PlanEntry};
+    use crate::math;
+    use crate::math::Real;
+    use crate::tgeo::TVector;
+
+    fn constraints() -> Constraints {
+        Constraints {
+            v_max: Real::from_f32(100.0),
+            a_max: Real::from_f32(1000.0),
+            j_max: Real::from_f32(10000.0),
+        }
+    }
+
+    fn planned_move(displacement_mm: f32, proj_prev: f32) -> PlanEntry {
+        PlanEntry::PlannedMove(
+            Segment::new(SegmentData {
+                speed_enter_mms: math::ZERO,
+                speed_exit_mms: math::ZERO,
+                speed_target_mms: Real::from_f32(100.0),
+                displacement_mm: Real::from_f32(displacement_mm),
+                speed_enter_constrained_mms: math::ZERO,
+                speed_exit_constrained_mms: math::ZERO,
+                proj_prev: Real::from_f32(proj_prev),
+                proj_next: math::ZERO,
+                unit_vector_dir: TVector::zero(),
+                dest_pos: TVector::zero(),
+                tool_power: math::ZERO,
+                speed_rate: math::ONE,
+                constraints: constraints(),
+            }),
+            hwa::DeferAction::LinearMove,
+            hwa::CommChannel::Internal,
+            false,
+        )
+    }
+
+    fn speeds(rb: &RingBuffer, index: usize) -> (f64, f64) {
+        match &rb.data[index] {
+            PlanEntry::PlannedMove(s, _, _, _) => (
+                s.segment_data.speed_enter_mms.to_f64(),
+                s.segment_data.speed_exit_mms.to_f64(),
+            ),
+            _ => panic!("Not a planned move"),
+        }
+    }
+
+    #[test]
+    fn reachable_speeds() {
+        let c = constraints();
+        // Long enough to reach the limit
+        assert_eq!(
+            reachable_speed(math::ZERO, Real::from_f32(100.0), Real::from_f32(50.0), &c),
+            Real::from_f32(50.0)
+        );
+        // Too short: the speed reached is feasible and close to the bound
+        let q = Real::from_f32(0.5);
+        let v = reachable_speed(math::ZERO, q, Real::from_f32(100.0), &c);
+        assert!(v > math::ZERO && v < Real::from_f32(100.0));
+        assert!(displacement_to_change_speed(math::ZERO, v, &c) < q);
+        assert!(displacement_to_change_speed(math::ZERO, v + Real::from_f32(0.5), &c) >= q);
+    }
+
+    #[test]
+    fn junction_speeds() {
+        let v_max = Real::from_f32(100.0);
+        let a_max = Real::from_f32(1000.0);
+        let jd = Real::from_f32(0.05);
+        // Projection model
+        assert_eq!(junction_speed(Real::from_f32(0.5), v_max, a_max, math::ZERO), Real::from_f32(50.0));
+        assert_eq!(junction_speed(math::ZERO, v_max, a_max, math::ZERO), math::ZERO);
+        // Junction deviation model
+        assert_eq!(junction_speed(math::ONE, v_max, a_max, jd), v_max);
+        assert_eq!(junction_speed(-math::ONE, v_max, a_max, jd), math::ZERO);
+        // Right angle: sqrt(1000 * 0.05 * s / (1 - s)) with s = sin(45º)
+        let v = junction_speed(math::ZERO, v_max, a_max, jd).to_f64();
+        assert!((v - 10.986).abs() < 0.01, "{}", v);
+        // Wider tolerance, faster corners
+        assert!(junction_speed(math::ZERO, v_max, a_max, Real::from_f32(0.1)).to_f64() > v);
+    }
+
+    #[test]
+    fn junction_cosines() {
+        let dir = |x: f32, y: f32, e: f32| {
+            TVector::from_coords(Some(Real::from_f32(x)), Some(Real::from_f32(y)), Some(math::ZERO), Some(Real::from_f32(e)))
+        };
+        let close = |a: Real, b: Real| (a - b).abs() < Real::from_f32(0.001);
+        // The extrusion does not change the angle
+        assert!(close(junction_cosine(&dir(1.0, 0.0, 0.0), &dir(1.0, 0.0, 0.0)), math::ONE));
+        assert!(close(junction_cosine(&dir(0.6, 0.0, 0.8), &dir(1.0, 0.0, 0.0)), math::ONE));
+        assert!(close(junction_cosine(&dir(0.6, 0.0, 0.8), &dir(0.0, 0.6, 0.8)), math::ZERO));
+        assert!(close(junction_cosine(&dir(1.0, 0.0, 0.5), &dir(-1.0, 0.0, 0.5)), -math::ONE));
+        // E only: Stop
+        assert_eq!(junction_cosine(&dir(0.0, 0.0, 1.0), &dir(1.0, 0.0, 0.0)), -math::ONE);
+    }
+
+    #[test]
+    fn short_collinear_moves_keep_speed() {
+        let mut rb = RingBuffer::new();
+        for i in 0..6 {
+            rb.data[i] = planned_move(1.0, if i == 0 { 0.0 } else { 1.0 });
+        }
+        rb.used = 6;
+        plan_lookahead(&mut rb, math::ZERO);
+
+        assert_eq!(speeds(&rb, 0).0, 0.0);
+        assert_eq!(speeds(&rb, 5).1, 0.0);
+        for i in 1..6 {
+            // Chained at the same speed and never slowing to zero in the middle
+            assert_eq!(speeds(&rb, i - 1).1, speeds(&rb, i).0);
+            assert!(speeds(&rb, i).0 > 0.0);
+        }
+        // Accelerating until the middle, then decelerating
+        assert!(speeds(&rb, 1).0 < speeds(&rb, 2).0);
+        assert!(speeds(&rb, 4).0 < speeds(&rb, 3).0);
+    }
+
+    #[test]
+    fn corners_and_locked_moves() {
+        let mut rb = RingBuffer::new();
+        rb.head = 1;
+        rb.data[1] = PlanEntry::Executing(
+            MovType::Move(hwa::DeferAction::LinearMove, hwa::CommChannel::Internal),
+            false,
+        );
+        rb.data[2] = planned_move(50.0, 1.0);
+        if let PlanEntry::PlannedMove(s, _, _, _) = &mut rb.data[2] {
+            // Given to the executing move as its exit speed
+            s.segment_data.speed_enter_mms = Real::from_f32(20.0);
+        }
+        // Right angle
+        rb.data[3] = planned_move(50.0, 0.0);
+        // Half speed junction
+        rb.data[4] = planned_move(50.0, 0.5);
+        rb.used = 4;
+        plan_lookahead(&mut rb, math::ZERO);
+
+        assert_eq!(speeds(&rb, 2), (20.0, 0.0));
+        assert_eq!(speeds(&rb, 3).0, 0.0);
+        assert_eq!(speeds(&rb, 3).1, 50.0);
+        assert_eq!(speeds(&rb, 4), (50.0, 0.0));
+
+        // The right angle is not a full stop with junction deviation
+        plan_lookahead(&mut rb, Real::from_f32(0.05));
+        assert_eq!(speeds(&rb, 2).0, 20.0);
+        assert!(speeds(&rb, 2).1 > 10.0);
+        assert_eq!(speeds(&rb, 2).1, speeds(&rb, 3).0);
+    }
+
+    #[test]
+    fn slowed_down_moves() {
+        let c = constraints();
+        let mut rb = RingBuffer::new();
+        for i in 0..4 {
+            rb.data[i] = planned_move(5.0, 1.0);
+        }
+        rb.used = 4;
+        plan_lookahead(&mut rb, math::ZERO);
+        // The first move is taken by the stepper at full speed, then the rate is set to 50%
+        let v_0 = Real::from_f32(60.0);
+        if let PlanEntry::PlannedMove(s, _, _, _) = &mut rb.data[0] {
+            s.segment_data.speed_enter_mms = v_0;
+        }
+        rb.set_speed_rate(math::HALF);
+        plan_lookahead(&mut rb, math::ZERO);
+
+        assert_eq!(speeds(&rb, 0).0, 60.0);
+        assert_eq!(speeds(&rb, 3).1, 0.0);
+        for i in 0..4 {
+            match &rb.data[i] {
+                PlanEntry::PlannedMove(s, _, _, _) => {
+                    let sd = &s.segment_data;
+                    assert_eq!(sd.speed_target_mms, Real::from_f32(50.0));
+                    assert_eq!(sd.speed_rate, math::HALF);
+                    // Every speed change fits in its move
+                    assert!(
+                        displacement_to_change_speed(sd.speed_enter_mms, sd.speed_exit_mms, &c)
+                            <= sd.displacement_mm + Real::from_f32(0.001)
+                    );
+                }
+                _ => panic!("Not a planned move"),
+            }
+            if i > 0 {
+                assert_eq!(speeds(&rb, i - 1).1, speeds(&rb, i).0);
+            }
+        }
+        // Decelerating from the locked entry speed
+        assert!(speeds(&rb, 0).1 < 60.0);
+        assert_eq!(
+            lowest_reachable_speed(v_0, Real::from_f32(1000.0), &c),
+            math::ZERO
+        );
+    }
+}
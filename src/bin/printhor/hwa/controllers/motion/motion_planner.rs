@@ -1,11 +1,16 @@
 use crate::{control, hwa, math, tgeo};
 use hwa::{EventFlags, EventStatus, PersistentState};
 use hwa::controllers::{motion, MovType, PlanEntry, ScheduledMove};
+use hwa::controllers::motion::Kinematics;
 use hwa::controllers::motion::motion_ring_buffer::RingBuffer;
 use hwa::drivers::motion_driver::MotionDriverRef;
 use math::Real;
 use tgeo::{CoordSel, TVector};
 use embassy_sync::mutex::{Mutex, MutexGuard};
+use alloc::borrow::Cow;
+
+/// The words of a move given as lengths (or length per time), subject to the unit mode
+pub const LENGTH_WORDS: &str = "xyzefijkr";
 
 #[derive(Clone)]
 pub struct MotionPlannerRef {
@@ -39,6 +44,7 @@ impl core::ops::Deref for MotionPlannerRef {
 /// * `available` - Configuration state indicating availability for new motion plans.
 /// * `motion_config` - Reference to the configuration settings for the motion control.
 /// * `motion_st` - A `Mutex` guarding the motion status data.
+/// * `babystepping` - A `Mutex` guarding the babysteps requested (M290).
 /// * `motion_driver` - Reference to the driver responsible for executing motion commands.
 pub struct MotionPlanner {
     //pub event_bus: EventBusRef,
@@ -50,6 +56,7 @@ pub struct MotionPlanner {
     available: PersistentState<hwa::ControllerMutexType, bool>,
     motion_config: motion::MotionConfigRef,
     motion_st: Mutex<hwa::ControllerMutexType, motion::MotionStatus>,
+    babystepping: Mutex<hwa::ControllerMutexType, motion::Babystepping>,
     pub motion_driver: MotionDriverRef,
 }
 
@@ -113,6 +120,7 @@ impl MotionPlanner {
             move_planned: PersistentState::new(),
             available: PersistentState::new(),
             motion_st: Mutex::new(motion::MotionStatus::new()),
+            babystepping: Mutex::new(motion::Babystepping::new()),
             motion_driver,
         }
     }
@@ -188,7 +196,7 @@ impl MotionPlanner {
     ) -> Option<(motion::Segment, hwa::CommChannel)> {
         loop {
             let _ = self.move_planned.wait().await;
-            let mut do_dwell = false;
+            let mut do_dwell = None;
             {
                 let mut rb = self.ringbuffer.lock().await;
                 let head = rb.head as usize;
@@ -196,10 +204,10 @@ impl MotionPlanner {
                     PlanEntry::Empty => {
                         self.move_planned.reset();
                     }
-                    PlanEntry::Dwell(channel, _deferred) => {
+                    PlanEntry::Dwell(dwell_ms, channel, _deferred) => {
                         rb.data[head] = PlanEntry::Executing(MovType::Dwell(channel), true);
                         // TODO: Need to revisit this logic
-                        do_dwell = true;
+                        do_dwell = Some(dwell_ms);
                     }
                     PlanEntry::PlannedMove(planned_data, action, channel, deferred) => {
                         hwa::debug!(
@@ -210,13 +218,15 @@ impl MotionPlanner {
                         );
                         rb.data[head] =
                             PlanEntry::Executing(MovType::Move(action, channel), deferred);
+                        // Any quick stop happened before this move was dequeued
+                        motion::STEP_DRIVER.resume();
                         return Some((planned_data, channel));
                     }
-                    PlanEntry::Homing(channel, _deferred) => {
+                    PlanEntry::Homing(request, channel, _deferred) => {
                         event_bus
                             .publish_event(hwa::EventStatus::containing(hwa::EventFlags::HOMING))
                             .await;
-                        rb.data[head] = PlanEntry::Executing(MovType::Homing(channel), true);
+                        rb.data[head] = PlanEntry::Executing(MovType::Homing(request, channel), true);
                         return None;
                     }
                     PlanEntry::Executing(_, _) => {
@@ -225,8 +235,11 @@ impl MotionPlanner {
                     }
                 }
             }
-            if do_dwell {
-                // Just consume. TODO: need to wait for some time as specified
+            if let Some(dwell_ms) = do_dwell {
+                // Wait for the specified time (if any) out of the lock, then consume
+                if let Some(ms) = dwell_ms {
+                    embassy_time::Timer::after_millis(ms.into()).await;
+                }
                 self.consume_current_segment_data(&event_bus).await;
             }
         }
@@ -264,7 +277,7 @@ impl MotionPlanner {
         let head = rb.head;
         hwa::debug!("Movement completed @rq[{}] (ongoing={})", head, rb.used - 1);
         match &rb.data[head as usize] {
-            PlanEntry::Executing(MovType::Homing(channel), _) => {
+            PlanEntry::Executing(MovType::Homing(_, channel), _) => {
                 event_bus
                     .publish_event(hwa::EventStatus::not_containing(hwa::EventFlags::HOMING))
                     .await;
@@ -312,6 +325,56 @@ impl MotionPlanner {
         rb.used
     }
 
+    /// Stops the motion at once (M410, M112): The queued moves are discarded and the one being
+    /// executed is cut short. The discarded deferred gcodes are completed, so nobody is left waiting.
+    ///
+    /// As the position reached is unknown, homing is required before moving again
+    pub async fn quick_stop(&self, event_bus: &hwa::EventBusRef) {
+        // The completions of the discarded entries, sent once the queue is released
+        let mut completions: heapless::Vec<(hwa::DeferAction, hwa::CommChannel), { hwa::SEGMENT_QUEUE_SIZE as usize }> = heapless::Vec::new();
+        {
+            // The queue is held until the stepping is aborted, so no discarded move can be taken meanwhile
+            let mut rb = self.ringbuffer.lock().await;
+            let head = rb.head;
+            // The entry being executed is kept, so the stepper consumes it as usual once cut
+            let kept = match rb.data[head as usize] {
+                PlanEntry::Executing(..) => 1u8,
+                _ => 0u8,
+            };
+            for offset in kept..rb.used {
+                let idx = (head as usize + offset as usize) % hwa::SEGMENT_QUEUE_SIZE as usize;
+                let completion = match core::mem::replace(&mut rb.data[idx], PlanEntry::Empty) {
+                    PlanEntry::PlannedMove(_, action, channel, true) => Some((action, channel)),
+                    PlanEntry::Homing(_, channel, true) => Some((hwa::DeferAction::Homing, channel)),
+                    PlanEntry::Dwell(_, channel, true) => Some((hwa::DeferAction::Dwell, channel)),
+                    _ => None,
+                };
+                if let Some(completion) = completion {
+                    let _ = completions.push(completion);
+                }
+            }
+            rb.used = kept;
+            if kept == 0 {
+                self.move_planned.reset();
+            }
+            motion::STEP_DRIVER.abort();
+            let queue_status_event = EventStatus::not_containing(EventFlags::MOV_QUEUE_FULL);
+            event_bus.publish_event(
+                match kept == 0 {
+                    true => queue_status_event.and_containing(EventFlags::MOV_QUEUE_EMPTY),
+                    false => queue_status_event,
+                }
+            ).await;
+        }
+        for (action, channel) in completions {
+            self.defer_channel
+                .send(hwa::DeferEvent::Completed(action, channel))
+                .await;
+        }
+        self.motion_st.lock().await.last_planned_pos = None;
+        self.available.signal(true);
+    }
+
     
     /// Schedules a raw movement operation for the motion planner.
     ///
@@ -373,6 +436,8 @@ impl MotionPlanner {
         line_tag: Option<u32>,
     ) -> Result<control::CodeExecutionSuccess, control::CodeExecutionFailure> {
         hwa::debug!("schedule_raw_move() BEGIN");
+        #[cfg(feature = "cornering")]
+        let junction_deviation = self.motion_config.lock().await.junction_deviation;
         loop {
             self.available.wait().await;
             {
@@ -380,101 +445,34 @@ impl MotionPlanner {
                 let mut is_defer = rb.used == hwa::SEGMENT_QUEUE_SIZE - 1;
 
                 if rb.used < hwa::SEGMENT_QUEUE_SIZE {
-                    #[cfg(feature = "cornering")]
-                    let mut do_adjust = false;
-
                     let curr_insert_index = rb.index_from_tail(0).unwrap();
 
                     let (curr_planned_entry, event) = match move_type {
                         ScheduledMove::Move(curr_segment_data) => {
+                            #[allow(unused_mut)]
                             let mut curr_segment = motion::Segment::new(curr_segment_data);
-                            // TODO:
-                            // 1) Find a better way to approximate
-                            // 2) Move outside ringbuffer lock
-                            // solving sqrt(((abs(x-v_0))/jmax)) > q_1  for x
-                            // x < v_0 - jmax q_1^2
-                            // x > jmax * q_1^2 + v_0
-
-                            let mut curr_vmax = curr_segment.segment_data.speed_target_mms;
-                            #[cfg(feature = "cornering")]
-                            {
-                                let q_1 = curr_segment.segment_data.displacement_mm;
-                                let v_0 = curr_segment.segment_data.speed_enter_mms;
-                                let t_jmax = curr_segment.segment_data.constraints.a_max
-                                    / curr_segment.segment_data.constraints.j_max;
-
-                                loop {
-                                    let t_jstar = Real::vmin(
-                                        (((curr_vmax - v_0).abs())
-                                            / curr_segment.segment_data.constraints.j_max)
-                                            .sqrt(),
-                                        Some(t_jmax),
-                                    )
-                                        .unwrap_or(math::ZERO);
-
-                                    let q_lim = if t_jstar < t_jmax {
-                                        t_jstar * (v_0 + curr_vmax)
-                                    } else {
-                                        ((v_0 + curr_vmax) / math::TWO)
-                                            * (t_jstar
-                                            + ((curr_vmax - v_0).abs()
-                                            / curr_segment.segment_data.constraints.a_max))
-                                    };
-
-                                    if q_1 >= q_lim {
-                                        break;
-                                    } else {
-                                        curr_vmax *= Real::from_f32(0.5);
-                                    }
-                                }
-                            }
-
-                            let curr_boundary =
-                                curr_vmax.min(curr_segment.segment_data.constraints.v_max);
-                            let v_marginal_gain =
-                                curr_boundary - curr_segment.segment_data.speed_exit_mms;
-                            hwa::debug!("\t- constraint = {} bound = {}", curr_vmax, curr_boundary);
-                            hwa::debug!("\t= v_margin: {}", v_marginal_gain);
-                            curr_segment.segment_data.speed_enter_constrained_mms = v_marginal_gain;
-                            curr_segment.segment_data.speed_exit_constrained_mms = v_marginal_gain;
-
-                            hwa::debug!(
-                                "s: vi = [{} < {}] - vtarget = {} - vo = [{} < {}] / d = {}",
-                                curr_segment.segment_data.speed_enter_mms,
-                                curr_segment.segment_data.speed_enter_constrained_mms,
-                                curr_segment.segment_data.speed_target_mms,
-                                curr_segment.segment_data.speed_exit_constrained_mms,
-                                curr_segment.segment_data.speed_exit_mms,
-                                curr_segment.segment_data.displacement_mm
-                            );
-
-                            hwa::debug!("\t= max_gain: {}", v_marginal_gain);
 
+                            // The junctions are only needed by the look-ahead planning
+                            #[cfg(feature = "cornering")]
                             if let Ok(prev_index) = rb.index_from_tail(1) {
                                 match &mut rb.data[prev_index as usize] {
                                     PlanEntry::PlannedMove(prev_segment, _, _, _) => {
-                                        let proj: Real = prev_segment
-                                            .segment_data
-                                            .unit_vector_dir
-                                            .orthogonal_projection(curr_segment.segment_data.unit_vector_dir);
-                                        if proj.is_defined_positive() {
-                                            hwa::debug!("RingBuffer [{}, {}] chained: ({}) proj ({}) = ({})", prev_index, curr_insert_index,
-                                                prev_segment.segment_data.unit_vector_dir, curr_segment.segment_data.unit_vector_dir, proj
-                                            );
-                                            hwa::debug!("\ts : vi = [{} < {}] - vtarget = {} - vo = [{} < {}]:",
-                                                prev_segment.segment_data.speed_enter_mms, prev_segment.segment_data.speed_enter_constrained_mms,
-                                                prev_segment.segment_data.speed_target_mms,
-                                                prev_segment.segment_data.speed_exit_mms, prev_segment.segment_data.speed_exit_constrained_mms,
-                                            );
-                                            hwa::debug!("\t\tproj = {}", proj);
-                                            cfg_if::cfg_if! {
-                                                if #[cfg(feature = "cornering")] {
-                                                    do_adjust = true;
-                                                }
-                                            }
-                                            prev_segment.segment_data.proj_next = proj;
-                                            curr_segment.segment_data.proj_prev = proj;
-                                        }
+                                        let proj: Real = motion::junction_cosine(
+                                            &prev_segment.segment_data.unit_vector_dir,
+                                            &curr_segment.segment_data.unit_vector_dir,
+                                        );
+                                        // Also kept when not positive, as the junction deviation model allows sharp corners
+                                        hwa::debug!("RingBuffer [{}, {}] chained: ({}) proj ({}) = ({})", prev_index, curr_insert_index,
+                                            prev_segment.segment_data.unit_vector_dir, curr_segment.segment_data.unit_vector_dir, proj
+                                        );
+                                        hwa::debug!("\ts : vi = [{} < {}] - vtarget = {} - vo = [{} < {}]:",
+                                            prev_segment.segment_data.speed_enter_mms, prev_segment.segment_data.speed_enter_constrained_mms,
+                                            prev_segment.segment_data.speed_target_mms,
+                                            prev_segment.segment_data.speed_exit_mms, prev_segment.segment_data.speed_exit_constrained_mms,
+                                        );
+                                        hwa::debug!("\t\tproj = {}", proj);
+                                        prev_segment.segment_data.proj_next = proj;
+                                        curr_segment.segment_data.proj_prev = proj;
                                     }
                                     _ => {}
                                 }
@@ -486,17 +484,17 @@ impl MotionPlanner {
                                 hwa::EventStatus::containing(EventFlags::NOTHING)
                             )
                         }
-                        ScheduledMove::Homing => {
+                        ScheduledMove::Homing(request) => {
                             is_defer = true;
                             (
-                                PlanEntry::Homing(channel, is_defer),
+                                PlanEntry::Homing(request, channel, is_defer),
                                 hwa::EventStatus::not_containing(hwa::EventFlags::HOMING),
                             )
                         }
-                        ScheduledMove::Dwell => {
+                        ScheduledMove::Dwell(dwell_ms) => {
                             is_defer = true;
                             (
-                                PlanEntry::Dwell(channel, is_defer),
+                                PlanEntry::Dwell(dwell_ms, channel, is_defer),
                                 hwa::EventStatus::containing(hwa::EventFlags::MOV_QUEUE_EMPTY),
                             )
                         }
@@ -511,9 +509,10 @@ impl MotionPlanner {
 
                     rb.data[curr_insert_index as usize] = curr_planned_entry;
                     rb.used += 1;
+                    // Entry and exit speeds of all the queued moves are recomputed with the new one
                     #[cfg(feature = "cornering")]
-                    if do_adjust {
-                        let _ = perform_cornering(rb);
+                    if matches!(curr_planned_entry, PlanEntry::PlannedMove(..)) {
+                        motion::plan_lookahead(&mut rb, junction_deviation);
                     }
 
                     event_bus
@@ -584,15 +583,14 @@ impl MotionPlanner {
         mg.last_planned_pos.replace(p.apply(pos));
     }
 
+    /// The position of the motors, in steps, at the last planned position (See [motion::Kinematics])
     pub async fn get_last_planned_real_pos(&self) -> Option<TVector<Real>> {
-        self.motion_st.lock().await.last_real_pos.clone()
-    }
-    pub async fn set_last_planned_real_pos(&self, pos: &TVector<Real>) {
-        self.motion_st
-            .lock()
-            .await
-            .last_real_pos
-            .replace(pos.map_nan(&crate::math::ZERO));
+        let last_planned_pos = self.motion_st.lock().await.last_planned_pos?;
+        let cfg = self.motion_config.lock().await;
+        let steps_per_mm = cfg.units_per_mm * cfg.get_usteps_as_vector();
+        let kinematics = cfg.kinematics;
+        drop(cfg);
+        Some((kinematics.tool_to_motor(&last_planned_pos) * steps_per_mm).round())
     }
 
     /***
@@ -613,14 +611,43 @@ impl MotionPlanner {
         Real::new(self.motion_config.lock().await.flow_rate as i64, 0) / math::ONE_HUNDRED
     }
 
+    /// Sets the speed rate (M220), in percent. It is applied at once to the moves already queued, so
+    /// their speeds are planned again (from the one of the move being executed)
     pub async fn set_speed_rate(&self, rate: u8) {
-        self.motion_config.lock().await.speed_rate = rate;
+        let mut cfg = self.motion_config.lock().await;
+        cfg.speed_rate = rate;
+        #[cfg(feature = "cornering")]
+        let junction_deviation = cfg.junction_deviation;
+        drop(cfg);
+        let mut rb = self.ringbuffer.lock().await;
+        rb.set_speed_rate(Real::from_lit(rate as i64, 0) / math::ONE_HUNDRED);
+        #[cfg(feature = "cornering")]
+        motion::plan_lookahead(&mut rb, junction_deviation);
     }
 
     pub async fn get_speed_rate_as_real(&self) -> Real {
         Real::new(self.motion_config.lock().await.speed_rate as i64, 0) / math::ONE_HUNDRED
     }
 
+    /// Requests the X, Y and Z babysteps (M290) given, in mm. They are done along the moves being executed
+    pub async fn babystep(&self, offset: [Option<Real>; 3]) {
+        self.babystepping.lock().await.request(offset);
+    }
+
+    /// The X, Y and Z babysteps requested since homing, in mm
+    pub async fn get_babystep_total(&self) -> [Real; 3] {
+        self.babystepping.lock().await.total()
+    }
+
+    /// Takes the babysteps pending, up to `max_offset` mm of each axis. See [motion::Babystepping::take]
+    pub async fn take_babystep(&self, max_offset: &[Real; 3]) -> Option<TVector<Real>> {
+        self.babystepping.lock().await.take(max_offset)
+    }
+
+    pub async fn reset_babystep(&self) {
+        self.babystepping.lock().await.reset();
+    }
+
     pub async fn get_default_travel_speed(&self) -> u16 {
         self.motion_config.lock().await.default_travel_speed
     }
@@ -722,10 +749,265 @@ impl MotionPlanner {
     }
 
 
+    pub async fn set_unit_mode(&self, mode: motion::UnitMode) {
+        self.motion_st.lock().await.unit_mode = mode;
+    }
+
+    pub async fn get_unit_mode(&self) -> motion::UnitMode {
+        self.motion_st.lock().await.unit_mode
+    }
+
+    /// Converts the numeric words among `letters` from the active units to millimeters
+    pub async fn params_to_mm<'a>(
+        &self,
+        params: &'a control::GCodeParams,
+        letters: &str,
+    ) -> Cow<'a, control::GCodeParams> {
+        match self.get_unit_mode().await {
+            motion::UnitMode::Millimeters => Cow::Borrowed(params),
+            mode => Cow::Owned(params.scaled(letters, mode.mm_per_unit())),
+        }
+    }
+
+    /// Converts the X, Y and Z words of an absolute move from work to machine coordinates
+    pub async fn params_to_machine<'a>(
+        &self,
+        params: Cow<'a, control::GCodeParams>,
+    ) -> Cow<'a, control::GCodeParams> {
+        let offset = {
+            let st = self.motion_st.lock().await;
+            if !st.absolute_positioning {
+                return params;
+            }
+            st.work_offset()
+        };
+        let mut machine_params = params;
+        for (letter, delta) in [('x', offset.x), ('y', offset.y), ('z', offset.z)] {
+            if let (Some(value), Some(delta)) = (machine_params.real(letter), delta) {
+                if !delta.is_zero() {
+                    machine_params
+                        .to_mut()
+                        .set(letter, control::GCodeParamValue::Number(value + delta));
+                }
+            }
+        }
+        machine_params
+    }
+
+    /// Work coordinates
+
+    /// Selects the work coordinate system (0 for G54 to 5 for G59)
+    pub async fn select_work_coordinate_system(&self, system: usize) {
+        self.motion_st.lock().await.work_coordinate_system =
+            system.min(motion::NUM_WORK_COORDINATE_SYSTEMS - 1);
+    }
+
+    pub async fn get_work_coordinate_system(&self) -> usize {
+        self.motion_st.lock().await.work_coordinate_system
+    }
+
+    pub async fn get_work_offsets(&self) -> [TVector<Real>; motion::NUM_WORK_COORDINATE_SYSTEMS] {
+        self.motion_st.lock().await.work_offsets
+    }
+
+    /// The G92 offset, if active
+    pub async fn get_g92_offset(&self) -> Option<TVector<Real>> {
+        let st = self.motion_st.lock().await;
+        st.g92_active.then_some(st.g92_offset)
+    }
+
+    /// Sets the given coords of the offset of a work coordinate system (G10 L2).
+    /// None refers to the selected one
+    pub async fn set_work_offset(&self, system: Option<usize>, offset: &TVector<Real>) {
+        let mut st = self.motion_st.lock().await;
+        let idx = system.unwrap_or(st.work_coordinate_system);
+        st.work_offsets[idx] = st.work_offsets[idx].apply(&offset.with_coord(CoordSel::E, None));
+    }
+
+    /// Sets the offset of a work coordinate system so that the given coords are the ones
+    /// of the current position in that system (G10 L20). None refers to the selected one
+    pub async fn set_work_position(
+        &self,
+        system: Option<usize>,
+        pos: &TVector<Real>,
+    ) -> Result<(), control::CodeExecutionFailure> {
+        let mut st = self.motion_st.lock().await;
+        let idx = system.unwrap_or(st.work_coordinate_system);
+        match st.set_work_position(idx, pos) {
+            true => Ok(()),
+            false => Err(control::CodeExecutionFailure::HomingRequired),
+        }
+    }
+
+    /// Sets the G92 offset so that the given coords are the ones of the current position (G92).
+    /// See [motion::MotionStatus::set_g92_position]
+    pub async fn set_g92_position(&self, pos: &TVector<Real>) {
+        self.motion_st.lock().await.set_g92_position(pos);
+    }
+
+    /// Cancels the G92 offset (G92.1) or just suspends it (G92.2)
+    pub async fn reset_g92_offset(&self, clear: bool) {
+        let mut st = self.motion_st.lock().await;
+        if clear {
+            st.g92_offset = motion::NO_OFFSET;
+        }
+        st.g92_active = false;
+    }
+
+    /// The last planned position in work coordinates
+    pub async fn get_last_planned_work_pos(&self) -> Option<TVector<Real>> {
+        let st = self.motion_st.lock().await;
+        st.last_planned_pos
+            .map(|p| p - st.work_offset().map_nan(&math::ZERO))
+    }
+
+    pub async fn set_arc_plane(&self, plane: motion::ArcPlane) {
+        self.motion_st.lock().await.arc_plane = plane;
+    }
+
+    pub async fn get_arc_plane(&self) -> motion::ArcPlane {
+        self.motion_st.lock().await.arc_plane
+    }
+
+    pub async fn set_arc_chord_tolerance(&self, tolerance: Real) {
+        self.motion_config.lock().await.arc_chord_tolerance = tolerance;
+    }
+
+    pub async fn set_bezier_max_deviation(&self, max_deviation: Real) {
+        self.motion_config.lock().await.bezier_max_deviation = max_deviation;
+    }
+
+    /// Sets the junction deviation in mm. Zero restores the projection rule for the corner speed
+    pub async fn set_junction_deviation(&self, junction_deviation: Real) {
+        self.motion_config.lock().await.junction_deviation = junction_deviation;
+    }
+
+    pub async fn get_junction_deviation(&self) -> Real {
+        self.motion_config.lock().await.junction_deviation
+    }
+
+    /// Firmware retraction
+
+    /// Retracts the filament and lifts Z as set by M207 (G10), with queued moves.
+    /// Nothing is done if already retracted
+    pub async fn retract(
+        &self,
+        channel: hwa::CommChannel,
+        blocking: bool,
+        event_bus: &hwa::EventBusRef,
+        num: u32,
+        line: Option<u32>,
+    ) -> Result<control::CodeExecutionSuccess, control::CodeExecutionFailure> {
+        if self.motion_st.lock().await.retraction.is_some() {
+            return Ok(control::CodeExecutionSuccess::OK);
+        }
+        let settings = self.motion_config.lock().await.retraction;
+        let p0 = self
+            .get_last_planned_pos()
+            .await
+            .ok_or(control::CodeExecutionFailure::HomingRequired)?;
+        let hop = settings.z_hop.is_defined_positive() && p0.z.is_some();
+        // When Z is lifted, it is the lift which is reported
+        let (retract_channel, retract_blocking) = match hop {
+            true => (hwa::CommChannel::Internal, true),
+            false => (channel, blocking),
+        };
+        let result = self.schedule_line(
+            "G10", retract_channel, hwa::DeferAction::LinearMove,
+            p0.with_coord(CoordSel::E, Some(p0.e.unwrap_or(math::ZERO) - settings.length)),
+            Some(settings.speed).filter(|s| s.is_defined_positive()),
+            retract_blocking, event_bus, num, line,
+        ).await?;
+        self.motion_st.lock().await.retraction = Some(motion::Retraction::new(settings.length));
+        if !hop {
+            return Ok(result);
+        }
+        let p1 = self
+            .get_last_planned_pos()
+            .await
+            .ok_or(control::CodeExecutionFailure::HomingRequired)?;
+        let result = self.schedule_line(
+            "G10", channel, hwa::DeferAction::LinearMove,
+            p1.with_coord(CoordSel::Z, p1.z.map(|z| z + settings.z_hop)),
+            None,
+            blocking, event_bus, num, line,
+        ).await?;
+        if let Some(retraction) = &mut self.motion_st.lock().await.retraction {
+            retraction.z_hop = settings.z_hop;
+        }
+        Ok(result)
+    }
+
+    /// Recovers from the retraction (G11) with queued moves: Lowers Z back and primes the filament
+    /// retracted plus the extra length set by M208. Nothing is done if not retracted
+    pub async fn recover(
+        &self,
+        channel: hwa::CommChannel,
+        blocking: bool,
+        event_bus: &hwa::EventBusRef,
+        num: u32,
+        line: Option<u32>,
+    ) -> Result<control::CodeExecutionSuccess, control::CodeExecutionFailure> {
+        let retraction = match self.motion_st.lock().await.retraction {
+            Some(retraction) => retraction,
+            None => return Ok(control::CodeExecutionSuccess::OK),
+        };
+        let settings = self.motion_config.lock().await.retraction;
+        if retraction.z_hop.is_defined_positive() {
+            let p0 = self
+                .get_last_planned_pos()
+                .await
+                .ok_or(control::CodeExecutionFailure::HomingRequired)?;
+            self.schedule_line(
+                "G11", hwa::CommChannel::Internal, hwa::DeferAction::LinearMove,
+                p0.with_coord(CoordSel::Z, p0.z.map(|z| z - retraction.z_hop)),
+                None,
+                true, event_bus, num, line,
+            ).await?;
+            if let Some(retraction) = &mut self.motion_st.lock().await.retraction {
+                retraction.z_hop = math::ZERO;
+            }
+        }
+        let p1 = self
+            .get_last_planned_pos()
+            .await
+            .ok_or(control::CodeExecutionFailure::HomingRequired)?;
+        let prime_length = retraction.prime_length(settings.recover_extra);
+        let result = self.schedule_line(
+            "G11", channel, hwa::DeferAction::LinearMove,
+            p1.with_coord(CoordSel::E, Some(p1.e.unwrap_or(math::ZERO) + prime_length)),
+            Some(settings.recover_speed()).filter(|s| s.is_defined_positive()),
+            blocking, event_bus, num, line,
+        ).await?;
+        self.motion_st.lock().await.retraction = None;
+        Ok(result)
+    }
+
+    /// With automatic retraction (M209 S1), whether a G1 with the given params is an E only move to be
+    /// taken as a retraction (`Some(true)`) or as a recovery (`Some(false)`)
+    async fn auto_retraction(&self, params: &control::GCodeParams) -> Option<bool> {
+        if !self.motion_config.lock().await.retraction.auto_retract {
+            return None;
+        }
+        let requested = params.vector();
+        if requested.x.is_some() || requested.y.is_some() || requested.z.is_some() {
+            return None;
+        }
+        let (_, target) = self.path_limits(params).await.ok()?;
+        let e = target.e?;
+        if e < math::ZERO {
+            Some(true)
+        } else if e.is_defined_positive() && self.motion_st.lock().await.retraction.is_some() {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
     /// Plans and schedules a series of motion commands based on the given GCode.
     ///
     /// Depending on the provided GCode, this method performs different motion planning 
-    /// and scheduling actions. It supports G0 (rapid move), G1 (linear move), G4 (dwell), 
+    /// and scheduling actions. It supports G0 (rapid move), G1 (linear move), G2/G3 (arc move), G5 (Bezier move), G4 (dwell), G53 (machine coordinates move), 
     /// G28 (homing), G29 (leveling), G29_1, and G29_2 commands. Any unsupported GCodes 
     /// will yield an error.
     ///
@@ -753,54 +1035,125 @@ impl MotionPlanner {
         blocking: bool,
         event_bus: &hwa::EventBusRef,
     ) -> Result<control::CodeExecutionSuccess, control::CodeExecutionFailure> {
+        let params = match gc.value {
+            // P and Q are the offsets of the second control point
+            control::GCodeValue::G5 => self.params_to_mm(&gc.params, "xyzefijpq").await,
+            _ => self.params_to_mm(&gc.params, LENGTH_WORDS).await,
+        };
+        // G53 applies to the move of its line, given before (G53 G0) or after it (G0 G53)
+        let machine_coords = match gc.value {
+            control::GCodeValue::G53 => true,
+            control::GCodeValue::G0 | control::GCodeValue::G1 => gc.params.int('g') == Some(53),
+            _ => false,
+        };
+        let params = match machine_coords {
+            true => params,
+            false => self.params_to_machine(params).await,
+        };
         match &gc.value {
-            control::GCodeValue::G0(t) => Ok(
+            control::GCodeValue::G0 => Ok(
                 self.schedule_move(
                     "G0",
                     channel,
                     hwa::DeferAction::RapidMove,
-                    TVector {
-                        x: t.x,
-                        y: t.y,
-                        z: t.z,
-                        e: None,
-                    },
-                    t.f,
+                    params.vector().with_coord(CoordSel::E, None),
+                    params.real('f'),
                     blocking,
                     event_bus,
                     gc.order_num, gc.line_tag,
                 ).await?
             ),
-            control::GCodeValue::G1(t) => Ok(
-                self.schedule_move(
-                    "G1",
+            // With automatic retraction (M209 S1), the E only moves are firmware retractions
+            control::GCodeValue::G1 => match self.auto_retraction(&params).await {
+                Some(true) => self.retract(channel, blocking, event_bus, gc.order_num, gc.line_tag).await,
+                Some(false) => self.recover(channel, blocking, event_bus, gc.order_num, gc.line_tag).await,
+                None => Ok(
+                    self.schedule_move(
+                        "G1",
+                        channel,
+                        hwa::DeferAction::LinearMove,
+                        params.vector(),
+                        params.real('f'),
+                        blocking,
+                        event_bus,
+                        gc.order_num, gc.line_tag,
+                    ).await?
+                ),
+            },
+            // G53 G0 or G53 G1 (given as a G word of G53)
+            control::GCodeValue::G53 => match params.int('g') {
+                Some(1) => self.schedule_move(
+                    "G53",
                     channel,
                     hwa::DeferAction::LinearMove,
-                    TVector {
-                        x: t.x,
-                        y: t.y,
-                        z: t.z,
-                        e: t.e,
-                    },
-                    t.f,
+                    params.vector(),
+                    params.real('f'),
                     blocking,
                     event_bus,
                     gc.order_num, gc.line_tag,
-                ).await?
-            ),
-            control::GCodeValue::G4 => Ok(
-                self.schedule_raw_move(
-                    "G4",
+                ).await,
+                _ => self.schedule_move(
+                    "G53",
+                    channel,
+                    hwa::DeferAction::RapidMove,
+                    params.vector().with_coord(CoordSel::E, None),
+                    params.real('f'),
+                    blocking,
+                    event_bus,
+                    gc.order_num, gc.line_tag,
+                ).await,
+            },
+            control::GCodeValue::G2 | control::GCodeValue::G3 => {
+                let clockwise = gc.value == control::GCodeValue::G2;
+                // Boxed, so the arcs do not make the futures of every gcode bigger
+                alloc::boxed::Box::pin(self.schedule_arc(
+                    if clockwise { "G2" } else { "G3" },
                     channel,
-                    hwa::DeferAction::Dwell,
-                    ScheduledMove::Dwell,
+                    clockwise,
+                    &params,
                     blocking,
                     event_bus,
                     gc.order_num, gc.line_tag,
+                )).await
+            }
+            control::GCodeValue::G5 => {
+                // Boxed, as the arcs
+                alloc::boxed::Box::pin(self.schedule_bezier(
+                    "G5",
+                    channel,
+                    &params,
+                    blocking,
+                    event_bus,
+                    gc.order_num, gc.line_tag,
+                )).await
+            }
+            control::GCodeValue::G4 => {
+                // P is given in milliseconds and S in seconds
+                let dwell_ms = gc.params.real('p')
+                    .or_else(|| gc.params.real('s').map(|s| s * math::ONE_THOUSAND))
+                    .and_then(|ms| ms.to_i32())
+                    .map(|ms| ms.max(0) as u32);
+                Ok(
+                    self.schedule_raw_move(
+                        "G4",
+                        channel,
+                        hwa::DeferAction::Dwell,
+                        ScheduledMove::Dwell(dwell_ms),
+                        blocking,
+                        event_bus,
+                        gc.order_num, gc.line_tag,
+                    )
+                    .await?
                 )
-                .await?
-            ),
-            control::GCodeValue::G28(_x) => {
+            }
+            control::GCodeValue::G28 => {
+                // The axes given (all of them if none)
+                let mut axes = CoordSel::empty();
+                axes.set(CoordSel::X, gc.params.has('x'));
+                axes.set(CoordSel::Y, gc.params.has('y'));
+                axes.set(CoordSel::Z, gc.params.has('z'));
+                // The filament is left as it is, and Z is homed
+                self.motion_st.lock().await.retraction = None;
                 event_bus
                     .publish_event(hwa::EventStatus::containing(hwa::EventFlags::HOMING))
                     .await;
@@ -809,7 +1162,25 @@ impl MotionPlanner {
                         "G28",
                         channel,
                         hwa::DeferAction::Homing,
-                        ScheduledMove::Homing,
+                        ScheduledMove::Homing(motion::HomingRequest::Home(axes)),
+                        blocking,
+                        event_bus,
+                        gc.order_num, gc.line_tag,
+                    )
+                    .await?)
+            }
+            #[cfg(feature = "with-z2-axis")]
+            control::GCodeValue::G34 => {
+                self.motion_st.lock().await.retraction = None;
+                event_bus
+                    .publish_event(hwa::EventStatus::containing(hwa::EventFlags::HOMING))
+                    .await;
+                Ok(self
+                    .schedule_raw_move(
+                        "G34",
+                        channel,
+                        hwa::DeferAction::Homing,
+                        ScheduledMove::Homing(motion::HomingRequest::AlignZ),
                         blocking,
                         event_bus,
                         gc.order_num, gc.line_tag,
@@ -823,6 +1194,207 @@ impl MotionPlanner {
         }
     }
 
+    /// Schedules an arc (G2/G3) in the selected plane as a sequence of chords.
+    ///
+    /// The centre is given either by the offsets relative to the start (I, J, K) or by the radius (R).
+    async fn schedule_arc(
+        &self,
+        mnemonic: &'static str,
+        channel: hwa::CommChannel,
+        clockwise: bool,
+        params: &control::GCodeParams,
+        blocking: bool,
+        event_bus: &hwa::EventBusRef,
+        num: u32,
+        line: Option<u32>,
+    ) -> Result<control::CodeExecutionSuccess, control::CodeExecutionFailure> {
+        let (start, end) = self.path_limits(params).await?;
+        let plane = self.get_arc_plane().await;
+        let chord_tolerance = self.motion_config.lock().await.arc_chord_tolerance;
+
+        let arc = match params.real('r') {
+            Some(radius) => motion::ArcInterpolator::from_radius(
+                plane, clockwise, start, end, radius, chord_tolerance,
+            ),
+            None => {
+                let (l0, l1) = plane.offset_letters();
+                motion::ArcInterpolator::from_offsets(
+                    plane, clockwise, start, end,
+                    (params.real(l0).unwrap_or(math::ZERO), params.real(l1).unwrap_or(math::ZERO)),
+                    chord_tolerance,
+                )
+            }
+        }.ok_or(control::CodeExecutionFailure::NumericalError)?;
+
+        self.schedule_path(
+            mnemonic, channel, arc, params.real('f'), blocking, event_bus, num, line
+        ).await
+    }
+
+    /// Schedules a cubic Bezier curve (G5) in the XY plane as a sequence of lines.
+    ///
+    /// The first control point is given relative to the start (I, J) and the second one relative to the end (P, Q).
+    async fn schedule_bezier(
+        &self,
+        mnemonic: &'static str,
+        channel: hwa::CommChannel,
+        params: &control::GCodeParams,
+        blocking: bool,
+        event_bus: &hwa::EventBusRef,
+        num: u32,
+        line: Option<u32>,
+    ) -> Result<control::CodeExecutionSuccess, control::CodeExecutionFailure> {
+        let (start, end) = self.path_limits(params).await?;
+        let max_deviation = self.motion_config.lock().await.bezier_max_deviation;
+        let curve = motion::BezierInterpolator::new(
+            start, end,
+            (params.real('i').unwrap_or(math::ZERO), params.real('j').unwrap_or(math::ZERO)),
+            (params.real('p').unwrap_or(math::ZERO), params.real('q').unwrap_or(math::ZERO)),
+            max_deviation,
+        );
+        self.schedule_path(
+            mnemonic, channel, curve, params.real('f'), blocking, event_bus, num, line
+        ).await
+    }
+
+    /// Computes the absolute (start, end) points of a curved move from the last planned position.
+    ///
+    /// E is given as an increment from zero at start (See [Self::update_last_planned_pos])
+    async fn path_limits(
+        &self,
+        params: &control::GCodeParams,
+    ) -> Result<(TVector<Real>, TVector<Real>), control::CodeExecutionFailure> {
+        let p0 = self
+            .get_last_planned_pos()
+            .await
+            .ok_or(control::CodeExecutionFailure::HomingRequired)?;
+        let absolute = self.is_absolute_positioning().await;
+        let requested = params.vector();
+        let target = match absolute {
+            true => p0.apply(&requested),
+            false => p0 + requested.map_nan(&math::ZERO),
+        };
+        let e_increment = match absolute {
+            true => requested.e.map(|e| e - p0.e.unwrap_or(math::ZERO)),
+            false => requested.e,
+        };
+        Ok((
+            p0.with_coord(CoordSel::E, e_increment.map(|_| math::ZERO)),
+            target.with_coord(CoordSel::E, e_increment),
+        ))
+    }
+
+    /// Schedules the lines through the absolute points of a path (See [Self::path_limits])
+    /// with [Self::schedule_move], so cornering and the motion profile apply to them as to any other linear move.
+    ///
+    /// The whole path is checked first (See [Self::check_path]). Only the last line is reported on
+    /// `channel`. The previous ones are queued as internal moves, waiting for room in the queue if needed.
+    async fn schedule_path<P>(
+        &self,
+        mnemonic: &'static str,
+        channel: hwa::CommChannel,
+        path: P,
+        feed_rate: Option<Real>,
+        blocking: bool,
+        event_bus: &hwa::EventBusRef,
+        num: u32,
+        line: Option<u32>,
+    ) -> Result<control::CodeExecutionSuccess, control::CodeExecutionFailure>
+    where
+        P: Iterator<Item = TVector<Real>> + Clone,
+    {
+        let p0 = self
+            .get_last_planned_pos()
+            .await
+            .ok_or(control::CodeExecutionFailure::HomingRequired)?;
+        let cfg_g = self.motion_config.lock().await;
+        let kinematics = cfg_g.kinematics;
+        let soft_endstops = cfg_g.soft_endstops;
+        drop(cfg_g);
+        Self::check_path(&kinematics, &soft_endstops, &p0, path.clone())?;
+        let absolute = self.is_absolute_positioning().await;
+        let mut path = path.peekable();
+        let mut e_done = math::ZERO;
+        let mut result = Ok(control::CodeExecutionSuccess::OK);
+        while let Some(point) = path.next() {
+            let e_line = point.e.map(|e| e - e_done);
+            e_done = point.e.unwrap_or(math::ZERO);
+            let current = self
+                .get_last_planned_pos()
+                .await
+                .ok_or(control::CodeExecutionFailure::HomingRequired)?;
+            let p1_t = match absolute {
+                true => point.with_coord(
+                    CoordSel::E, e_line.map(|de| current.e.unwrap_or(math::ZERO) + de)
+                ),
+                false => (point - current).with_coord(CoordSel::E, e_line),
+            };
+            result = if path.peek().is_none() {
+                self.schedule_move(
+                    mnemonic, channel, hwa::DeferAction::LinearMove,
+                    p1_t, feed_rate,
+                    blocking, event_bus, num, line,
+                ).await
+            } else {
+                self.schedule_move(
+                    mnemonic, hwa::CommChannel::Internal, hwa::DeferAction::LinearMove,
+                    p1_t, feed_rate,
+                    true, event_bus, num, line,
+                ).await
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Checks the moves from `p0` through the absolute points of a path (See [Self::path_limits]) as
+    /// [Self::schedule_move] does, so none of them is queued when any is rejected
+    fn check_path<P>(
+        kinematics: &motion::MachineKinematics,
+        soft_endstops: &motion::SoftEndstops,
+        p0: &TVector<Real>,
+        path: P,
+    ) -> Result<(), control::CodeExecutionFailure>
+    where
+        P: Iterator<Item = TVector<Real>>,
+    {
+        let mut p0 = *p0;
+        for point in path {
+            let pdest = p0.apply(&point.with_coord(CoordSel::E, None));
+            p0 = Self::check_line(kinematics, soft_endstops, &p0, &pdest)?;
+        }
+        Ok(())
+    }
+
+    /// Checks a line from `p0` to `pdest` (absolute). Returns the position it ends at, kept inside the
+    /// workspace of the machine and checked against the soft endstops, or an error when it is rejected
+    fn check_line(
+        kinematics: &motion::MachineKinematics,
+        soft_endstops: &motion::SoftEndstops,
+        p0: &TVector<Real>,
+        pdest: &TVector<Real>,
+    ) -> Result<TVector<Real>, control::CodeExecutionFailure> {
+        // E is not subject to the workspace
+        let pdest = kinematics.clamp_to_workspace(pdest).with_coord(CoordSel::E, pdest.e);
+        let pdest = soft_endstops
+            .apply(&pdest)
+            .map_err(|_| control::CodeExecutionFailure::OutOfBounds)?;
+        // The lines the move is split into would leave it otherwise
+        if !kinematics.line_in_workspace(p0, &pdest) {
+            return Err(control::CodeExecutionFailure::OutOfBounds);
+        }
+        Ok(pdest)
+    }
+
+    /// Schedules a linear move to `p1_t` (absolute or relative, according to the positioning mode),
+    /// kept inside the workspace of the machine and checked against the soft endstops
+    /// (See [motion::SoftEndstops::apply]).
+    ///
+    /// When the kinematics is not linear, the move is split into lines short enough to be driven
+    /// linearly in motor space (See [motion::Kinematics::max_segment_length]). Only the last one is
+    /// reported on `channel`.
     async fn schedule_move(
         &self,
         mnemonic: &'static str,
@@ -835,24 +1407,95 @@ impl MotionPlanner {
         num: u32,
         line: Option<u32>,
     ) -> Result<control::CodeExecutionSuccess, control::CodeExecutionFailure> {
-
-        // TODO Reduce locks
         let p0 = self
             .get_last_planned_pos()
             .await
             .ok_or(control::CodeExecutionFailure::HomingRequired)?;
-        let _pdest = if self.is_absolute_positioning().await {
-            p1_t
+        // The coordinates not given are kept
+        let pdest = if self.is_absolute_positioning().await {
+            p0.apply(&p1_t)
         } else {
-            p0 + p1_t
+            p0 + p1_t.map_nan(&math::ZERO)
+        };
+
+        let cfg_g = self.motion_config.lock().await;
+        let kinematics = cfg_g.kinematics;
+        let soft_endstops = cfg_g.soft_endstops;
+        let speed = requested_motion_speed
+            .unwrap_or(Real::from_lit(cfg_g.default_travel_speed as i64, 0));
+        drop(cfg_g);
+
+        let pdest = Self::check_line(&kinematics, &soft_endstops, &p0, &pdest)?;
+
+        let num_segments = match kinematics.max_segment_length(speed) {
+            Some(max_length) => {
+                let length = (pdest - p0)
+                    .with_coord(CoordSel::E, None)
+                    .norm2()
+                    .unwrap_or(math::ZERO);
+                (length / max_length).ceil().to_i32().unwrap_or(1).max(1)
+            }
+            None => 1,
         };
+        if num_segments == 1 {
+            return self.schedule_line(
+                mnemonic, channel, action, pdest, requested_motion_speed,
+                blocking, event_bus, num, line,
+            ).await;
+        }
+
+        let n = Real::from_lit(num_segments as i64, 0);
+        let e0 = p0.e.unwrap_or(math::ZERO);
+        // E is given as an increment from the last planned position (See [Self::update_last_planned_pos])
+        let e_line = pdest.e.map(|e| e0 + (e - e0) / n);
+        let mut result = Ok(control::CodeExecutionSuccess::OK);
+        for i in 1..=num_segments {
+            let point = (p0 + (pdest - p0) * (Real::from_lit(i as i64, 0) / n))
+                .with_coord(CoordSel::E, e_line);
+            result = if i == num_segments {
+                self.schedule_line(
+                    mnemonic, channel, action, point, requested_motion_speed,
+                    blocking, event_bus, num, line,
+                ).await
+            } else {
+                self.schedule_line(
+                    mnemonic, hwa::CommChannel::Internal, action, point, requested_motion_speed,
+                    true, event_bus, num, line,
+                ).await
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Schedules a linear move from the last planned position to `_pdest` (absolute)
+    async fn schedule_line(
+        &self,
+        mnemonic: &'static str,
+        channel: hwa::CommChannel,
+        action: hwa::DeferAction,
+        _pdest: TVector<Real>,
+        requested_motion_speed: Option<Real>,
+        blocking: bool,
+        event_bus: &hwa::EventBusRef,
+        num: u32,
+        line: Option<u32>,
+    ) -> Result<control::CodeExecutionSuccess, control::CodeExecutionFailure> {
+
+        // TODO Reduce locks
+        let p0 = self
+            .get_last_planned_pos()
+            .await
+            .ok_or(control::CodeExecutionFailure::HomingRequired)?;
 
         let steps_per_unit =
             self.get_steps_per_mm_as_vector().await * self.get_usteps_as_vector().await;
         let rounded_pos: TVector<Real> = ((_pdest - p0) * steps_per_unit).ceil() / steps_per_unit;
 
         let p1 = p0 + rounded_pos;
-        hwa::debug!("p1 [{}] -> [{}]", p1_t, p1);
+        hwa::debug!("p1 [{}] -> [{}]", _pdest, p1);
         hwa::debug!("P_POS: {} d: {}", p1, p1 - p0);
 
         let cfg = self.motion_cfg();
@@ -870,6 +1513,7 @@ impl MotionPlanner {
         let max_jerk = cfg_g
             .max_jerk
             .map_coords(|c| Some(Real::from_lit(c as i64, 0)));
+        let kinematics = cfg_g.kinematics;
         //----
         drop(cfg_g);
 
@@ -901,9 +1545,27 @@ impl MotionPlanner {
         // Finally, per-axis relative speed
         let speed_vector = clamped_speed * speed_rate;
 
-        let module_target_speed = speed_vector.norm2().unwrap_or(math::ZERO);
-        let module_target_accel = (unit_vector_dir.abs() * max_accel).norm2().unwrap_or(math::ZERO);
-        let module_target_jerk = (unit_vector_dir.abs() * max_jerk).norm2().unwrap_or(math::ZERO);
+        // The limits are given per motor, so they are also enforced in motor space
+        let motor_dir = kinematics.motor_direction(&p1, &unit_vector_dir, module_target_distance);
+        let motor_limited = |module: Real, limits: &TVector<Real>| {
+            match motion::max_tool_rate(&motor_dir, limits) {
+                Some(max_module) => module.min(max_module),
+                None => module,
+            }
+        };
+
+        let module_target_speed = motor_limited(
+            speed_vector.norm2().unwrap_or(math::ZERO),
+            &(max_speed * speed_rate),
+        );
+        let module_target_accel = motor_limited(
+            (unit_vector_dir.abs() * max_accel).norm2().unwrap_or(math::ZERO),
+            &max_accel,
+        );
+        let module_target_jerk = motor_limited(
+            (unit_vector_dir.abs() * max_jerk).norm2().unwrap_or(math::ZERO),
+            &max_jerk,
+        );
 
         let move_result = if module_target_distance.is_negligible() {
             Ok(control::CodeExecutionSuccess::OK)
@@ -923,6 +1585,7 @@ impl MotionPlanner {
                 unit_vector_dir,
                 dest_pos: p1,
                 tool_power: Real::zero(),
+                speed_rate,
                 constraints: control::motion::Constraints {
                     v_max: module_target_speed,
                     a_max: module_target_accel,
@@ -931,6 +1594,7 @@ impl MotionPlanner {
                 proj_next: Real::zero(),
             };
 
+            // A full queue is reported to the caller as BUSY, when not blocking
             let r = self
                 .schedule_raw_move(
                     mnemonic,
@@ -941,7 +1605,7 @@ impl MotionPlanner {
                     event_bus,
                     num, line,
                 )
-                .await?;
+                .await;
 
             hwa::debug!(
                 "speed: {} -> {} ",
@@ -953,36 +1617,66 @@ impl MotionPlanner {
             hwa::debug!("speed_rates: {}", speed_rate.rdp(4));
             hwa::debug!("speed_module: {}", module_target_speed.rdp(4));
 
-            return Ok(r);
+            r
         } else {
             hwa::warn!("Incomplete move");
             Ok(control::CodeExecutionSuccess::OK)
         };
-        match move_result {
-            Ok(resp) => Ok(resp),
-            Err(err) => match err {
-                control::CodeExecutionFailure::BUSY => {
-                    todo!("Delegate to deferrals!!!")
-                }
-                _ => Err(err),
-            },
-        }
+        move_result
     }
 
+    /// Homes the axes requested by the homing entry being executed (See [MovType::Homing]), or aligns
+    /// the Z motors.
+    ///
+    /// The axes not homed keep their position. All of them are homed if it is unknown.
     pub async fn do_homing(&self, event_bus: &hwa::EventBusRef) -> Result<(), ()> {
-        match self
-            .motion_driver
-            .lock()
-            .await
-            .homing_action(&self.motion_config)
-            .await
+        let request = {
+            let rb = self.ringbuffer.lock().await;
+            match &rb.data[rb.head as usize] {
+                PlanEntry::Executing(MovType::Homing(request, _), _) => *request,
+                _ => motion::HomingRequest::Home(CoordSel::empty()),
+            }
+        };
+        let last_planned_pos = self.get_last_planned_pos().await;
+        let position = last_planned_pos.unwrap_or(TVector::zero());
+        let mut driver = self.motion_driver.lock().await;
+        let result = match request {
+            motion::HomingRequest::Home(axes) => {
+                let axes = match last_planned_pos {
+                    Some(_) => axes,
+                    None => CoordSel::empty(),
+                };
+                driver.homing_action(&self.motion_config, axes, position).await
+            }
+            // Homed before, and Z again once aligned, as its height has changed
+            #[cfg(feature = "with-z2-axis")]
+            motion::HomingRequest::AlignZ => {
+                match driver.homing_action(&self.motion_config, CoordSel::empty(), position).await {
+                    Ok(homed) => {
+                        let aligned = driver.z_align_action(&self.motion_config, homed).await;
+                        let position = match aligned {
+                            Ok(p) | Err(p) => p,
+                        };
+                        match driver.homing_action(&self.motion_config, CoordSel::Z, position).await {
+                            Ok(p) if aligned.is_ok() => Ok(p),
+                            Ok(p) | Err(p) => Err(p),
+                        }
+                    }
+                    Err(p) => Err(p),
+                }
+            }
+        };
+        drop(driver);
+        match result
         {
             Ok(_pos) => {
                 self.set_last_planned_pos(&_pos).await;
             }
             Err(_pos) => {
-
-                self.set_last_planned_pos(&_pos).await;
+                // Still unknown if it was: The homing did not complete
+                if last_planned_pos.is_some() {
+                    self.set_last_planned_pos(&_pos).await;
+                }
                 // hwa::error!("Unable to complete homming. [Not yet] Raising SYS_ALARM");
                 // self.event_bus.publish_event(EventStatus::containing(EventFlags::SYS_ALARM)).await;
                 // return Err(())
@@ -1018,127 +1712,6 @@ impl MotionPlanner {
 }
 
 
-/// This method performs the cornering optimization algorithm on the given `RingBuffer`.
-///
-/// # Arguments
-///
-/// * `rb` - A mutable guard that locks the `hwa::ControllerMutexType` and provides access to the `RingBuffer`.
-///
-/// # Returns
-///
-/// * `Result<(), ()>` - Returns `Ok(())` if the cornering operation is successful, otherwise returns `Err(())`.
-///
-/// # Usage
-///
-/// This method is used to optimize the movement segments that are stored in the `RingBuffer`,
-/// ensuring smooth transitions and efficient processing of corners or turns in the motion path.
-/// It does this by adjusting the `speed_enter_mms` and `speed_exit_mms` fields of the segments
-/// based on their projected speeds and constraints.
-///
-/// The optimization runs a flood fill algorithm to determine the best possible speed values
-/// for entering and exiting each segment, aiming for minimal speed variations and smooth transitions.
-///
-/// The method is typically invoked during the planning phase of motion control, where multiple
-/// segments are queued, and the goal is to optimize the entire motion path by smoothing out the
-/// corners, hence enhancing overall motion performance.
-#[cfg(feature = "cornering")]
-fn perform_cornering(mut rb: MutexGuard<hwa::ControllerMutexType, RingBuffer>) -> Result<(), ()> {
-    let mut left_offset = 2;
-    let mut left_watermark = math::ZERO;
-    let mut right_watermark = math::ZERO;
-    // Assuming queued items are computed left to right:
-    // First, locate the top left segment. That one with null projection
-    // Also, set
-    for index in 2..rb.used {
-        match rb.planned_segment_from_tail(index) {
-            Ok(prev_segment_candidate) => {
-                if prev_segment_candidate
-                    .segment_data
-                    .proj_next
-                    .is_defined_positive()
-                {
-                    left_offset = index;
-                    left_watermark = prev_segment_candidate.segment_data.speed_enter_mms;
-                } else {
-                    break;
-                }
-            }
-            Err(_) => break,
-        }
-    }
-
-    #[allow(unused)]
-    let from_offset = left_offset;
-    #[allow(unused)]
-    let to_offset = 1;
-
-    hwa::debug!("Cornering algorithm START");
-    let mut right_offset = 1;
-
-    // Perform cornering optimization in a single pass with a flood fill algorithm
-    loop {
-        if right_offset > left_offset {
-            break;
-        } else if left_offset == right_offset {
-            let mid_segment = rb.mut_planned_segment_from_tail(left_offset)?;
-
-            mid_segment.segment_data.speed_enter_mms = left_watermark;
-            mid_segment.segment_data.speed_exit_mms = right_watermark;
-            break;
-        } else {
-            let (left_segment, right_segment) =
-                match rb.entries_from_tail(left_offset, right_offset) {
-                    (
-                        Some(PlanEntry::PlannedMove(_s, _, _, _)),
-                        Some(PlanEntry::PlannedMove(_t, _, _, _)),
-                    ) => (_s, _t),
-                    _ => panic!(""),
-                };
-
-            hwa::trace!("\tleft [{}] right[{}]", left_offset, right_offset);
-
-            let left_max_inc = (left_segment.segment_data.proj_next
-                * left_segment.segment_data.speed_target_mms)
-                .min(left_segment.segment_data.speed_exit_constrained_mms);
-
-            let right_max_inc = (right_segment.segment_data.proj_prev
-                * right_segment.segment_data.speed_target_mms)
-                .min(right_segment.segment_data.speed_enter_constrained_mms);
-
-            let water_left =
-                (left_watermark + left_max_inc).min(left_segment.segment_data.speed_target_mms);
-            let water_right =
-                (right_watermark + right_max_inc).min(right_segment.segment_data.speed_target_mms);
-
-            if water_left <= water_right {
-                // Flood at right
-                hwa::trace!("flood to right  [{} {}]", water_left, water_right);
-                left_segment.segment_data.speed_enter_mms = left_watermark;
-                left_segment.segment_data.speed_exit_mms = water_left;
-                right_segment.segment_data.speed_enter_mms = water_left;
-                right_segment.segment_data.speed_exit_mms = right_watermark;
-                left_watermark = water_left;
-                left_offset -= 1;
-            } else {
-                // Flood at left
-                hwa::trace!("flood to left [{} {}]", water_left, water_right);
-                left_segment.segment_data.speed_enter_mms = left_watermark;
-                left_segment.segment_data.speed_exit_mms = water_right;
-                right_segment.segment_data.speed_enter_mms = water_right;
-                right_segment.segment_data.speed_exit_mms = right_watermark;
-                right_watermark = water_right;
-                right_offset += 1;
-            }
-        }
-    }
-    #[cfg(feature = "native")]
-    if hwa::is_log_debug_enabled() {
-        display_content(&rb, from_offset, to_offset)?;
-        hwa::debug!("Cornering algorithm END");
-    }
-    Ok(())
-}
-
 #[cfg(feature = "native")]
 #[allow(unused)]
 pub fn display_content(
@@ -1166,6 +1739,33 @@ pub mod planner_test {
     use crate::hwa::controllers::{LinearMicrosegmentStepInterpolator, MotionConfig, StepPlanner};
     use crate::hwa;
 
+    #[test]
+    fn path_checked() {
+        use crate::hwa::controllers::motion::{ArcInterpolator, ArcPlane, SoftEndstops, MACHINE_KINEMATICS};
+        use crate::hwa::controllers::motion::MotionPlanner;
+        use crate::math;
+        use crate::math::Real;
+        use crate::tgeo::TVector;
+
+        let point = |x: f32, y: f32| TVector::from_coords(
+            Some(Real::from_f32(x)), Some(Real::from_f32(y)), Some(math::ZERO), Some(math::ZERO),
+        );
+        let mut soft_endstops = SoftEndstops::new();
+        soft_endstops.set_limits(&point(0.0, 0.0), &point(200.0, 200.0));
+        soft_endstops.enabled = true;
+        let (start, end) = (point(190.0, 100.0), point(190.0, 140.0));
+        let arc = |clockwise| ArcInterpolator::from_offsets(
+            ArcPlane::XY, clockwise, start, end, (math::ZERO, Real::from_f32(20.0)), Real::from_f32(0.01),
+        ).unwrap();
+
+        // Both ends are inside, but only the clockwise half stays in
+        assert!(MotionPlanner::check_path(&MACHINE_KINEMATICS, &soft_endstops, &start, arc(true)).is_ok());
+        assert!(matches!(
+            MotionPlanner::check_path(&MACHINE_KINEMATICS, &soft_endstops, &start, arc(false)),
+            Err(crate::control::CodeExecutionFailure::OutOfBounds)
+        ));
+    }
+
     //#[cfg(feature = "wip-tests")]
     #[test]
     fn discrete_positioning_case_1() {
@@ -1205,6 +1805,7 @@ pub mod planner_test {
                 None,
             ),
             tool_power: math::ZERO,
+            speed_rate: math::ONE,
             constraints: Constraints {
                 v_max: Real::from_f32(200.0),
                 a_max: Real::from_f32(3000.0),
@@ -1362,6 +1963,7 @@ pub mod planner_test {
                 None,
             ),
             tool_power: math::ZERO,
+            speed_rate: math::ONE,
             constraints: Constraints {
                 v_max: Real::from_f32(400.0),
                 a_max: Real::from_f32(3000.0),
@@ -1517,6 +2119,7 @@ pub mod planner_test {
                 None,
             ),
             tool_power: math::ZERO,
+            speed_rate: math::ONE,
             constraints: Constraints {
                 v_max: Real::from_f32(400.0),
                 a_max: Real::from_f32(3000.0),
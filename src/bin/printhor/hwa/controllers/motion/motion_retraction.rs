@@ -0,0 +1,97 @@
+//! Firmware retraction: The retraction of the filament (and Z hop) done by the firmware on G10 and
+//! undone on G11, as set by M207 and M208, so it can be tuned without slicing again.
+//!
+//! With automatic retraction (M209 S1), the E only moves of G1 are taken as retractions (when negative)
+//! and recoveries (when positive, if retracted). See [crate::hwa::controllers::MotionPlanner::retract].
+use crate::math;
+use crate::math::Real;
+
+/// The firmware retraction settings
+#[derive(Clone, Copy)]
+pub struct RetractionConfig {
+    /// The length of filament to retract, in mm (M207 S)
+    pub length: Real,
+    /// The speed of the retraction, in the units of the feed rate (M207 F)
+    pub speed: Real,
+    /// The height to lift Z while retracted, in mm (M207 Z)
+    pub z_hop: Real,
+    /// The length of filament to prime on top of the one retracted, in mm (M208 S)
+    pub recover_extra: Real,
+    /// The speed of the recovery, in the units of the feed rate. Zero to take the one of the retraction (M208 F)
+    pub recover_speed: Real,
+    /// Whether the E only moves are taken as retractions and recoveries (M209 S)
+    pub auto_retract: bool,
+}
+
+impl RetractionConfig {
+    /// No retraction
+    pub const fn new() -> Self {
+        Self {
+            length: Real::zero(),
+            speed: Real::zero(),
+            z_hop: Real::zero(),
+            recover_extra: Real::zero(),
+            recover_speed: Real::zero(),
+            auto_retract: false,
+        }
+    }
+
+    /// The speed of the recovery
+    pub fn recover_speed(&self) -> Real {
+        match self.recover_speed.is_defined_positive() {
+            true => self.recover_speed,
+            false => self.speed,
+        }
+    }
+}
+
+/// A retraction done and not recovered yet.
+///
+/// It keeps what was done, so the recovery undoes it even if the settings (or the tool) have changed since
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Retraction {
+    /// The length of filament retracted, in mm
+    pub length: Real,
+    /// The height Z is lifted, in mm
+    pub z_hop: Real,
+}
+
+impl Retraction {
+    /// A retraction of `length` mm of filament, with Z not lifted yet
+    pub const fn new(length: Real) -> Self {
+        Self {
+            length,
+            z_hop: Real::zero(),
+        }
+    }
+
+    /// The length of filament to prime to recover from it, with `recover_extra` mm on top
+    pub fn prime_length(&self, recover_extra: Real) -> Real {
+        (self.length + recover_extra).max(math::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retraction_recovery() {
+        let mut settings = RetractionConfig {
+            length: Real::from_f32(3.0),
+            speed: Real::from_f32(45.0),
+            ..RetractionConfig::new()
+        };
+        // The recovery takes the speed of the retraction unless set
+        assert_eq!(settings.recover_speed(), Real::from_f32(45.0));
+        settings.recover_speed = Real::from_f32(20.0);
+        assert_eq!(settings.recover_speed(), Real::from_f32(20.0));
+
+        // The recovery primes what was retracted, whatever the length set now
+        let retraction = Retraction::new(settings.length);
+        settings.length = Real::from_f32(1.0);
+        assert_eq!(retraction.prime_length(math::ZERO), Real::from_f32(3.0));
+        assert_eq!(retraction.prime_length(Real::from_f32(0.5)), Real::from_f32(3.5));
+        assert_eq!(retraction.prime_length(Real::from_f32(-5.0)), math::ZERO);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::hwa;
 use crate::hwa::controllers::{motion, PlanEntry};
+use crate::math::Real;
 
 /// A ring buffer data structure designed for storing `PlanEntry` items efficiently.
 ///
@@ -167,13 +168,32 @@ impl RingBuffer {
             (r.get_mut(0), l.get_mut(index2))
         }
     }
+
+    /// Rescales the target speed of the planned moves to the speed rate `rate` (as a factor).
+    ///
+    /// The entry and exit speeds are left as they are, so the look-ahead is expected to be planned again.
+    pub fn set_speed_rate(&mut self, rate: Real) {
+        let len = self.data.len();
+        for offset in 0..self.used as usize {
+            if let PlanEntry::PlannedMove(segment, _, _, _) = &mut self.data[(self.head as usize + offset) % len] {
+                let sd = &mut segment.segment_data;
+                if sd.speed_rate.is_defined_positive() && rate.is_defined_positive() {
+                    let ratio = rate / sd.speed_rate;
+                    sd.speed_target_mms *= ratio;
+                    sd.constraints.v_max *= ratio;
+                    sd.speed_rate = rate;
+                }
+            }
+        }
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
     use printhor_hwa_common::CommChannel;
-    use crate::hwa::controllers::MovType;
+    use crate::hwa::controllers::{HomingRequest, MovType};
+    use crate::tgeo::CoordSel;
     use super::*;
     
     #[test]
@@ -195,7 +215,7 @@ mod tests {
         let mut rb = RingBuffer::new();
         rb.head = 1;
         rb.used = 1;
-        rb.data[0] = PlanEntry::Executing(MovType::Homing(CommChannel::Internal), true);
+        rb.data[0] = PlanEntry::Executing(MovType::Homing(HomingRequest::Home(CoordSel::empty()), CommChannel::Internal), true);
 
         let t = rb.planned_segment_from_tail(1);
         assert!(t.is_err(), "No planned entry");
@@ -210,9 +230,9 @@ mod tests {
         let mut rb = RingBuffer::new();
         rb.head = 1;
         rb.used = 3;
-        rb.data[1] = PlanEntry::Executing(MovType::Homing(CommChannel::Internal), true);
-        rb.data[2] = PlanEntry::Dwell(CommChannel::Internal, true);
-        rb.data[3] = PlanEntry::Homing(CommChannel::Internal, true);
+        rb.data[1] = PlanEntry::Executing(MovType::Homing(HomingRequest::Home(CoordSel::empty()), CommChannel::Internal), true);
+        rb.data[2] = PlanEntry::Dwell(None, CommChannel::Internal, true);
+        rb.data[3] = PlanEntry::Homing(HomingRequest::Home(CoordSel::empty()), CommChannel::Internal, true);
 
         assert!(matches!(rb.entry_from_tail(0), Some(PlanEntry::Executing(..))));
         assert!(matches!(rb.entry_from_tail(1), Some(PlanEntry::Dwell(..))));
@@ -18,6 +18,7 @@ use crate::tgeo::TVector;
 /// - `unit_vector_dir`: Unit vector for the direction of movement.
 /// - `dest_pos`: Destination position vector.
 /// - `tool_power`: Tool power utilized in the segment.
+/// - `speed_rate`: Speed rate (M220) the target speed is planned with.
 /// - `constraints`: Motion constraints applicable to the segment.
 #[derive(Clone, Copy)]
 pub struct SegmentData {
@@ -47,6 +48,8 @@ pub struct SegmentData {
     #[allow(unused)]
     /// Tool power utilized in the segment.
     pub tool_power: Real,
+    /// Speed rate (M220) the target speed is planned with, as a factor.
+    pub speed_rate: Real,
     /// Motion constraints applicable to the segment.
     pub constraints: Constraints,
 }
@@ -185,6 +188,7 @@ mod tests {
             unit_vector_dir: TVector::one(),
             dest_pos: TVector::one() * math::ONE_HUNDRED,
             tool_power: Real::from_f32(5.0),
+            speed_rate: math::ONE,
             constraints: Constraints {
                 v_max: math::ONE_HUNDRED,
                 a_max: math::ONE_THOUSAND,
@@ -0,0 +1,413 @@
+//! Input shaping: Cancellation of the ringing of the machine at its resonance frequencies.
+//!
+//! The commanded trajectory of each motor is convolved with a train of impulses (the shaper), so
+//! the vibration excited by each impulse cancels out with the ones of the others. The shapers are
+//! set per stepper channel (X, Y, Z) with M593. The extruder is never shaped.
+//!
+//! The shaping is done in motor space, right before the step generation of [crate::control::task_stepper],
+//! so it does not alter the motion plan. The shaped trajectory lags behind the commanded one by up to
+//! the duration of the shaper, and catches up with it when the machine stops.
+use crate::math;
+use crate::math::Real;
+use crate::tgeo::TVector;
+use strum::{AsRefStr, EnumString, VariantNames};
+
+/// The maximum number of impulses of a shaper
+pub const MAX_SHAPER_IMPULSES: usize = 4;
+
+/// The number of micro-segments kept to shape the trajectory. A shaper lasting longer than them
+/// sees the position before the oldest one
+const INPUT_SHAPING_HISTORY: usize = 64;
+
+/// The tolerated residual vibration of the EI shapers (5%)
+fn vibration_tolerance() -> Real {
+    Real::from_lit(5, 2)
+}
+
+/// The kinds of shaper, from shorter to more robust against a wrong frequency
+#[derive(Clone, Copy, PartialEq, Debug, EnumString, AsRefStr, VariantNames)]
+#[strum(ascii_case_insensitive)]
+pub enum ShaperType {
+    /// No shaping
+    #[strum(serialize = "none")]
+    None,
+    /// Zero Vibration: two impulses, half a period long
+    #[strum(serialize = "zv")]
+    ZV,
+    /// Modified ZV: three impulses, 3/4 of a period long
+    #[strum(serialize = "mzv")]
+    MZV,
+    /// ZV-Derivative: three impulses, one period long
+    #[strum(serialize = "zvd")]
+    ZVD,
+    /// Extra-Insensitive: three impulses, one period long
+    #[strum(serialize = "ei")]
+    EI,
+    /// Two-hump Extra-Insensitive: four impulses, 1.5 periods long
+    #[strum(serialize = "2hump_ei")]
+    TwoHumpEI,
+}
+
+/// A shaper: a train of impulses tuned to the resonance frequency and damping ratio of an axis
+#[derive(Clone, Copy, PartialEq)]
+pub struct InputShaper {
+    kind: ShaperType,
+    /// The resonance frequency, in Hz
+    frequency: Real,
+    /// The damping ratio of the resonance
+    damping: Real,
+    /// The delay (in seconds) and amplitude of each impulse. The amplitudes add up to one
+    impulses: [(Real, Real); MAX_SHAPER_IMPULSES],
+    num_impulses: usize,
+}
+
+impl InputShaper {
+    /// No shaping: a single impulse
+    pub const fn none() -> Self {
+        Self {
+            kind: ShaperType::None,
+            frequency: Real::zero(),
+            damping: Real::zero(),
+            impulses: [
+                (Real::zero(), Real::one()),
+                (Real::zero(), Real::zero()),
+                (Real::zero(), Real::zero()),
+                (Real::zero(), Real::zero()),
+            ],
+            num_impulses: 1,
+        }
+    }
+
+    /// The shaper of the given kind for a resonance at `frequency` Hz with the given damping ratio
+    /// (within [0, 1)). No shaping if the frequency is not positive
+    pub fn new(kind: ShaperType, frequency: Real, damping: Real) -> Self {
+        if !frequency.is_defined_positive() || kind == ShaperType::None {
+            return Self::none();
+        }
+        let damping = damping.max(math::ZERO).min(Real::from_lit(99, 2));
+        let damped = (math::ONE - damping * damping).sqrt().unwrap_or(math::ONE);
+        // The damped period of the resonance
+        let td = math::ONE / (frequency * damped);
+        let k = (-damping * math::PI / damped).exp();
+        let tol = vibration_tolerance();
+        let (amplitudes, delays): ([Real; MAX_SHAPER_IMPULSES], [Real; MAX_SHAPER_IMPULSES]) = match kind {
+            ShaperType::None => unreachable!(),
+            ShaperType::ZV => (
+                [math::ONE, k, math::ZERO, math::ZERO],
+                [math::ZERO, math::HALF, math::ZERO, math::ZERO],
+            ),
+            ShaperType::MZV => {
+                let k = (-Real::from_lit(75, 2) * damping * math::PI / damped).exp();
+                let a1 = math::ONE - math::ONE / math::TWO.sqrt().unwrap_or(math::ONE);
+                let a2 = (math::TWO.sqrt().unwrap_or(math::ONE) - math::ONE) * k;
+                (
+                    [a1, a2, a1 * k * k, math::ZERO],
+                    [math::ZERO, Real::from_lit(375, 3), Real::from_lit(75, 2), math::ZERO],
+                )
+            }
+            ShaperType::ZVD => (
+                [math::ONE, math::TWO * k, k * k, math::ZERO],
+                [math::ZERO, math::HALF, math::ONE, math::ZERO],
+            ),
+            ShaperType::EI => {
+                let a1 = Real::from_lit(25, 2) * (math::ONE + tol);
+                let a2 = math::HALF * (math::ONE - tol) * k;
+                (
+                    [a1, a2, a1 * k * k, math::ZERO],
+                    [math::ZERO, math::HALF, math::ONE, math::ZERO],
+                )
+            }
+            ShaperType::TwoHumpEI => {
+                // (3X^2 + 2X + 3V^2) / 16X, with X = (V^2 (sqrt(1 - V^2) + 1))^(1/3), for V = 5%
+                let a1 = Real::from_lit(159797, 6);
+                let a2 = (math::HALF - a1) * k;
+                (
+                    [a1, a2, a2 * k, a1 * k * k * k],
+                    [math::ZERO, math::HALF, math::ONE, math::ONE_AND_HALF],
+                )
+            }
+        };
+        let num_impulses = match kind {
+            ShaperType::ZV => 2,
+            ShaperType::TwoHumpEI => 4,
+            _ => 3,
+        };
+        let total = amplitudes.iter().fold(math::ZERO, |acc, a| acc + *a);
+        let mut impulses = [(math::ZERO, math::ZERO); MAX_SHAPER_IMPULSES];
+        for (impulse, (a, d)) in impulses.iter_mut().zip(amplitudes.iter().zip(delays.iter())) {
+            *impulse = (*d * td, *a / total);
+        }
+        Self {
+            kind,
+            frequency,
+            damping,
+            impulses,
+            num_impulses,
+        }
+    }
+
+    pub fn kind(&self) -> ShaperType {
+        self.kind
+    }
+
+    /// The resonance frequency, in Hz
+    pub fn frequency(&self) -> Real {
+        self.frequency
+    }
+
+    /// The damping ratio
+    pub fn damping(&self) -> Real {
+        self.damping
+    }
+
+    /// The (delay, amplitude) of each impulse
+    pub fn impulses(&self) -> &[(Real, Real)] {
+        &self.impulses[..self.num_impulses]
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.num_impulses > 1
+    }
+
+    /// The delay of the last impulse, in seconds
+    pub fn duration(&self) -> Real {
+        self.impulses[self.num_impulses - 1].0
+    }
+}
+
+/// The state of the input shaping along the step generation.
+///
+/// The positions are in (micro)steps of each motor, relative to the last time the machine stopped.
+pub struct InputShaping {
+    /// The shapers of the X, Y and Z channels
+    shapers: [InputShaper; 3],
+    /// The shapers to use from the next stop on
+    pending: Option<[InputShaper; 3]>,
+    /// The duration and the commanded position at the end of the last micro-segments (a ring)
+    history: [(Real, TVector<Real>); INPUT_SHAPING_HISTORY],
+    /// The index of the next entry of the history
+    head: usize,
+    /// The number of entries of the history
+    len: usize,
+    /// The commanded position before the oldest entry of the history
+    history_origin: TVector<Real>,
+    /// The commanded position at the start of the segment
+    segment_origin: TVector<Real>,
+    /// The commanded position at the end of the last micro-segment
+    commanded: TVector<Real>,
+    /// The steps already given (the rounded shaped position)
+    emitted: TVector<Real>,
+    /// The time since the commanded position last changed
+    still_time: Real,
+}
+
+impl InputShaping {
+    pub const fn new() -> Self {
+        Self {
+            shapers: [InputShaper::none(); 3],
+            pending: None,
+            history: [(Real::zero(), TVector::new()); INPUT_SHAPING_HISTORY],
+            head: 0,
+            len: 0,
+            history_origin: TVector::new(),
+            segment_origin: TVector::new(),
+            commanded: TVector::new(),
+            emitted: TVector::new(),
+            still_time: Real::zero(),
+        }
+    }
+
+    /// Whether any channel is shaped
+    pub fn is_active(&self) -> bool {
+        self.shapers.iter().any(|s| s.is_active())
+    }
+
+    /// Sets the shapers of the X, Y and Z channels. They are taken at once when the machine is
+    /// stopped, or else when it stops
+    pub fn set_shapers(&mut self, shapers: &[InputShaper; 3]) {
+        if *shapers == self.shapers {
+            self.pending = None;
+        } else if self.is_settled() {
+            if !self.is_active() {
+                self.reset();
+            }
+            self.shapers = *shapers;
+            self.pending = None;
+        } else {
+            self.pending = Some(*shapers);
+        }
+    }
+
+    /// Forgets the trajectory, taking the current position of the motors as the origin
+    pub fn reset(&mut self) {
+        let origin = TVector::zero();
+        self.head = 0;
+        self.len = 0;
+        self.history_origin = origin;
+        self.segment_origin = origin;
+        self.commanded = origin;
+        self.emitted = origin;
+        self.still_time = math::ZERO;
+    }
+
+    /// Whether the shaped trajectory has caught up with the commanded one
+    pub fn is_settled(&self) -> bool {
+        let duration = self.shapers.iter()
+            .filter(|s| s.is_active())
+            .fold(math::ZERO, |acc, s| acc.max(s.duration()));
+        self.still_time >= duration
+    }
+
+    /// Advances a micro-segment lasting `duration` seconds, at the end of which the motors are
+    /// commanded `segment_steps` away from the start of the segment. The steps not given are zero.
+    ///
+    /// Returns the (signed) steps of each motor along the micro-segment
+    pub fn advance(&mut self, duration: Real, segment_steps: &TVector<Real>) -> TVector<Real> {
+        let commanded = self.segment_origin + segment_steps.map_nan(&math::ZERO);
+        if commanded == self.commanded {
+            self.still_time += duration;
+        } else {
+            self.still_time = math::ZERO;
+        }
+        self.commanded = commanded;
+        self.record(duration, commanded);
+
+        let shaped = TVector::from_coords(
+            Some(self.shaped_coord(0, |p| p.x)),
+            Some(self.shaped_coord(1, |p| p.y)),
+            Some(self.shaped_coord(2, |p| p.z)),
+            commanded.e,
+        ).round();
+        let steps = shaped - self.emitted;
+        self.emitted = shaped;
+        steps
+    }
+
+    /// Ends the segment: The next one starts where it ends.
+    /// When settled, the pending shapers (if any) are taken and the origin is moved to the current position
+    pub fn end_segment(&mut self) {
+        self.segment_origin = self.commanded;
+        if self.is_settled() {
+            let offset = self.emitted;
+            self.segment_origin -= offset;
+            self.commanded -= offset;
+            self.emitted = TVector::zero();
+            self.history_origin = self.commanded;
+            self.head = 0;
+            self.len = 0;
+            if let Some(shapers) = self.pending.take() {
+                self.shapers = shapers;
+            }
+        }
+    }
+
+    fn record(&mut self, duration: Real, commanded: TVector<Real>) {
+        if self.len == INPUT_SHAPING_HISTORY {
+            // The oldest entry is the one to overwrite
+            self.history_origin = self.history[self.head].1;
+            self.len -= 1;
+        }
+        self.history[self.head] = (duration, commanded);
+        self.head = (self.head + 1) % INPUT_SHAPING_HISTORY;
+        self.len += 1;
+    }
+
+    /// The commanded position `age` seconds before the end of the last micro-segment,
+    /// interpolated linearly along the micro-segments
+    fn commanded_at(&self, age: Real) -> TVector<Real> {
+        let mut end_age = math::ZERO;
+        for i in 0..self.len {
+            let index = (self.head + INPUT_SHAPING_HISTORY - 1 - i) % INPUT_SHAPING_HISTORY;
+            let (duration, end) = self.history[index];
+            let start = match i + 1 < self.len {
+                true => self.history[(index + INPUT_SHAPING_HISTORY - 1) % INPUT_SHAPING_HISTORY].1,
+                false => self.history_origin,
+            };
+            let start_age = end_age + duration;
+            if age <= start_age && duration.is_defined_positive() {
+                return end + (start - end) * ((age - end_age) / duration);
+            }
+            end_age = start_age;
+        }
+        self.history_origin
+    }
+
+    /// The shaped position of a coordinate: the sum of the commanded positions at the delay of
+    /// each impulse, weighted by its amplitude
+    fn shaped_coord<F>(&self, shaper: usize, coord: F) -> Real
+    where
+        F: Fn(&TVector<Real>) -> Option<Real>,
+    {
+        self.shapers[shaper].impulses().iter().fold(math::ZERO, |acc, (delay, amplitude)| {
+            let position = match delay.is_zero() {
+                true => self.commanded,
+                false => self.commanded_at(*delay),
+            };
+            acc + *amplitude * coord(&position).unwrap_or(math::ZERO)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Real, b: f32) -> bool {
+        (a - Real::from_f32(b)).abs() < Real::from_f32(0.001)
+    }
+
+    #[test]
+    fn shaper_impulses() {
+        let f = Real::from_f32(50.0);
+        let zv = InputShaper::new(ShaperType::ZV, f, math::ZERO);
+        // Undamped: two equal impulses half a period apart
+        assert_eq!(zv.impulses().len(), 2);
+        assert!(close(zv.impulses()[0].1, 0.5));
+        assert!(close(zv.impulses()[1].0, 0.01));
+        assert!(close(zv.duration(), 0.01));
+
+        for kind in [ShaperType::MZV, ShaperType::ZVD, ShaperType::EI, ShaperType::TwoHumpEI] {
+            let shaper = InputShaper::new(kind, f, Real::from_f32(0.1));
+            let total = shaper.impulses().iter().fold(math::ZERO, |acc, i| acc + i.1);
+            assert!(close(total, 1.0));
+        }
+        assert!(close(InputShaper::new(ShaperType::TwoHumpEI, f, math::ZERO).duration(), 0.03));
+        assert!(!InputShaper::new(ShaperType::ZV, math::ZERO, math::ZERO).is_active());
+        assert_eq!("2HUMP_EI".parse::<ShaperType>(), Ok(ShaperType::TwoHumpEI));
+    }
+
+    #[test]
+    fn shaped_steps() {
+        let mut shaping = InputShaping::new();
+        let zv = InputShaper::new(ShaperType::ZV, Real::from_f32(50.0), math::ZERO);
+        shaping.set_shapers(&[zv, InputShaper::none(), InputShaper::none()]);
+        assert!(shaping.is_active());
+
+        // A step of 100 steps in X and Y: Y at once, X half now and half 10 ms later
+        let dt = Real::from_f32(0.002);
+        let target = TVector::from_coords(
+            Some(Real::from_f32(100.0)), Some(Real::from_f32(100.0)), None, None,
+        );
+        let steps = shaping.advance(dt, &target);
+        assert_eq!(steps.x, Some(Real::from_f32(50.0)));
+        assert_eq!(steps.y, Some(Real::from_f32(100.0)));
+        shaping.end_segment();
+        assert!(!shaping.is_settled());
+
+        let mut total = steps;
+        while !shaping.is_settled() {
+            total += shaping.advance(dt, &TVector::zero());
+        }
+        shaping.end_segment();
+        assert_eq!(total.x, Some(Real::from_f32(100.0)));
+        assert_eq!(total.y, Some(Real::from_f32(100.0)));
+
+        // A ramp of 10 steps per ms is delayed 5 ms, on average
+        let mut total = TVector::zero();
+        for i in 1..=20 {
+            let ramp = TVector::from_coords(Some(Real::from_f32(10.0 * i as f32)), None, None, None);
+            total += shaping.advance(Real::from_f32(0.001), &ramp);
+        }
+        assert_eq!(total.x, Some(Real::from_f32(150.0)));
+    }
+}
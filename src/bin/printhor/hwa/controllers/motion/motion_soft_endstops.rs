@@ -0,0 +1,96 @@
+//! Soft endstops: The limits of the position of the tool, checked before a move is queued (M211).
+//!
+//! A move ending out of the limits is either clamped to them or rejected with
+//! [OutOfBounds](crate::control::CodeExecutionFailure::OutOfBounds), so nothing is queued.
+//! They are checked in tool space, on top of the workspace of the kinematics (See
+//! [crate::hwa::controllers::motion::Kinematics::clamp_to_workspace]). E is never limited.
+use crate::math::Real;
+use crate::tgeo::{CoordSel, TVector};
+
+/// What is done with a move ending out of the soft endstops
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SoftEndstopAction {
+    /// The move ends at the limits instead
+    Clamp,
+    /// The move is not queued
+    Reject,
+}
+
+/// The soft endstops settings
+#[derive(Clone, Copy)]
+pub struct SoftEndstops {
+    /// Whether the limits are checked (M211 S)
+    pub enabled: bool,
+    /// What is done with the moves out of the limits
+    pub action: SoftEndstopAction,
+    /// The lowest X, Y and Z, in mm. A coordinate not set is not limited
+    pub min: TVector<Real>,
+    /// The highest X, Y and Z, in mm. A coordinate not set is not limited
+    pub max: TVector<Real>,
+}
+
+impl SoftEndstops {
+    /// Disabled, with no limits
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            action: SoftEndstopAction::Reject,
+            min: TVector::new(),
+            max: TVector::new(),
+        }
+    }
+
+    /// Sets the limits. E is dropped, as it is never limited
+    pub fn set_limits(&mut self, min: &TVector<Real>, max: &TVector<Real>) {
+        self.min = min.with_coord(CoordSel::E, None);
+        self.max = max.with_coord(CoordSel::E, None);
+    }
+
+    /// Checks the position a move ends at.
+    ///
+    /// Returns the position to move to (clamped, if so set), or an error when it is rejected
+    pub fn apply(&self, pos: &TVector<Real>) -> Result<TVector<Real>, ()> {
+        if !self.enabled {
+            return Ok(*pos);
+        }
+        let clamped = pos.clamp(self.max).clamp_min(self.min);
+        if clamped == *pos {
+            Ok(clamped)
+        } else {
+            match self.action {
+                SoftEndstopAction::Clamp => Ok(clamped),
+                SoftEndstopAction::Reject => Err(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math;
+
+    #[test]
+    fn soft_endstops() {
+        let pos = |x: f32, z: f32| {
+            TVector::from_coords(Some(Real::from_f32(x)), Some(math::ZERO), Some(Real::from_f32(z)), Some(Real::from_f32(500.0)))
+        };
+        let mut soft_endstops = SoftEndstops::new();
+        soft_endstops.set_limits(
+            &TVector::from_coords(Some(math::ZERO), Some(math::ZERO), Some(math::ZERO), None),
+            &TVector::from_coords(Some(Real::from_f32(200.0)), Some(Real::from_f32(200.0)), None, None),
+        );
+        // Disabled, anything goes
+        assert!(soft_endstops.apply(&pos(9999.0, 0.0)) == Ok(pos(9999.0, 0.0)));
+
+        soft_endstops.enabled = true;
+        assert!(soft_endstops.apply(&pos(100.0, 50.0)) == Ok(pos(100.0, 50.0)));
+        assert!(soft_endstops.apply(&pos(9999.0, 0.0)).is_err());
+        assert!(soft_endstops.apply(&pos(100.0, -1.0)).is_err());
+        // Z has no upper limit, and E is never limited
+        assert!(soft_endstops.apply(&pos(100.0, 9999.0)) == Ok(pos(100.0, 9999.0)));
+
+        soft_endstops.action = SoftEndstopAction::Clamp;
+        assert!(soft_endstops.apply(&pos(9999.0, -1.0)) == Ok(pos(200.0, 0.0)));
+    }
+}
@@ -1,15 +1,56 @@
+use crate::math;
 use crate::math::Real;
-use crate::tgeo::TVector;
+use crate::tgeo::{CoordSel, TVector};
+use crate::hwa::controllers::motion::{ArcPlane, Retraction};
 
-/// Represents the motion status with optional real and planned positions,
+/// The units in which the lengths of the gcodes are given (G20, G21)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UnitMode {
+    /// G21
+    Millimeters,
+    /// G20
+    Inches,
+}
+
+impl UnitMode {
+    /// The length in millimeters of one unit
+    pub fn mm_per_unit(&self) -> Real {
+        match self {
+            UnitMode::Millimeters => math::ONE,
+            UnitMode::Inches => Real::new(254, 1),
+        }
+    }
+}
+
+/// The number of work coordinate systems (G54 to G59)
+pub const NUM_WORK_COORDINATE_SYSTEMS: usize = 6;
+
+/// A null offset of the X, Y and Z axes
+pub const NO_OFFSET: TVector<Real> = TVector::from_coords(
+    Some(math::ZERO), Some(math::ZERO), Some(math::ZERO), None
+);
+
+/// Represents the motion status with an optional planned position,
 /// absolute positioning flag, and an optional laser flag when the `with-laser` feature is enabled.
 pub struct MotionStatus {
-    /// Last recorded real position.
-    pub last_real_pos: Option<TVector<Real>>,
     /// Last planned position.
     pub last_planned_pos: Option<TVector<Real>>,
     /// Flag indicating if absolute positioning is enabled.
     pub absolute_positioning: bool,
+    /// The plane selected for arc moves (G17, G18, G19).
+    pub arc_plane: ArcPlane,
+    /// The units of the lengths given in the gcodes (G20, G21).
+    pub unit_mode: UnitMode,
+    /// The offsets of the work coordinate systems (G54 to G59) from the machine coordinates.
+    pub work_offsets: [TVector<Real>; NUM_WORK_COORDINATE_SYSTEMS],
+    /// The selected work coordinate system (0 for G54 to 5 for G59).
+    pub work_coordinate_system: usize,
+    /// The offset set by G92, on top of the selected work coordinate system.
+    pub g92_offset: TVector<Real>,
+    /// Flag indicating if the G92 offset is applied (G92.2 suspends it).
+    pub g92_active: bool,
+    /// The firmware retraction done (G10) and not recovered yet (G11).
+    pub retraction: Option<Retraction>,
     /// Flag indicating if the laser is enabled (only present when the `with-laser` feature is enabled).
     #[cfg(feature = "with-laser")]
     #[allow(unused)]
@@ -21,15 +62,127 @@ impl MotionStatus {
     ///
     /// # Returns
     ///
-    /// A new instance of `MotionStatus` with `last_real_pos` and `last_planned_pos` set to `None`,
-    /// `absolute_positioning` set to `true`, and `laser` set to `false` when the `with-laser` feature is enabled.
+    /// A new instance of `MotionStatus` with `last_planned_pos` set to `None`,
+    /// `absolute_positioning` set to `true`, `arc_plane` set to XY, `unit_mode` set to millimeters, no work offsets with G54 selected, and `laser` set to `false` when the `with-laser` feature is enabled.
     pub const fn new() -> Self {
         Self {
-            last_real_pos: None,
             last_planned_pos: None,
             absolute_positioning: true,
+            arc_plane: ArcPlane::XY,
+            unit_mode: UnitMode::Millimeters,
+            work_offsets: [NO_OFFSET; NUM_WORK_COORDINATE_SYSTEMS],
+            work_coordinate_system: 0,
+            g92_offset: NO_OFFSET,
+            g92_active: false,
+            retraction: None,
             #[cfg(feature = "with-laser")]
             laser: false,
         }
     }
+
+    /// The offset from the machine coordinates to the work coordinates:
+    /// The one of the selected work coordinate system plus the G92 offset, if active,
+    /// and the Z hop of the firmware retraction, if retracted.
+    pub fn work_offset(&self) -> TVector<Real> {
+        let offset = match self.g92_active {
+            true => self.work_offsets[self.work_coordinate_system] + self.g92_offset,
+            false => self.work_offsets[self.work_coordinate_system],
+        };
+        match self.retraction {
+            Some(retraction) => offset + NO_OFFSET.with_coord(CoordSel::Z, Some(retraction.z_hop)),
+            None => offset,
+        }
+    }
+
+    /// The G92 offset, or none if not active
+    fn active_g92_offset(&self) -> TVector<Real> {
+        match self.g92_active {
+            true => self.g92_offset,
+            false => NO_OFFSET,
+        }
+    }
+
+    /// Sets the offset of a work coordinate system (0 for G54 to 5 for G59) so that the given X, Y and Z
+    /// are the ones of the current position in that system (G10 L20). The coords not given are kept.
+    ///
+    /// Returns false if the position is unknown (not homed), so there is nothing to offset from
+    pub fn set_work_position(&mut self, system: usize, pos: &TVector<Real>) -> bool {
+        let machine_pos = match self.last_planned_pos {
+            Some(machine_pos) => machine_pos,
+            None => return false,
+        };
+        // Missing coords give None, so the current offset is kept
+        let offset = machine_pos - self.active_g92_offset() - pos.with_coord(CoordSel::E, None);
+        self.work_offsets[system] = self.work_offsets[system].apply(&offset);
+        true
+    }
+
+    /// Sets the G92 offset so that the given X, Y and Z are the ones of the current position, and the
+    /// position of E, which has no offset (G92). The coords not given are kept.
+    ///
+    /// The position is unknown until homed: There is nothing to offset from then, so X, Y and Z are
+    /// left as they are, and so is E, as homing starts it from zero
+    pub fn set_g92_position(&mut self, pos: &TVector<Real>) {
+        let machine_pos = match self.last_planned_pos.as_mut() {
+            Some(machine_pos) => machine_pos,
+            None => return,
+        };
+        if pos.e.is_some() {
+            machine_pos.e = pos.e;
+        }
+        if pos.x.is_none() && pos.y.is_none() && pos.z.is_none() {
+            return;
+        }
+        let offset = *machine_pos
+            - self.work_offsets[self.work_coordinate_system]
+            - pos.with_coord(CoordSel::E, None);
+        self.g92_offset = self.active_g92_offset().apply(&offset);
+        self.g92_active = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(x: f32, y: f32, z: f32, e: f32) -> TVector<Real> {
+        TVector::from_coords(
+            Some(Real::from_f32(x)), Some(Real::from_f32(y)), Some(Real::from_f32(z)), Some(Real::from_f32(e)),
+        )
+    }
+
+    #[test]
+    fn work_coordinates() {
+        let mut st = MotionStatus::new();
+        // Not homed: G92 and G10 L20 have nothing to offset from
+        assert!(!st.set_work_position(0, &vector(0.0, 0.0, 0.0, 0.0)));
+        st.set_g92_position(&vector(0.0, 0.0, 0.0, 5.0));
+        assert!(st.last_planned_pos.is_none());
+        assert!(!st.g92_active);
+
+        st.last_planned_pos = Some(vector(100.0, 50.0, 10.0, 20.0));
+        // G10 L20 P1 X0 Y0: The current position is the origin of G54. Z is kept
+        assert!(st.set_work_position(0, &TVector::from_coords(Some(math::ZERO), Some(math::ZERO), None, None)));
+        assert!(st.work_offsets[0] == NO_OFFSET.with_coord(CoordSel::X, Some(Real::from_f32(100.0)))
+            .with_coord(CoordSel::Y, Some(Real::from_f32(50.0))));
+        assert!(st.work_offset() == st.work_offsets[0]);
+
+        // G92 Z5 E0: On top of G54. E has no offset
+        st.set_g92_position(&TVector::from_coords(None, None, Some(Real::from_f32(5.0)), Some(math::ZERO)));
+        assert!(st.g92_active);
+        assert!(st.g92_offset == NO_OFFSET.with_coord(CoordSel::Z, Some(Real::from_f32(5.0))));
+        assert!(st.last_planned_pos.unwrap() == vector(100.0, 50.0, 10.0, 0.0));
+        // So the work position is X0 Y0 Z5
+        let work_pos = st.last_planned_pos.unwrap() - st.work_offset();
+        assert!(work_pos.with_coord(CoordSel::E, None) == vector(0.0, 0.0, 5.0, 0.0).with_coord(CoordSel::E, None));
+
+        // G92 E only does not change the offset
+        st.set_g92_position(&TVector::from_coords(None, None, None, Some(Real::from_f32(3.0))));
+        assert!(st.g92_offset == NO_OFFSET.with_coord(CoordSel::Z, Some(Real::from_f32(5.0))));
+        assert!(st.last_planned_pos.unwrap().e == Some(Real::from_f32(3.0)));
+
+        // G10 L20 with G92 active leaves the G92 offset out
+        assert!(st.set_work_position(1, &TVector::from_coords(None, None, Some(math::ZERO), None)));
+        assert!(st.work_offsets[1] == NO_OFFSET.with_coord(CoordSel::Z, Some(Real::from_f32(5.0))));
+    }
 }
@@ -53,6 +53,9 @@ pub struct SoftTimerDriver {
     /// Flags indicating the current forward direction stepper channels.
     current_stepper_dir_fwd_flags: StepperChannel,
 
+    /// Set by a quick stop: The micro-segments are discarded until the next move.
+    aborted: bool,
+
     /// The number of pulses generated for each stepper channel (for debugging/testing).
     #[cfg(any(feature = "assert-motion", test))]
     pub pulses: [u32; 4],
@@ -101,15 +104,14 @@ impl SoftTimerDriver {
                             if self.current_stepper_enable_flags
                                 != self.current.stepper_enable_flags
                             {
-                                _drv.pins.enable(self.current.stepper_enable_flags);
+                                _drv.enable_steppers(self.current.stepper_enable_flags);
                                 self.current_stepper_enable_flags =
                                     self.current.stepper_enable_flags;
                             }
                             if self.current_stepper_dir_fwd_flags
                                 != self.current.stepper_dir_fwd_flags
                             {
-                                _drv.pins
-                                    .set_forward_direction(self.current.stepper_dir_fwd_flags);
+                                _drv.set_forward_direction(self.current.stepper_dir_fwd_flags);
                                 self.current_stepper_dir_fwd_flags =
                                     self.current.stepper_dir_fwd_flags;
                             }
@@ -376,6 +378,7 @@ impl SoftTimer {
             drv: None,
             current_stepper_enable_flags: StepperChannel::empty(),
             current_stepper_dir_fwd_flags: StepperChannel::empty(),
+            aborted: false,
             #[cfg(any(feature = "assert-motion", test))]
             pulses: [0, 0, 0, 0],
         })))
@@ -477,7 +480,9 @@ impl SoftTimer {
     ) -> Poll<()> {
         critical_section::with(|cs| {
             let mut r = self.0.borrow_ref_mut(cs);
-            if r.num_queued as usize >= r.queue.len() {
+            if r.aborted {
+                Poll::Ready(())
+            } else if r.num_queued as usize >= r.queue.len() {
                 r.waker.register(cx.waker());
                 Poll::Pending
             } else {
@@ -559,6 +564,36 @@ impl SoftTimer {
             r.current_stepper_dir_fwd_flags = StepperChannel::UNSET;
         });
     }
+
+    /// Stops stepping at once (quick stop).
+    ///
+    /// The queued micro-segments and the ongoing one are discarded, and so are the ones
+    /// pushed afterward, until [SoftTimer::resume] is called.
+    pub fn abort(&self) {
+        critical_section::with(|cs| {
+            let mut r = self.0.borrow_ref_mut(cs);
+            r.queue = [None; TIMER_QUEUE_SIZE];
+            r.head = 0;
+            r.tail = 0;
+            r.num_queued = 0;
+            r.current = StepPlanner::new();
+            r.state = State::Idle;
+            r.aborted = true;
+            r.waker.wake();
+        });
+    }
+
+    /// Accepts micro-segments again after [SoftTimer::abort].
+    pub fn resume(&self) {
+        critical_section::with(|cs| {
+            self.0.borrow_ref_mut(cs).aborted = false;
+        });
+    }
+
+    /// True while the micro-segments are being discarded after [SoftTimer::abort].
+    pub fn is_aborted(&self) -> bool {
+        critical_section::with(|cs| self.0.borrow_ref(cs).aborted)
+    }
     
     /// Polls the current state of the `SoftTimer` to determine if the flush operation
     /// can be completed.
@@ -167,6 +167,43 @@ impl MultiTimer {
         self.width = width;
     }
 
+    /// Spreads the given steps of each channel uniformly along the width.
+    ///
+    /// # Arguments
+    ///
+    /// * `steps` - The number of steps of each channel. Channels with no steps are disabled.
+    /// * `width` - The width to set.
+    pub fn set_uniform_steps(&mut self, steps: &TVector<u32>, width: u32) {
+        let tick_period_by_axis = steps.map_all(|c| match c {
+            Some(c) if c > 0 => Some(width / c),
+            _ => None,
+        });
+        self.set_width(width);
+        self.set_max_count(steps);
+        #[cfg(feature = "with-x-axis")]
+        self.set_channel_ticks(StepperChannel::X, tick_period_by_axis.x);
+        #[cfg(feature = "with-y-axis")]
+        self.set_channel_ticks(StepperChannel::Y, tick_period_by_axis.y);
+        #[cfg(feature = "with-z-axis")]
+        self.set_channel_ticks(StepperChannel::Z, tick_period_by_axis.z);
+        #[cfg(feature = "with-e-axis")]
+        self.set_channel_ticks(StepperChannel::E, tick_period_by_axis.e);
+    }
+
+    /// Spreads the given steps of the E channel uniformly along the width, leaving the other channels as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `steps` - The number of steps of the E channel. It is disabled if there are no steps.
+    #[cfg(feature = "with-e-axis")]
+    pub fn set_extruder_steps(&mut self, steps: u32) {
+        self.max_count.set_coord(CoordSel::E, Some(steps));
+        self.set_channel_ticks(StepperChannel::E, match steps {
+            0 => None,
+            _ => Some(self.width / steps),
+        });
+    }
+
     /// Gets the width of the MultiTimer.
     ///
     /// # Returns
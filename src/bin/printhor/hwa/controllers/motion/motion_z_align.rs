@@ -0,0 +1,78 @@
+//! Z alignment (G34): The Z motors of a gantry with several of them (See [crate::hwa::StepperChannel::mirrored])
+//! are levelled by probing the bed close to each one and moving them on their own by the differences, until
+//! the heights probed converge within a tolerance.
+//!
+//! The machine is homed before, and Z is homed again afterwards, as its height has changed
+//! (See [crate::hwa::drivers::MotionDriver::z_align_action]).
+use crate::math;
+use crate::math::Real;
+use crate::tgeo::TVector;
+
+/// The Z motors aligned: The primary one and its secondary
+pub const Z_ALIGN_MOTORS: usize = 2;
+
+/// The Z alignment settings
+#[derive(Clone, Copy)]
+pub struct ZAlignConfig {
+    /// Where the bed is probed for each Z motor, close to it (X and Y, in mm) (M422)
+    pub points: [TVector<Real>; Z_ALIGN_MOTORS],
+    /// The height Z is raised to before moving between the points, in mm
+    pub clearance: Real,
+    /// The highest difference between the heights probed for the motors to be aligned, in mm
+    pub tolerance: Real,
+    /// The most rounds of probing and correction before giving up
+    pub max_iterations: u8,
+}
+
+impl ZAlignConfig {
+    /// No points and no rounds. They are to be set
+    pub const fn new() -> Self {
+        Self {
+            points: [TVector::new(); Z_ALIGN_MOTORS],
+            clearance: Real::zero(),
+            tolerance: Real::zero(),
+            max_iterations: 0,
+        }
+    }
+
+    /// The correction of each Z motor (in mm, upwards) for the `heights` probed at its point, or None when
+    /// they are aligned within the tolerance.
+    ///
+    /// The higher the bed is probed, the lower the gantry is there: Each motor goes up by the difference
+    /// with the lowest height, so the gantry ends as high as its highest side.
+    pub fn corrections(&self, heights: &[Real; Z_ALIGN_MOTORS]) -> Option<[Real; Z_ALIGN_MOTORS]> {
+        let lowest = heights.iter().fold(heights[0], |lowest, h| lowest.min(*h));
+        let highest = heights.iter().fold(heights[0], |highest, h| highest.max(*h));
+        if highest - lowest <= self.tolerance {
+            return None;
+        }
+        let mut corrections = [math::ZERO; Z_ALIGN_MOTORS];
+        for (correction, height) in corrections.iter_mut().zip(heights.iter()) {
+            *correction = *height - lowest;
+        }
+        Some(corrections)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn z_align_corrections() {
+        let mut z_align = ZAlignConfig::new();
+        z_align.tolerance = Real::from_f32(0.02);
+
+        // The side of the second motor is 0.3 mm lower
+        let corrections = z_align.corrections(&[Real::from_f32(1.0), Real::from_f32(1.3)]).unwrap();
+        assert!(corrections[0].is_zero());
+        assert!((corrections[1] - Real::from_f32(0.3)).abs() < Real::from_f32(0.0001));
+        let corrections = z_align.corrections(&[Real::from_f32(-0.5), Real::from_f32(-0.6)]).unwrap();
+        assert!((corrections[0] - Real::from_f32(0.1)).abs() < Real::from_f32(0.0001));
+        assert!(corrections[1].is_zero());
+
+        // Aligned within the tolerance
+        assert!(z_align.corrections(&[Real::from_f32(1.0), Real::from_f32(1.01)]).is_none());
+        assert!(z_align.corrections(&[math::ZERO, math::ZERO]).is_none());
+    }
+}
@@ -8,8 +8,9 @@ use embedded_sdmmc::{
 use printhor_hwa_common::ControllerMutex;
 use printhor_hwa_common::{StandardControllerMutex, TrackedStaticCell};
 
-const MAX_DIRS: usize = 3usize;
-const MAX_FILES: usize = 1usize;
+const MAX_DIRS: usize = 4usize;
+/// The print job plus the nested macros
+const MAX_FILES: usize = 4usize;
 
 #[cfg(feature = "sdcard-uses-spi")]
 pub type SDCardBlockDevice = hwa::adapters::SPIAdapter<hwa::device::SpiCardCSPin>;
@@ -663,6 +664,18 @@ impl SDCardStream {
             current_byte_index: 0,
         }
     }
+
+    /// Closes the file and the directories of its path
+    async fn release(&mut self) {
+        if let Some(file) = self.file.take() {
+            if self.card_controller.close_file(file).await.is_err() {
+                hwa::error!("Unexpected error closing file");
+            }
+        }
+        while let Some(dir) = self.path.pop() {
+            self.card_controller.close_dir(dir).await;
+        }
+    }
 }
 
 impl async_gcode::ByteStream for SDCardStream {
@@ -677,7 +690,8 @@ impl async_gcode::ByteStream for SDCardStream {
             self.current_byte_index = 0;
             self.bytes_read = 0;
             let result = match self.file.as_mut() {
-                None => Err(SDCardError::NotFound),
+                // Already released
+                None => return None,
                 Some(f) => {
                     self.card_controller.read(f, &mut self.buffer).await
                 }
@@ -693,10 +707,16 @@ impl async_gcode::ByteStream for SDCardStream {
                         // FIXME not correct
                         self.bytes_read = 0;
                         self.current_byte_index = 0;
+                        // EOF: The file and its directories can be opened again
+                        self.release().await;
                         None
                     }
                 },
-                Err(_) => Some(Err(async_gcode::Error::NumberOverflow)),
+                Err(_) => {
+                    // The file cannot be read any further: It is released, so the stream ends
+                    self.release().await;
+                    Some(Err(async_gcode::Error::NumberOverflow))
+                }
             }
         }
     }
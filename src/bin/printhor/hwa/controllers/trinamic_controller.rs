@@ -2,6 +2,14 @@
 use crate::hwa;
 use embassy_time::Instant;
 use hwa::soft_uart::SerialError;
+use hwa::StepperChannel;
+
+/// The TCOOLTHRS set while StallGuard is armed: It is active at any speed
+const STALLGUARD_TCOOLTHRS: u32 = 0xFFFFF;
+
+/// The time between two polls of SG_RESULT through the UART, when the stall is not signaled by DIAG
+#[cfg(feature = "trinamic-stallguard-uart")]
+const STALLGUARD_POLL_PERIOD: embassy_time::Duration = embassy_time::Duration::from_millis(10);
 
 /// Represents errors that can occur in the Trinamic UART controller.
 #[allow(unused)]
@@ -32,6 +40,8 @@ pub struct TrinamicController {
     /// of the stepper motors, such as microstep configurations and possibly other
     /// parameters needed to correctly drive the motors.
     motion_config: hwa::controllers::MotionConfigRef,
+    /// The motors with StallGuard armed on their drivers, as virtual endstops (See [TrinamicController::arm_stallguard]).
+    armed: StepperChannel,
 }
 impl TrinamicController {
     pub const fn new(
@@ -41,6 +51,7 @@ impl TrinamicController {
         Self {
             uart,
             motion_config,
+            armed: StepperChannel::empty(),
         }
     }
 
@@ -93,6 +104,19 @@ impl TrinamicController {
                 }
             }
         }
+        // The secondary Z motor of the machines without extruder is on the E driver (as in the SKR Mini E3)
+        #[cfg(all(feature = "with-z2-axis", not(feature = "with-e-axis")))]
+        {
+            #[cfg(feature = "trinamic-uart-multi-channel")]
+            self.uart.set_axis_channel(Some(hwa::device::AxisChannel::TMCUartE));
+            if self
+                .init_stepper(3, get_microsteps(mcfg.micro_steps_per_axis[2]))
+                .await
+                .is_ok()
+            {
+                hwa::info!("Trinamic_uart Z2 init OK");
+            }
+        }
 
         Ok(())
     }
@@ -104,18 +128,8 @@ impl TrinamicController {
     ) -> Result<(), TrinamicError> {
         hwa::debug!("Trinamic_uart applying gconf on {}", addr);
 
-        let mut gconf = tmc2209::reg::GCONF::default();
-        // Invert direction
-        gconf.set_shaft(false);
-        // Enable UART Comm
-        gconf.set_pdn_disable(true);
         // Enable Spread Cycle: Higher Torque, more noise
-        gconf.set_en_spread_cycle(true);
-        // Enable multistepping
-        gconf.set_mstep_reg_select(true);
-        // Enable multistepping filtering
-        gconf.set_multistep_filt(false);
-        if self.write_register(addr, gconf).await.is_ok() {
+        if self.write_register(addr, gconf(true)).await.is_ok() {
             hwa::debug!("Trinamic_uart applying chopconf on {}", addr);
             let mut chopconf = tmc2209::reg::CHOPCONF::default();
             chopconf.set_intpol(false);
@@ -132,6 +146,112 @@ impl TrinamicController {
         Ok(())
     }
 
+    /// Arms StallGuard on the drivers of the motors of the `axis` (0 for X to 2 for Z), with its threshold (M914),
+    /// so a stall of each motor is signaled as its endstop while homing.
+    ///
+    /// StallGuard4 only works in StealthChop, so the drivers leave Spread Cycle until disarmed.
+    pub async fn arm_stallguard(&mut self, axis: usize) -> Result<(), TrinamicError> {
+        let threshold = self.motion_config.lock().await.stallguard_threshold[axis];
+        for motor in axis_motors(axis).iter() {
+            let addr = self.select_motor(motor);
+            hwa::debug!("Trinamic_uart arming StallGuard on {} (SGTHRS={})", addr, threshold);
+            self.write_register(addr, gconf(false)).await?;
+            self.write_register(addr, tmc2209::reg::TCOOLTHRS(STALLGUARD_TCOOLTHRS)).await?;
+            self.write_register(addr, tmc2209::reg::SGTHRS(threshold.into())).await?;
+            self.armed.insert(motor);
+        }
+        Ok(())
+    }
+
+    /// Disarms StallGuard on the drivers of the motors of the `axis`, back to Spread Cycle.
+    /// All of them are disarmed, even if one fails
+    pub async fn disarm_stallguard(&mut self, axis: usize) -> Result<(), TrinamicError> {
+        let mut result = Ok(());
+        for motor in axis_motors(axis).iter() {
+            self.armed.remove(motor);
+            let addr = self.select_motor(motor);
+            hwa::debug!("Trinamic_uart disarming StallGuard on {}", addr);
+            let disarmed = match self.write_register(addr, tmc2209::reg::TCOOLTHRS(0)).await {
+                Ok(_) => self.write_register(addr, gconf(true)).await,
+                err => err,
+            };
+            if disarmed.is_err() {
+                result = disarmed;
+            }
+        }
+        result
+    }
+
+    /// Whether every motor of the `axis` has a driver, so a sensorless homing can stop each one on its
+    /// own stall (squaring the axis)
+    pub fn stallguard_supported(axis: usize) -> bool {
+        axis_motors(axis) == axis_channel(axis).mirrored()
+    }
+
+    /// Keeps the motors of the `channels` with StallGuard armed and stalled in `stalls`, until dropped.
+    ///
+    /// With DIAG wired to the endstop pins (SKR Mini E3), the stall is seen as an endstop and there is
+    /// nothing to watch. Otherwise (`trinamic-stallguard-uart`), SG_RESULT is polled through the UART
+    /// every [STALLGUARD_POLL_PERIOD], along with the steps instead of between them: The motor is
+    /// stalled when it is below twice SGTHRS.
+    #[allow(unused)]
+    pub async fn watch_stalls(&mut self, channels: StepperChannel, stalls: &core::cell::Cell<StepperChannel>) {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "trinamic-stallguard-uart")] {
+                let thresholds = self.motion_config.lock().await.stallguard_threshold;
+                loop {
+                    embassy_time::Timer::after(STALLGUARD_POLL_PERIOD).await;
+                    let mut stalled = StepperChannel::empty();
+                    for (axis, threshold) in thresholds.iter().enumerate() {
+                        for motor in (axis_motors(axis) & self.armed & channels).iter() {
+                            let addr = self.select_motor(motor);
+                            match self.read_register::<tmc2209::reg::SG_RESULT>(addr).await {
+                                Ok(sg_result) => stalled.set(motor, sg_result.0 <= 2 * u32::from(*threshold)),
+                                Err(_) => hwa::warn!("Trinamic_uart unable to read SG_RESULT of {}", addr),
+                            }
+                        }
+                    }
+                    stalls.set(stalled);
+                }
+            }
+            else {
+                core::future::pending::<()>().await
+            }
+        }
+    }
+
+    /// Selects the UART channel of the driver of the `axis` (if several), returning its address
+    fn select_axis(&mut self, axis: usize) -> u8 {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "trinamic-uart-multi-channel")] {
+                self.uart.set_axis_channel(Some(match axis {
+                    0 => hwa::device::AxisChannel::TMCUartX,
+                    1 => hwa::device::AxisChannel::TMCUartY,
+                    _ => hwa::device::AxisChannel::TMCUartZ,
+                }));
+            }
+        }
+        axis as u8
+    }
+
+    /// Selects the UART channel of the driver of the `motor` (if several), returning its address.
+    /// The secondary Z motor of the machines without extruder is on the E driver (See [TrinamicController::init])
+    fn select_motor(&mut self, motor: StepperChannel) -> u8 {
+        #[cfg(all(feature = "with-z2-axis", not(feature = "with-e-axis")))]
+        if motor == StepperChannel::Z2 {
+            #[cfg(feature = "trinamic-uart-multi-channel")]
+            self.uart.set_axis_channel(Some(hwa::device::AxisChannel::TMCUartE));
+            return 3;
+        }
+        if motor == StepperChannel::X {
+            self.select_axis(0)
+        } else if motor == StepperChannel::Y {
+            self.select_axis(1)
+        } else {
+            self.select_axis(2)
+        }
+    }
+
     #[inline]
     async fn write_register<T: tmc2209::WritableRegister>(
         &mut self,
@@ -216,6 +336,38 @@ impl TrinamicController {
     }
 }
 
+/// The general configuration of the drivers, in Spread Cycle (higher torque, more noise) or StealthChop
+fn gconf(spread_cycle: bool) -> tmc2209::reg::GCONF {
+    let mut gconf = tmc2209::reg::GCONF::default();
+    // Invert direction
+    gconf.set_shaft(false);
+    // Enable UART Comm
+    gconf.set_pdn_disable(true);
+    gconf.set_en_spread_cycle(spread_cycle);
+    // Enable multistepping
+    gconf.set_mstep_reg_select(true);
+    // Enable multistepping filtering
+    gconf.set_multistep_filt(false);
+    gconf
+}
+
+fn axis_channel(axis: usize) -> StepperChannel {
+    match axis {
+        0 => StepperChannel::X,
+        1 => StepperChannel::Y,
+        _ => StepperChannel::Z,
+    }
+}
+
+/// The motors of the `axis` with a driver of their own
+fn axis_motors(axis: usize) -> StepperChannel {
+    #[allow(unused_mut)]
+    let mut motors = axis_channel(axis);
+    #[cfg(all(feature = "with-z2-axis", not(feature = "with-e-axis")))]
+    motors.set(StepperChannel::Z2, motors.contains(StepperChannel::Z));
+    motors
+}
+
 fn get_microsteps(msteps: u16) -> u32 {
     match msteps {
         1 => 8,
@@ -12,10 +12,15 @@ use core::ops::Neg;
 use embassy_time::Duration;
 
 use hwa::controllers::motion;
+use hwa::controllers::motion::Kinematics;
 use hwa::{InterruptControllerRef, StepperChannel};
 
 pub type MotionDriverRef = InterruptControllerRef<MotionDriver>;
 
+// The Z motors are aligned moving each one on its own, so Z has to be driven by motors of its own
+#[cfg(all(feature = "with-z2-axis", any(feature = "kinematics-corexz", feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar")))]
+compile_error!("with-z2-axis requires a kinematics with motors of its own for Z");
+
 #[cfg(feature = "with-motion")]
 pub struct MotionDriverParams {
     pub motion_device: hwi::device::MotionDevice,
@@ -101,37 +106,40 @@ impl MotionDriver {
         self.tmon.swap(PinState::USCLK)
     }
 
+    // The secondary motors of an axis (if any) mirror the primary one (See [StepperChannel::mirrored]).
+    // They are only driven on their own through the pins (squaring when homing, Z alignment)
+
     #[inline(always)]
     pub fn disable_steppers(&mut self, channels: StepperChannel) {
-        self.pins.disable(channels);
+        self.pins.disable(channels.mirrored());
     }
 
     #[inline(always)]
     pub fn enable_steppers(&mut self, channels: StepperChannel) {
-        self.pins.enable(channels);
+        self.pins.enable(channels.mirrored());
     }
 
     #[inline(always)]
     pub fn set_forward_direction(&mut self, channels: StepperChannel) {
-        self.pins.set_forward_direction(channels);
+        self.pins.set_forward_direction(channels.mirrored());
     }
 
     #[allow(unused)]
     #[inline(always)]
     pub fn step_toggle(&mut self, channels: StepperChannel) {
-        self.pins.step_toggle(channels);
+        self.pins.step_toggle(channels.mirrored());
     }
 
     #[allow(unused)]
     #[inline(always)]
     pub fn step_high(&mut self, channels: StepperChannel) {
-        self.pins.step_high(channels);
+        self.pins.step_high(channels.mirrored());
     }
 
     #[allow(unused)]
     #[inline(always)]
     pub fn step_low(&mut self, channels: StepperChannel) {
-        self.pins.step_low(channels);
+        self.pins.step_low(channels.mirrored());
     }
 
     #[inline(always)]
@@ -167,15 +175,18 @@ impl MotionDriver {
     /// This method performs the homing action for the stepper motors.
     ///
     /// The homing action involves moving the stepper motors to a known reference 
-    /// position. This is typically done by moving the motors towards the end-stop 
-    /// switches and then setting the current position to zero, which serves as a 
-    /// reference point for future movements.
+    /// position. Each axis is moved towards its end-stop switch (or the probe, for Z),
+    /// backed off and approached again slowly, as described by the homing settings of
+    /// the motion configuration (See [motion::HomingConfig]). Its position is then the
+    /// one of its end, which serves as a reference point for future movements.
     ///
     /// # Arguments
     ///
     /// * `motion_config_ref` - A reference to the motion configuration which contains
     ///                         parameters such as units per millimeter, micro-steps 
-    ///                         per axis, and machine bounds.
+    ///                         per axis, machine bounds and the homing settings.
+    /// * `axes` - The axes to home (all of them if none of X, Y and Z).
+    /// * `position` - The current position, kept by the axes not homed.
     ///
     /// # Returns
     ///
@@ -185,7 +196,7 @@ impl MotionDriver {
     /// # Examples
     ///
     /// ```
-    /// let result = controller.homing_action(&motion_config_ref).await;
+    /// let result = controller.homing_action(&motion_config_ref, CoordSel::X | CoordSel::Y, position).await;
     /// match result {
     ///     Ok(position) => println!("New homing position: {:?}", position),
     ///     Err(err_position) => eprintln!("Homing failed at position: {:?}", err_position),
@@ -198,27 +209,26 @@ impl MotionDriver {
     /// process, such as if an end-stop switch is not triggered within the expected 
     /// range of motion.
     // TODO: Carefully review
+    #[cfg(not(any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar")))]
     pub async fn homing_action(
         &mut self,
         motion_config_ref: &motion::MotionConfigRef,
+        axes: CoordSel,
+        position: TVector<Real>,
     ) -> Result<TVector<Real>, TVector<Real>> {
 
         #[cfg(feature = "trace-commands")]
         hwa::info!("[Homing]");
 
-        let mut homming_position = TVector::zero();
+        let mut homming_position = position;
 
         let motion_config = motion_config_ref.lock().await;
-        let steps_per_mm = motion_config.units_per_mm
-            * TVector::from_coords(
-            Some(Real::from_lit(motion_config.micro_steps_per_axis[0].into(), 0)),
-            Some(Real::from_lit(motion_config.micro_steps_per_axis[1].into(), 0)),
-            Some(Real::from_lit(motion_config.micro_steps_per_axis[2].into(), 0)),
-            None,
-        );
+        let steps_per_mm = Self::homing_steps_per_mm(&motion_config);
         #[cfg(feature = "trace-commands")]
         hwa::info!("[Homing] Steps per mm: {}", steps_per_mm);
         let machine_bounds = motion_config.machine_bounds;
+        let homing = motion_config.homing;
+        let probe_z_offset = motion_config.probe_offset.z.unwrap_or(math::ZERO);
         drop(motion_config);
 
         #[cfg(feature = "trace-commands")]
@@ -227,165 +237,540 @@ impl MotionDriver {
         #[cfg(feature = "trace-commands")]
         hwa::info!("[Homing] - Assuming at: {}", homming_position);
 
-        // Raise Z axis 10mm to avoid obstacles during X and Y homing
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] Raise Z +10mm");
-        self.shabbily_move_to(
-            TVector::from_coords(None, None, Some(math::ONE), None),
-            Real::from_lit(10, 0),
-            steps_per_mm,
-            2000,
-            false,
-            Some(&mut homming_position),
-        ).await;
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] - Now at: {}", homming_position);
+        let coords = [CoordSel::X, CoordSel::Y, CoordSel::Z];
+        let bounds = [machine_bounds.x, machine_bounds.y, machine_bounds.z];
+        // Whether Z is away from its endstop, so the other axes can be homed
+        let mut z_raised = false;
+
+        for axis in homing.sequence(axes).iter().flatten() {
+            let axis = *axis;
+            let axis_homing = homing.axes[axis];
+
+            if axis != 2 && !z_raised && homing.z_raise.is_positive() {
+                // Raise Z axis to avoid obstacles during X and Y homing
+                #[cfg(feature = "trace-commands")]
+                hwa::info!("[Homing] Raise Z {} mm", homing.z_raise);
+                let up = TVector::from_coords(None, None, Some(homing.axes[2].sign().neg()), None);
+                self.shabbily_move_to(
+                    up,
+                    homing.z_raise,
+                    steps_per_mm,
+                    Self::homing_step_frequency(&up, homing.axes[2].fast_speed, &steps_per_mm),
+                    false,
+                    false,
+                    Some(&mut homming_position),
+                ).await;
+                #[cfg(feature = "trace-commands")]
+                hwa::info!("[Homing] - Now at: {}", homming_position);
+                z_raised = true;
+            }
 
-        // Home the X axis quickly
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] Quick Homing X axis with a max of -{} mm", machine_bounds.x.unwrap_or(math::ZERO));
-        self.shabbily_move_to(
-            TVector::from_coords(Some(math::ONE.neg()), None, None, None),
-            machine_bounds.x.unwrap_or(Real::zero()),
-            steps_per_mm,
-            2000,
-            true,
-            None,
-        ).await;
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] - Now at: {}", homming_position);
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "with-probe")] {
+                    if axis_homing.use_probe && axis == 2 {
+                        self.probe_controller.lock().await.probe_pin_down(100).await;
+                    }
+                }
+            }
 
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] Quick Separate X axis 5 mm");
-        // Home the X axis precisely
-        self.shabbily_move_to(
-            TVector::from_coords(Some(math::ONE), None, None, None),
-            Real::from_lit(5, 0),
-            steps_per_mm,
-            1000,
-            false,
-            Some(&mut homming_position),
-        ).await;
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] - Now at: {}", homming_position);
+            let towards = TVector::new().with_coord(coords[axis], Some(axis_homing.sign()));
+            let away = towards * math::ONE.neg();
+            let bound = bounds[axis].unwrap_or(math::ZERO);
+            // Each motor of the axis stops at its own endstop (or stall, if sensorless), squaring it.
+            // There is a single probe, though
+            let square = !axis_homing.use_probe;
 
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] Slow approximate X axis up to 6 mm");
+            // The stall of each motor is its endstop on a sensorless axis
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "with-trinamic")] {
+                    let sensorless = axis_homing.sensorless;
+                    if sensorless && !hwa::controllers::TrinamicController::stallguard_supported(axis) {
+                        hwa::error!("[Homing] Axis {} has motors without a driver to home sensorless", axis);
+                        return Err(homming_position);
+                    }
+                    if sensorless && self.trinamic_controller.arm_stallguard(axis).await.is_err() {
+                        hwa::error!("[Homing] Unable to arm StallGuard on axis {}", axis);
+                        let _ = self.trinamic_controller.disarm_stallguard(axis).await;
+                        return Err(homming_position);
+                    }
+                }
+                else {
+                    let sensorless = false;
+                }
+            }
 
-        // Home the X axis precisely
-        self.shabbily_move_to(
-            TVector::from_coords(Some(math::ONE.neg()), None, None, None),
-            Real::from_lit(6, 0),
-            steps_per_mm,
-            1000,
-            true,
-            Some(&mut homming_position),
-        ).await;
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] - Now at: {}", homming_position);
+            #[cfg(feature = "trace-commands")]
+            hwa::info!("[Homing] Quick homing of axis {} with a max of {} mm", axis, bound);
+            self.shabbily_move_to(
+                towards,
+                bound,
+                steps_per_mm,
+                Self::homing_step_frequency(&towards, axis_homing.fast_speed, &steps_per_mm),
+                true,
+                square,
+                None,
+            ).await;
+
+            if !sensorless {
+                #[cfg(feature = "trace-commands")]
+                hwa::info!("[Homing] Quick separate axis {} {} mm", axis, axis_homing.bump);
+                self.shabbily_move_to(
+                    away,
+                    axis_homing.bump,
+                    steps_per_mm,
+                    Self::homing_step_frequency(&away, axis_homing.fast_speed, &steps_per_mm),
+                    false,
+                    false,
+                    None,
+                ).await;
+
+                #[cfg(feature = "trace-commands")]
+                hwa::info!("[Homing] Slow approximate axis {} up to {} mm", axis, axis_homing.bump * math::TWO);
+                self.shabbily_move_to(
+                    towards,
+                    axis_homing.bump * math::TWO,
+                    steps_per_mm,
+                    Self::homing_step_frequency(&towards, axis_homing.slow_speed, &steps_per_mm),
+                    true,
+                    square,
+                    None,
+                ).await;
+            }
+
+            #[cfg(feature = "with-trinamic")]
+            if sensorless && self.trinamic_controller.disarm_stallguard(axis).await.is_err() {
+                hwa::error!("[Homing] Unable to disarm StallGuard on axis {}", axis);
+            }
+
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "with-probe")] {
+                    if axis_homing.use_probe && axis == 2 {
+                        self.probe_controller.lock().await.probe_pin_up(100).await;
+                    }
+                }
+            }
+
+            homming_position = homming_position.with_coord(
+                coords[axis],
+                Some(homing.home_position(axis, bound, probe_z_offset)),
+            );
+            if axis == 2 {
+                z_raised = false;
+            }
+            #[cfg(feature = "trace-commands")]
+            hwa::info!("[Homing] - Now at: {}", homming_position);
+        }
 
-        // Home the Y axis quickly
         #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] Quick Homing Y axis with a max of -{} mm", machine_bounds.y.unwrap_or(math::ZERO));
-        self.shabbily_move_to(
-            TVector::from_coords(None, Some(math::ONE.neg()), None, None),
-            machine_bounds.y.unwrap_or(Real::zero()),
-            steps_per_mm,
-            2000,
-            true,
+        hwa::info!("[Homing] Done. Finally at: {}", homming_position);
+
+        Ok(homming_position)
+    }
+
+    /// The step frequency (in Hz) of the fastest motor to move along `vdir` (in tool space) at `speed` mm/s
+    #[cfg(not(any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar")))]
+    fn homing_step_frequency(vdir: &TVector<Real>, speed: Real, steps_per_mm: &TVector<Real>) -> u64 {
+        let motor_vdir = motion::MACHINE_KINEMATICS.tool_to_motor(vdir);
+        let steps_per_second = (motor_vdir * *steps_per_mm).abs().vmax().unwrap_or(math::ZERO) * speed;
+        steps_per_second.to_i32().unwrap_or(0).max(1) as u64
+    }
+
+    /// The steps per mm of the X, Y and Z motors
+    fn homing_steps_per_mm(motion_config: &motion::MotionConfig) -> TVector<Real> {
+        motion_config.units_per_mm
+            * TVector::from_coords(
+            Some(Real::from_lit(motion_config.micro_steps_per_axis[0].into(), 0)),
+            Some(Real::from_lit(motion_config.micro_steps_per_axis[1].into(), 0)),
+            Some(Real::from_lit(motion_config.micro_steps_per_axis[2].into(), 0)),
             None,
-        ).await;
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] - Now at: {}", homming_position);
+        )
+    }
 
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] Quick Separate Y axis 5 mm");
-        // Home the Y axis precisely
-        self.shabbily_move_to(
-            TVector::from_coords(None, Some(math::ONE), None, None),
-            Real::from_lit(5, 0),
-            steps_per_mm,
-            2000,
-            false,
-            Some(&mut homming_position),
-        ).await;
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] - Now at: {}", homming_position);
+    /// Aligns the Z motors (G34) of a machine already homed.
+    ///
+    /// The bed is probed at the point of each Z motor (See [motion::ZAlignConfig]), with Z raised to
+    /// the clearance between them. Each motor is then moved on its own by its correction, and the
+    /// bed probed again, until the heights are within the tolerance or the rounds are exhausted.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` with the position once aligned, or an error with it if the motors did not converge.
+    /// Z is to be homed again either way, as its height has changed.
+    #[cfg(feature = "with-z2-axis")]
+    pub async fn z_align_action(
+        &mut self,
+        motion_config_ref: &motion::MotionConfigRef,
+        position: TVector<Real>,
+    ) -> Result<TVector<Real>, TVector<Real>> {
+        let motion_config = motion_config_ref.lock().await;
+        let steps_per_mm = Self::homing_steps_per_mm(&motion_config);
+        let z_align = motion_config.z_align;
+        let probe_offset = motion_config.probe_offset.map_nan(&math::ZERO);
+        let homing = motion_config.homing;
+        drop(motion_config);
 
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] Slow approximate Y axis up to 6 mm");
-        // Home the X axis precisely
-        self.shabbily_move_to(
-            TVector::from_coords(None, Some(math::ONE.neg()), None, None),
-            Real::from_lit(6, 0),
-            steps_per_mm,
-            1000,
-            true,
-            Some(&mut homming_position),
-        ).await;
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] - Now at: {}", homming_position);
+        let mut position = position.map_nan(&math::ZERO);
+        let z_steps_per_mm = steps_per_mm.z.unwrap_or(math::ONE);
+
+        for round in 0..z_align.max_iterations {
+            let mut heights = [math::ZERO; motion::Z_ALIGN_MOTORS];
+            for (height, point) in heights.iter_mut().zip(z_align.points.iter()) {
+                // Up to the clearance, then over the point (with the probe) one axis at a time
+                let clearance = z_align.clearance - position.z.unwrap_or(math::ZERO);
+                self.shabbily_move_axis(CoordSel::Z, clearance, homing.axes[2].fast_speed, &steps_per_mm, false, &mut position).await;
+                let targets = [
+                    (CoordSel::X, point.x, probe_offset.x, position.x),
+                    (CoordSel::Y, point.y, probe_offset.y, position.y),
+                ];
+                for (axis, (coord, target, offset, current)) in targets.iter().enumerate() {
+                    let delta = target.unwrap_or(math::ZERO) - offset.unwrap_or(math::ZERO) - current.unwrap_or(math::ZERO);
+                    self.shabbily_move_axis(*coord, delta, homing.axes[axis].fast_speed, &steps_per_mm, false, &mut position).await;
+                }
 
-        // TODO:
-        // Go to centerç
-        #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] Upper Z + 10mm");
+                cfg_if::cfg_if! {
+                    if #[cfg(feature = "with-probe")] {
+                        self.probe_controller.lock().await.probe_pin_down(100).await;
+                    }
+                }
+                // Down until the probe triggers, a bit past the lowest Z so it is not missed
+                let depth = (position.z.unwrap_or(math::ZERO) + z_align.clearance).neg();
+                self.shabbily_move_axis(CoordSel::Z, depth, homing.axes[2].slow_speed, &steps_per_mm, true, &mut position).await;
+                *height = position.z.unwrap_or(math::ZERO);
+                cfg_if::cfg_if! {
+                    if #[cfg(feature = "with-probe")] {
+                        self.probe_controller.lock().await.probe_pin_up(100).await;
+                    }
+                }
+            }
+
+            match z_align.corrections(&heights) {
+                None => {
+                    hwa::info!("[G34] Z motors aligned after {} corrections", round);
+                    return Ok(position);
+                }
+                Some(corrections) => {
+                    #[cfg(feature = "trace-commands")]
+                    hwa::info!("[G34] Round {}: Heights {} {}", round, heights[0], heights[1]);
+                    let motors = [StepperChannel::Z, StepperChannel::Z2];
+                    for (motor, correction) in motors.iter().zip(corrections.iter()) {
+                        self.shabbily_step_motor(*motor, *correction, homing.axes[2].slow_speed, z_steps_per_mm).await;
+                    }
+                }
+            }
+        }
+        hwa::warn!("[G34] Z motors not aligned within {} rounds", z_align.max_iterations);
+        Err(position)
+    }
+
+    /// Moves the tool along a single `axis` by `delta` mm (signed) at `speed` mm/s, keeping track of the
+    /// `position`
+    #[cfg(feature = "with-z2-axis")]
+    async fn shabbily_move_axis(
+        &mut self,
+        axis: CoordSel,
+        delta: Real,
+        speed: Real,
+        steps_per_mm: &TVector<Real>,
+        check_endstops: bool,
+        position: &mut TVector<Real>,
+    ) {
+        if delta.is_zero() {
+            return;
+        }
+        let vdir = TVector::new().with_coord(axis, Some(delta.sign()));
         self.shabbily_move_to(
-            TVector::from_coords(None, None, Some(math::ONE.neg()), None),
-            Real::from_lit(10, 0),
-            steps_per_mm,
-            1000,
+            vdir,
+            delta.abs(),
+            *steps_per_mm,
+            Self::homing_step_frequency(&vdir, speed, steps_per_mm),
+            check_endstops,
             false,
-            Some(&mut homming_position),
+            Some(position),
         ).await;
+    }
+
+    /// Steps a single `motor` by `distance` mm (upwards when positive) at `speed` mm/s, while the other ones
+    /// of its axis stay
+    #[cfg(feature = "with-z2-axis")]
+    async fn shabbily_step_motor(&mut self, motor: StepperChannel, distance: Real, speed: Real, steps_per_mm: Real) {
+        let steps = (distance.abs() * steps_per_mm).round().to_i32().unwrap_or(0);
+        if steps <= 0 {
+            return;
+        }
+        let step_frequency = (steps_per_mm * speed).to_i32().unwrap_or(0).max(1) as u64;
+        self.pins.enable(motor);
+        match distance.is_positive() {
+            true => self.pins.set_forward_direction(motor),
+            false => self.pins.set_forward_direction(StepperChannel::empty()),
+        }
+        let mut ticker = embassy_time::Ticker::every(Duration::from_hz(step_frequency));
+        for _ in 0..steps {
+            self.pins.step_toggle(motor);
+            ticker.next().await;
+        }
+    }
+
+    /// Performs the homing of a linear delta: The three carriages go up together at the fast speed, each one
+    /// stopping at its endstop, then they back off the bump distance and approach their endstops again at the
+    /// slow speed. The settings of each tower are the ones of its channel (See [motion::HomingConfig]).
+    ///
+    /// The position is then the one of the homed carriages (See [motion::DeltaKinematics::homed_motor_pos]),
+    /// plus the Z home offset (M206). As every axis depends on the three towers, they are homed together
+    /// whatever the axes requested. It is an error if any carriage does not reach its endstop, or if the
+    /// home position is out of reach of the geometry (checked before moving, keeping the position).
+    #[cfg(feature = "kinematics-delta")]
+    pub async fn homing_action(
+        &mut self,
+        motion_config_ref: &motion::MotionConfigRef,
+        _axes: CoordSel,
+        position: TVector<Real>,
+    ) -> Result<TVector<Real>, TVector<Real>> {
+
         #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] - Now at: {}", homming_position);
+        hwa::info!("[Homing] Delta");
 
-        cfg_if::cfg_if! {
-            if #[cfg(feature = "with-probe")] {
-                self.probe_controller.lock().await.probe_pin_down(100).await;
-            }
+        let motion_config = motion_config_ref.lock().await;
+        let steps_per_mm = Self::homing_steps_per_mm(&motion_config);
+        let kinematics = motion_config.kinematics;
+        let homing = motion_config.homing;
+        drop(motion_config);
+
+        let homed_motor_pos = kinematics.homed_motor_pos();
+        let home = kinematics.motor_to_tool(&homed_motor_pos);
+        if [homed_motor_pos.x, homed_motor_pos.y, homed_motor_pos.z, home.x, home.y, home.z].iter().any(Option::is_none) {
+            hwa::error!("[Homing] The home position is out of reach of the geometry (M665, M666)");
+            return Err(position);
         }
+        // The carriages cannot be higher over Z = 0 than when homed
+        let max_travel = homed_motor_pos.vmax().unwrap_or(math::ZERO);
 
-        // FIXME: As of now, until tested in real hardware, for safety
-        let zbounds = homming_position.z.unwrap_or(math::ZERO)
-            + machine_bounds.z.unwrap_or(math::ONE)  / Real::from_lit(16, 0);
         #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] Lower Z up to {} mm", zbounds); // reduced for safety
-        self.shabbily_move_to(
-            TVector::from_coords(None, None, Some(math::ONE.neg()), None),
-            zbounds,
-            steps_per_mm,
-            1000,
-            true,
-            None,
-        ).await;
+        hwa::info!("[Homing] Towers with a max of {} mm", max_travel);
+        let up = TVector::from_coords(Some(max_travel), Some(max_travel), Some(max_travel), None);
+        let homed = self.home_motors(up, &homing, steps_per_mm).await;
+
+        let homming_position = home.with_coord(CoordSel::Z, home.z.map(|z| z + homing.axes[2].offset));
+
         #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] - Now at: {}", homming_position);
+        hwa::info!("[Homing] Done. Finally at: {}", homming_position);
 
-        cfg_if::cfg_if! {
-            if #[cfg(feature = "with-probe")] {
-                self.probe_controller.lock().await.probe_pin_up(100).await;
-            }
+        match homed {
+            true => Ok(homming_position),
+            false => Err(homming_position),
         }
+    }
+
+    /// Performs the homing of a SCARA or polar machine, in the order of the homing sequence
+    /// (See [motion::HomingConfig]):
+    /// - The X and Y channel motors (when X or Y are requested) go together to their (angular) endstops, at
+    ///   the end given by the direction of X and Y, each one stopping at its own, then back off and approach
+    ///   them again slowly. Z is raised first, if not homed yet. The speeds and the bump of each motor are the
+    ///   ones of its channel, in units (degrees for the angular ones) instead of mm.
+    /// - Z (when requested) goes to its endstop (or the probe) as in a cartesian machine.
+    ///
+    /// The position is then the one of the homed motors (See [motion::ScaraKinematics::homed_motor_pos]
+    /// and [motion::PolarKinematics::homed_motor_pos]), plus the Z home offset (M206). The axes not homed
+    /// keep their position. It is an error if any motor does not reach its endstop.
+    #[cfg(any(feature = "kinematics-scara", feature = "kinematics-polar"))]
+    pub async fn homing_action(
+        &mut self,
+        motion_config_ref: &motion::MotionConfigRef,
+        axes: CoordSel,
+        position: TVector<Real>,
+    ) -> Result<TVector<Real>, TVector<Real>> {
 
         #[cfg(feature = "trace-commands")]
-        hwa::info!("[Homing] Upper Z + 10mm again por safety");
-        self.shabbily_move_to(
-            TVector::from_coords(None, None, Some(math::ONE), None),
-            Real::from_lit(10, 0),
-            steps_per_mm,
-            2000,
-            false,
-            Some(&mut homming_position),
-        ).await;
+        hwa::info!("[Homing] Angular");
+
+        let motion_config = motion_config_ref.lock().await;
+        // Steps per degree in the angular motors
+        let steps_per_unit = Self::homing_steps_per_mm(&motion_config);
+        let kinematics = motion_config.kinematics;
+        let machine_bounds = motion_config.machine_bounds;
+        let homing = motion_config.homing;
+        let probe_z_offset = motion_config.probe_offset.z.unwrap_or(math::ZERO);
+        drop(motion_config);
+
+        let mut homming_position = position;
+        let mut homed = true;
+        let mut z_raised = false;
+        let mut arms_homed = false;
+        let z_bound = machine_bounds.z.unwrap_or(math::ZERO);
+
+        for axis in homing.sequence(axes).iter().flatten() {
+            match *axis {
+                0 | 1 => {
+                    if arms_homed {
+                        continue;
+                    }
+                    if !z_raised && homing.z_raise.is_defined_positive() {
+                        #[cfg(feature = "trace-commands")]
+                        hwa::info!("[Homing] Raise Z {} mm", homing.z_raise);
+                        let raise = homing.z_raise * homing.axes[2].sign().neg();
+                        let speed = TVector::from_coords(None, None, Some(homing.axes[2].fast_speed), None);
+                        self.shabbily_move_motors(
+                            TVector::from_coords(None, None, Some(raise), None),
+                            math::ONE,
+                            steps_per_unit,
+                            Self::motors_step_frequency(&speed, &steps_per_unit),
+                            false,
+                        ).await;
+                        homming_position = homming_position.with_coord(
+                            CoordSel::Z, homming_position.z.map(|z| z + raise),
+                        );
+                        z_raised = true;
+                    }
+                    let max_travel = kinematics.max_homing_travel();
+                    let towards = TVector::from_coords(
+                        max_travel.x.map(|t| t * homing.axes[0].sign()),
+                        max_travel.y.map(|t| t * homing.axes[1].sign()),
+                        None,
+                        None,
+                    );
+                    #[cfg(feature = "trace-commands")]
+                    hwa::info!("[Homing] X and Y motors with a max of {}", max_travel);
+                    homed = self.home_motors(towards, &homing, steps_per_unit).await && homed;
+                    let home = kinematics.motor_to_tool(&kinematics.homed_motor_pos());
+                    homming_position = homming_position.with_coord(CoordSel::X, home.x).with_coord(CoordSel::Y, home.y);
+                    arms_homed = true;
+                }
+                _ => {
+                    cfg_if::cfg_if! {
+                        if #[cfg(feature = "with-probe")] {
+                            if homing.axes[2].use_probe {
+                                self.probe_controller.lock().await.probe_pin_down(100).await;
+                            }
+                        }
+                    }
+                    #[cfg(feature = "trace-commands")]
+                    hwa::info!("[Homing] Z with a max of {} mm", z_bound);
+                    let towards = TVector::from_coords(None, None, Some(z_bound * homing.axes[2].sign()), None);
+                    homed = self.home_motors(towards, &homing, steps_per_unit).await && homed;
+                    cfg_if::cfg_if! {
+                        if #[cfg(feature = "with-probe")] {
+                            if homing.axes[2].use_probe {
+                                self.probe_controller.lock().await.probe_pin_up(100).await;
+                            }
+                        }
+                    }
+                    homming_position = homming_position.with_coord(
+                        CoordSel::Z, Some(homing.home_position(2, z_bound, probe_z_offset)),
+                    );
+                    z_raised = false;
+                }
+            }
+        }
 
         #[cfg(feature = "trace-commands")]
         hwa::info!("[Homing] Done. Finally at: {}", homming_position);
 
-        Ok(homming_position)
+        match homed {
+            true => Ok(homming_position),
+            false => Err(homming_position),
+        }
+    }
+
+    /// Homes the motors given in `towards` (in motor space, each one with its longest travel to its endstop,
+    /// signed): Each one goes to its endstop at the fast speed, backs off the bump distance and approaches it
+    /// again at the slow speed, with the settings of its channel. The result is whether all of them reached it
+    #[cfg(any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar"))]
+    async fn home_motors(
+        &mut self,
+        towards: TVector<Real>,
+        homing: &motion::HomingConfig,
+        steps_per_mm: TVector<Real>,
+    ) -> bool {
+        // The settings of the motors to home
+        let setting = |value: fn(&motion::AxisHoming) -> Real| {
+            TVector::from_coords(
+                towards.x.map(|_| value(&homing.axes[0])),
+                towards.y.map(|_| value(&homing.axes[1])),
+                towards.z.map(|_| value(&homing.axes[2])),
+                None,
+            )
+        };
+        let fast_frequency = Self::motors_step_frequency(&setting(|a| a.fast_speed), &steps_per_mm);
+        let slow_frequency = Self::motors_step_frequency(&setting(|a| a.slow_speed), &steps_per_mm);
+        let bump = towards.map_coords(|t| Some(t.sign())) * setting(|a| a.bump);
+
+        self.shabbily_move_motors(towards, math::ONE, steps_per_mm, fast_frequency, true).await;
+        self.shabbily_move_motors(bump * math::ONE.neg(), math::ONE, steps_per_mm, fast_frequency, false).await;
+        self.shabbily_move_motors(bump * math::TWO, math::ONE, steps_per_mm, slow_frequency, true).await
     }
 
+    /// The step frequency (in Hz) of the motors moved together at `speeds` (in units/s each one): The one of the
+    /// slowest, as they step at once
+    #[cfg(any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar"))]
+    fn motors_step_frequency(speeds: &TVector<Real>, steps_per_mm: &TVector<Real>) -> u64 {
+        let steps_per_second = (*speeds * *steps_per_mm).abs().vmin().unwrap_or(math::ZERO);
+        steps_per_second.to_i32().unwrap_or(0).max(1) as u64
+    }
+
+    /// Moves the X, Y and Z channel motors along `vdir` (in motor space) up to `module` units,
+    /// each one independently (the towers of a linear delta, the arms of a SCARA...).
+    ///
+    /// When `check_endstops`, each motor stops at its own endstop, and the result is whether
+    /// all the motors moved reached it.
+    #[cfg(any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar"))]
+    async fn shabbily_move_motors(
+        &mut self,
+        vdir: TVector<Real>,
+        module: Real,
+        steps_per_mm: TVector<Real>,
+        step_frequency: u64,
+        check_endstops: bool,
+    ) -> bool {
+        let steps_to_advance: TVector<u32> = (vdir * module * steps_per_mm)
+            .abs()
+            .round()
+            .map_coords(|c| c.to_i32().and_then(|c| Some(c as u32)));
+        let motors = [StepperChannel::X, StepperChannel::Y, StepperChannel::Z];
+        let mut steps_left = [
+            steps_to_advance.x.unwrap_or(0),
+            steps_to_advance.y.unwrap_or(0),
+            steps_to_advance.z.unwrap_or(0),
+        ];
+        let mut moved = StepperChannel::empty();
+        for (motor, left) in motors.iter().zip(steps_left.iter()) {
+            moved.set(*motor, *left > 0);
+        }
+
+        self.enable_and_set_dir(&vdir);
+
+        let mut ticker = embassy_time::Ticker::every(Duration::from_hz(step_frequency));
+
+        loop {
+            let mut channel = StepperChannel::empty();
+            for (motor, left) in motors.iter().zip(steps_left.iter_mut()) {
+                if *left > 0 && !(check_endstops && self.endstop_triggered(*motor)) {
+                    channel.set(*motor, true);
+                    *left -= 1;
+                }
+            }
+            if channel.is_empty() {
+                break;
+            }
+            cfg_if::cfg_if! {
+                if #[cfg(feature="pulsed")] {
+                    compile_error!("Not yet implemented");
+                }
+                else {
+                    self.step_toggle(channel);
+                }
+            }
+            ticker.next().await;
+        }
+        !check_endstops || motors.iter().all(|motor| !moved.contains(*motor) || self.endstop_triggered(*motor))
+    }
+
+    /// Moves the tool along `vdir` (in tool space) up to `module` mm, driving the motors
+    /// according to the machine kinematics. The endstops checked are the ones of the tool axes
+    /// involved.
+    ///
+    /// When `square` and the axis moved is driven by a single motor with a secondary one (See
+    /// [StepperChannel::mirrored]), each one of them stops at its own endstop instead, so they end up
+    /// aligned. The motors of several axes (CoreXY, CoreXZ) stop at the endstop of the tool axis.
+    #[cfg(not(any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar")))]
     async fn shabbily_move_to(
         &mut self,
         vdir: TVector<Real>,
@@ -393,15 +778,31 @@ impl MotionDriver {
         steps_per_mm: TVector<Real>,
         step_frequency: u64,
         check_endstops: bool,
+        square: bool,
         position: Option<&mut TVector<Real>>,
     ) -> TVector<u32> {
-        let steps_to_advance: TVector<u32> = (vdir * module * steps_per_mm)
+        let motor_vdir = motion::MACHINE_KINEMATICS.tool_to_motor(&vdir);
+        let steps_to_advance: TVector<u32> = (motor_vdir * module * steps_per_mm)
             .abs()
             .round()
             .map_coords(|c| c.to_i32().and_then(|c| Some(c as u32)));
         let mut steps_advanced: TVector<u32> = TVector::zero();
 
-        self.enable_and_set_dir(&vdir);
+        self.enable_and_set_dir(&motor_vdir);
+
+        let mut endstop_channel = StepperChannel::empty();
+        vdir.apply_coords(|(coord, v)| {
+            let applied = !v.is_zero();
+            if coord.contains(CoordSel::X) {
+                endstop_channel.set(StepperChannel::X, applied);
+            }
+            if coord.contains(CoordSel::Y) {
+                endstop_channel.set(StepperChannel::Y, applied);
+            }
+            if coord.contains(CoordSel::Z) {
+                endstop_channel.set(StepperChannel::Z, applied);
+            }
+        });
 
         let mut channel = StepperChannel::empty();
         let mut coord_sel = CoordSel::empty();
@@ -421,45 +822,99 @@ impl MotionDriver {
             }
         });
 
-        let mut ticker = embassy_time::Ticker::every(Duration::from_hz(step_frequency));
-
-        loop {
-            let mut completed = false;
-            if check_endstops && self.endstop_triggered(channel) {
-                hwa::debug!("ENDSTOP TRIGGERED");
-                completed = true;
-            }
-            if !steps_advanced.bounded_by(&steps_to_advance) {
-                hwa::debug!("FULL ADV");
-                completed = true;
-            }
-            if completed {
-                match position {
-                    Some(mut _p) => {
-                        *_p += vdir
-                            * steps_advanced.map_coords(|c| Some(Real::from_lit(c.into(), 0)))
-                            / steps_per_mm;
+        // The primary and secondary motors squared, if any, and the ones of them stopped at their endstop
+        let squared = match check_endstops && square {
+            true => Self::squared_motors(channel, endstop_channel),
+            false => None,
+        };
+        let mut stopped = StepperChannel::empty();
+        // The motors stalled, when the stall is not signaled by their endstops (See [hwa::controllers::TrinamicController::watch_stalls])
+        let stalls = core::cell::Cell::new(StepperChannel::empty());
+
+        let pins = &mut self.pins;
+        let moving = async {
+            let mut ticker = embassy_time::Ticker::every(Duration::from_hz(step_frequency));
+            loop {
+                let mut completed = false;
+                match squared {
+                    Some(motors) => {
+                        for motor in motors.iter() {
+                            if pins.endstop_triggered(motor) || stalls.get().contains(motor) {
+                                stopped.insert(motor);
+                            }
+                        }
+                        if stopped == motors {
+                            hwa::debug!("ENDSTOPS TRIGGERED");
+                            completed = true;
+                        }
+                    }
+                    None => {
+                        if check_endstops && (pins.endstop_triggered(endstop_channel) || stalls.get().intersects(channel.mirrored())) {
+                            hwa::debug!("ENDSTOP TRIGGERED");
+                            completed = true;
+                        }
                     }
-                    None => {}
                 }
+                if !steps_advanced.bounded_by(&steps_to_advance) {
+                    hwa::debug!("FULL ADV");
+                    completed = true;
+                }
+                if completed {
+                    match position {
+                        Some(mut _p) => {
+                            let motor_displacement = motor_vdir.map_coords(|c| Some(c.sign()))
+                                * steps_advanced.map_coords(|c| Some(Real::from_lit(c.into(), 0)))
+                                / steps_per_mm;
+                            *_p += motion::MACHINE_KINEMATICS.motor_to_tool(&motor_displacement);
+                        }
+                        None => {}
+                    }
 
-                return steps_advanced;
-            }
-            cfg_if::cfg_if! {
-                if #[cfg(feature="pulsed")] {
-                    compile_error!("Not yet implemented");
-                    self.step_high(channel);
-                    // TODO
-                    //crate::control::motion_timing::s_block_for(Duration::from_micros(1));
-                    self.step_low(channel);
+                    return steps_advanced;
+                }
+                cfg_if::cfg_if! {
+                    if #[cfg(feature="pulsed")] {
+                        compile_error!("Not yet implemented");
+                        pins.step_high(channel);
+                        // TODO
+                        //crate::control::motion_timing::s_block_for(Duration::from_micros(1));
+                        pins.step_low(channel);
 
+                    }
+                    else {
+                        pins.step_toggle(channel.mirrored().difference(stopped));
+                    }
                 }
-                else {
-                    self.step_toggle(channel);
+                steps_advanced.increment(coord_sel, 1);
+                ticker.next().await;
+            }
+        };
+
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "with-trinamic")] {
+                let watching = match check_endstops {
+                    true => self.trinamic_controller.watch_stalls(channel.mirrored(), &stalls),
+                    false => self.trinamic_controller.watch_stalls(StepperChannel::empty(), &stalls),
+                };
+                match embassy_futures::select::select(moving, watching).await {
+                    embassy_futures::select::Either::First(steps_advanced) => steps_advanced,
+                    embassy_futures::select::Either::Second(_) => unreachable!("the stalls are watched until dropped"),
                 }
             }
-            steps_advanced.increment(coord_sel, 1);
-            ticker.next().await;
+            else {
+                moving.await
+            }
+        }
+    }
+
+    /// The primary and secondary motors to square when the motor `channel` moves the tool axis of the
+    /// `endstop_channel` on its own, or None if that axis has no secondary motor or shares its motors
+    #[cfg(not(any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar")))]
+    fn squared_motors(channel: StepperChannel, endstop_channel: StepperChannel) -> Option<StepperChannel> {
+        let motors = channel.mirrored();
+        match channel == endstop_channel && channel.bits().count_ones() == 1 && motors != channel {
+            true => Some(motors),
+            false => None,
         }
     }
 }
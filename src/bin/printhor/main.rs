@@ -403,6 +403,91 @@ async fn spawn_tasks(
         motion_planer.set_machine_bounds(200, 200, 200).await;
         motion_planer.set_flow_rate(100).await;
         motion_planer.set_speed_rate(100).await;
+        // Max. deviation of the arc chords: 0.01mm
+        motion_planer.set_arc_chord_tolerance(math::Real::new(1, 2)).await;
+        // Max. deviation of the Bezier curve lines: 0.01mm
+        motion_planer.set_bezier_max_deviation(math::Real::new(1, 2)).await;
+        // Firmware retraction: 3mm at 45mm/s, without Z hop
+        motion_planer.motion_cfg().lock().await.retraction = hwa::controllers::RetractionConfig {
+            length: math::Real::new(3, 0),
+            speed: math::Real::new(45, 0),
+            ..hwa::controllers::RetractionConfig::new()
+        };
+        // Soft endstops: The machine bounds, from zero. Disabled until M211 S1, then the moves out of them are rejected
+        #[cfg(not(any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar")))]
+        {
+            let cfg = motion_planer.motion_cfg();
+            let mut cfg_g = cfg.lock().await;
+            let machine_bounds = cfg_g.machine_bounds;
+            cfg_g.soft_endstops.set_limits(&machine_bounds.map_val(&math::Real::zero()), &machine_bounds);
+        }
+        // Homing: X, Y and Z at their min. end, with 5mm bumps, raising Z 10mm first
+        #[cfg(not(any(feature = "kinematics-delta", feature = "kinematics-scara", feature = "kinematics-polar")))]
+        {
+            let cfg = motion_planer.motion_cfg();
+            let mut cfg_g = cfg.lock().await;
+            let homing = &mut cfg_g.homing;
+            homing.z_raise = math::Real::new(10, 0);
+            // X and Y at 25mm/s, then 12.5mm/s
+            for axis_homing in homing.axes[0..2].iter_mut() {
+                axis_homing.fast_speed = math::Real::new(25, 0);
+                axis_homing.slow_speed = math::Real::new(125, 1);
+                axis_homing.bump = math::Real::new(5, 0);
+            }
+            // Z at 5mm/s, then 2.5mm/s, with the probe if any
+            homing.axes[2].fast_speed = math::Real::new(5, 0);
+            homing.axes[2].slow_speed = math::Real::new(25, 1);
+            homing.axes[2].bump = math::Real::new(5, 0);
+            homing.axes[2].use_probe = cfg!(feature = "with-probe");
+        }
+        // Z alignment: Probing at 10% and 90% of X, in the middle of Y, up to 5 rounds until within 0.02mm
+        #[cfg(feature = "with-z2-axis")]
+        {
+            let cfg = motion_planer.motion_cfg();
+            let mut cfg_g = cfg.lock().await;
+            let bounds = cfg_g.machine_bounds.map_nan(&math::Real::zero());
+            let y = bounds.y.map(|y| y * math::Real::new(5, 1));
+            cfg_g.z_align.points = [
+                tgeo::TVector::from_coords(bounds.x.map(|x| x * math::Real::new(1, 1)), y, None, None),
+                tgeo::TVector::from_coords(bounds.x.map(|x| x * math::Real::new(9, 1)), y, None, None),
+            ];
+            cfg_g.z_align.clearance = math::Real::new(10, 0);
+            cfg_g.z_align.tolerance = math::Real::new(2, 2);
+            cfg_g.z_align.max_iterations = 5;
+        }
+        // StallGuard thresholds of the sensorless homing. Enable it (M210 H1) in the axes without switches
+        // (with DIAG wired to the endstop pins, or `trinamic-stallguard-uart`)
+        #[cfg(feature = "with-trinamic")]
+        {
+            motion_planer.motion_cfg().lock().await.stallguard_threshold = [75, 75, 75];
+        }
+        // Delta: 250mm rods, 125mm radius, 300mm high, 100mm of print radius and 200 lines per second
+        #[cfg(feature = "kinematics-delta")]
+        motion_planer.motion_cfg().lock().await.kinematics.set_geometry(
+            Some(math::Real::new(250, 0)),
+            Some(math::Real::new(125, 0)),
+            Some(math::Real::new(300, 0)),
+            Some(math::Real::new(100, 0)),
+            Some(math::Real::new(200, 0)),
+            [None, None, None],
+        );
+        // SCARA: 150mm and 100mm arms, shoulder at (0, -100) homed at 0 and 90 degrees and 200 lines per second
+        #[cfg(feature = "kinematics-scara")]
+        motion_planer.motion_cfg().lock().await.kinematics.set_geometry(
+            Some(math::Real::new(150, 0)),
+            Some(math::Real::new(100, 0)),
+            [Some(math::Real::new(0, 0)), Some(math::Real::new(-100, 0))],
+            [Some(math::Real::new(0, 0)), Some(math::Real::new(90, 0))],
+            Some(math::Real::new(200, 0)),
+        );
+        // Polar: 100mm of bed radius, homed at 0 degrees and at the centre and 200 lines per second
+        #[cfg(feature = "kinematics-polar")]
+        motion_planer.motion_cfg().lock().await.kinematics.set_geometry(
+            Some(math::Real::new(100, 0)),
+            Some(math::Real::new(0, 0)),
+            Some(math::Real::new(0, 0)),
+            Some(math::Real::new(200, 0)),
+        );
 
         spawner
             .spawn(control::task_stepper::task_stepper(
@@ -427,16 +512,38 @@ async fn spawn_tasks(
         ))
         .map_err(|_| ())?;
 
+    #[cfg(feature = "with-serial-usb")]
+    spawner
+        .spawn(control::task_input_scan::task_input_scan_usb(
+            _io_devices.serial_usb_rx_stream,
+            processor.clone(),
+        ))
+        .map_err(|_| ())?;
+    #[cfg(feature = "with-serial-port-1")]
+    spawner
+        .spawn(control::task_input_scan::task_input_scan_port1(
+            _io_devices.serial_port1_rx_stream,
+            processor.clone(),
+        ))
+        .map_err(|_| ())?;
+    #[cfg(feature = "with-serial-port-2")]
+    spawner
+        .spawn(control::task_input_scan::task_input_scan_port2(
+            _io_devices.serial_port2_rx_stream,
+            processor.clone(),
+        ))
+        .map_err(|_| ())?;
+
     spawner
         .spawn(control::task_control::task_control(
             processor.clone(),
             control::GCodeMultiplexedInputStream::new(
                 #[cfg(feature = "with-serial-usb")]
-                _io_devices.serial_usb_rx_stream,
+                control::ScannedInputStream::new(&control::task_input_scan::SERIAL_USB_INPUT),
                 #[cfg(feature = "with-serial-port-1")]
-                _io_devices.serial_port1_rx_stream,
+                control::ScannedInputStream::new(&control::task_input_scan::SERIAL_PORT1_INPUT),
                 #[cfg(feature = "with-serial-port-2")]
-                _io_devices.serial_port2_rx_stream,
+                control::ScannedInputStream::new(&control::task_input_scan::SERIAL_PORT2_INPUT),
             ),
             ControlTaskControllers {
                 #[cfg(feature = "with-printjob")]
@@ -195,6 +195,17 @@ cfg_if::cfg_if! {
                 Real(self.0.cos())
             }
 
+            #[inline]
+            pub fn sin(self) -> Self {
+                Real(self.0.sin())
+            }
+
+            /// Four quadrant arctangent of self (y) and x
+            #[inline]
+            pub fn atan2(self, x: Self) -> Self {
+                Real(self.0.atan2(x.0))
+            }
+
             #[inline]
             pub fn ln(self) -> Self {
                 Real(self.0.ln())
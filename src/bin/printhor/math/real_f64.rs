@@ -153,6 +153,17 @@ cfg_if::cfg_if! {
                 Real(micromath::F32(self.0 as f32).cos().0 as f64)
             }
 
+            #[inline]
+            pub(crate) fn sin(self) -> Self {
+                Real(micromath::F32(self.0 as f32).sin().0 as f64)
+            }
+
+            /// Four quadrant arctangent of self (y) and x
+            #[inline]
+            pub(crate) fn atan2(self, x: Self) -> Self {
+                Real(micromath::F32(self.0 as f32).atan2(micromath::F32(x.0 as f32)).0 as f64)
+            }
+
             #[inline]
             pub(crate) fn ln(self) -> Self {
                 Real(micromath::F32(self.0 as f32).ln().0 as f64)
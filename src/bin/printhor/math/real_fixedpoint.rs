@@ -135,6 +135,20 @@ cfg_if::cfg_if! {
                 Real(self.0.cos())
             }
 
+            #[inline]
+            pub(crate) fn sin(self) -> Self {
+                Real(self.0.sin())
+            }
+
+            /// Four quadrant arctangent of self (y) and x
+            /// Note: There is no atan in rust_decimal, so it is computed in f32
+            #[inline]
+            pub(crate) fn atan2(self, x: Self) -> Self {
+                let y = micromath::F32(self.0.to_f32().unwrap_or(0.0f32));
+                let x = micromath::F32(x.0.to_f32().unwrap_or(0.0f32));
+                Real::from_f32(y.atan2(x).0)
+            }
+
             #[inline]
             pub(crate) fn ln(self) -> Self {
                 Real(self.0.ln())